@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod tests {
+    use iz80::Machine;
+
+    use crate::floppy_controller::Drive;
+    use crate::kaypro_machine::{KayproMachine, VideoMode};
+    use crate::test_support::{machine, temp_path};
+
+    /// TurboROM machine with the Advent board and a DSDD image in drive C.
+    fn machine_with_drive_c(path: &str) -> KayproMachine {
+        let mut image = vec![0xE5; 40 * 2 * 10 * 512];
+        image[..512].fill(0xCC);
+        std::fs::write(path, image).unwrap();
+        let mut machine = machine("roms/trom34.rom", VideoMode::Sy6545Crtc, false);
+        machine.floppy_controller.attach_drive(Drive::C, path).unwrap();
+        machine.advent_pio_enabled = true;
+        machine
+    }
+
+    #[test]
+    fn test_advent_drive_presence() {
+        let path = temp_path("advent_presence.img");
+        let mut machine = machine_with_drive_c(&path);
+        let present: Vec<bool> = (1..=5).map(|select| {
+            machine.port_out(0x8A, select);
+            machine.port_in(0x8B) & 0x04 == 0
+        }).collect();
+        assert_eq!(present, [true, true, true, false, false]);
+
+        // Drive D isn't connected: NOT READY
+        machine.port_out(0x8A, 4);
+        assert_eq!(machine.floppy_controller.drive, 3);
+        assert_eq!(machine.port_in(0x10) & 0x80, 0x80);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_advent_select_reads_drive_c() {
+        let path = temp_path("advent_select.img");
+        let mut machine = machine_with_drive_c(&path);
+        machine.port_out(0x8A, 3);
+        // Native select lines left clear keep the Advent select...
+        machine.port_out(0x14, 0x5C);
+        assert_eq!(machine.floppy_controller.drive, 2);
+        assert_eq!(machine.port_in(0x10) & 0x80, 0);
+
+        machine.port_out(0x12, 0);
+        machine.port_out(0x10, 0x80); // READ SECTOR
+        let data: Vec<u8> = (0..512).map(|_| machine.port_in(0x13)).collect();
+        assert_eq!(data, vec![0xCC; 512]);
+
+        // ... until it selects a native drive again
+        machine.port_out(0x8A, 1);
+        machine.port_out(0x14, 0x5D);
+        assert_eq!(machine.floppy_controller.drive, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_native_select_takes_over_from_advent() {
+        let path = temp_path("advent_native.img");
+        let mut machine = machine_with_drive_c(&path);
+        machine.port_out(0x8A, 3);
+        assert_eq!(machine.floppy_controller.drive, 2);
+
+        // Drive A on the native select lines
+        machine.port_out(0x14, 0x5E);
+        assert_eq!(machine.floppy_controller.drive, 0);
+        // Clearing them again doesn't bring drive C back
+        machine.port_out(0x14, 0x5C);
+        assert_eq!(machine.floppy_controller.drive, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
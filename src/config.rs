@@ -143,6 +143,28 @@ pub struct Config {
     
     /// Disk image for drive B (optional, overrides model default)
     pub disk_b: Option<String>,
+
+    /// Drive A mechanism, e.g. "40ds" or "80ds,double-step" (optional,
+    /// the drive follows the inserted media if not set)
+    pub drive_a_type: Option<String>,
+
+    /// Drive B mechanism (optional)
+    pub drive_b_type: Option<String>,
+
+    /// Disk images for the third and fourth floppy on the Advent
+    /// personality board (optional, TurboROM models only)
+    pub disk_c: Option<String>,
+    pub disk_d: Option<String>,
+
+    /// Advent RAM disk card size, e.g. "1M" (optional, TurboROM models only)
+    pub ram_disk: Option<String>,
+
+    /// File that keeps the RAM disk contents across runs (optional)
+    pub ram_disk_file: Option<String>,
+
+    /// Hard disk drive, e.g. "st225" or "612/4/17" (optional, overrides
+    /// the model default)
+    pub hd_geometry: Option<String>,
 }
 
 impl Default for Config {
@@ -155,6 +177,13 @@ impl Default for Config {
             side1_sector_base: None,
             disk_a: None,
             disk_b: None,
+            drive_a_type: None,
+            drive_b_type: None,
+            disk_c: None,
+            disk_d: None,
+            ram_disk: None,
+            ram_disk_file: None,
+            hd_geometry: None,
         }
     }
 }
@@ -332,6 +361,30 @@ impl Config {
         }
     }
     
+    /// Get the hard disk drive for this configuration. The Kaypro 10 and
+    /// the bundled TurboROM images use the 10MB ST-412.
+    pub fn get_hd_geometry(&self) -> &str {
+        self.hd_geometry.as_deref().unwrap_or("st412")
+    }
+
+    /// Get the Advent RAM disk size for this configuration, if it has one.
+    /// The card needs TurboROM; the Ultimate preset fits a 1M card unless
+    /// the size is "none".
+    pub fn get_ram_disk_size(&self) -> Option<&str> {
+        let size = match (self.model, self.ram_disk.as_deref()) {
+            (_, Some("none")) => None,
+            (KayproModel::Ultimate, None) => Some("1M"),
+            (_, size) => size,
+        };
+        match self.model {
+            KayproModel::TurboRom
+            | KayproModel::TurboRomHd
+            | KayproModel::Ultimate
+            | KayproModel::Custom => size,
+            _ => None,
+        }
+    }
+
     /// Get a description of this configuration
     pub fn get_description(&self) -> String {
         match self.model {
@@ -347,6 +400,21 @@ impl Config {
         }
     }
     
+    /// Get the model name as written in the config file and on the command line
+    pub fn get_model_name(&self) -> &'static str {
+        match self.model {
+            KayproModel::KayproII => "kaypro_ii",
+            KayproModel::Kaypro4_83 => "kaypro4_83",
+            KayproModel::Kaypro4_84 => "kaypro4_84",
+            KayproModel::TurboRom => "turbo_rom",
+            KayproModel::TurboRomHd => "turbo_rom_hd",
+            KayproModel::Ultimate => "ultimate",
+            KayproModel::KayPlus84 => "kayplus_84",
+            KayproModel::Kaypro10 => "kaypro10",
+            KayproModel::Custom => "custom",
+        }
+    }
+
     /// Get a short user-friendly name for display in the emulator title
     pub fn get_display_name(&self) -> &str {
         match self.model {
@@ -0,0 +1,732 @@
+// CP/M 2.2 filesystem access to disk images from the host side.
+//
+// The directory and allocation logic works on any `CpmVolume`, a flat
+// sequence of logical tracks of 128-byte records, as seen by the BDOS
+// after the BIOS sector translation.  `MediaVolume` adapts a floppy
+// `Media` using the Disk Parameter Block that matches its `DiskGeometry`.
+
+use std::io::{Error, ErrorKind, Result};
+
+use crate::media::{self, DiskGeometry, Media};
+
+pub const RECORD_SIZE: usize = 128;
+const DIR_ENTRY_SIZE: usize = 32;
+const EMPTY: u8 = 0xE5;
+/// CP/M text end-of-file marker, used to pad the last record on `put`
+const CTRL_Z: u8 = 0x1A;
+
+/// CP/M 2.2 Disk Parameter Block.
+#[derive(Clone, Copy, Debug)]
+pub struct Dpb {
+    /// 128-byte records per logical track
+    pub spt: u16,
+    /// Block shift: block size = 128 << bsh
+    pub bsh: u8,
+    pub blm: u8,
+    /// Extent mask: logical 16K extents per directory entry, minus one
+    pub exm: u8,
+    /// Highest block number
+    pub dsm: u16,
+    /// Highest directory entry number
+    pub drm: u16,
+    /// Directory allocation bitmap (blocks reserved for the directory)
+    pub al0: u8,
+    pub al1: u8,
+    /// Reserved (system) tracks before the directory
+    pub off: u16,
+}
+
+impl Dpb {
+    /// Derive a DPB the way a BIOS author would: the data area is whatever
+    /// is left after `off` reserved tracks, rounded down to whole blocks.
+    /// CP/M has no 1K blocks on disks of more than 256 blocks.
+    pub fn new(spt: u16, tracks: u16, off: u16, block_size: usize, dir_entries: usize) -> Result<Dpb> {
+        let invalid = |what: String| Error::new(ErrorKind::InvalidInput, what);
+        let bsh = (block_size / RECORD_SIZE).trailing_zeros() as u8;
+        let blm = ((block_size / RECORD_SIZE) - 1) as u8;
+        let data_records = tracks.saturating_sub(off) as usize * spt as usize;
+        let dsm = (data_records * RECORD_SIZE / block_size).checked_sub(1)
+            .ok_or_else(|| invalid(format!("No data blocks after {} reserved tracks", off)))? as u16;
+        let exm = if dsm < 256 {
+            block_size / 1024 - 1
+        } else {
+            (block_size / 2048).checked_sub(1).ok_or_else(|| invalid(format!(
+                "{} blocks of {} bytes, more than 256 need 2K blocks", dsm as usize + 1, block_size)))?
+        } as u8;
+        let dir_blocks = (dir_entries * DIR_ENTRY_SIZE).div_ceil(block_size);
+        let al: u16 = !(0xFFFFu16 >> dir_blocks);
+        Ok(Dpb {
+            spt,
+            bsh,
+            blm,
+            exm,
+            dsm,
+            drm: (dir_entries - 1) as u16,
+            al0: (al >> 8) as u8,
+            al1: al as u8,
+            off,
+        })
+    }
+
+    /// A DPB as the BIOS stores it: 15 bytes, words little-endian.
+    pub fn from_bytes(bytes: &[u8]) -> Dpb {
+        let word = |i: usize| bytes[i] as u16 | (bytes[i + 1] as u16) << 8;
+        Dpb {
+            spt: word(0),
+            bsh: bytes[2],
+            blm: bytes[3],
+            exm: bytes[4],
+            dsm: word(5),
+            drm: word(7),
+            al0: bytes[9],
+            al1: bytes[10],
+            off: word(13),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        RECORD_SIZE << self.bsh
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.dsm as usize + 1
+    }
+
+    pub fn dir_entries(&self) -> usize {
+        self.drm as usize + 1
+    }
+
+    pub fn records_per_block(&self) -> usize {
+        self.blm as usize + 1
+    }
+
+    /// Disks with more than 256 blocks use 16-bit block pointers,
+    /// 8 per directory entry instead of 16.
+    pub fn wide_pointers(&self) -> bool {
+        self.dsm > 255
+    }
+
+    pub fn pointers_per_entry(&self) -> usize {
+        if self.wide_pointers() { 8 } else { 16 }
+    }
+
+    /// Records addressed by one directory entry.
+    pub fn records_per_entry(&self) -> usize {
+        (self.exm as usize + 1) * 128
+    }
+
+    /// Blocks reserved for the directory, the first ones of the data area.
+    pub fn dir_blocks(&self) -> usize {
+        ((self.al0 as u16) << 8 | self.al1 as u16).count_ones() as usize
+    }
+
+    /// Translate a record number within the data area to (track, record).
+    fn locate(&self, block: usize, record_in_block: usize) -> (usize, usize) {
+        let abs = block * self.records_per_block() + record_in_block;
+        (self.off as usize + abs / self.spt as usize, abs % self.spt as usize)
+    }
+}
+
+/// Logical record storage beneath the CP/M directory.
+pub trait CpmVolume {
+    fn read_record(&self, track: usize, record: usize, buf: &mut [u8; RECORD_SIZE]) -> Result<()>;
+    fn write_record(&mut self, track: usize, record: usize, buf: &[u8; RECORD_SIZE]) -> Result<()>;
+}
+
+// ── Floppy formats ───────────────────────────────────────────────────────────
+
+/// CP/M parameters for one of the floppy geometries in `media`.
+/// Logical tracks are single sides: on double-sided disks, logical track
+/// 2t is cylinder t side 0 and 2t+1 is cylinder t side 1, which matches the
+/// side-interleaved image layout.
+struct FloppyFormat {
+    /// Name for `mkdisk --geometry`
+    name: &'static str,
+    geometry: DiskGeometry,
+    /// Reserved logical tracks (OFF)
+    off: u16,
+    block_size: usize,
+    dir_entries: usize,
+    /// BIOS sector skew in physical sectors (1 = no translation)
+    skew: u8,
+}
+
+const FLOPPY_FORMATS: [FloppyFormat; 10] = [
+    FloppyFormat { name: "kaypro_sssd", geometry: media::GEOM_KAYPRO_SSSD, off: 3, block_size: 2048, dir_entries: 64, skew: 2 },
+    FloppyFormat { name: "kaypro_ssdd", geometry: media::GEOM_KAYPRO_SSDD, off: 1, block_size: 1024, dir_entries: 64, skew: 1 },
+    FloppyFormat { name: "kaypro_dsdd", geometry: media::GEOM_KAYPRO_DSDD, off: 1, block_size: 2048, dir_entries: 64, skew: 1 },
+    FloppyFormat { name: "kayplus_dsdd", geometry: media::GEOM_KAYPLUS_DSDD, off: 1, block_size: 2048, dir_entries: 64, skew: 1 },
+    FloppyFormat { name: "advent_ssdd", geometry: media::GEOM_ADVENT_SSDD, off: 3, block_size: 1024, dir_entries: 64, skew: 1 },
+    // NOTE: Advent DSDD and QX-10 parameters need verification with real images.
+    FloppyFormat { name: "advent_dsdd48", geometry: media::GEOM_ADVENT_DSDD48, off: 6, block_size: 2048, dir_entries: 128, skew: 1 },
+    FloppyFormat { name: "advent_dsdd96", geometry: media::GEOM_ADVENT_DSDD96, off: 6, block_size: 2048, dir_entries: 128, skew: 1 },
+    FloppyFormat { name: "microcornucopia", geometry: media::GEOM_MICROCORNUCOPIA, off: 4, block_size: 2048, dir_entries: 128, skew: 1 },
+    FloppyFormat { name: "xerox820", geometry: media::GEOM_XEROX_820, off: 3, block_size: 1024, dir_entries: 32, skew: 5 },
+    FloppyFormat { name: "qx10", geometry: media::GEOM_EPSON_QX10, off: 4, block_size: 2048, dir_entries: 128, skew: 1 },
+];
+
+fn floppy_format(geom: &DiskGeometry) -> Option<&'static FloppyFormat> {
+    FLOPPY_FORMATS.iter().find(|f| f.geometry.same_layout(geom))
+}
+
+/// Geometry preset by its `mkdisk` name.
+pub fn geometry_by_name(name: &str) -> Option<DiskGeometry> {
+    FLOPPY_FORMATS.iter().find(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.geometry)
+}
+
+pub fn geometry_names() -> Vec<&'static str> {
+    FLOPPY_FORMATS.iter().map(|f| f.name).collect()
+}
+
+/// DPB for a floppy geometry, or None for geometries without a CP/M layout.
+pub fn dpb_for_geometry(geom: &DiskGeometry) -> Option<Dpb> {
+    floppy_format(geom).and_then(|f| format_dpb(f, geom).ok())
+}
+
+fn format_dpb(format: &FloppyFormat, geom: &DiskGeometry) -> Result<Dpb> {
+    let sides = if geom.double_sided { 2 } else { 1 };
+    let spt = geom.sectors_per_track as u16 * ((RECORD_SIZE << geom.n) / RECORD_SIZE) as u16;
+    Dpb::new(spt, geom.tracks as u16 * sides, format.off, format.block_size, format.dir_entries)
+}
+
+/// BIOS sector translation table: logical sector -> physical sector index.
+fn skew_table(sectors: usize, skew: usize) -> Vec<usize> {
+    let mut table = Vec::with_capacity(sectors);
+    let mut used = vec![false; sectors];
+    let mut pos = 0;
+    for _ in 0..sectors {
+        while used[pos] {
+            pos = (pos + 1) % sectors;
+        }
+        table.push(pos);
+        used[pos] = true;
+        pos = (pos + skew) % sectors;
+    }
+    table
+}
+
+/// A floppy `Media` viewed as CP/M logical tracks.
+pub struct MediaVolume {
+    pub media: Media,
+    geometry: DiskGeometry,
+    xlt: Vec<usize>,
+}
+
+impl MediaVolume {
+    pub fn new(media: Media) -> Result<(MediaVolume, Dpb)> {
+        let geometry = media.geometry.ok_or_else(||
+            Error::new(ErrorKind::InvalidData, "Unknown disk geometry"))?;
+        let format = floppy_format(&geometry).ok_or_else(||
+            Error::new(ErrorKind::InvalidData,
+                format!("No CP/M parameters for {}", geometry.label)))?;
+        let dpb = format_dpb(format, &geometry)?;
+        let xlt = skew_table(geometry.sectors_per_track as usize, format.skew as usize);
+        Ok((MediaVolume { media, geometry, xlt }, dpb))
+    }
+
+    /// Image offset of a logical record.
+    fn record_index(&self, track: usize, record: usize) -> Result<usize> {
+        let records_per_sector = (RECORD_SIZE << self.geometry.n) / RECORD_SIZE;
+        let (cylinder, side_2) = if self.geometry.double_sided {
+            (track / 2, track % 2 == 1)
+        } else {
+            (track, false)
+        };
+        let logical_sector = record / records_per_sector;
+        let physical = *self.xlt.get(logical_sector).ok_or_else(||
+            Error::new(ErrorKind::InvalidInput, "Record beyond end of track"))?;
+        let base = if side_2 {
+            self.geometry.side1_sector_id_base
+        } else {
+            self.geometry.sector_id_base
+        };
+        let (valid, index, _) = self.media.sector_index(side_2, cylinder as u8, base + physical as u8);
+        if !valid {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                format!("Track {} sector {} is outside the image", track, logical_sector)));
+        }
+        Ok(index + (record % records_per_sector) * RECORD_SIZE)
+    }
+
+    /// Allocation block of each record of the image, None for the system
+    /// tracks.
+    pub fn block_map(&self, dpb: &Dpb) -> Vec<Option<usize>> {
+        let mut map = vec![None; self.media.content.len().div_ceil(RECORD_SIZE)];
+        for block in 0..dpb.blocks() {
+            for r in 0..dpb.records_per_block() {
+                let (track, record) = dpb.locate(block, r);
+                if let Ok(index) = self.record_index(track, record) {
+                    map[index / RECORD_SIZE] = Some(block);
+                }
+            }
+        }
+        map
+    }
+}
+
+impl CpmVolume for MediaVolume {
+    fn read_record(&self, track: usize, record: usize, buf: &mut [u8; RECORD_SIZE]) -> Result<()> {
+        let index = self.record_index(track, record)?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.media.read_byte(index + i);
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, track: usize, record: usize, buf: &[u8; RECORD_SIZE]) -> Result<()> {
+        let index = self.record_index(track, record)?;
+        for (i, b) in buf.iter().enumerate() {
+            self.media.write_byte(index + i, *b);
+        }
+        Ok(())
+    }
+}
+
+// ── Directory ────────────────────────────────────────────────────────────────
+
+/// A file name as CP/M stores it: user area plus space-padded 8.3 name.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct CpmName {
+    pub user: u8,
+    pub name: [u8; 8],
+    pub ext: [u8; 3],
+}
+
+impl CpmName {
+    /// Parse `[user:]NAME.EXT`.  Names are upper-cased; user defaults to 0.
+    pub fn parse(text: &str) -> Result<CpmName> {
+        let invalid = || Error::new(ErrorKind::InvalidInput,
+            format!("Invalid CP/M file name '{}'", text));
+        let (user, file) = match text.split_once(':') {
+            Some((u, f)) => (u.parse::<u8>().map_err(|_| invalid())?, f),
+            None => (0, text),
+        };
+        if user > 15 {
+            return Err(invalid());
+        }
+        let (base, ext) = file.split_once('.').unwrap_or((file, ""));
+        if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+            return Err(invalid());
+        }
+        let valid_char = |c: char| c.is_ascii_graphic() && !"<>.,;:=?*[]_%|()/\\".contains(c);
+        if !base.chars().all(valid_char) || !ext.chars().all(valid_char) {
+            return Err(invalid());
+        }
+        let mut name = CpmName { user, name: [b' '; 8], ext: [b' '; 3] };
+        name.name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        name.ext[..ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+        Ok(name)
+    }
+
+    fn from_entry(entry: &[u8]) -> CpmName {
+        let mut name = CpmName { user: entry[0], name: [0; 8], ext: [0; 3] };
+        for i in 0..8 {
+            name.name[i] = entry[1 + i] & 0x7F;
+        }
+        for i in 0..3 {
+            name.ext[i] = entry[9 + i] & 0x7F;
+        }
+        name
+    }
+
+    /// `NAME.EXT` without the user area, suitable as a host file name.
+    pub fn file_name(&self) -> String {
+        let base = String::from_utf8_lossy(&self.name).trim_end().to_string();
+        let ext = String::from_utf8_lossy(&self.ext).trim_end().to_string();
+        if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+    }
+}
+
+impl std::fmt::Display for CpmName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.user, self.file_name())
+    }
+}
+
+/// A file as listed by `CpmFs::list`, with its extents merged.
+pub struct CpmFile {
+    pub name: CpmName,
+    pub records: usize,
+    pub blocks: usize,
+    pub read_only: bool,
+    pub system: bool,
+}
+
+struct Extent {
+    slot: usize,
+    name: CpmName,
+    /// Logical extent number: S2 * 32 + EX
+    number: usize,
+    /// Records used in this directory entry
+    records: usize,
+    blocks: Vec<usize>,
+    read_only: bool,
+    system: bool,
+}
+
+pub struct CpmFs<V: CpmVolume> {
+    pub volume: V,
+    pub dpb: Dpb,
+    dir: Vec<u8>,
+}
+
+impl<V: CpmVolume> CpmFs<V> {
+    pub fn new(volume: V, dpb: Dpb) -> Result<CpmFs<V>> {
+        let mut fs = CpmFs { volume, dpb, dir: Vec::new() };
+        fs.dir = vec![0; dpb.dir_entries() * DIR_ENTRY_SIZE];
+        let mut buf = [0u8; RECORD_SIZE];
+        for r in 0..fs.dir.len() / RECORD_SIZE {
+            let (track, record) = dpb.locate(r / dpb.records_per_block(), r % dpb.records_per_block());
+            fs.volume.read_record(track, record, &mut buf)?;
+            fs.dir[r * RECORD_SIZE..(r + 1) * RECORD_SIZE].copy_from_slice(&buf);
+        }
+        Ok(fs)
+    }
+
+    fn write_directory(&mut self) -> Result<()> {
+        let mut buf = [0u8; RECORD_SIZE];
+        for r in 0..self.dir.len() / RECORD_SIZE {
+            let (track, record) = self.dpb.locate(
+                r / self.dpb.records_per_block(), r % self.dpb.records_per_block());
+            buf.copy_from_slice(&self.dir[r * RECORD_SIZE..(r + 1) * RECORD_SIZE]);
+            self.volume.write_record(track, record, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn extents(&self) -> Vec<Extent> {
+        let mut extents = Vec::new();
+        for (slot, entry) in self.dir.chunks(DIR_ENTRY_SIZE).enumerate() {
+            // 0x00-0x0F are user areas; E5 is free, anything else is a
+            // label, timestamp or garbage.
+            if entry[0] > 0x0F {
+                continue;
+            }
+            let ex = entry[12] as usize;
+            let s2 = entry[14] as usize & 0x3F;
+            let rc = entry[15] as usize;
+            let mut blocks = Vec::new();
+            if self.dpb.wide_pointers() {
+                for p in entry[16..32].chunks(2) {
+                    blocks.push(p[0] as usize | (p[1] as usize) << 8);
+                }
+            } else {
+                blocks.extend(entry[16..32].iter().map(|&b| b as usize));
+            }
+            blocks.retain(|&b| b != 0 && b < self.dpb.blocks());
+            extents.push(Extent {
+                slot,
+                name: CpmName::from_entry(entry),
+                number: s2 * 32 + ex,
+                records: (ex & self.dpb.exm as usize) * 128 + rc.min(128),
+                blocks,
+                read_only: entry[9] & 0x80 != 0,
+                system: entry[10] & 0x80 != 0,
+            });
+        }
+        extents
+    }
+
+    fn file_extents(&self, name: &CpmName) -> Vec<Extent> {
+        let mut extents: Vec<Extent> = self.extents().into_iter()
+            .filter(|e| e.name == *name)
+            .collect();
+        extents.sort_by_key(|e| e.number);
+        extents
+    }
+
+    /// All files, sorted by user area and name.
+    pub fn list(&self) -> Vec<CpmFile> {
+        let mut files: Vec<CpmFile> = Vec::new();
+        let mut extents = self.extents();
+        extents.sort_by(|a, b| a.name.cmp(&b.name).then(a.number.cmp(&b.number)));
+        for e in extents {
+            match files.last_mut() {
+                Some(f) if f.name == e.name => {
+                    f.records = e.number / (self.dpb.exm as usize + 1) * self.dpb.records_per_entry()
+                        + e.records;
+                    f.blocks += e.blocks.len();
+                }
+                _ => files.push(CpmFile {
+                    name: e.name,
+                    records: e.number / (self.dpb.exm as usize + 1) * self.dpb.records_per_entry()
+                        + e.records,
+                    blocks: e.blocks.len(),
+                    read_only: e.read_only,
+                    system: e.system,
+                }),
+            }
+        }
+        files
+    }
+
+    /// Blocks of the file `name`, in file order.
+    pub fn file_blocks(&self, name: &CpmName) -> Vec<usize> {
+        self.file_extents(name).into_iter().flat_map(|e| e.blocks).collect()
+    }
+
+    fn allocation(&self) -> Vec<bool> {
+        let mut used = vec![false; self.dpb.blocks()];
+        for b in used.iter_mut().take(self.dpb.dir_blocks()) {
+            *b = true;
+        }
+        for e in self.extents() {
+            for b in e.blocks {
+                used[b] = true;
+            }
+        }
+        used
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.allocation().iter().filter(|&&u| !u).count()
+    }
+
+    pub fn read_file(&self, name: &CpmName) -> Result<Vec<u8>> {
+        let extents = self.file_extents(name);
+        if extents.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} not found", name)));
+        }
+        let mut data = Vec::new();
+        let mut buf = [0u8; RECORD_SIZE];
+        let rpb = self.dpb.records_per_block();
+        for e in extents {
+            for r in 0..e.records {
+                let Some(&block) = e.blocks.get(r / rpb) else { break };
+                let (track, record) = self.dpb.locate(block, r % rpb);
+                self.volume.read_record(track, record, &mut buf)?;
+                data.extend_from_slice(&buf);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Check that the directory looks like one before writing to it: with
+    /// the wrong parameters it is file data, and the files written would go
+    /// over the files on the disk.
+    fn check_directory(&self) -> Result<()> {
+        let invalid = |slot: usize, what: &str| Error::new(ErrorKind::InvalidData,
+            format!("Not a CP/M directory for these disk parameters, entry {} {}", slot, what));
+        let mut used = vec![false; self.dpb.blocks()];
+        for b in used.iter_mut().take(self.dpb.dir_blocks()) {
+            *b = true;
+        }
+        for (slot, entry) in self.dir.chunks(DIR_ENTRY_SIZE).enumerate() {
+            match entry[0] {
+                0x00..=0x0F => {},
+                // CP/M 3 passwords, label and time stamps
+                EMPTY | 0x10..=0x21 => continue,
+                status => return Err(invalid(slot, &format!("has status {:02X}h", status))),
+            }
+            if !entry[1..12].iter().all(|&c| (0x20..0x7F).contains(&(c & 0x7F))) {
+                return Err(invalid(slot, "has a bad file name"));
+            }
+            if entry[12] > 0x1F || entry[15] > 0x80 {
+                return Err(invalid(slot, "has a bad extent"));
+            }
+            let pointers: Vec<usize> = if self.dpb.wide_pointers() {
+                entry[16..32].chunks(2).map(|p| p[0] as usize | (p[1] as usize) << 8).collect()
+            } else {
+                entry[16..32].iter().map(|&b| b as usize).collect()
+            };
+            for block in pointers.into_iter().filter(|&b| b != 0) {
+                if block >= used.len() || used[block] {
+                    return Err(invalid(slot, &format!("has a bad block {}", block)));
+                }
+                used[block] = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a file by marking all its directory entries free.
+    pub fn delete(&mut self, name: &CpmName) -> Result<()> {
+        self.check_directory()?;
+        let extents = self.file_extents(name);
+        if extents.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} not found", name)));
+        }
+        for e in extents {
+            self.dir[e.slot * DIR_ENTRY_SIZE] = EMPTY;
+        }
+        self.write_directory()
+    }
+
+    /// Write a file, replacing any existing file of the same name.  The
+    /// last record is padded with ^Z.
+    pub fn write_file(&mut self, name: &CpmName, data: &[u8]) -> Result<()> {
+        self.check_directory()?;
+        let records = data.len().div_ceil(RECORD_SIZE);
+        let rpb = self.dpb.records_per_block();
+        let per_entry = self.dpb.records_per_entry();
+        let entries_needed = records.div_ceil(per_entry).max(1);
+        let blocks_needed = records.div_ceil(rpb);
+
+        // Free the old copy only in memory until the new one is known to fit
+        let old = self.file_extents(name);
+        let saved_dir = self.dir.clone();
+        for e in &old {
+            self.dir[e.slot * DIR_ENTRY_SIZE] = EMPTY;
+        }
+        let free_slots: Vec<usize> = self.dir.chunks(DIR_ENTRY_SIZE)
+            .enumerate()
+            .filter(|(_, e)| e[0] == EMPTY)
+            .map(|(i, _)| i)
+            .collect();
+        let free_blocks: Vec<usize> = self.allocation().iter()
+            .enumerate()
+            .filter(|(_, &u)| !u)
+            .map(|(i, _)| i)
+            .collect();
+        if free_slots.len() < entries_needed {
+            self.dir = saved_dir;
+            return Err(Error::other("Directory full"));
+        }
+        if free_blocks.len() < blocks_needed {
+            self.dir = saved_dir;
+            return Err(Error::other(format!("Disk full: {} blocks needed, {} free",
+                blocks_needed, free_blocks.len())));
+        }
+
+        let mut buf = [0u8; RECORD_SIZE];
+        for (r, chunk) in data.chunks(RECORD_SIZE).enumerate() {
+            buf.fill(CTRL_Z);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let (track, record) = self.dpb.locate(free_blocks[r / rpb], r % rpb);
+            self.volume.write_record(track, record, &buf)?;
+        }
+
+        let blocks_per_entry = self.dpb.pointers_per_entry();
+        for (i, &free_slot) in free_slots.iter().take(entries_needed).enumerate() {
+            let first_record = i * per_entry;
+            let entry_records = records.saturating_sub(first_record).min(per_entry);
+            let extent = i * (self.dpb.exm as usize + 1)
+                + entry_records.saturating_sub(1) / 128;
+            let rc = entry_records - entry_records.saturating_sub(1) / 128 * 128;
+
+            let slot = free_slot * DIR_ENTRY_SIZE;
+            let entry = &mut self.dir[slot..slot + DIR_ENTRY_SIZE];
+            entry.fill(0);
+            entry[0] = name.user;
+            entry[1..9].copy_from_slice(&name.name);
+            entry[9..12].copy_from_slice(&name.ext);
+            entry[12] = (extent & 0x1F) as u8;
+            entry[14] = (extent >> 5) as u8;
+            entry[15] = rc as u8;
+            let first_block = i * blocks_per_entry;
+            let entry_blocks = entry_records.div_ceil(rpb);
+            for (p, &block) in free_blocks[first_block..first_block + entry_blocks].iter().enumerate() {
+                if self.dpb.wide_pointers() {
+                    entry[16 + p * 2] = block as u8;
+                    entry[17 + p * 2] = (block >> 8) as u8;
+                } else {
+                    entry[16 + p] = block as u8;
+                }
+            }
+        }
+        self.write_directory()
+    }
+}
+
+// ── Host command ─────────────────────────────────────────────────────────────
+
+/// Open a floppy image as a CP/M filesystem.
+pub fn open_image(path: &str, side1_sector_base: u8) -> Result<CpmFs<MediaVolume>> {
+    let mut media = Media::new(side1_sector_base);
+    media.load_disk(path)?;
+    let (volume, dpb) = MediaVolume::new(media)?;
+    CpmFs::new(volume, dpb)
+}
+
+/// Print a CP/M directory listing, DIR-style with sizes.
+pub fn print_listing<V: CpmVolume>(fs: &CpmFs<V>, title: &str) {
+    let block_k = fs.dpb.block_size() / 1024;
+    println!("{}", title);
+    println!("{}K blocks, {} directory entries, {} reserved tracks",
+        block_k, fs.dpb.dir_entries(), fs.dpb.off);
+    println!();
+    let files = fs.list();
+    for f in &files {
+        let attrs = format!("{}{}",
+            if f.read_only { "R/O " } else { "" },
+            if f.system { "SYS" } else { "" });
+        println!("{:>2}: {:<12} {:>5}K {:>5} recs  {}",
+            f.name.user, f.name.file_name(), f.blocks * block_k, f.records, attrs.trim_end());
+    }
+    println!();
+    println!("{} file(s), {}K free", files.len(), fs.free_blocks() * block_k);
+}
+
+/// A freshly formatted disk: every sector E5, so the directory is empty.
+/// With `system`, the reserved tracks are copied sector by sector from a
+/// boot disk of the same physical layout.
+pub fn make_disk(geometry: DiskGeometry, system: Option<&Media>) -> Result<Media> {
+    let dpb = dpb_for_geometry(&geometry).ok_or_else(||
+        Error::new(ErrorKind::InvalidInput, format!("No CP/M parameters for {}", geometry.label)))?;
+    let sides = if geometry.double_sided { 2 } else { 1 };
+    let size = geometry.tracks as usize * sides
+        * geometry.sectors_per_track as usize * (RECORD_SIZE << geometry.n);
+    let mut media = Media {
+        content: vec![EMPTY; size],
+        name: geometry.label.to_string(),
+        ..Media::new(geometry.side1_sector_id_base)
+    };
+    media.apply_geometry(geometry);
+    // Every track explicitly, so the disk can also be saved as IMD
+    for track in 0..geometry.tracks {
+        for side in 0..sides {
+            let side_2 = side == 1;
+            media.track_geometry.insert((track, side_2), media::TrackGeometry {
+                n: geometry.n,
+                sector_count: geometry.sectors_per_track,
+                sector_base: if side_2 { geometry.side1_sector_id_base } else { geometry.sector_id_base },
+                single_density: geometry.single_density,
+                sector_ids: Vec::new(),
+            });
+        }
+    }
+
+    if let Some(source) = system {
+        let source_geometry = source.geometry.ok_or_else(||
+            Error::new(ErrorKind::InvalidData, format!("Unknown disk geometry of {}", source.name)))?;
+        if source_geometry.n != geometry.n
+            || source_geometry.sectors_per_track != geometry.sectors_per_track
+            || source_geometry.double_sided != geometry.double_sided {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("{} is {}, not {}", source.name, source_geometry.label, geometry.label)));
+        }
+        for track in 0..dpb.off as usize {
+            let (cylinder, side_2) = if geometry.double_sided {
+                ((track / 2) as u8, track % 2 == 1)
+            } else {
+                (track as u8, false)
+            };
+            for i in 0..geometry.sectors_per_track {
+                let (source_base, base) = if side_2 {
+                    (source_geometry.side1_sector_id_base, geometry.side1_sector_id_base)
+                } else {
+                    (source_geometry.sector_id_base, geometry.sector_id_base)
+                };
+                let (valid, from, from_end) = source.sector_index(side_2, cylinder, source_base + i);
+                let (_, to, to_end) = media.sector_index(side_2, cylinder, base + i);
+                if !valid || from_end - from != to_end - to {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("{}: sector {} of system track {} does not match {}",
+                            source.name, source_base + i, track, geometry.label)));
+                }
+                media.content[to..to_end].copy_from_slice(&source.content[from..from_end]);
+            }
+        }
+    }
+    Ok(media)
+}
+
+/// Save the image after modifying it, refusing read-only files.
+pub fn flush_image(fs: &mut CpmFs<MediaVolume>) -> Result<()> {
+    let media = &mut fs.volume.media;
+    if media.is_write_protected() {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+            format!("{} is read-only", media.name)));
+    }
+    media.try_flush_disk()
+}
@@ -0,0 +1,175 @@
+#[cfg(test)]
+mod tests {
+    use crate::cpm_fs::{self, CpmFs, CpmName, CpmVolume, MediaVolume};
+    use crate::media::{self, DiskGeometry, Media};
+
+    fn blank_fs(geom: DiskGeometry) -> CpmFs<MediaVolume> {
+        let sides = if geom.double_sided { 2 } else { 1 };
+        let size = geom.tracks as usize * sides
+            * geom.sectors_per_track as usize * (128usize << geom.n);
+        let mut media = Media {
+            content: vec![0xE5; size],
+            ..Media::new(10)
+        };
+        media.apply_geometry(geom);
+        let (volume, dpb) = MediaVolume::new(media).unwrap();
+        CpmFs::new(volume, dpb).unwrap()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip_all_geometries() {
+        let geometries = [
+            media::GEOM_KAYPRO_SSSD,
+            media::GEOM_KAYPRO_SSDD,
+            media::GEOM_KAYPRO_DSDD,
+            media::GEOM_KAYPLUS_DSDD,
+            media::GEOM_ADVENT_SSDD,
+            media::GEOM_ADVENT_DSDD48,
+            media::GEOM_ADVENT_DSDD96,
+            media::GEOM_MICROCORNUCOPIA,
+            media::GEOM_XEROX_820,
+            media::GEOM_EPSON_QX10,
+        ];
+        for geom in geometries {
+            let mut fs = blank_fs(geom);
+            let free = fs.free_blocks();
+            // Large enough to span several directory entries on every format
+            let data = pattern(40_000);
+            let name = CpmName::parse("2:test.dat").unwrap();
+            fs.write_file(&name, &data).unwrap();
+            fs.write_file(&CpmName::parse("small").unwrap(), b"hello").unwrap();
+
+            // Re-read the directory from the media to check what was stored
+            let dpb = fs.dpb;
+            let fs = CpmFs::new(fs.volume, dpb).unwrap();
+            let files = fs.list();
+            assert_eq!(files.len(), 2, "{}", geom.label);
+            let big = files.iter().find(|f| f.name == name).unwrap();
+            assert_eq!(big.records, 313, "{}", geom.label);
+            let read = fs.read_file(&name).unwrap();
+            assert_eq!(&read[..data.len()], &data[..], "{}", geom.label);
+            assert!(read[data.len()..].iter().all(|&b| b == 0x1A), "{}", geom.label);
+
+            let mut fs = fs;
+            fs.delete(&name).unwrap();
+            fs.delete(&CpmName::parse("SMALL").unwrap()).unwrap();
+            assert_eq!(fs.free_blocks(), free, "{}", geom.label);
+            assert!(fs.list().is_empty(), "{}", geom.label);
+        }
+    }
+
+    #[test]
+    fn test_kaypro_dsdd_dpb() {
+        let dpb = cpm_fs::dpb_for_geometry(&media::GEOM_KAYPRO_DSDD).unwrap();
+        assert_eq!((dpb.spt, dpb.bsh, dpb.blm, dpb.exm), (40, 4, 15, 1));
+        assert_eq!((dpb.dsm, dpb.drm, dpb.al0, dpb.al1, dpb.off), (196, 63, 0x80, 0x00, 1));
+        let dpb = cpm_fs::dpb_for_geometry(&media::GEOM_KAYPRO_SSDD).unwrap();
+        assert_eq!((dpb.spt, dpb.bsh, dpb.exm, dpb.dsm, dpb.al0, dpb.off), (40, 3, 0, 194, 0xC0, 1));
+    }
+
+    #[test]
+    fn test_dpb_limits() {
+        // 760 1K blocks
+        assert!(cpm_fs::Dpb::new(40, 160, 8, 1024, 64).is_err());
+        assert_eq!(cpm_fs::Dpb::new(40, 160, 8, 2048, 128).unwrap().exm, 0);
+        assert_eq!(cpm_fs::Dpb::new(40, 40, 1, 1024, 64).unwrap().dsm, 194);
+        assert!(cpm_fs::Dpb::new(40, 2, 2, 1024, 64).is_err());
+        assert!(cpm_fs::Dpb::new(40, 1, 2, 1024, 64).is_err());
+    }
+
+    #[test]
+    fn test_detect_kaypro_ssdd_image() {
+        let mut media = Media::new(10);
+        media.load_disk("disks/productivity/CalcStar.img").unwrap();
+        assert!(media.geometry.unwrap().same_layout(&media::GEOM_KAYPRO_SSDD));
+        let (volume, dpb) = MediaVolume::new(media).unwrap();
+        let mut fs = CpmFs::new(volume, dpb).unwrap();
+        let names: Vec<String> = fs.list().iter().map(|f| f.name.to_string()).collect();
+        assert!(names.contains(&"0:CS.COM".to_string()), "{:?}", names);
+        // Only in memory, the image isn't saved
+        let name = CpmName::parse("NEW.TXT").unwrap();
+        fs.write_file(&name, b"new").unwrap();
+        fs.delete(&name).unwrap();
+    }
+
+    #[test]
+    fn test_no_writes_over_a_bad_directory() {
+        let mut media = Media::new(10);
+        media.load_disk("disks/productivity/CalcStar.img").unwrap();
+        // The Advent SSDD directory is in the files of a Kaypro disk
+        media.apply_geometry(media::GEOM_ADVENT_SSDD);
+        let (volume, dpb) = MediaVolume::new(media).unwrap();
+        let mut fs = CpmFs::new(volume, dpb).unwrap();
+        let before = fs.volume.media.content.clone();
+        assert!(fs.write_file(&CpmName::parse("NEW.TXT").unwrap(), b"new").is_err());
+        assert!(fs.delete(&CpmName::parse("CS.COM").unwrap()).is_err());
+        assert!(fs.volume.media.content == before);
+    }
+
+    #[test]
+    fn test_disk_full_keeps_old_file() {
+        let mut fs = blank_fs(media::GEOM_XEROX_820);
+        let name = CpmName::parse("KEEP.ME").unwrap();
+        fs.write_file(&name, b"original").unwrap();
+        assert!(fs.write_file(&name, &pattern(200_000)).is_err());
+        assert_eq!(&fs.read_file(&name).unwrap()[..8], b"original");
+    }
+
+    #[test]
+    fn test_flush_reports_write_errors() {
+        let path = std::env::temp_dir().join(format!("izkaypro-flush-{}.img", std::process::id()));
+        let mut fs = blank_fs(media::GEOM_XEROX_820);
+        std::fs::write(&path, &fs.volume.media.content).unwrap();
+        // A handle opened for reading can't write the image back
+        fs.volume.media.file = Some(std::fs::File::open(&path).unwrap());
+        fs.write_file(&CpmName::parse("NEW.TXT").unwrap(), b"new").unwrap();
+        assert!(cpm_fs::flush_image(&mut fs).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        assert!(CpmName::parse("TOOLONGNAME.COM").is_err());
+        assert!(CpmName::parse("A.COMX").is_err());
+        assert!(CpmName::parse("16:A.COM").is_err());
+        assert!(CpmName::parse("A*.COM").is_err());
+        assert_eq!(CpmName::parse("15:a.com").unwrap().to_string(), "15:A.COM");
+    }
+
+    #[test]
+    fn test_make_disk_with_system_tracks() {
+        for name in cpm_fs::geometry_names() {
+            let geom = cpm_fs::geometry_by_name(name).unwrap();
+            let mut boot = blank_fs(geom);
+            // Mark every reserved sector, and put a file on the source disk
+            let dpb = boot.dpb;
+            for track in 0..dpb.off as usize {
+                for record in 0..dpb.spt as usize {
+                    boot.volume.write_record(track, record, &[(track * 7 + record) as u8; 128]).unwrap();
+                }
+            }
+            boot.write_file(&CpmName::parse("CPM.SYS").unwrap(), b"not copied").unwrap();
+
+            let disk = cpm_fs::make_disk(geom, Some(&boot.volume.media)).unwrap();
+            assert_eq!(disk.content.len(), boot.volume.media.content.len(), "{}", name);
+            let (volume, dpb) = MediaVolume::new(disk).unwrap();
+            let fs = CpmFs::new(volume, dpb).unwrap();
+            assert!(fs.list().is_empty(), "{}", name);
+            let mut buf = [0u8; 128];
+            for track in 0..dpb.off as usize {
+                for record in 0..dpb.spt as usize {
+                    fs.volume.read_record(track, record, &mut buf).unwrap();
+                    assert_eq!(buf, [(track * 7 + record) as u8; 128], "{}", name);
+                }
+            }
+            assert!(fs.volume.media.to_imd().tracks.len() >= geom.tracks as usize);
+        }
+        assert!(cpm_fs::geometry_by_name("Kaypro_DSDD").is_some());
+        let sssd = blank_fs(media::GEOM_KAYPRO_SSSD);
+        assert!(cpm_fs::make_disk(media::GEOM_KAYPRO_DSDD, Some(&sssd.volume.media)).is_err());
+    }
+}
@@ -503,7 +503,8 @@ pub struct BootTestConfig {
 /// Each test boots the machine headlessly and checks that "A>" appears in VRAM
 /// within a reasonable instruction count, and that the CPU is not stuck in an
 /// infinite loop (detected by PC repeating at the same address).
-pub fn run_boot_tests() -> Vec<TestResult> {
+/// With `clock_start` the RTC runs on emulated time from that date.
+pub fn run_boot_tests(clock_start: Option<i64>) -> Vec<TestResult> {
     let configs = vec![
         BootTestConfig {
             name: "Kaypro II (81-149c)",
@@ -593,14 +594,16 @@ pub fn run_boot_tests() -> Vec<TestResult> {
 
     let mut results = Vec::new();
     for cfg in &configs {
-        results.push(run_single_boot_test(cfg));
+        results.push(run_single_boot_test(cfg, clock_start));
     }
     results
 }
 
-fn run_single_boot_test(cfg: &BootTestConfig) -> TestResult {
+fn run_single_boot_test(cfg: &BootTestConfig, clock_start: Option<i64>) -> TestResult {
     use iz80::*;
     use crate::config::resolve_path;
+    use crate::kaypro_machine::RunExit;
+    use crate::scheduler::Event;
 
     let disk_a = resolve_path(cfg.disk_a);
     let disk_b = resolve_path(cfg.disk_b);
@@ -617,6 +620,9 @@ fn run_single_boot_test(cfg: &BootTestConfig) -> TestResult {
 
     // Disable idle sleep for headless boot tests (no interactive keyboard)
     machine.keyboard.idle_sleep_enabled = false;
+    if let Some(start_secs) = clock_start {
+        machine.rtc.set_virtual_clock(start_secs);
+    }
 
     // No floppy in drive when booting from HD (ROM checks NOT READY for boot priority)
     if cfg.is_kaypro10_hardware && cfg.hd_image.is_some() {
@@ -655,8 +661,6 @@ fn run_single_boot_test(cfg: &BootTestConfig) -> TestResult {
 
     let max_instructions: u64 = 200_000_000;
     let mut counter: u64 = 0;
-    let mut nmi_pending = false;
-    let mut nmi_deadline: u64 = 0;
     let mut prompt_found = false;
     let mut prompt_at: u64 = 0;
 
@@ -665,40 +669,15 @@ fn run_single_boot_test(cfg: &BootTestConfig) -> TestResult {
     let mut fdc_motor_toggles: u32 = 0;
     let mut last_fdc_motor = false;
 
+    // Check the screen for the prompt every 400K T-states
+    let prompt_check_tstates: u64 = 400_000;
+    machine.scheduler.schedule(prompt_check_tstates, Event::Refresh);
+
     let result = loop {
-        cpu.execute_instruction(&mut machine);
+        let exit = machine.tick(&mut cpu);
         counter += 1;
 
-        // SIO interrupt processing (keyboard)
-        if counter % 1024 == 0 {
-            let i_reg = cpu.registers().get8(iz80::Reg8::I);
-            if let Some(handler) = machine.sio_check_interrupt(i_reg) {
-                let regs = cpu.registers();
-                let pc = regs.pc();
-                let mut sp = regs.get16(iz80::Reg16::SP);
-                sp = sp.wrapping_sub(2);
-                regs.set16(iz80::Reg16::SP, sp);
-                machine.poke(sp, pc as u8);
-                machine.poke(sp.wrapping_add(1), (pc >> 8) as u8);
-                cpu.registers().set_pc(handler);
-            }
-        }
-
-        // NMI processing (same logic as main loop)
-        if machine.floppy_controller.raise_nmi {
-            machine.floppy_controller.raise_nmi = false;
-            nmi_pending = true;
-            nmi_deadline = counter + 10_000_000;
-        }
-        let mut nmi_signaled = false;
-        if nmi_pending && (cpu.is_halted()
-            || (counter >= nmi_deadline && machine.nmi_vector_is_safe()))
-        {
-            cpu.signal_nmi();
-            nmi_pending = false;
-            nmi_signaled = true;
-        }
-        if !nmi_signaled && cpu.is_halted() {
+        if exit == Some(RunExit::Halted) {
             if prompt_found {
                 break TestResult {
                     name: format!("Boot {}", cfg.name),
@@ -715,8 +694,9 @@ fn run_single_boot_test(cfg: &BootTestConfig) -> TestResult {
         }
 
         // Check for A> prompt periodically
-        if !prompt_found && counter % 100_000 == 0 {
-            if check_for_prompt(&machine) {
+        if exit == Some(RunExit::Event(Event::Refresh)) {
+            machine.scheduler.schedule(machine.tstates + prompt_check_tstates, Event::Refresh);
+            if !prompt_found && check_for_prompt(&machine) {
                 prompt_found = true;
                 prompt_at = counter;
                 last_fdc_motor = machine.floppy_controller.motor_on;
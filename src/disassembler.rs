@@ -0,0 +1,275 @@
+// Z80 disassembler for the monitor.
+//
+// Decodes the instruction at an address with Zilog mnemonics, including
+// the undocumented IXH/IXL forms and the DDCB/FDCB register copies. The
+// bytes are read through a function so the caller decides what is mapped:
+// the monitor reads them as the CPU sees them, ROM or RAM depending on
+// the bank.
+
+pub struct Instruction {
+    pub text: String,
+    pub len: u16,
+    /// CALL, RST, DJNZ, a repeating block instruction or HALT: stepping
+    /// over it runs up to the next instruction
+    pub steps_over: bool,
+}
+
+const REG: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const IM: [&str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// Decode the instruction at `address`.
+pub fn disassemble(read: impl Fn(u16) -> u8, address: u16) -> Instruction {
+    let mut decoder = Decoder { read, address, len: 0, index: None, steps_over: false };
+    let text = decoder.instruction();
+    Instruction { text, len: decoder.len, steps_over: decoder.steps_over }
+}
+
+struct Decoder<F: Fn(u16) -> u8> {
+    read: F,
+    address: u16,
+    len: u16,
+    /// IX or IY after a DD or FD prefix
+    index: Option<&'static str>,
+    steps_over: bool,
+}
+
+impl<F: Fn(u16) -> u8> Decoder<F> {
+    fn byte(&mut self) -> u8 {
+        let value = (self.read)(self.address.wrapping_add(self.len));
+        self.len += 1;
+        value
+    }
+
+    fn word(&mut self) -> u16 {
+        let lo = self.byte() as u16;
+        let hi = self.byte() as u16;
+        hi << 8 | lo
+    }
+
+    fn n(&mut self) -> String {
+        let value = self.byte();
+        hex8(value)
+    }
+
+    fn nn(&mut self) -> String {
+        let value = self.word();
+        hex16(value)
+    }
+
+    /// Target of a relative jump
+    fn d(&mut self) -> String {
+        let offset = self.byte() as i8;
+        hex16(self.address.wrapping_add(self.len).wrapping_add(offset as u16))
+    }
+
+    /// HL, or the index register
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    /// Register `r`. With an index prefix (HL) becomes (IX+d), and H and
+    /// L become IXH and IXL unless `halves` is false: they stay H and L
+    /// next to an (IX+d) operand.
+    fn reg(&mut self, r: u8, halves: bool) -> String {
+        match (self.index, r) {
+            (Some(index), 6) => {
+                let offset = self.byte() as i8;
+                indexed(index, offset)
+            },
+            (Some(index), 4) if halves => format!("{}H", index),
+            (Some(index), 5) if halves => format!("{}L", index),
+            _ => REG[r as usize].to_string(),
+        }
+    }
+
+    fn rp(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP[p as usize] }
+    }
+
+    fn rp2(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP2[p as usize] }
+    }
+
+    fn instruction(&mut self) -> String {
+        let opcode = self.byte();
+        match opcode {
+            0xCB => return self.cb(),
+            0xED => return self.ed(),
+            0xDD | 0xFD => {
+                let next = (self.read)(self.address.wrapping_add(1));
+                if matches!(next, 0xDD | 0xED | 0xFD) {
+                    // A prefix followed by another one does nothing
+                    return format!("DB {}", hex8(opcode));
+                }
+                self.index = Some(if opcode == 0xDD { "IX" } else { "IY" });
+                let opcode = self.byte();
+                if opcode == 0xCB {
+                    return self.index_cb();
+                }
+                return self.unprefixed(opcode);
+            },
+            _ => {},
+        }
+        self.unprefixed(opcode)
+    }
+
+    fn unprefixed(&mut self, opcode: u8) -> String {
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => "NOP".to_string(),
+                1 => "EX AF,AF'".to_string(),
+                2 => {
+                    self.steps_over = true;
+                    format!("DJNZ {}", self.d())
+                },
+                3 => format!("JR {}", self.d()),
+                _ => format!("JR {},{}", CC[y as usize - 4], self.d()),
+            },
+            (0, 1) if q == 0 => format!("LD {},{}", self.rp(p), self.nn()),
+            (0, 1) => format!("ADD {},{}", self.hl(), self.rp(p)),
+            (0, 2) => match (q, p) {
+                (0, 0) => "LD (BC),A".to_string(),
+                (0, 1) => "LD (DE),A".to_string(),
+                (0, 2) => format!("LD ({}),{}", self.nn(), self.hl()),
+                (0, _) => format!("LD ({}),A", self.nn()),
+                (_, 0) => "LD A,(BC)".to_string(),
+                (_, 1) => "LD A,(DE)".to_string(),
+                (_, 2) => format!("LD {},({})", self.hl(), self.nn()),
+                (_, _) => format!("LD A,({})", self.nn()),
+            },
+            (0, 3) => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, self.rp(p)),
+            (0, 4) => format!("INC {}", self.reg(y, true)),
+            (0, 5) => format!("DEC {}", self.reg(y, true)),
+            (0, 6) => {
+                let r = self.reg(y, true);
+                format!("LD {},{}", r, self.n())
+            },
+            (0, _) => ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y as usize].to_string(),
+            (1, 6) if y == 6 => {
+                self.steps_over = true;
+                "HALT".to_string()
+            },
+            (1, _) => {
+                let halves = y != 6 && z != 6;
+                let to = self.reg(y, halves);
+                let from = self.reg(z, halves);
+                format!("LD {},{}", to, from)
+            },
+            (2, _) => format!("{}{}", ALU[y as usize], self.reg(z, true)),
+            (_, 0) => format!("RET {}", CC[y as usize]),
+            (_, 1) if q == 0 => format!("POP {}", self.rp2(p)),
+            (_, 1) => match p {
+                0 => "RET".to_string(),
+                1 => "EXX".to_string(),
+                2 => format!("JP ({})", self.hl()),
+                _ => format!("LD SP,{}", self.hl()),
+            },
+            (_, 2) => format!("JP {},{}", CC[y as usize], self.nn()),
+            (_, 3) => match y {
+                0 => format!("JP {}", self.nn()),
+                2 => format!("OUT ({}),A", self.n()),
+                3 => format!("IN A,({})", self.n()),
+                4 => format!("EX (SP),{}", self.hl()),
+                5 => "EX DE,HL".to_string(),
+                6 => "DI".to_string(),
+                // 1 is the CB prefix, taken before
+                _ => "EI".to_string(),
+            },
+            (_, 4) => {
+                self.steps_over = true;
+                format!("CALL {},{}", CC[y as usize], self.nn())
+            },
+            (_, 5) if q == 0 => format!("PUSH {}", self.rp2(p)),
+            (_, 5) => {
+                // The prefixes are taken before
+                self.steps_over = true;
+                format!("CALL {}", self.nn())
+            },
+            (_, 6) => format!("{}{}", ALU[y as usize], self.n()),
+            (_, _) => {
+                self.steps_over = true;
+                format!("RST {}", hex8(y * 8))
+            },
+        }
+    }
+
+    fn cb(&mut self) -> String {
+        let opcode = self.byte();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let r = REG[z as usize];
+        match x {
+            0 => format!("{} {}", ROT[y as usize], r),
+            1 => format!("BIT {},{}", y, r),
+            2 => format!("RES {},{}", y, r),
+            _ => format!("SET {},{}", y, r),
+        }
+    }
+
+    /// DDCB and FDCB: the displacement comes before the opcode. Other
+    /// than BIT, the undocumented forms with a register also copy the
+    /// result to it.
+    fn index_cb(&mut self) -> String {
+        let offset = self.byte() as i8;
+        let opcode = self.byte();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let operand = indexed(self.hl(), offset);
+        let text = match x {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => return format!("BIT {},{}", y, operand),
+            2 => format!("RES {},{}", y, operand),
+            _ => format!("SET {},{}", y, operand),
+        };
+        if z == 6 { text } else { format!("{},{}", text, REG[z as usize]) }
+    }
+
+    fn ed(&mut self) -> String {
+        let opcode = self.byte();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 0) if y == 6 => "IN (C)".to_string(),
+            (1, 0) => format!("IN {},(C)", REG[y as usize]),
+            (1, 1) if y == 6 => "OUT (C),0".to_string(),
+            (1, 1) => format!("OUT (C),{}", REG[y as usize]),
+            (1, 2) => format!("{} HL,{}", if q == 0 { "SBC" } else { "ADC" }, RP[p as usize]),
+            (1, 3) if q == 0 => format!("LD ({}),{}", self.nn(), RP[p as usize]),
+            (1, 3) => format!("LD {},({})", RP[p as usize], self.nn()),
+            (1, 4) => "NEG".to_string(),
+            (1, 5) => if y == 1 { "RETI" } else { "RETN" }.to_string(),
+            (1, 6) => format!("IM {}", IM[y as usize]),
+            (1, _) => ["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD", "NOP", "NOP"][y as usize].to_string(),
+            (2, 0..=3) if y >= 4 => {
+                self.steps_over = y >= 6;
+                BLOCK[y as usize - 4][z as usize].to_string()
+            },
+            _ => format!("DB {},{}", hex8(0xED), hex8(opcode)),
+        }
+    }
+}
+
+fn indexed(index: &str, offset: i8) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("({}{}{:02X}H)", index, sign, offset.unsigned_abs())
+}
+
+/// Assembler hex, with a leading 0 when it starts with a letter
+pub fn hex8(value: u8) -> String {
+    if value >= 0xA0 { format!("0{:02X}H", value) } else { format!("{:02X}H", value) }
+}
+
+pub fn hex16(value: u16) -> String {
+    if value >= 0xA000 { format!("0{:04X}H", value) } else { format!("{:04X}H", value) }
+}
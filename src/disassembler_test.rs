@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::disassemble;
+
+    fn decode(bytes: &[u8]) -> (String, u16, bool) {
+        let instruction = disassemble(|address| bytes.get(address as usize - 0x100).copied().unwrap_or(0), 0x100);
+        (instruction.text, instruction.len, instruction.steps_over)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        for (bytes, text, len) in [
+            (&[0x00][..], "NOP", 1),
+            (&[0x08], "EX AF,AF'", 1),
+            (&[0x18, 0xFE], "JR 0100H", 2),
+            (&[0x20, 0x10], "JR NZ,0112H", 2),
+            (&[0x21, 0x34, 0x12], "LD HL,1234H", 3),
+            (&[0x22, 0x00, 0xF0], "LD (0F000H),HL", 3),
+            (&[0x3A, 0x5C, 0x00], "LD A,(005CH)", 3),
+            (&[0x36, 0xE5], "LD (HL),0E5H", 2),
+            (&[0x7E], "LD A,(HL)", 1),
+            (&[0x96], "SUB (HL)", 1),
+            (&[0x8F], "ADC A,A", 1),
+            (&[0xC3, 0x00, 0x01], "JP 0100H", 3),
+            (&[0xD3, 0x1C], "OUT (1CH),A", 2),
+            (&[0xE9], "JP (HL)", 1),
+            (&[0xFE, 0x0D], "CP 0DH", 2),
+            (&[0xF5], "PUSH AF", 1),
+            (&[0xCB, 0x7E], "BIT 7,(HL)", 2),
+            (&[0xCB, 0x38], "SRL B", 2),
+            (&[0xED, 0xA0], "LDI", 2),
+            (&[0xED, 0x43, 0x00, 0x02], "LD (0200H),BC", 4),
+            (&[0xED, 0x78], "IN A,(C)", 2),
+            (&[0xED, 0x5E], "IM 2", 2),
+            (&[0xED, 0x4D], "RETI", 2),
+            (&[0xED, 0x00], "DB 0EDH,00H", 2),
+            (&[0xDD, 0x21, 0x00, 0x80], "LD IX,8000H", 4),
+            (&[0xDD, 0x7E, 0xFB], "LD A,(IX-05H)", 3),
+            (&[0xFD, 0x36, 0x02, 0x41], "LD (IY+02H),41H", 4),
+            (&[0xDD, 0x66, 0x01], "LD H,(IX+01H)", 3),
+            (&[0xDD, 0x65], "LD IXH,IXL", 2),
+            (&[0xFD, 0xE9], "JP (IY)", 2),
+            (&[0xDD, 0x29], "ADD IX,IX", 2),
+            (&[0xDD, 0xCB, 0x03, 0x46], "BIT 0,(IX+03H)", 4),
+            (&[0xFD, 0xCB, 0xFF, 0xC7], "SET 0,(IY-01H),A", 4),
+            (&[0xDD, 0xDD], "DB 0DDH", 1),
+        ] {
+            assert_eq!(decode(bytes), (text.to_string(), len, false), "{:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_steps_over() {
+        for (bytes, text, len) in [
+            (&[0xCD, 0x05, 0x00][..], "CALL 0005H", 3),
+            (&[0xC4, 0x00, 0xE0], "CALL NZ,0E000H", 3),
+            (&[0xFF], "RST 38H", 1),
+            (&[0x10, 0xFE], "DJNZ 0100H", 2),
+            (&[0xED, 0xB8], "LDDR", 2),
+            (&[0x76], "HALT", 1),
+        ] {
+            assert_eq!(decode(bytes), (text.to_string(), len, true), "{:02X?}", bytes);
+        }
+    }
+}
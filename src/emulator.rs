@@ -0,0 +1,906 @@
+// Emulation thread.
+//
+// The machine and the CPU run on their own thread. The terminal and GUI
+// front ends send the keys and commands over channels and get back frame
+// snapshots to draw and the results of the commands, so slow terminal
+// output or window events never stall the emulation.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use iz80::{Cpu, Machine, Reg16, Reg8};
+
+use super::floppy_controller::Drive;
+use super::gdb::{self, Action, GdbStub};
+use super::kaypro_machine::{KayproMachine, RunExit, VideoMode};
+use super::media::MediaFormat;
+use super::monitor::{Monitor, Reply};
+use super::replay::{self, Input, Recorder, Replay};
+use super::rewind::{self, History};
+use super::rtc;
+use super::scheduler::Event;
+use super::snapshot::{self, CpuState, StateReader, StateWriter};
+
+// The emulation thread looks at the requests every slice
+const SLICE_TSTATES: u64 = 8 * 1024;
+// Frames are sent at most at 60 Hz of host time
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+// Updates the front end hasn't taken yet; the emulation waits for it
+// beyond that, as when the front end prompts for a file name.
+const UPDATE_QUEUE: usize = 4;
+
+/// Commands from the front end
+pub enum Request {
+    LoadDisk(Drive, String),
+    SaveBios,
+    /// Snapshot the machine to snapshot::QUICK_SAVE
+    SaveState,
+    LoadState(String),
+    /// Go back to a rewind point, 1 for the latest
+    Rewind(usize),
+    SetTrace(bool),
+    /// CPU clock in MHz, None for unlimited
+    SetSpeed(Option<f64>),
+    /// Stop the machine in the monitor
+    Break,
+    /// A command line for the monitor, see monitor.rs
+    Monitor(String),
+    /// From the connection of a debugger, see gdb.rs
+    Gdb(gdb::Input),
+    /// Sent by EmulatorHandle::stop, as the thread of the debugger keeps
+    /// the channel open
+    Quit,
+}
+
+/// Sent by the emulation thread to the front end
+pub enum Update {
+    Frame(Box<Frame>),
+    #[allow(dead_code)] // The drive and path are only shown by the GUI
+    DiskLoaded(Drive, String, Result<(), String>),
+    BiosSaved(Result<String, String>),
+    StateSaved(Result<String, String>),
+    #[allow(dead_code)] // The path is only shown by the GUI
+    StateLoaded(String, Result<(), String>),
+    Rewound(Result<String, String>),
+    /// The replay got to the end of the session, to the same machine
+    /// state or not
+    ReplayFinished(Result<(), String>),
+    /// The machine stopped in the monitor: the registers and the next
+    /// instruction
+    Stopped(String),
+    /// The output of a monitor command, the machine still stopped
+    Monitor(String),
+    /// A monitor command or the debugger let the machine go
+    Resumed,
+    /// The CPU executed a HALT that will never be interrupted. The
+    /// emulation is stopped but still serves the requests.
+    Halted,
+}
+
+/// What the front ends show, copied from the machine between slices.
+pub struct Frame {
+    pub video_mode: VideoMode,
+    /// Memory-mapped video RAM, or the SY6545 character and attribute RAM
+    pub vram: [u8; 4096],
+    pub vram_dirty: bool,
+    // SY6545 display registers
+    pub start_addr: usize,
+    pub cursor_addr: usize,
+    pub cursor_mode: u8,
+    pub displayed_rows: usize,
+    pub system_bits: u8,
+    pub last_key: u8,
+    pub sio_status: String,
+    pub motor_on: bool,
+    pub drive: u8,
+    pub single_density: bool,
+    /// Media info of drives A to D, None for the drives not connected
+    pub media: [Option<String>; 4],
+    /// Rewind points to go back to
+    pub rewind_points: usize,
+}
+
+impl Frame {
+    fn capture(machine: &mut KayproMachine) -> Frame {
+        let crtc_mode = machine.video_mode == VideoMode::Sy6545Crtc;
+        let fdc = &machine.floppy_controller;
+        let media = [Drive::A, Drive::B, Drive::C, Drive::D].map(|drive| {
+            fdc.drive_present(drive as u8).then(|| fdc.media(drive).info())
+        });
+        let frame = Frame {
+            video_mode: machine.video_mode,
+            vram: if crtc_mode { machine.crtc.vram } else { machine.vram },
+            vram_dirty: if crtc_mode { machine.crtc.vram_dirty } else { machine.vram_dirty },
+            start_addr: machine.crtc.start_addr(),
+            cursor_addr: machine.crtc.cursor_addr(),
+            cursor_mode: machine.crtc.cursor_mode(),
+            displayed_rows: machine.crtc.displayed_rows(),
+            system_bits: machine.system_bits,
+            last_key: machine.keyboard.peek_key(),
+            sio_status: machine.sio.status_string(),
+            motor_on: fdc.motor_on,
+            drive: fdc.drive,
+            single_density: fdc.single_density,
+            media,
+            rewind_points: 0,
+        };
+        machine.crtc.vram_dirty = false;
+        machine.vram_dirty = false;
+        frame
+    }
+
+    /// Media info of `drive`, empty if not connected
+    pub fn media_info(&self, drive: usize) -> &str {
+        self.media[drive].as_deref().unwrap_or("")
+    }
+
+    /// SY6545 character at `offset`, wrapping at 2KB
+    pub fn get_vram(&self, offset: usize) -> u8 {
+        self.vram[offset & 0x7FF]
+    }
+
+    /// SY6545 attribute at `offset`
+    pub fn get_attr(&self, offset: usize) -> u8 {
+        self.vram[(offset & 0x7FF) + 0x800]
+    }
+}
+
+/// The front end's side of the emulation thread
+pub struct EmulatorHandle {
+    pub requests: Sender<Request>,
+    pub keys: Sender<u8>,
+    pub updates: Receiver<Update>,
+    thread: JoinHandle<()>,
+}
+
+impl EmulatorHandle {
+    /// Stop the emulation and wait for the thread to flush the disks.
+    pub fn stop(self) {
+        let EmulatorHandle { requests, keys, updates, thread } = self;
+        let _ = requests.send(Request::Quit);
+        drop(requests);
+        drop(keys);
+        drop(updates);
+        let _ = thread.join();
+    }
+}
+
+pub struct Emulator {
+    machine: KayproMachine,
+    throttle: Throttle,
+    is_kaypro10_hardware: bool,
+    pub guest_trace: Option<GuestTrace>,
+    last_frame: Instant,
+    // Emulated time of the last frame, for deterministic runs
+    last_frame_us: u64,
+    deterministic: bool,
+    last_system_bits: u8,
+    frame_forced: bool,
+    // CPU of a snapshot loaded before the thread started
+    cpu_state: Option<CpuState>,
+    history: History,
+    // T-states of the next rewind point
+    rewind_at: u64,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    // Instruction count of the first input the replay got late, if any
+    replay_late: Option<u64>,
+    // Keys for the guest, taken from the front end or the replay between
+    // slices
+    guest_keys: Option<Sender<u8>>,
+    monitor: Monitor,
+    // Stopped in the monitor, only its commands run
+    stopped: bool,
+    gdb: Option<GdbStub>,
+}
+
+/// How a slice of the run loop ended
+#[derive(PartialEq)]
+enum SliceEnd {
+    Refresh,
+    /// The CPU halted for good
+    Halted,
+    /// A breakpoint or the monitor's target came up
+    Breakpoint,
+}
+
+impl Emulator {
+    /// `clock_mhz` paces the emulation, None runs unthrottled. Emulated
+    /// time runs at the set speed, or at the model's clock when unlimited.
+    pub fn new(mut machine: KayproMachine, clock_mhz: Option<f64>, is_kaypro10_hardware: bool) -> Emulator {
+        machine.set_cpu_clock(clock_mhz.unwrap_or(machine.video_mode.cpu_mhz()));
+        Emulator {
+            machine,
+            throttle: Throttle::new(clock_mhz),
+            is_kaypro10_hardware,
+            guest_trace: None,
+            last_frame: Instant::now(),
+            last_frame_us: 0,
+            deterministic: false,
+            last_system_bits: 0,
+            frame_forced: true,
+            cpu_state: None,
+            history: History::new(),
+            rewind_at: 0,
+            recorder: None,
+            replay: None,
+            replay_late: None,
+            guest_keys: None,
+            monitor: Monitor::new(),
+            stopped: false,
+            gdb: None,
+        }
+    }
+
+    /// Resume from the snapshot at `path` when the thread starts.
+    pub fn load_state(&mut self, path: &str) -> std::io::Result<()> {
+        self.cpu_state = Some(snapshot::load(path, &mut self.machine)?);
+        Ok(())
+    }
+
+    /// Write the inputs of the session to a replay file.
+    pub fn record(&mut self, path: &str) -> std::io::Result<()> {
+        let start_secs = self.virtual_clock("Recording")?;
+        self.recorder = Some(Recorder::create(path, start_secs)?);
+        Ok(())
+    }
+
+    /// Feed the inputs of a replay file instead of those of the front end
+    /// until the end of the recorded session.
+    pub fn replay(&mut self, path: &str) -> std::io::Result<()> {
+        let start_secs = self.virtual_clock("A replay")?;
+        let replay = Replay::open(path)?;
+        if replay.start_secs != start_secs {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "The replay was recorded with the clock starting at {}, not {}",
+                rtc::format_date(replay.start_secs), rtc::format_date(start_secs))));
+        }
+        self.replay = Some(replay);
+        Ok(())
+    }
+
+    /// Start of the clock of a deterministic run, for `what` that needs one.
+    fn virtual_clock(&self, what: &str) -> std::io::Result<i64> {
+        self.machine.rtc.virtual_clock().filter(|_| self.deterministic).ok_or_else(||
+            Error::new(ErrorKind::InvalidInput, format!("{} needs a deterministic run", what)))
+    }
+
+    /// Run on emulated time only: the RTC counts from `start_secs` (see
+    /// rtc::parse_date), there is no pacing to the host clock nor idle
+    /// sleeps, and frames are taken every FRAME_INTERVAL of emulated time.
+    /// The same inputs at the same instruction counts give the same frames.
+    pub fn set_deterministic(&mut self, start_secs: i64) {
+        self.machine.rtc.set_virtual_clock(start_secs);
+        self.machine.keyboard.idle_sleep_enabled = false;
+        self.throttle.set_speed(None, self.machine.tstates);
+        self.deterministic = true;
+    }
+
+    /// Start stopped in the monitor, before the first instruction.
+    pub fn debug(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Start the emulation thread.
+    pub fn spawn(mut self, trace_cpu: bool) -> EmulatorHandle {
+        let (requests, request_rx) = mpsc::channel();
+        let (keys, key_rx) = mpsc::channel();
+        let (update_tx, updates) = mpsc::sync_channel(UPDATE_QUEUE);
+        let (guest_keys, guest_key_rx) = mpsc::channel();
+        self.machine.keyboard.set_key_input(guest_key_rx);
+        self.guest_keys = Some(guest_keys);
+        let thread = thread::spawn(move || {
+            // The CPU isn't Send, it lives on the emulation thread
+            let mut cpu = Cpu::new_z80();
+            cpu.set_trace(trace_cpu);
+            if let Some(state) = self.cpu_state.take() {
+                state.apply(&mut cpu);
+            }
+            self.run(&mut cpu, request_rx, key_rx, update_tx);
+        });
+        EmulatorHandle { requests, keys, updates, thread }
+    }
+
+    fn run(&mut self, cpu: &mut Cpu, requests: Receiver<Request>, keys: Receiver<u8>, updates: SyncSender<Update>) {
+        if self.stopped {
+            self.stop(cpu, &updates, true);
+        }
+        loop {
+            loop {
+                match requests.try_recv() {
+                    Ok(Request::Quit) | Err(TryRecvError::Disconnected) => {
+                        self.finish(cpu);
+                        return;
+                    }
+                    Ok(request) => self.take_request(cpu, request, &updates),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+            for key in keys.try_iter() {
+                self.take_key(key);
+            }
+            self.take_replay_inputs(cpu, &updates);
+            if self.stopped && !self.serve_monitor(cpu, &requests, &updates) {
+                return;
+            }
+
+            let end = self.run_slice(cpu);
+            if end == SliceEnd::Breakpoint {
+                // The debugger takes the stops it waits for
+                let show_monitor = !self.gdb.as_ref().is_some_and(|gdb| gdb.is_running());
+                self.stop(cpu, &updates, show_monitor);
+                continue;
+            }
+            let halted = end == SliceEnd::Halted;
+            if !halted && self.machine.tstates >= self.rewind_at {
+                self.take_rewind_point(cpu);
+            }
+            self.throttle.pace(self.machine.tstates);
+            self.send_frame(&updates, halted);
+
+            if halted {
+                let _ = updates.send(Update::Halted);
+                self.take_replay_inputs(cpu, &updates);
+                // Still serves the requests; from the monitor the machine
+                // can go on
+                while !self.stopped {
+                    match requests.recv() {
+                        Ok(Request::Quit) | Err(_) => {
+                            self.finish(cpu);
+                            return;
+                        },
+                        Ok(request) => self.take_request(cpu, request, &updates),
+                    }
+                }
+            }
+        }
+    }
+
+    /// A request from the front end. Ignored while replaying.
+    fn take_request(&mut self, cpu: &mut Cpu, request: Request, updates: &SyncSender<Update>) {
+        if self.replay.is_some() {
+            return;
+        }
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.request(self.machine.instructions, &request);
+        }
+        self.handle(cpu, request, updates);
+    }
+
+    /// A key from the front end. Dropped while replaying.
+    fn take_key(&mut self, key: u8) {
+        if self.replay.is_some() {
+            return;
+        }
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.key(self.machine.instructions, key);
+        }
+        self.send_guest_key(key);
+    }
+
+    fn send_guest_key(&self, key: u8) {
+        if let Some(ref guest_keys) = self.guest_keys {
+            let _ = guest_keys.send(key);
+        }
+    }
+
+    /// Hand over the inputs of the replay due at this instruction.
+    fn take_replay_inputs(&mut self, cpu: &mut Cpu, updates: &SyncSender<Update>) {
+        let Some(ref mut replay) = self.replay else {
+            return;
+        };
+        let instructions = self.machine.instructions;
+        let due = replay.take_due(instructions);
+        let finished = replay.is_finished();
+        let mut result = Ok(());
+        for (at, input) in due {
+            if at != instructions && self.replay_late.is_none() {
+                self.replay_late = Some(at);
+            }
+            match input {
+                Input::Key(key) => self.send_guest_key(key),
+                Input::Request(request) => self.handle(cpu, request, updates),
+                Input::End(hash) => {
+                    result = if let Some(late) = self.replay_late {
+                        Err(format!("The replay lost step at instruction {}", late))
+                    } else if hash != self.state_hash(cpu) {
+                        Err("The replay ended in another machine state".to_string())
+                    } else {
+                        Ok(())
+                    };
+                }
+            }
+        }
+        if finished {
+            self.replay = None;
+            self.frame_forced = true;
+            let _ = updates.send(Update::ReplayFinished(result));
+        }
+    }
+
+    /// Hash of the machine state, for the end of a replay.
+    fn state_hash(&mut self, cpu: &mut Cpu) -> u64 {
+        self.machine.settle(cpu);
+        let mut state = StateWriter::new();
+        self.machine.save_state(&mut state);
+        CpuState::capture(cpu).save_state(&mut state);
+        replay::state_hash(&state.into_bytes())
+    }
+
+    /// The front end is gone: end the recording and flush the disks.
+    fn finish(&mut self, cpu: &mut Cpu) {
+        if self.recorder.is_some() {
+            // Stamped before settling, where the replay takes it
+            let instructions = self.machine.instructions;
+            let hash = self.state_hash(cpu);
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.end(instructions, hash);
+            }
+        }
+        self.flush();
+    }
+
+    /// Run SLICE_TSTATES, or up to a breakpoint.
+    fn run_slice(&mut self, cpu: &mut Cpu) -> SliceEnd {
+        self.machine.scheduler.schedule(self.machine.tstates + SLICE_TSTATES, Event::Refresh);
+        let watching = self.monitor.is_watching();
+        loop {
+            let exit = self.machine.tick(cpu);
+            if let Some(ref mut trace) = self.guest_trace {
+                trace.trace(&self.machine, cpu);
+            }
+            // An NMI signaled to the CPU goes first, and a halted CPU
+            // stays on its PC
+            if watching && self.machine.is_settled() && !cpu.is_halted()
+                    && self.monitor.stops_at(cpu.registers().pc()) {
+                return SliceEnd::Breakpoint;
+            }
+            match exit {
+                Some(RunExit::Event(Event::Refresh)) => return SliceEnd::Refresh,
+                Some(RunExit::Halted) => return SliceEnd::Halted,
+                _ => {},
+            }
+        }
+    }
+
+    /// Stop the machine, telling the debugger if it waits for it, and
+    /// show where in the monitor of the front end if `show_monitor`.
+    fn stop(&mut self, cpu: &mut Cpu, updates: &SyncSender<Update>, show_monitor: bool) {
+        self.stopped = true;
+        self.machine.settle(cpu);
+        self.send_frame(updates, true);
+        if let Some(ref mut gdb) = self.gdb {
+            gdb.stopped();
+        }
+        if show_monitor {
+            let _ = updates.send(Update::Stopped(self.monitor.stopped_at(&self.machine, cpu)));
+        }
+    }
+
+    fn go(&mut self, updates: &SyncSender<Update>) {
+        self.stopped = false;
+        let _ = updates.send(Update::Resumed);
+    }
+
+    /// Serve the requests until a monitor command lets the machine go.
+    /// Returns false if the front end is gone.
+    fn serve_monitor(&mut self, cpu: &mut Cpu, requests: &Receiver<Request>, updates: &SyncSender<Update>) -> bool {
+        while self.stopped {
+            match requests.recv() {
+                Ok(Request::Quit) | Err(_) => {
+                    self.finish(cpu);
+                    return false;
+                },
+                Ok(request) => self.take_request(cpu, request, updates),
+            }
+        }
+        true
+    }
+
+    fn monitor_command(&mut self, cpu: &mut Cpu, line: &str, updates: &SyncSender<Update>) {
+        if !self.stopped {
+            // Sent as the machine went on
+            return;
+        }
+        match self.monitor.command(line, &mut self.machine, cpu) {
+            Reply::Stopped(output) => {
+                // The command may have changed the screen
+                self.send_frame(updates, true);
+                let _ = updates.send(Update::Monitor(output));
+            },
+            Reply::Go => self.go(updates),
+        }
+    }
+
+    fn gdb_input(&mut self, cpu: &mut Cpu, input: gdb::Input, updates: &SyncSender<Update>) {
+        match input {
+            gdb::Input::Connected(stream) => {
+                self.gdb = Some(GdbStub::new(stream));
+                // The debugger starts on a stopped machine
+                if !self.stopped {
+                    self.stop(cpu, updates, false);
+                }
+            },
+            gdb::Input::Packet(packet) => {
+                let Some(ref mut gdb) = self.gdb else {
+                    return;
+                };
+                match gdb.packet(&packet, self.stopped, &mut self.machine, cpu, &mut self.monitor) {
+                    // A step may have changed the screen
+                    Action::Stay => self.send_frame(updates, false),
+                    Action::Go => self.go(updates),
+                    Action::Detach => {
+                        self.gdb = None;
+                        if self.stopped {
+                            self.go(updates);
+                        }
+                    },
+                }
+            },
+            gdb::Input::Interrupt => {
+                if let Some(ref mut gdb) = self.gdb {
+                    if gdb.is_running() && !self.stopped {
+                        gdb.interrupt();
+                        self.stop(cpu, updates, false);
+                    }
+                }
+            },
+            gdb::Input::Disconnected => {
+                // Not after a detach
+                if self.gdb.take().is_some() && self.stopped {
+                    self.go(updates);
+                }
+            },
+        }
+    }
+
+    /// Send a frame if the screen changed and the last one is old enough.
+    fn send_frame(&mut self, updates: &SyncSender<Update>, force: bool) {
+        let vram_dirty = self.machine.vram_dirty || self.machine.crtc.vram_dirty;
+        let changed = vram_dirty
+            || self.frame_forced
+            || self.machine.system_bits != self.last_system_bits;
+        let now_us = self.machine.now_us();
+        let due = if self.deterministic {
+            // Rewinds take the emulated time back
+            now_us.abs_diff(self.last_frame_us) >= FRAME_INTERVAL.as_micros() as u64
+        } else {
+            self.last_frame.elapsed() >= FRAME_INTERVAL
+        };
+        if !force && (!changed || !due) {
+            return;
+        }
+        self.last_frame = Instant::now();
+        self.last_frame_us = now_us;
+        self.last_system_bits = self.machine.system_bits;
+        self.frame_forced = false;
+        let mut frame = Frame::capture(&mut self.machine);
+        frame.rewind_points = self.history.len();
+        let _ = updates.send(Update::Frame(Box::new(frame)));
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, request: Request, updates: &SyncSender<Update>) {
+        let update = match request {
+            Request::LoadDisk(drive, path) => {
+                let result = self.load_disk(drive, &path);
+                if result.is_ok() {
+                    // The journal of the disk is gone
+                    self.forget_rewind_points();
+                }
+                Update::DiskLoaded(drive, path, result)
+            },
+            Request::SaveBios => Update::BiosSaved(self.machine.save_bios()),
+            Request::SaveState => Update::StateSaved(self.save_snapshot(cpu, snapshot::QUICK_SAVE)),
+            Request::LoadState(path) => {
+                let result = self.load_snapshot(cpu, &path);
+                // Even a failed load may have changed the disks
+                self.forget_rewind_points();
+                Update::StateLoaded(path, result)
+            },
+            Request::Rewind(back) => Update::Rewound(self.rewind(cpu, back)),
+            Request::SetTrace(trace) => {
+                cpu.set_trace(trace);
+                return;
+            },
+            Request::SetSpeed(clock_mhz) => {
+                let cpu_mhz = self.machine.video_mode.cpu_mhz();
+                self.machine.set_cpu_clock(clock_mhz.unwrap_or(cpu_mhz));
+                let pace_mhz = if self.deterministic { None } else { clock_mhz };
+                self.throttle.set_speed(pace_mhz, self.machine.tstates);
+                return;
+            },
+            Request::Break => {
+                // Shows where it is again if already stopped
+                self.stop(cpu, updates, true);
+                return;
+            },
+            Request::Monitor(line) => {
+                self.monitor_command(cpu, &line, updates);
+                return;
+            },
+            Request::Gdb(input) => {
+                self.gdb_input(cpu, input, updates);
+                return;
+            },
+            // Taken as it comes
+            Request::Quit => return,
+        };
+        // The status shows the media
+        self.frame_forced = true;
+        let _ = updates.send(update);
+    }
+
+    fn load_disk(&mut self, drive: Drive, path: &str) -> Result<(), String> {
+        let fdc = &mut self.machine.floppy_controller;
+        fdc.media_mut(drive).load_disk(path).map_err(|err| err.to_string())?;
+        if let Drive::A = drive {
+            fdc.disk_in_drive = true;
+            fdc.motor_on = true;
+            // Kaypro 10: the ROM cached the floppy drive type at
+            // boot when no disk was present (defaulting to SSDD).
+            // Patch the drive type table at 0xFFF6 to match the
+            // actual format of the inserted disk image.
+            if self.is_kaypro10_hardware {
+                let type_byte = match fdc.media_a().format {
+                    MediaFormat::DsDd => 0x09,
+                    MediaFormat::SsDd => 0x05,
+                    _ => 0x01,
+                };
+                self.machine.poke(0xFFF6, type_byte);
+            }
+        }
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, cpu: &mut Cpu, path: &str) -> Result<String, String> {
+        self.machine.settle(cpu);
+        self.flush();
+        let state = CpuState::capture(cpu);
+        snapshot::save(path, &self.machine, &state)
+            .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+        Ok(path.to_string())
+    }
+
+    fn load_snapshot(&mut self, cpu: &mut Cpu, path: &str) -> Result<(), String> {
+        self.machine.settle(cpu);
+        let state = snapshot::load(path, &mut self.machine)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        state.apply(cpu);
+        // The T-states went back or forward to the snapshot's
+        self.throttle.set_speed(self.throttle.mhz, self.machine.tstates);
+        Ok(())
+    }
+
+    /// Keep the state of the machine as a rewind point.
+    fn take_rewind_point(&mut self, cpu: &mut Cpu) {
+        self.machine.settle(cpu);
+        let mut state = StateWriter::new();
+        self.machine.save_state(&mut state);
+        let cpu_state = CpuState::capture(cpu);
+        let (point, oldest) = self.history.push(self.machine.now_us(), state.into_bytes(), cpu_state);
+        self.machine.mark_rewind_point(point, oldest);
+        self.rewind_at = self.machine.tstates + rewind::INTERVAL_TSTATES;
+        // The front ends show how far back they can go
+        self.frame_forced = true;
+    }
+
+    /// Go back `back` rewind points, 1 for the latest.
+    fn rewind(&mut self, cpu: &mut Cpu, back: usize) -> Result<String, String> {
+        self.machine.settle(cpu);
+        let now_us = self.machine.now_us();
+        let Some(point) = self.history.rewind(back) else {
+            return Err(format!("No rewind point {}, there are {}", back, self.history.len()));
+        };
+        self.machine.rewind_disks(point.id);
+        let mut state = StateReader::new(&point.machine);
+        self.machine.load_state(&mut state)
+            .and_then(|_| state.finish())
+            .map_err(|err| format!("Rewind failed: {}", err))?;
+        point.cpu.apply(cpu);
+        let seconds = now_us.saturating_sub(point.time_us) as f64 / 1_000_000.0;
+        self.rewind_at = self.machine.tstates + rewind::INTERVAL_TSTATES;
+        self.throttle.set_speed(self.throttle.mhz, self.machine.tstates);
+        Ok(format!("Rewound {:.1} seconds", seconds))
+    }
+
+    fn forget_rewind_points(&mut self) {
+        self.history.clear();
+        self.machine.forget_rewind_points();
+        self.rewind_at = self.machine.tstates;
+    }
+
+    fn flush(&mut self) {
+        for drive in [Drive::A, Drive::B, Drive::C, Drive::D] {
+            self.machine.floppy_controller.media_mut(drive).flush_disk();
+        }
+        if let Some(ref mut hd) = self.machine.hard_disk {
+            hd.flush();
+        }
+    }
+}
+
+/// Paces the emulation to a CPU clock by sleeping whenever the executed
+/// T-states run ahead of the host clock. None runs unthrottled.
+struct Throttle {
+    mhz: Option<f64>,
+    start_time: Instant,
+    start_tstates: u64,
+}
+
+impl Throttle {
+    fn new(mhz: Option<f64>) -> Throttle {
+        Throttle { mhz, start_time: Instant::now(), start_tstates: 0 }
+    }
+
+    fn set_speed(&mut self, mhz: Option<f64>, tstates: u64) {
+        self.mhz = mhz;
+        self.start_time = Instant::now();
+        self.start_tstates = tstates;
+    }
+
+    /// Sleep until the host clock catches up with `tstates`.
+    fn pace(&mut self, tstates: u64) {
+        let Some(mhz) = self.mhz else {
+            return;
+        };
+        let target_cycles_per_sec = mhz * 1_000_000.0;
+        let elapsed = self.start_time.elapsed();
+        let expected_cycles = elapsed.as_secs_f64() * target_cycles_per_sec;
+        let cycles = (tstates - self.start_tstates) as f64;
+        if cycles > expected_cycles {
+            // We're running too fast, need to wait
+            let wait_secs = (cycles - expected_cycles) / target_cycles_per_sec;
+            if wait_secs > 0.0001 {
+                thread::sleep(Duration::from_secs_f64(wait_secs));
+            }
+        }
+        // Restart the measurement periodically to avoid drift
+        if elapsed.as_secs() >= 1 {
+            self.set_speed(self.mhz, tstates);
+        }
+    }
+}
+
+/// Traces the BIOS entry points and the BDOS calls of the guest. The BIOS
+/// base is discovered at runtime, so it works with any ROM.
+pub struct GuestTrace {
+    trace_bios: bool,
+    trace_bdos: bool,
+    log: Option<File>,
+    counter: u64,
+    bios_base: Option<u16>,
+    last_rom_rank: bool,
+}
+
+impl GuestTrace {
+    /// BIOS entries are traced only to the `log` file, BDOS calls to the
+    /// log or to stdout.
+    pub fn new(trace_rom: bool, trace_bdos: bool, log: Option<File>) -> GuestTrace {
+        GuestTrace {
+            trace_bios: (trace_rom || trace_bdos) && log.is_some(),
+            trace_bdos,
+            log,
+            counter: 1,
+            bios_base: None,
+            last_rom_rank: true, // Start in ROM mode
+        }
+    }
+
+    fn trace(&mut self, machine: &KayproMachine, cpu: &mut Cpu) {
+        self.counter += 1;
+        let counter = self.counter;
+
+        // Runtime BIOS base discovery: detect ROM→RAM transition
+        if self.trace_bios {
+            let in_rom = machine.is_rom_rank();
+            if in_rom != self.last_rom_rank {
+                self.last_rom_rank = in_rom;
+                if !in_rom && self.bios_base.is_none() {
+                    let warm_lo = machine.peek(0x0001) as u16;
+                    let warm_hi = machine.peek(0x0002) as u16;
+                    let warm_boot = (warm_hi << 8) | warm_lo;
+                    if warm_boot > 0x100 && warm_boot < 0xFFFF {
+                        let base = warm_boot - 3;
+                        self.bios_base = Some(base);
+                        if let Some(ref mut f) = self.log {
+                            let _ = writeln!(f, "[{:>10}] BIOS base discovered: 0x{:04X}", counter, base);
+                        }
+                    }
+                }
+            }
+
+            let pc = cpu.registers().pc();
+
+            // BIOS entry point tracing
+            if !in_rom {
+                if let Some(base) = self.bios_base {
+                    if pc >= base && pc <= base + 51 && (pc - base).is_multiple_of(3) {
+                        let entry = (pc - base) / 3;
+                        let msg: Option<String> = match entry {
+                            0 => Some("BOOT".into()),
+                            1 => Some("WBOOT".into()),
+                            8 => Some("HOME".into()),
+                            9 => Some(format!("SELDSK drive={} ({})",
+                                cpu.registers().get8(Reg8::C),
+                                (b'A' + cpu.registers().get8(Reg8::C)) as char)),
+                            10 => Some(format!("SETTRK track={}",
+                                cpu.registers().get8(Reg8::C))),
+                            11 => Some(format!("SETSEC sector={}",
+                                cpu.registers().get8(Reg8::C))),
+                            12 => Some("SETDMA".into()),
+                            13 => Some("READ".into()),
+                            14 => Some("WRITE".into()),
+                            16 => {
+                                let sec = cpu.registers().get16(Reg16::BC);
+                                let xlt = cpu.registers().get16(Reg16::DE);
+                                Some(format!("SECTRAN sector={} xlt=0x{:04X}", sec, xlt))
+                            },
+                            _ => None,
+                        };
+                        if let Some(m) = msg {
+                            if let Some(ref mut f) = self.log {
+                                let _ = writeln!(f, "[{:>10}] BIOS: {}", counter, m);
+                                let _ = f.flush();
+                            } else {
+                                println!("BIOS: {}", m);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.trace_bdos && !machine.is_rom_rank()
+                && cpu.registers().pc() == 0x0005 {
+            let command = cpu.registers().get8(Reg8::C);
+            if command != 0x06 /*C_RAWIO*/ {
+                let args = cpu.registers().get16(Reg16::DE);
+                let name = if command < BDOS_COMMAND_NAMES.len() as u8 {
+                    BDOS_COMMAND_NAMES[command as usize]
+                } else {
+                    "unknown"
+                };
+
+                if let Some(ref mut f) = self.log {
+                    let _ = writeln!(f, "[{:>10}] BDOS {}: {}({:04x})", counter, command, name, args);
+                    // For file operations, dump FCB filename
+                    if command == 15 || command == 17 || command == 22 {
+                        let de = args;
+                        let fcb_drive = machine.peek(de);
+                        let mut filename = String::new();
+                        for i in 1..=11u16 {
+                            let ch = machine.peek(de.wrapping_add(i)) & 0x7F;
+                            if ch >= 0x20 { filename.push(ch as char); }
+                        }
+                        let _ = writeln!(f, "[{:>10}]   FCB: drive={} file=\"{}\"",
+                            counter, fcb_drive, filename.trim());
+                    }
+                    let _ = f.flush();
+                } else {
+                    println!("BDOS command {}: {}({:04x})", command, name, args);
+                }
+            }
+        }
+    }
+}
+
+const BDOS_COMMAND_NAMES: [&str; 50] = [
+    // 0
+    "P_TERMCPM", "C_READ", "C_WRITE", "A_READ", "A_WRITE",
+    "L_WRITE", "C_RAWIO", "A_STATIN", "A_STATOUT", "C_WRITESTR",
+    // 10
+    "C_READSTR", "C_STAT", "S_BDOSVER", "DRV_ALLRESET", "DRV_SET",
+    "F_OPEN", "F_CLOSE", "F_SFIRST", "F_SNEXT", "F_DELETE",
+    // 20
+    "F_READ", "F_WRITE", "F_MAKE", "F_RENAME", "DRV_LOGINVEC",
+    "DRV_GET", "F_DMAOFF", "DRV_ALLOCVEC", "DRV_SETRO", "DRV_ROVEC",
+    // 30
+    "F_ATTRIB", "DRV_DPB", "F_USERNUM", "F_READRAND", "F_WRITERAND",
+    "F_SIZE", "F_RANDREC", "DRV_RESET", "*", "",
+    // 40
+    "F_WRITEZ", "", "", "", "",
+    "F_ERRMODE", "", "", "", "",
+    ];
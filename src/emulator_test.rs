@@ -0,0 +1,94 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use iz80::Machine;
+
+    use crate::emulator::{Emulator, Request, Update};
+    use crate::floppy_controller::Drive;
+    use crate::kaypro_machine::{KayproMachine, VideoMode};
+    use crate::rtc::{parse_date, DEFAULT_START_DATE};
+    use crate::test_support::machine;
+
+    fn kaypro_4() -> KayproMachine {
+        machine_with_video(VideoMode::MemoryMapped)
+    }
+
+    fn machine_with_video(video_mode: VideoMode) -> KayproMachine {
+        machine("roms/81-292a.rom", video_mode, false)
+    }
+
+    #[test]
+    fn test_keys_come_from_the_front_end() {
+        let mut machine = kaypro_4();
+        let (keys, key_rx) = mpsc::channel();
+        machine.keyboard.set_key_input(key_rx);
+        assert_eq!(machine.port_in(0x07) & 0x01, 0);
+        keys.send(b'a').unwrap();
+        assert_eq!(machine.port_in(0x07) & 0x01, 0x01);
+        assert_eq!(machine.port_in(0x05), b'a');
+    }
+
+    #[test]
+    fn test_emulation_thread_serves_the_front_end() {
+        let emulator = Emulator::new(kaypro_4(), None, false).spawn(false);
+        let timeout = Duration::from_secs(10);
+
+        // The first frame is sent without waiting for a change
+        let Ok(Update::Frame(frame)) = emulator.updates.recv_timeout(timeout) else {
+            panic!("no first frame");
+        };
+        assert!(frame.media[Drive::B as usize].is_some());
+        assert!(frame.media[Drive::C as usize].is_none());
+
+        emulator.requests.send(Request::LoadDisk(Drive::B, "__nonexistent_disk__".into())).unwrap();
+        loop {
+            match emulator.updates.recv_timeout(timeout) {
+                Ok(Update::DiskLoaded(_, path, result)) => {
+                    assert_eq!(path, "__nonexistent_disk__");
+                    assert!(result.is_err());
+                    break;
+                },
+                Ok(_) => {},
+                Err(e) => panic!("no reply: {}", e),
+            }
+        }
+        // Returns once the thread has flushed the disks
+        emulator.stop();
+    }
+
+    /// The screens of the frames sent up to the CP/M prompt.
+    fn deterministic_screens() -> Vec<String> {
+        let mut emulator = Emulator::new(machine_with_video(VideoMode::Sy6545Crtc), Some(1.0), false);
+        emulator.set_deterministic(parse_date(DEFAULT_START_DATE).unwrap());
+        let emulator = emulator.spawn(false);
+        let mut screens = Vec::new();
+        loop {
+            match emulator.updates.recv_timeout(Duration::from_secs(10)) {
+                Ok(Update::Frame(frame)) => {
+                    let screen: String = (0..0x800).map(|offset| (frame.get_vram(offset) & 0x7F) as char).collect();
+                    let prompt = screen.contains("A0>");
+                    screens.push(screen);
+                    if prompt {
+                        break;
+                    }
+                },
+                Ok(_) => {},
+                Err(e) => panic!("no prompt: {}", e),
+            }
+        }
+        emulator.stop();
+        screens
+    }
+
+    #[test]
+    fn test_deterministic_runs_send_the_same_frames() {
+        // Several seconds at 1 MHz, not paced to the host clock
+        let start = Instant::now();
+        let screens = deterministic_screens();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(screens.len() > 1);
+        assert!(deterministic_screens() == screens);
+    }
+}
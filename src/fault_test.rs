@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod tests {
+    use crate::floppy_controller::FloppyController;
+    use crate::imd::{ImdImage, ImdTrack};
+    use crate::media::{self, Media, MediaFormat};
+    use crate::test_support::temp_path;
+
+    fn cleanup(path: &str) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(media::fault_map_path(path));
+    }
+
+    /// Kaypro DSDD image on drive B, heads on track 1.
+    fn fdc_with_faults(path: &str, faults: &str) -> FloppyController {
+        std::fs::write(path, vec![0xE5; 40 * 2 * 10 * 512]).unwrap();
+        std::fs::write(media::fault_map_path(path), faults).unwrap();
+        let mut fdc = FloppyController::new(
+            "__nonexistent_test_a__",
+            path,
+            MediaFormat::DsDd,
+            10,
+            false,
+            false,
+        );
+        fdc.set_drive(1);
+        fdc.set_motor(true);
+        fdc.set_single_density(false);
+        fdc.put_data(1);
+        fdc.put_command(0x10); // SEEK track 1
+        fdc
+    }
+
+    fn read_sector(fdc: &mut FloppyController, sector: u8) -> (Vec<u8>, u8) {
+        fdc.put_sector(sector);
+        fdc.put_command(0x80);
+        if fdc.get_status() & 0x10 != 0 {
+            return (Vec::new(), fdc.get_status());
+        }
+        let data = (0..512).map(|_| fdc.get_data()).collect();
+        (data, fdc.get_status())
+    }
+
+    fn write_sector(fdc: &mut FloppyController, command: u8, sector: u8, value: u8) -> u8 {
+        fdc.put_sector(sector);
+        fdc.put_command(command);
+        if fdc.get_status() & 0x01 != 0 {
+            for _ in 0..512 {
+                fdc.put_data(value);
+            }
+        }
+        fdc.get_status()
+    }
+
+    #[test]
+    fn test_parse_fault_map() {
+        let faults = media::parse_fault_map(
+            "# track side sector faults\n\n1 0 2 crc\n1 1 12 deleted weak # comment\n1 0 2 rnf\n").unwrap();
+        assert_eq!(faults.len(), 2);
+        assert_eq!(faults[&(1, false, 2)], media::FAULT_CRC | media::FAULT_RNF);
+        assert_eq!(faults[&(1, true, 12)], media::FAULT_DELETED | media::FAULT_WEAK);
+
+        let error = media::parse_fault_map("1 0 2 crc\n1 0 3 smudged\n").unwrap_err();
+        assert!(error.to_string().contains("line 2"));
+        assert!(media::parse_fault_map("1 2 3 crc\n").is_err());
+        assert!(media::parse_fault_map("1 0 3\n").is_err());
+    }
+
+    #[test]
+    fn test_fdc_reports_sector_faults() {
+        let path = temp_path("fault_fdc.img");
+        let mut fdc = fdc_with_faults(&path, "1 0 2 crc\n1 0 3 rnf\n1 0 4 deleted\n1 0 5 weak\n1 0 6 wfault\n");
+
+        let (data, status) = read_sector(&mut fdc, 1);
+        assert_eq!((data, status), (vec![0xE5; 512], 0x00));
+        let (data, status) = read_sector(&mut fdc, 2);
+        assert_eq!((data, status), (vec![0xE5; 512], 0x08));
+        assert_eq!(read_sector(&mut fdc, 3).1 & 0x10, 0x10);
+        assert_eq!(read_sector(&mut fdc, 4).1, 0x20);
+
+        let (first, status) = read_sector(&mut fdc, 5);
+        assert_eq!(status, 0x08);
+        let (second, _) = read_sector(&mut fdc, 5);
+        assert_ne!(first, second);
+        assert!(first.iter().filter(|&&b| b == 0xE5).count() > 400);
+
+        // Multi-sector read stops at the CRC error
+        fdc.put_sector(1);
+        fdc.put_command(0x90);
+        let data: Vec<u8> = (0..1024).map(|_| fdc.get_data()).collect();
+        assert_eq!(data, vec![0xE5; 1024]);
+        assert_eq!(fdc.get_status(), 0x08);
+        assert_eq!(fdc.get_sector(), 2);
+
+        assert_eq!(write_sector(&mut fdc, 0xA0, 6, 0x66), 0x20);
+        assert_eq!(write_sector(&mut fdc, 0xA0, 3, 0x33) & 0x10, 0x10);
+
+        // Rewriting a sector fixes its CRC; a0 writes a deleted data mark
+        assert_eq!(write_sector(&mut fdc, 0xA0, 2, 0x22), 0x00);
+        assert_eq!(read_sector(&mut fdc, 2), (vec![0x22; 512], 0x00));
+        assert_eq!(write_sector(&mut fdc, 0xA1, 7, 0x77), 0x00);
+        assert_eq!(read_sector(&mut fdc, 7), (vec![0x77; 512], 0x20));
+        assert_eq!(fdc.media_b().fault(false, 1, 6), media::FAULT_WRITE);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_imd_sector_types_round_trip() {
+        let path = temp_path("fault_imd.imd");
+        let tracks = (0..80u8).map(|t| {
+            let mut sector_types = vec![1; 10];
+            if t == 2 {
+                sector_types[..3].copy_from_slice(&[3, 5, 7]);
+            }
+            ImdTrack {
+                mode: 5,
+                cylinder: t / 2,
+                head: t % 2,
+                n: 2,
+                sector_ids: (0..10).map(|i| i + (t % 2) * 10).collect(),
+                cylinder_map: None,
+                head_map: None,
+                sector_types,
+                data: vec![vec![0xE5; 512]; 10],
+            }
+        }).collect();
+        let image = ImdImage { header: b"IMD 1.18: 01/01/1983 00:00:00\r\n".to_vec(), tracks };
+        std::fs::write(&path, image.to_bytes()).unwrap();
+        std::fs::write(media::fault_map_path(&path), "1 0 5 weak\n").unwrap();
+
+        let mut media = Media::new(10);
+        media.load_disk(&path).unwrap();
+        assert_eq!(media.fault(false, 1, 0), media::FAULT_DELETED);
+        assert_eq!(media.fault(false, 1, 1), media::FAULT_CRC);
+        assert_eq!(media.fault(false, 1, 2), media::FAULT_DELETED | media::FAULT_CRC);
+        assert_eq!(media.fault(false, 1, 5), media::FAULT_WEAK);
+
+        // Writing sector 1 clears its CRC error when the image is saved;
+        // the sidecar's weak sector 5 stays out of it
+        let (_, index, last) = media.sector_index(false, 1, 1);
+        for i in index..last {
+            media.write_byte(i, 0x11);
+        }
+        media.sector_written(false, 1, 1, false);
+        media.flush_disk();
+
+        let saved = ImdImage::parse(&std::fs::read(&path).unwrap()).unwrap();
+        cleanup(&path);
+        assert_eq!(saved.tracks[2].sector_types[..6], [3, 1, 7, 1, 1, 1]);
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::VecDeque;
 use std::fs::{self, File, OpenOptions};
 use std::io::Read;
+use super::floppy_drive::DriveType;
+use super::host_dir;
 use super::media::{self, *};
+use super::scheduler::Event;
+use super::snapshot::{self, StateReader, StateWriter};
 
 /// Route FDC trace output to trace_file when available, otherwise println!.
 /// This allows --trace-log to capture FDC traces without disrupting the screen.
@@ -20,11 +24,18 @@ macro_rules! fdc_log {
 static FALLBACK_DISK_DSDD: &[u8] = include_bytes!("../disks/system/cpm22g-rom292a.img");
 static FALLBACK_BLANK_DSDD: &[u8] = include_bytes!("../disks/blank_disks/cpm22-kaypro4-blank.img");
 
+#[derive(Clone, Copy)]
 pub enum Drive {
     A = 0,
     B = 1,
+    C = 2,
+    D = 3,
 }
 
+/// Drives the controller can select: A and B on the native select lines,
+/// C and D through the Advent personality board.
+pub const DRIVE_COUNT: usize = 4;
+
 pub struct FloppyController {
     pub motor_on: bool,
     /// Whether a floppy disk is physically in the drive. Controls the
@@ -41,7 +52,9 @@ pub struct FloppyController {
     data: u8,
     status: u8,
 
-    media: [Media ;2],
+    media: [Media; DRIVE_COUNT],
+    /// Drive mechanisms; None follows the inserted media
+    drive_types: [Option<DriveType>; DRIVE_COUNT],
 
     pub read_index: usize,
     pub read_last: usize,
@@ -57,19 +70,30 @@ pub struct FloppyController {
     write_track_head: u8,     // Head position latched at WRITE TRACK start
     multi_sector: bool,
 
-    // Index pulse simulation - counter for toggling index bit
-    status_read_count: u32,
+    // Sector fault state of the current READ/WRITE SECTOR transfer
+    transfer_track: u8,       // Track the sector was looked up with
+    pending_status: u8,       // Fault status bits reported when the sector ends
+    weak_read: bool,          // Sector has weak bits: reads are randomized
+    weak_seed: u32,
+    write_deleted: bool,      // WRITE SECTOR a0: deleted data mark
+
+    // Emulated time in microseconds, set by the machine before each access.
+    // The index pulse and the sector under the head follow from it.
+    pub now_us: u64,
+    // Sector count since power-on of the last ID field READ ADDRESS returned
+    last_id_field: u64,
+    // Events posted at an emulated time, collected by the machine
+    pub posted: Vec<(u64, Event)>,
 
     // Counts consecutive status reads without data access while BUSY.
     // When the program stops reading/writing data and just polls status,
     // the transfer has been abandoned and BUSY should clear.
     status_polls_without_data: u8,
 
-    // READ ADDRESS busy countdown: when >0, BUSY remains set.
-    // Decremented on each status read; when it reaches 0, BUSY clears
-    // and the completion NMI fires. This allows both polling-based ROMs
-    // (KayPLUS) and NMI-driven ROMs (81-292a) to work correctly.
-    read_address_countdown: u8,
+    // READ ADDRESS in progress: BUSY remains set until the ID field (or
+    // the lack of one) is found, when the posted FdcIdField event comes.
+    // Polling ROMs (KayPLUS) see BUSY for as long as the disk takes.
+    read_address_busy: bool,
 
     pub raise_nmi: bool,
     pub trace: bool,
@@ -89,15 +113,58 @@ pub struct FloppyController {
 pub enum FDCStatus {
     NotReady = 0x80,
     WriteProtected = 0x40,
-    _WriteFault = 0x20,
+    WriteFault = 0x20,
     SeekErrorOrRecordNotFound = 0x10,
-    _CRCError = 0x08,
+    CRCError = 0x08,
     LostDataOrTrack0 = 0x04,
     DataRequest = 0x02,
     Busy = 0x01,
     NoError = 0x00,
 }
 
+/// READ SECTOR reports the data mark in the bit used by writes for WRITE FAULT
+/// (S5: 1 = deleted data mark).
+const STATUS_RECORD_TYPE: u8 = FDCStatus::WriteFault as u8;
+
+/// Status bits reported at the end of a sector transfer.
+fn fault_status(fault: u8) -> u8 {
+    let mut status = 0;
+    if fault & (media::FAULT_CRC | media::FAULT_WEAK) != 0 {
+        status |= FDCStatus::CRCError as u8;
+    }
+    if fault & media::FAULT_DELETED != 0 {
+        status |= STATUS_RECORD_TYPE;
+    }
+    status
+}
+
+/// Track number that matches no track of any media, used when the drive
+/// has no recorded track under the head.
+const NO_TRACK: u8 = 0xFF;
+
+/// Raw track length in bytes at 300 RPM: 125 kbit/s FM, 250 kbit/s MFM.
+const RAW_TRACK_LEN_FM: usize = 3125;
+const RAW_TRACK_LEN_MFM: usize = 6250;
+
+/// One revolution at 300 RPM, and the index pulse that ends it, in µs.
+const REVOLUTION_US: u64 = 200_000;
+const INDEX_PULSE_US: u64 = 4_000;
+/// READ ADDRESS gives up after five index pulses without an ID field.
+const ID_SEARCH_US: u64 = 5 * REVOLUTION_US;
+
+/// CRC-CCITT of an ID or data field, as the WD1793 computes it from the
+/// first A1 sync byte (MFM) or the address mark (FM) on.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
 impl FloppyController {
     /// Create a new floppy controller with specified disk images and format.
     /// `side1_sector_base`: sector ID base for side 1 headers (10 = standard Kaypro, 0 = KayPLUS).
@@ -135,37 +202,47 @@ impl FloppyController {
             name: disk_a_name,
             content: disk_a_content,
             format: format_a,
-            geometry: None,
             write_protected: disk_a_wp,
-            side1_sector_base,
-            learned_n: None,
-            learned_sector_base: None,
-            track_geometry: std::collections::HashMap::new(),
-            write_min: usize::MAX,
-            write_max: 0,
+            ..Media::new(side1_sector_base)
         };
         let mut media_b = Media {
             file: disk_b_file,
             name: disk_b_name,
             content: disk_b_content,
             format: format_b,
-            geometry: None,
             write_protected: disk_b_wp,
-            side1_sector_base,
-            learned_n: None,
-            learned_sector_base: None,
-            track_geometry: std::collections::HashMap::new(),
-            write_min: usize::MAX,
-            write_max: 0,
+            ..Media::new(side1_sector_base)
         };
 
         // Apply detected geometry to pre-populate track_geometry so READ ADDRESS
         // returns the correct N immediately (no WRITE TRACK pass required).
-        if let Some(geom) = media::auto_detect_geometry(&media_a.content, side1_sector_base) {
-            media_a.apply_geometry(geom);
+        for media in [&mut media_a, &mut media_b] {
+            let geometry = media::auto_detect_geometry(&media.content, side1_sector_base);
+            match media::decode_container(&media.content, geometry.is_some()) {
+                Ok(Some((image, writable))) => {
+                    if !writable {
+                        eprintln!("Note: Disk image '{}' is imported read-only (write-protected)", media.name);
+                        media.file = None;
+                        media.write_protected = true;
+                    }
+                    media.load_imd(image);
+                }
+                Ok(None) => if let Some(geom) = geometry {
+                    media.apply_geometry(geom);
+                },
+                Err(e) => eprintln!("Warning: Could not decode disk image '{}': {}", media.name, e),
+            }
         }
-        if let Some(geom) = media::auto_detect_geometry(&media_b.content, side1_sector_base) {
-            media_b.apply_geometry(geom);
+        for (media, path) in [(&mut media_a, disk_a_path), (&mut media_b, disk_b_path)] {
+            if host_dir::host_path(path).is_some() {
+                if let Err(e) = media.load_disk(path) {
+                    eprintln!("Warning: Could not mount '{}': {}, using fallback", path, e);
+                }
+            } else if media.name == path {
+                if let Err(e) = media.load_fault_map() {
+                    eprintln!("Warning: Could not load fault map of '{}': {}", path, e);
+                }
+            }
         }
 
         FloppyController {
@@ -180,7 +257,8 @@ impl FloppyController {
             single_density: false,
             data: 0,
             status: 0,
-            media: [media_a, media_b],
+            media: [media_a, media_b, Media::new(side1_sector_base), Media::new(side1_sector_base)],
+            drive_types: [None; DRIVE_COUNT],
 
             read_index: 0,
             read_last: 0,
@@ -195,9 +273,17 @@ impl FloppyController {
             write_track_head: 0,
             multi_sector: false,
 
-            status_read_count: 0,
+            transfer_track: 0,
+            pending_status: 0,
+            weak_read: false,
+            weak_seed: 0x2545_F491,
+            write_deleted: false,
+
+            now_us: 0,
+            last_id_field: 0,
+            posted: Vec::new(),
             status_polls_without_data: 0,
-            read_address_countdown: 0,
+            read_address_busy: false,
 
             raise_nmi: false,
             trace,
@@ -211,6 +297,10 @@ impl FloppyController {
     /// Load a disk image from file, falling back to embedded data if not found.
     /// Returns (content, name, file_handle, write_protected).
     fn load_disk_or_fallback(path: &str, fallback: &'static [u8], fallback_name: &str) -> (Vec<u8>, String, Option<File>, bool) {
+        if host_dir::host_path(path).is_some() {
+            // Mounted by new() once the media exists
+            return (fallback.to_vec(), fallback_name.to_string(), None, false);
+        }
         match OpenOptions::new().read(true).write(true).open(path) {
             Ok(mut file) => {
                 let mut content = Vec::new();
@@ -241,22 +331,234 @@ impl FloppyController {
         &self.media[Drive::A as usize]
     }
 
+    #[allow(dead_code)]
     pub fn media_b(&self) -> &Media {
         &self.media[Drive::B as usize]
     }
 
+    #[allow(dead_code)]
     pub fn media_a_mut(&mut self) -> &mut Media {
         &mut self.media[Drive::A as usize]
     }
 
+    #[allow(dead_code)]
     pub fn media_b_mut(&mut self) -> &mut Media {
         &mut self.media[Drive::B as usize]
     }
 
+    pub fn media(&self, drive: Drive) -> &Media {
+        &self.media[drive as usize]
+    }
+
+    pub fn media_mut(&mut self, drive: Drive) -> &mut Media {
+        &mut self.media[drive as usize]
+    }
+
     pub fn media_selected(&mut self) -> &mut Media {
         &mut self.media[self.drive as usize]
     }
 
+    /// Connect drive C or D with the disk image at `path`.  Drives A and B
+    /// are always there; C and D exist only once a disk has been loaded.
+    pub fn attach_drive(&mut self, drive: Drive, path: &str) -> std::io::Result<()> {
+        self.media_mut(drive).load_disk(path)?;
+        if self.trace {
+            let info = self.media(drive).info();
+            fdc_log!(self, "FDC: Drive {} attached: {}", drive as usize, info);
+        }
+        Ok(())
+    }
+
+    /// Start journaling the disk writes after rewind point `point`.
+    pub fn mark_rewind_point(&mut self, point: u64, oldest: u64) {
+        for media in self.media.iter_mut() {
+            media.mark_rewind_point(point, oldest);
+        }
+    }
+
+    /// Undo the disk writes since rewind point `point`.
+    pub fn rewind(&mut self, point: u64) {
+        for media in self.media.iter_mut() {
+            media.rewind(point);
+        }
+    }
+
+    pub fn forget_rewind_points(&mut self) {
+        for media in self.media.iter_mut() {
+            media.journal.clear();
+        }
+    }
+
+    /// The controller registers and the transfer in progress. Of the
+    /// media only the names are saved, the disks are flushed when the
+    /// snapshot is taken.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for media in self.media.iter() {
+            state.str(&media.name);
+        }
+        state.bool(self.motor_on);
+        state.bool(self.disk_in_drive);
+        state.u8(self.drive);
+        state.bool(self.side_2);
+        state.u8(self.track);
+        state.u8(self.head_position);
+        state.u8(self.step_direction as u8);
+        state.u8(self.sector);
+        state.bool(self.single_density);
+        state.u8(self.data);
+        state.u8(self.status);
+        state.usize(self.read_index);
+        state.usize(self.read_last);
+        let data_buffer: Vec<u8> = self.data_buffer.iter().copied().collect();
+        state.bytes(&data_buffer);
+        state.bool(self.write_track_active);
+        state.bytes(&self.write_track_buffer);
+        state.usize(self.write_track_remaining);
+        state.u8(self.write_track_drive);
+        state.bool(self.write_track_side);
+        state.u8(self.write_track_head);
+        state.bool(self.multi_sector);
+        state.u8(self.transfer_track);
+        state.u8(self.pending_status);
+        state.bool(self.weak_read);
+        state.u32(self.weak_seed);
+        state.bool(self.write_deleted);
+        state.u64(self.last_id_field);
+        state.u8(self.status_polls_without_data);
+        state.bool(self.read_address_busy);
+        state.bool(self.raise_nmi);
+        state.u8(self.last_command);
+        state.u64(self.last_command_count);
+    }
+
+    pub fn save_disk_hashes(&self, state: &mut StateWriter) {
+        for media in self.media.iter() {
+            state.u64(snapshot::content_hash(&media.content));
+        }
+    }
+
+    /// The disks must hold what they held when the snapshot was taken.
+    pub fn check_disk_hashes(&self, state: &mut StateReader) -> std::io::Result<()> {
+        for media in self.media.iter() {
+            let hash = state.u64()?;
+            if !media.name.is_empty() && hash != snapshot::content_hash(&media.content) {
+                return Err(snapshot::invalid(format!(
+                    "Disk {} has been written since the snapshot was taken", media.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The disks that aren't the ones saved are loaded again by name.
+    pub fn load_state(&mut self, state: &mut StateReader) -> std::io::Result<()> {
+        for drive in 0..DRIVE_COUNT {
+            let name = state.string()?;
+            if !name.is_empty() && name != self.media[drive].name {
+                self.media[drive].load_disk(&name).map_err(|err| {
+                    snapshot::invalid(format!("Disk {} of the snapshot: {}", name, err))
+                })?;
+            }
+        }
+        self.motor_on = state.bool()?;
+        self.disk_in_drive = state.bool()?;
+        self.drive = state.u8()? % DRIVE_COUNT as u8;
+        self.side_2 = state.bool()?;
+        self.track = state.u8()?;
+        self.head_position = state.u8()?;
+        self.step_direction = state.u8()? as i8;
+        self.sector = state.u8()?;
+        self.single_density = state.bool()?;
+        self.data = state.u8()?;
+        self.status = state.u8()?;
+        self.read_index = state.usize()?;
+        self.read_last = state.usize()?;
+        self.data_buffer = state.bytes()?.iter().copied().collect();
+        self.write_track_active = state.bool()?;
+        self.write_track_buffer = state.bytes()?.to_vec();
+        self.write_track_remaining = state.usize()?;
+        self.write_track_drive = state.u8()? % DRIVE_COUNT as u8;
+        self.write_track_side = state.bool()?;
+        self.write_track_head = state.u8()?;
+        self.multi_sector = state.bool()?;
+        self.transfer_track = state.u8()?;
+        self.pending_status = state.u8()?;
+        self.weak_read = state.bool()?;
+        self.weak_seed = state.u32()?;
+        self.write_deleted = state.bool()?;
+        self.last_id_field = state.u64()?;
+        self.status_polls_without_data = state.u8()?;
+        self.read_address_busy = state.bool()?;
+        self.raise_nmi = state.bool()?;
+        self.last_command = state.u8()?;
+        self.last_command_count = state.u64()?;
+        self.posted.clear();
+        Ok(())
+    }
+
+    /// True if drive number `drive` is connected to the controller.
+    pub fn drive_present(&self, drive: u8) -> bool {
+        match drive as usize {
+            0 | 1 => true,
+            d if d < DRIVE_COUNT => !self.media[d].content.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// READY input of the selected drive.
+    fn drive_ready(&self) -> bool {
+        self.disk_in_drive && self.drive_present(self.drive)
+    }
+
+    pub fn set_drive_type(&mut self, drive: Drive, drive_type: Option<DriveType>) {
+        let drive = drive as usize;
+        if self.trace {
+            match drive_type {
+                Some(ref drive_type) => fdc_log!(self, "FDC: Drive {} mechanism: {}", drive, drive_type.label()),
+                None => fdc_log!(self, "FDC: Drive {} mechanism follows the media", drive),
+            }
+        }
+        self.drive_types[drive] = drive_type;
+    }
+
+    /// Last head position of the selected drive.  Without a configured
+    /// mechanism the drive has as many tracks as the media (at least 40).
+    fn last_head_position(&self) -> u8 {
+        match self.drive_types[self.drive as usize] {
+            Some(drive_type) => drive_type.last_position(),
+            None => self.media[self.drive as usize].tracks().max(40) - 1,
+        }
+    }
+
+    /// Media track under the head at `position`, if any.
+    fn media_track_at(&self, position: u8) -> Option<u8> {
+        match self.drive_types[self.drive as usize] {
+            Some(drive_type) => drive_type.media_track(position, self.media[self.drive as usize].tracks()),
+            None => Some(position),
+        }
+    }
+
+    /// Side of the media under the selected head: single sided drives
+    /// ignore the side select.
+    fn head_side(&self) -> bool {
+        match self.drive_types[self.drive as usize] {
+            Some(drive_type) => self.side_2 && drive_type.double_sided,
+            None => self.side_2,
+        }
+    }
+
+    /// Media track READ/WRITE SECTOR look up.  When each step moves one
+    /// track of the media, that is the track register (see READ SECTOR);
+    /// otherwise the ID fields under the head must match the register.
+    fn sector_track(&self) -> Option<u8> {
+        match self.drive_types[self.drive as usize] {
+            Some(drive_type) if !drive_type.steps_by_track(self.media[self.drive as usize].tracks()) => {
+                self.media_track_at(self.head_position).filter(|&track| track == self.track)
+            }
+            Some(_) => self.media_track_at(self.head_position).map(|_| self.track),
+            None => Some(self.track),
+        }
+    }
+
     pub fn set_motor(&mut self, motor_on: bool) {
         self.media_selected().flush_disk();
         self.motor_on = motor_on;
@@ -300,6 +602,10 @@ impl FloppyController {
         }
 
         self.media_selected().flush_disk();
+        self.pending_status = 0;
+        self.weak_read = false;
+        // A new command ends a READ ADDRESS still waiting for its ID field
+        self.read_address_busy = false;
 
         if (command & 0xf0) == 0x00 {
             // RESTORE command, type I
@@ -321,7 +627,13 @@ impl FloppyController {
             if self.trace {
                 fdc_log!(self, "FDC: Seek track {}", track);
             }
-            if self.media_selected().is_valid_track(track) {
+            let valid = match self.drive_types[self.drive as usize] {
+                // V flag: the ID field under the head must match
+                Some(_) => track <= self.last_head_position()
+                    && (command & 0x04 == 0 || self.media_track_at(track) == Some(track)),
+                None => self.media_selected().is_valid_track(track),
+            };
+            if valid {
                 self.track = track;
                 self.head_position = track; // Physical head moves to target
                 self.status = self.type_i_status(FDCStatus::NoError as u8);
@@ -336,7 +648,7 @@ impl FloppyController {
             let update_track = (command & 0x10) != 0;
             if self.step_direction > 0 {
                 // Step in (towards higher tracks)
-                if self.head_position < 39 {
+                if self.head_position < self.last_head_position() {
                     self.head_position += 1;
                 }
             } else {
@@ -365,7 +677,7 @@ impl FloppyController {
             let update_track = (command & 0x10) != 0;
             self.step_direction = 1; // Remember direction for STEP command
             // Always move physical head
-            if self.head_position < 39 {
+            if self.head_position < self.last_head_position() {
                 self.head_position += 1;
             }
             if update_track {
@@ -401,7 +713,8 @@ impl FloppyController {
             // READ SECTOR command, type II
             // 100mSEC0
             let controller_sd = self.single_density;
-            if !self.media_selected().density_matches(controller_sd) {
+            let (side_2, track) = (self.head_side(), self.sector_track().unwrap_or(NO_TRACK));
+            if !self.media_selected().density_matches(side_2, track, controller_sd) {
                 // Wrong density mode: WD1793 cannot find matching IDAM/DAM.
                 self.status = FDCStatus::Busy as u8 | FDCStatus::SeekErrorOrRecordNotFound as u8;
                 self.raise_nmi = true;
@@ -416,19 +729,20 @@ impl FloppyController {
                     self.multi_sector, side_compare, side_flag);
             }
 
-            let side_2 = self.side_2;
             // Use the track register for sector lookup, not head_position.
             // On a real WD1793, READ SECTOR matches sector ID fields against
             // the Track Register. The Kaypro 10 HD BIOS dispatches floppy I/O
             // through the ROM, which may SEEK to a wrong physical track but
             // then set the track register to the correct value.
-            let track = self.track;
+            // A configured drive mechanism may put no track under the head.
             let sector = self.sector;
             let (valid, index, last) =  self.media_selected().sector_index(side_2, track, sector);
-            if valid {
+            let fault = self.media_selected().fault(side_2, track, sector);
+            if valid && fault & media::FAULT_RNF == 0 {
                 self.read_index = index;
                 self.read_last = last;
                 self.status = FDCStatus::Busy as u8;
+                self.begin_sector_faults(track, fault);
                 if self.trace || self.trace_rw {
                     fdc_log!(self, "FDC: Read sector setup: index={}, last={}, transfer_size={}", index, last, last - index);
                 }
@@ -467,7 +781,8 @@ impl FloppyController {
             // 101mSECa
             self.multi_sector = (command & 0x10) != 0;
             // a0 (bit 0): 0=normal data mark (FB), 1=deleted data mark (F8)
-            // We accept both but don't distinguish in sector images
+            // Sector images hold no data marks; the fault map records them.
+            self.write_deleted = (command & 0x01) != 0;
             if self.trace || self.trace_rw {
                 fdc_log!(self, "FDC: Write sector (Si:{}, Tr:{}, Se:{}, Head:{}, multi:{})", self.side_2, self.track, self.sector, self.head_position, self.multi_sector);
             }
@@ -478,16 +793,24 @@ impl FloppyController {
                 return;
             }
 
-            let side_2 = self.side_2;
+            let side_2 = self.head_side();
             // Use the track register for sector lookup, not head_position.
             // Same as READ SECTOR: the WD1793 matches sector ID fields
             // against the Track Register, not the physical head position.
-            let track = self.track;
+            let track = self.sector_track().unwrap_or(NO_TRACK);
             let sector = self.sector;
             let (valid, index, last) =  self.media_selected().sector_index(side_2, track, sector);
-            if valid {
+            let fault = self.media_selected().fault(side_2, track, sector);
+            if valid && fault & media::FAULT_RNF == 0 {
+                if fault & media::FAULT_WRITE != 0 {
+                    // The drive raises WRITE FAULT as soon as the gate opens
+                    self.status = FDCStatus::WriteFault as u8;
+                    self.raise_nmi = true;
+                    return;
+                }
                 self.read_index = index;
                 self.read_last = last;
+                self.transfer_track = track;
                 self.status = FDCStatus::Busy as u8;
             } else {
                 self.status = FDCStatus::Busy as u8 | FDCStatus::SeekErrorOrRecordNotFound as u8;
@@ -498,15 +821,14 @@ impl FloppyController {
             // READ ADDRESS command, type III
             // 1100_0E00
             let controller_sd = self.single_density;
-            if !self.media_selected().density_matches(controller_sd) {
+            let (side_2, track) = (self.head_side(), self.media_track_at(self.head_position).unwrap_or(NO_TRACK));
+            if !self.media_selected().density_matches(side_2, track, controller_sd) {
                 // Wrong density mode: no readable ID field in this mode.
                 self.status = FDCStatus::Busy as u8 | FDCStatus::SeekErrorOrRecordNotFound as u8;
-                self.read_address_countdown = 10;
+                self.start_read_address(self.now_us + ID_SEARCH_US);
                 self.raise_nmi = true;
                 return;
             }
-            let side_2 = self.side_2;
-            let track = self.head_position; // Use physical head position
             let sector = self.sector;
 
             let (valid, base_sector_id, n_code) = self.media_selected().read_address(side_2, track, sector);
@@ -520,8 +842,18 @@ impl FloppyController {
                         .map(|g| g.sector_count)
                         .unwrap_or_else(|| m.sectors_per_side())
                 };
-                let rotation_pos = (self.status_read_count / 10) as u8 % spt;
-                let sector_id = base_sector_id + rotation_pos;
+                // The next ID field to pass under the head, and never the one
+                // the previous READ ADDRESS already went past.
+                let id_number = (self.now_us * spt as u64 / REVOLUTION_US + 1)
+                    .max(self.last_id_field + 1);
+                self.last_id_field = id_number;
+                let rotation_pos = (id_number % spt as u64) as u8;
+                // Tracks with a known sector map (IMD, WRITE TRACK) return
+                // their IDs in rotational order.
+                let sector_id = match self.media_selected().track_geometry.get(&(track, side_2)) {
+                    Some(g) => g.id_at(rotation_pos),
+                    None => base_sector_id + rotation_pos,
+                };
 
                 if self.trace {
                     fdc_log!(self, "FDC: Read address ({},{},{}) -> sector_id={} n={} spt={}", side_2, track, sector, sector_id, n_code, spt);
@@ -535,30 +867,29 @@ impl FloppyController {
                 } else {
                     sector_id
                 };
+                let id_field = [track, side_2 as u8, sector_id, n_code]; // N: sector size code from actual geometry
+                let crc = if self.single_density {
+                    crc16(&[&[0xFE][..], &id_field].concat())
+                } else {
+                    crc16(&[&[0xA1, 0xA1, 0xA1, 0xFE][..], &id_field].concat())
+                };
                 self.data_buffer.clear();
-                self.data_buffer.push_back(self.head_position);
-                self.data_buffer.push_back(side_2 as u8);
-                self.data_buffer.push_back(sector_id);
-                self.data_buffer.push_back(n_code); // N: sector size code from actual geometry
-                self.data_buffer.push_back(0xde);
-                self.data_buffer.push_back(0xad);
+                self.data_buffer.extend(id_field);
+                self.data_buffer.extend(crc.to_be_bytes());
                 // Set BUSY - the real WD1793 stays busy while scanning for the
                 // next sector ID. BUSY clears after the ID field is read.
-                // We use a countdown decremented on status reads so that
-                // polling ROMs (KayPLUS) see BUSY for a realistic duration.
                 self.status = FDCStatus::Busy as u8;
-                self.read_address_countdown = 10;
+                self.start_read_address(self.last_id_field * REVOLUTION_US / spt as u64);
                 self.raise_nmi = true;
             } else {
                 if self.trace {
                     fdc_log!(self, "FDC: Read address ({},{},{}) = Error", side_2, track, sector);
                 }
-                // Real WD1793: stays BUSY while scanning for sector headers
-                // (~5 revolutions), then clears BUSY and sets RNF. Use the
-                // same countdown as the success path so the BIOS sees the
+                // Real WD1793: stays BUSY while scanning for sector headers,
+                // then clears BUSY and sets RNF, so the BIOS sees the
                 // BUSY→not-BUSY transition it expects before checking error bits.
                 self.status = FDCStatus::Busy as u8 | FDCStatus::SeekErrorOrRecordNotFound as u8;
-                self.read_address_countdown = 10;
+                self.start_read_address(self.now_us + ID_SEARCH_US);
                 self.raise_nmi = true;
             }
         } else if (command & 0xf0) == 0xd0 {
@@ -581,7 +912,6 @@ impl FloppyController {
             self.read_last = 0;
             self.data_buffer.clear();
             self.multi_sector = false;
-            self.read_address_countdown = 0;
             let mut base = FDCStatus::NoError as u8;
             if self.head_position == 0 {
                 base |= FDCStatus::LostDataOrTrack0 as u8;
@@ -597,10 +927,20 @@ impl FloppyController {
         } else if (command & 0xf0) == 0xe0 {
             // READ TRACK command, type III
             // 1110_0E00
-            if self.trace {
-                fdc_log!(self, "FDC: Read track (not implemented, returning empty)");
+            // The whole track from index to index, gaps and marks included,
+            // one byte per DRQ. BUSY clears when the buffer has been read.
+            let side_2 = self.head_side();
+            let track = self.media_track_at(self.head_position).unwrap_or(NO_TRACK);
+            let stream = self.read_track_stream(side_2, track);
+            if self.trace || self.trace_rw {
+                fdc_log!(self, "FDC: Read track (Drive:{}, Si:{}, Head:{}, SD:{}) {} bytes",
+                    self.drive, side_2, track, self.single_density, stream.len());
             }
-            self.status = FDCStatus::NoError as u8;
+            self.read_index = 0;
+            self.read_last = 0;
+            self.multi_sector = false;
+            self.data_buffer = stream.into();
+            self.status = FDCStatus::Busy as u8;
             self.raise_nmi = true;
         } else if (command & 0xf0) == 0xf0 {
             // WRITE TRACK command, type III (format track)
@@ -615,8 +955,8 @@ impl FloppyController {
             }
             self.write_track_active = true;
             self.write_track_drive = self.drive;
-            self.write_track_side = self.side_2;
-            self.write_track_head = self.head_position;
+            self.write_track_side = self.head_side();
+            self.write_track_head = self.media_track_at(self.head_position).unwrap_or(NO_TRACK);
             self.write_track_buffer.clear();
             self.write_track_remaining = if self.single_density { 3125 } else { 0 };
             self.status = FDCStatus::Busy as u8;
@@ -628,28 +968,30 @@ impl FloppyController {
         }
     }
 
-    pub fn get_status(&mut self) -> u8 {
-        self.status_read_count = self.status_read_count.wrapping_add(1);
-
-        // READ ADDRESS busy countdown: the WD1793 stays BUSY while scanning
-        // for the next sector ID field. We decrement on each status poll;
-        // when it reaches 0, BUSY clears and the completion NMI fires.
-        if self.read_address_countdown > 0 {
-            self.read_address_countdown -= 1;
-            if self.read_address_countdown == 0 {
-                // Clear BUSY but preserve error bits (e.g., RNF from
-                // READ ADDRESS on a non-existent side).
-                self.status &= !(FDCStatus::Busy as u8);
-            }
+    /// READ ADDRESS stays BUSY until emulated time `at_us`.
+    fn start_read_address(&mut self, at_us: u64) {
+        self.read_address_busy = true;
+        self.posted.push((at_us, Event::FdcIdField));
+    }
+
+    /// FdcIdField event: READ ADDRESS found the ID field, or gave up.
+    pub fn id_field_passed(&mut self) {
+        if self.read_address_busy {
+            self.read_address_busy = false;
+            // Clear BUSY but preserve error bits (e.g., RNF from
+            // READ ADDRESS on a non-existent side).
+            self.status &= !(FDCStatus::Busy as u8);
         }
+    }
 
+    pub fn get_status(&mut self) -> u8 {
         let mut status = self.status;
 
         // NOT READY (bit 7) is real-time on the WD1793: it reflects the
         // current state of the drive's READY input, not a latched value.
         // Update it dynamically so inserting/removing a disk takes effect
         // immediately without requiring a new FDC command first.
-        if self.disk_in_drive {
+        if self.drive_ready() {
             status &= !(FDCStatus::NotReady as u8);
         } else {
             status |= FDCStatus::NotReady as u8;
@@ -670,7 +1012,7 @@ impl FloppyController {
                     self.status_polls_without_data = 0;
                     status = self.status;
                 }
-            } else if self.read_address_countdown > 0 {
+            } else if self.read_address_busy {
                 status |= FDCStatus::DataRequest as u8;
             } else {
                 self.status &= !(FDCStatus::Busy as u8);
@@ -679,7 +1021,7 @@ impl FloppyController {
             }
         } else {
             // Type I status (or idle): bit 1 = Index pulse
-            if self.motor_on && (self.status_read_count % 100) < 5 {
+            if self.motor_on && self.now_us % REVOLUTION_US >= REVOLUTION_US - INDEX_PULSE_US {
                 status |= 0x02;
             }
         }
@@ -750,6 +1092,8 @@ impl FloppyController {
             self.raise_nmi = true;
             if self.read_index == self.read_last {
                 // We are done writing this sector
+                let (side_2, track, sector, deleted) = (self.head_side(), self.transfer_track, self.sector, self.write_deleted);
+                self.media_selected().sector_written(side_2, track, sector, deleted);
                 self.media_selected().flush_disk();
                 if self.trace {
                     fdc_log!(self, "FDC: Set data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
@@ -757,14 +1101,16 @@ impl FloppyController {
                 if self.multi_sector {
                     // Auto-increment sector and continue to next
                     self.sector += 1;
-                    let side_2 = self.side_2;
-                    let track = self.head_position;
+                    let side_2 = self.head_side();
+                    let track = self.transfer_track;
                     let sector = self.sector;
                     let (valid, index, last) = self.media[self.drive as usize]
                         .sector_index(side_2, track, sector);
-                    if valid {
+                    let fault = self.media[self.drive as usize].fault(side_2, track, sector);
+                    if valid && fault & (media::FAULT_RNF | media::FAULT_WRITE) == 0 {
                         self.read_index = index;
                         self.read_last = last;
+                        self.transfer_track = track;
                         if self.trace {
                             fdc_log!(self, "FDC: Multi-sector write continuing with sector {}", self.sector);
                         }
@@ -802,16 +1148,24 @@ impl FloppyController {
             // Prepare next byte
             let index = self.read_index;
             self.data = self.media_selected().read_byte(index);
+            if self.weak_read {
+                self.data ^= self.weak_bits();
+            }
             self.read_index += 1;
             self.raise_nmi = true;
             if self.read_index == self.read_last {
                 // We are done reading this sector's actual data.
+                self.status |= self.pending_status;
+                if self.pending_status & FDCStatus::CRCError as u8 != 0 {
+                    // A CRC error ends a multi-sector read
+                    self.multi_sector = false;
+                }
                 if self.trace {
                     fdc_log!(self, "FDC: Get data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
                 }
                 if self.trace || self.trace_rw {
-                    let track = self.head_position;
-                    let side = self.side_2;
+                    let track = self.transfer_track;
+                    let side = self.head_side();
                     let sector_size = if let Some(geom) = self.media[self.drive as usize].track_geometry.get(&(track, side)) {
                         128usize << (geom.n as usize)
                     } else {
@@ -828,14 +1182,16 @@ impl FloppyController {
                 if self.multi_sector {
                     // Auto-increment sector and continue to next
                     self.sector += 1;
-                    let side_2 = self.side_2;
-                    let track = self.head_position;
+                    let side_2 = self.head_side();
+                    let track = self.transfer_track;
                     let sector = self.sector;
                     let (valid, index, last) = self.media[self.drive as usize]
                         .sector_index(side_2, track, sector);
-                    if valid {
+                    let fault = self.media[self.drive as usize].fault(side_2, track, sector);
+                    if valid && fault & media::FAULT_RNF == 0 {
                         self.read_index = index;
                         self.read_last = last;
+                        self.begin_sector_faults(track, fault);
                         if self.trace {
                             fdc_log!(self, "FDC: Multi-sector read continuing with sector {}", self.sector);
                         }
@@ -873,6 +1229,28 @@ impl FloppyController {
         self.data
     }
 
+    /// Arm the fault reporting of a sector about to be read.
+    fn begin_sector_faults(&mut self, track: u8, fault: u8) {
+        self.transfer_track = track;
+        // The record type reflects the last sector read
+        self.status &= !STATUS_RECORD_TYPE;
+        self.pending_status = fault_status(fault);
+        self.weak_read = fault & media::FAULT_WEAK != 0;
+    }
+
+    /// Random bit flips for a weak sector: about one byte in 16 reads back
+    /// with a bit flipped (xorshift32).
+    fn weak_bits(&mut self) -> u8 {
+        self.weak_seed ^= self.weak_seed << 13;
+        self.weak_seed ^= self.weak_seed >> 17;
+        self.weak_seed ^= self.weak_seed << 5;
+        if self.weak_seed & 0x0F == 0 {
+            1 << ((self.weak_seed >> 4) & 7)
+        } else {
+            0
+        }
+    }
+
     fn type_i_status(&self, base_status: u8) -> u8 {
         let mut status = base_status;
         if self.media[self.drive as usize].is_write_protected() {
@@ -881,16 +1259,96 @@ impl FloppyController {
         if self.motor_on {
             status |= 0x20; // S5: Head Loaded
         }
-        if !self.disk_in_drive {
+        if !self.drive_ready() {
             status |= FDCStatus::NotReady as u8; // S7: no disk in drive
         }
         status
     }
 
+    /// Build the bytes READ TRACK returns for a track: the layout of the
+    /// stream WRITE TRACK takes to format it, with the sync bytes read back
+    /// as A1 and real CRCs in place of the F7 markers.  Sector faults show
+    /// as they would on the disk: a missing ID field (rnf), a bad data CRC
+    /// (crc, weak), an F8 data mark (deleted).  A track of the wrong density
+    /// or off the media reads as gap bytes only.
+    fn read_track_stream(&mut self, side_2: bool, track: u8) -> Vec<u8> {
+        let single_density = self.single_density;
+        let (gap, raw_len) = if single_density {
+            (0xFFu8, RAW_TRACK_LEN_FM)
+        } else {
+            (0x4Eu8, RAW_TRACK_LEN_MFM)
+        };
+        let drive = self.drive as usize;
+        let layout = match self.media[drive].track_layout(side_2, track) {
+            Some(layout) if self.media[drive].density_matches(side_2, track, single_density) => layout,
+            _ => return vec![gap; raw_len],
+        };
+
+        // Sync bytes ahead of each mark, and the part of them the CRC covers
+        let (sync, sync_len, crc_from) = if single_density { (0x00u8, 3, 3) } else { (0xA1u8, 3, 0) };
+        let zeros = if single_density { 0 } else { 12 };
+        let sector_size = 128usize << (layout.n as usize);
+        let mut stream = vec![gap; if single_density { 16 } else { 80 }];
+        let mut field = Vec::with_capacity(sector_size + 4);
+
+        for id in layout.ids() {
+            let fault = self.media[drive].fault(side_2, track, id);
+            if fault & media::FAULT_RNF != 0 {
+                continue;
+            }
+            let (valid, index, last) = self.media[drive].sector_index(side_2, track, id);
+
+            // ID field
+            field.clear();
+            field.extend(std::iter::repeat_n(sync, sync_len));
+            field.extend_from_slice(&[0xFE, track, side_2 as u8, id, layout.n]);
+            let crc = crc16(&field[crc_from..]);
+            stream.extend(std::iter::repeat_n(0x00, zeros));
+            stream.extend_from_slice(&field);
+            stream.extend_from_slice(&crc.to_be_bytes());
+            stream.extend(std::iter::repeat_n(gap, if single_density { 11 } else { 22 }));
+
+            // Data field
+            field.clear();
+            field.extend(std::iter::repeat_n(sync, sync_len));
+            field.push(if fault & media::FAULT_DELETED != 0 { 0xF8 } else { 0xFB });
+            for i in 0..sector_size {
+                let mut byte = if valid && index + i < last { self.media[drive].read_byte(index + i) } else { 0xE5 };
+                if fault & media::FAULT_WEAK != 0 {
+                    byte ^= self.weak_bits();
+                }
+                field.push(byte);
+            }
+            let mut crc = crc16(&field[crc_from..]);
+            if fault & (media::FAULT_CRC | media::FAULT_WEAK) != 0 {
+                crc = !crc;
+            }
+            stream.extend(std::iter::repeat_n(0x00, zeros));
+            stream.extend_from_slice(&field);
+            stream.extend_from_slice(&crc.to_be_bytes());
+            stream.extend(std::iter::repeat_n(gap, if single_density { 10 } else { 24 }));
+        }
+
+        // Gap 4 up to the index hole
+        if stream.len() < raw_len {
+            stream.resize(raw_len, gap);
+        }
+        stream
+    }
+
     fn finish_write_track(&mut self) {
         let drive = self.write_track_drive as usize;
         let side_2 = self.write_track_side;
         let track = self.write_track_head;
+        if track == NO_TRACK {
+            if self.trace || self.trace_rw {
+                fdc_log!(self, "FDC: WriteTrack with the head off the media tracks, nothing written");
+            }
+            self.write_track_active = false;
+            self.status = FDCStatus::NoError as u8;
+            self.raise_nmi = true;
+            return;
+        }
 
         if side_2 && !self.media[drive].double_sided() {
             if self.trace || self.trace_rw {
@@ -904,6 +1362,7 @@ impl FloppyController {
         let mut track_learned_n: Option<u8> = None;
         let mut min_sector_id: u8 = 255;
         let mut sector_count: u8 = 0;
+        let mut sector_ids: Vec<u8> = Vec::new();
 
         // Sync byte depends on density:
         //   MFM (double density): F5 F5 F5 = A1 sync with missing clock
@@ -927,6 +1386,7 @@ impl FloppyController {
                         track_learned_n = Some(id_n);
                     }
                     sector_count += 1;
+                    sector_ids.push(id_sector);
                     i += 4;
                     // Skip past CRC marker, DAM, data, CRC marker
                     let stream_size = 128usize << (id_n as usize);
@@ -953,6 +1413,8 @@ impl FloppyController {
                 n,
                 sector_count,
                 sector_base: min_sector_id,
+                single_density: self.single_density,
+                sector_ids,
             };
             self.media[drive].track_geometry.insert((track, side_2), geom);
 
@@ -0,0 +1,95 @@
+// Floppy drive mechanisms.
+//
+// The WD1793 only counts step pulses; where the head ends up on the disk
+// depends on the drive.  A drive has 40 or 80 cylinders, one or two heads
+// and a track pitch of 48 or 96 tracks per inch.  Media written at 48 TPI
+// (40 tracks) read in a 96 TPI drive sit under every other cylinder, so
+// either the software steps twice per track or the drive is set up to
+// double-step: every pulse moves the head two cylinders.  Media written at
+// 96 TPI in a 48 TPI drive show every other track.
+//
+// A drive type is written as "<tracks><ss|ds>" with optional ",48tpi",
+// ",96tpi" (by default 40 tracks are 48 TPI and 80 tracks 96 TPI) and
+// ",double-step", e.g. "40ds", "80ds", "80ds,double-step".
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DriveType {
+    pub tracks: u8,
+    pub double_sided: bool,
+    pub tpi: u8,
+    pub double_step: bool,
+}
+
+/// Track pitch the media was written at, from its track count.
+fn media_tpi(media_tracks: u8) -> u8 {
+    if media_tracks > 42 { 96 } else { 48 }
+}
+
+impl DriveType {
+    pub fn parse(spec: &str) -> Result<DriveType, String> {
+        let mut parts = spec.split(',').map(|p| p.trim().to_ascii_lowercase());
+        let first = parts.next().unwrap_or_default();
+        let (tracks, double_sided) = if let Some(tracks) = first.strip_suffix("ds") {
+            (tracks, true)
+        } else if let Some(tracks) = first.strip_suffix("ss") {
+            (tracks, false)
+        } else {
+            return Err(format!("Drive type '{}': expected ss or ds after the track count", spec));
+        };
+        let tracks = match tracks {
+            "40" => 40,
+            "80" => 80,
+            _ => return Err(format!("Drive type '{}': expected 40 or 80 tracks", spec)),
+        };
+        let mut drive = DriveType {
+            tracks,
+            double_sided,
+            tpi: if tracks == 80 { 96 } else { 48 },
+            double_step: false,
+        };
+        for part in parts {
+            match part.as_str() {
+                "48tpi" => drive.tpi = 48,
+                "96tpi" => drive.tpi = 96,
+                "double-step" => drive.double_step = true,
+                _ => return Err(format!("Drive type '{}': unknown option '{}'", spec, part)),
+            }
+        }
+        Ok(drive)
+    }
+
+    pub fn label(&self) -> String {
+        format!("{} tracks, {}, {} TPI{}", self.tracks,
+            if self.double_sided { "double sided" } else { "single sided" },
+            self.tpi,
+            if self.double_step { ", double-stepping" } else { "" })
+    }
+
+    /// Last head position, counted in step pulses from track 0.
+    pub fn last_position(&self) -> u8 {
+        let cylinders = if self.double_step { self.tracks / 2 } else { self.tracks };
+        cylinders - 1
+    }
+
+    /// Track of the media under the head, `position` step pulses from
+    /// track 0, or None if the head is between or past the recorded tracks.
+    pub fn media_track(&self, position: u8, media_tracks: u8) -> Option<u8> {
+        if position > self.last_position() {
+            return None;
+        }
+        let cylinder = if self.double_step { position * 2 } else { position };
+        let track = match (self.tpi, media_tpi(media_tracks)) {
+            (96, 48) if cylinder % 2 == 1 => return None,
+            (96, 48) => cylinder / 2,
+            (48, 96) => cylinder * 2,
+            _ => cylinder,
+        };
+        (track < media_tracks).then_some(track)
+    }
+
+    /// True if each step pulse moves the head one track of the media.
+    pub fn steps_by_track(&self, media_tracks: u8) -> bool {
+        let tpi = if self.double_step { self.tpi / 2 } else { self.tpi };
+        tpi == media_tpi(media_tracks)
+    }
+}
@@ -0,0 +1,123 @@
+#[cfg(test)]
+mod tests {
+    use crate::floppy_controller::{Drive, FloppyController};
+    use crate::floppy_drive::DriveType;
+    use crate::media;
+    use crate::test_support::floppy_controller;
+
+    /// Drive B with a blank `tracks`-track DSDD disk, heads on track 0.
+    fn fdc_with_media(tracks: u8, drive_type: Option<&str>) -> FloppyController {
+        let mut fdc = floppy_controller();
+        let media = fdc.media_b_mut();
+        media.content = vec![0xE5; tracks as usize * 2 * 10 * 512];
+        media.track_geometry.clear();
+        media.apply_geometry(if tracks == 80 { media::GEOM_MICROCORNUCOPIA } else { media::GEOM_KAYPRO_DSDD });
+        fdc.set_drive_type(Drive::B, drive_type.map(|spec| DriveType::parse(spec).unwrap()));
+        fdc.set_drive(1);
+        fdc.set_motor(true);
+        fdc.set_single_density(false);
+        fdc.put_command(0x00); // RESTORE
+        fdc
+    }
+
+    /// Track number in the ID field under the head, if there is one.
+    fn read_address(fdc: &mut FloppyController) -> Option<u8> {
+        fdc.put_command(0xC0);
+        // BUSY until the machine delivers the posted FdcIdField event
+        assert_eq!(fdc.get_status() & 0x01, 0x01);
+        for (at_us, _) in std::mem::take(&mut fdc.posted) {
+            fdc.now_us = at_us;
+            fdc.id_field_passed();
+        }
+        if fdc.get_status() & 0x10 != 0 {
+            return None;
+        }
+        let id: Vec<u8> = (0..6).map(|_| fdc.get_data()).collect();
+        Some(id[0])
+    }
+
+    fn step_in(fdc: &mut FloppyController, steps: u8) {
+        for _ in 0..steps {
+            fdc.put_command(0x50); // STEP IN, update track register
+        }
+    }
+
+    #[test]
+    fn test_parse_drive_type() {
+        let drive = DriveType::parse("80DS,double-step").unwrap();
+        assert_eq!((drive.tracks, drive.double_sided, drive.tpi, drive.double_step), (80, true, 96, true));
+        let drive = DriveType::parse("40ss").unwrap();
+        assert_eq!((drive.tracks, drive.double_sided, drive.tpi, drive.double_step), (40, false, 48, false));
+        assert_eq!(DriveType::parse("40ds, 96tpi").unwrap().tpi, 96);
+        assert!(DriveType::parse("35ds").is_err());
+        assert!(DriveType::parse("40").is_err());
+        assert!(DriveType::parse("80ds,turbo").is_err());
+    }
+
+    #[test]
+    fn test_media_track_under_head() {
+        let drive96 = DriveType::parse("80ds").unwrap();
+        assert_eq!(drive96.last_position(), 79);
+        assert_eq!(drive96.media_track(10, 40), Some(5));
+        assert_eq!(drive96.media_track(11, 40), None);
+        assert_eq!(drive96.media_track(11, 80), Some(11));
+        assert!(!drive96.steps_by_track(40));
+
+        let double = DriveType::parse("80ds,double-step").unwrap();
+        assert_eq!(double.last_position(), 39);
+        assert_eq!(double.media_track(7, 40), Some(7));
+        assert!(double.steps_by_track(40));
+
+        let drive48 = DriveType::parse("40ds").unwrap();
+        assert_eq!(drive48.media_track(7, 80), Some(14));
+        assert_eq!(drive48.media_track(7, 40), Some(7));
+        assert_eq!(drive48.media_track(40, 40), None);
+    }
+
+    #[test]
+    fn test_step_honors_drive_mechanism() {
+        // Without a configured drive, 80-track media can be stepped through
+        let mut fdc = fdc_with_media(80, None);
+        step_in(&mut fdc, 79);
+        assert_eq!(fdc.head_position, 79);
+        assert_eq!(read_address(&mut fdc), Some(79));
+
+        // A 40-track drive stops at track 39 and sees every other track
+        let mut fdc = fdc_with_media(80, Some("40ds"));
+        step_in(&mut fdc, 45);
+        assert_eq!(fdc.head_position, 39);
+        assert_eq!(read_address(&mut fdc), Some(78));
+
+        // 40-track media in an 80-track drive: tracks under even cylinders
+        let mut fdc = fdc_with_media(40, Some("80ds"));
+        step_in(&mut fdc, 3);
+        assert_eq!(read_address(&mut fdc), None);
+        step_in(&mut fdc, 1);
+        assert_eq!(read_address(&mut fdc), Some(2));
+        fdc.put_sector(0);
+        fdc.put_command(0x80);
+        assert_eq!(fdc.get_status() & 0x10, 0x10, "ID track 2 doesn't match track register 4");
+
+        // ... unless the drive double-steps
+        let mut fdc = fdc_with_media(40, Some("80ds,double-step"));
+        fdc.put_data(39);
+        fdc.put_command(0x14); // SEEK with verify
+        assert_eq!(fdc.get_status() & 0x10, 0);
+        assert_eq!(read_address(&mut fdc), Some(39));
+        fdc.put_data(40);
+        fdc.put_command(0x14);
+        assert_eq!(fdc.get_status() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn test_single_sided_drive_ignores_side_select() {
+        let mut fdc = fdc_with_media(40, Some("40ss"));
+        fdc.set_side(true);
+        fdc.put_sector(10);
+        fdc.put_command(0x80);
+        assert_eq!(fdc.get_status() & 0x10, 0x10);
+        fdc.put_sector(0);
+        fdc.put_command(0x80);
+        assert_eq!(fdc.get_status() & 0x10, 0);
+    }
+}
@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod tests {
-    use crate::floppy_controller::FloppyController;
-    use crate::media::MediaFormat;
+    use crate::floppy_controller::{self, FloppyController};
+    use crate::media::{self, MediaFormat};
 
     fn build_format_stream(density_sd: bool, track: u8, head: u8, n: u8, sectors: &[u8], fill: u8) -> Vec<u8> {
         let sector_size = 128usize << (n as usize);
@@ -625,4 +625,108 @@ mod tests {
 
         eprintln!("  PASS: SSDD disk in DSDD machine - side 0 readable, side 1 returns RNF");
     }
+
+    /// What READ TRACK should return for a track formatted with `stream`:
+    /// F5 reads back as the A1 sync byte, F7 as the two CRC bytes, and the
+    /// track ends after one revolution.
+    fn expected_read_track(stream: &[u8], density_sd: bool) -> Vec<u8> {
+        let mut track = Vec::new();
+        let mut crc_start = None;
+        for &byte in stream {
+            match byte {
+                0xF5 if !density_sd => {
+                    crc_start.get_or_insert(track.len());
+                    track.push(0xA1);
+                }
+                0xFE | 0xFB if density_sd => {
+                    crc_start.get_or_insert(track.len());
+                    track.push(byte);
+                }
+                0xF7 => {
+                    let crc = floppy_controller::crc16(&track[crc_start.take().unwrap()..]);
+                    track.extend_from_slice(&crc.to_be_bytes());
+                }
+                _ => track.push(byte),
+            }
+        }
+        track.truncate(if density_sd { 3125 } else { 6250 });
+        track
+    }
+
+    fn read_track(fdc: &mut FloppyController) -> Vec<u8> {
+        fdc.put_command(0xE4);
+        assert_eq!(fdc.get_status() & 0x03, 0x03, "READ TRACK should be BUSY with DRQ");
+        let mut track = Vec::new();
+        while fdc.get_status() & 0x02 != 0 {
+            fdc.raise_nmi = false;
+            track.push(fdc.get_data());
+            assert!(fdc.raise_nmi, "each byte should raise DRQ");
+        }
+        assert_eq!(fdc.get_status() & 0x01, 0, "BUSY should clear after the last byte");
+        track
+    }
+
+    #[test]
+    fn test_read_track_matches_format_stream() {
+        let mut fdc = FloppyController::new(
+            "__nonexistent_test_a__",
+            "__nonexistent_test_b__",
+            MediaFormat::DsDd,
+            0,
+            false,
+            false,
+        );
+        fdc.media_b_mut().content = vec![0xE5; 409600];
+        fdc.media_b_mut().geometry = None;
+        fdc.media_b_mut().learned_n = None;
+        fdc.media_b_mut().learned_sector_base = None;
+        fdc.media_b_mut().track_geometry.clear();
+        fdc.media_b_mut().write_protected = false;
+        fdc.set_drive(1);
+        fdc.set_motor(true);
+
+        // Xerox 820-II: FM 18x128 on track 0, skewed MFM 17x256 elsewhere
+        let layouts = [(false, 0u8, true, 0u8, apply_skew(18, 1, 5)), (true, 1, false, 1, apply_skew(17, 1, 3))];
+        for (side_2, track, density_sd, n, sectors) in layouts {
+            fdc.set_side(side_2);
+            fdc.set_single_density(density_sd);
+            fdc.head_position = track;
+            fdc.put_track(track);
+            let stream = build_format_stream_unique(density_sd, track, side_2 as u8, n, &sectors);
+            fdc.put_command(0xF0);
+            for &byte in &stream {
+                fdc.put_data(byte);
+                if !fdc.write_track_active { break; }
+            }
+            if fdc.write_track_active {
+                fdc.put_command(0xD0);
+            }
+
+            assert_eq!(read_track(&mut fdc), expected_read_track(&stream, density_sd),
+                "track {} side {}", track, side_2 as u8);
+
+            // The other density finds no marks
+            fdc.set_single_density(!density_sd);
+            assert!(read_track(&mut fdc).iter().all(|&b| b == if density_sd { 0x4E } else { 0xFF }));
+        }
+
+        // A deleted sector with a bad CRC, and one whose ID can't be found
+        fdc.media_b_mut().faults.insert((1, true, 4), media::FAULT_DELETED | media::FAULT_CRC);
+        fdc.media_b_mut().faults.insert((1, true, 5), media::FAULT_RNF);
+        fdc.set_single_density(false);
+        let track = read_track(&mut fdc);
+        let mut marks = Vec::new();
+        for i in 0..track.len() - 4 {
+            if track[i..i + 3] == [0xA1; 3] && (i == 0 || track[i - 1] != 0xA1) {
+                let len = if track[i + 3] == 0xFE { 4 } else { 256 };
+                let crc = floppy_controller::crc16(&track[i..i + 4 + len]);
+                let stored = u16::from_be_bytes([track[i + 4 + len], track[i + 5 + len]]);
+                marks.push((track[i + 3], track.get(i + 6).copied(), crc == stored));
+            }
+        }
+        assert_eq!(marks.iter().filter(|m| m.0 == 0xFE).count(), 16);
+        assert!(!marks.iter().any(|m| m.0 == 0xFE && m.1 == Some(5)));
+        let bad: Vec<u8> = marks.iter().filter(|m| !m.2).map(|m| m.0).collect();
+        assert_eq!(bad, [0xF8]);
+    }
 }
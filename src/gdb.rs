@@ -0,0 +1,337 @@
+// GDB remote serial protocol stub.
+//
+// A debugger connects to a local TCP port, see --gdb. A thread reads its
+// packets and hands them to the emulation thread as requests, where they
+// run with the machine stopped, as the monitor commands do; the two share
+// the breakpoints and the stopped machine. The registers are those of GDB's
+// z80 target, in order:
+//
+//     AF BC DE HL SP PC IX IY AF' BC' DE' HL' IR
+//
+// 16 bits each, little endian, IR being I in the high byte and R in the low
+// one. Memory is read and written as the CPU sees it in the current bank,
+// like the monitor does. The breakpoints don't touch the memory, both Z0
+// and Z1 set them.
+
+use std::io::{BufReader, Read, Result, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use iz80::{Cpu, Machine, Reg8};
+
+use crate::emulator::Request;
+use crate::kaypro_machine::KayproMachine;
+use crate::monitor::Monitor;
+use crate::snapshot::CpuState;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// What the connection thread passes to the emulation thread
+pub enum Input {
+    /// A debugger connected; replies go to the stream
+    Connected(TcpStream),
+    /// A packet, without the framing
+    Packet(String),
+    /// Ctrl-C: stop the machine
+    Interrupt,
+    Disconnected,
+}
+
+/// What the machine does after a packet
+pub enum Action {
+    Stay,
+    Go,
+    /// Let the machine go and forget the debugger
+    Detach,
+}
+
+/// Listens for one debugger at a time.
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    /// Listen on `port` of the loopback interface, 0 for any free port.
+    pub fn bind(port: u16) -> Result<Server> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        Ok(Server { listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve the debuggers that connect on a thread of their own, until
+    /// the emulation thread is gone.
+    pub fn serve(self, requests: Sender<Request>) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                // The acknowledgement and the reply are separate writes,
+                // the second one would wait for the first one to be acked
+                let _ = stream.set_nodelay(true);
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                if requests.send(Request::Gdb(Input::Connected(writer))).is_err() {
+                    return;
+                }
+                read_packets(stream, &requests);
+                if requests.send(Request::Gdb(Input::Disconnected)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Pass the packets of a connection on, acknowledging them, until it
+/// closes.
+fn read_packets(stream: TcpStream, requests: &Sender<Request>) {
+    let mut bytes = BufReader::new(&stream).bytes().map_while(|byte| byte.ok());
+    while let Some(byte) = bytes.next() {
+        let input = match byte {
+            0x03 => Input::Interrupt,
+            b'$' => {
+                let mut packet = Vec::new();
+                for byte in bytes.by_ref() {
+                    if byte == b'#' {
+                        break;
+                    }
+                    packet.push(byte);
+                }
+                let checksum: String = bytes.by_ref().take(2).map(char::from).collect();
+                let sum = packet.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+                if u8::from_str_radix(&checksum, 16) != Ok(sum) {
+                    let _ = (&stream).write_all(b"-");
+                    continue;
+                }
+                let _ = (&stream).write_all(b"+");
+                Input::Packet(String::from_utf8_lossy(&packet).into_owned())
+            },
+            // The acknowledgements of the replies, and noise
+            _ => continue,
+        };
+        if requests.send(Request::Gdb(input)).is_err() {
+            return;
+        }
+    }
+}
+
+/// The debugger connected, on the emulation thread.
+pub struct GdbStub {
+    stream: TcpStream,
+    /// The debugger let the machine go and waits for it to stop
+    running: bool,
+    /// The stop comes from an interrupt
+    interrupted: bool,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub { stream, running: false, interrupted: false }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// The next stop comes from the debugger's interrupt.
+    pub fn interrupt(&mut self) {
+        self.interrupted = true;
+    }
+
+    /// The machine stopped: tell the debugger if it is waiting for it.
+    pub fn stopped(&mut self) {
+        if self.running {
+            self.running = false;
+            let signal = if self.interrupted { SIGINT } else { SIGTRAP };
+            self.interrupted = false;
+            self.reply(&format!("S{:02x}", signal));
+        }
+    }
+
+    /// Run a packet. The machine must be settled if `stopped`.
+    pub fn packet(&mut self, packet: &str, stopped: bool, machine: &mut KayproMachine, cpu: &mut Cpu,
+            monitor: &mut Monitor) -> Action {
+        if !stopped {
+            // The monitor let the machine go under the debugger
+            self.reply("E01");
+            return Action::Stay;
+        }
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let mut action = Action::Stay;
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => registers(cpu).iter().map(|&value| hex16(value)).collect(),
+            "G" => match parse_registers(args) {
+                Some(values) => {
+                    set_registers(cpu, &values);
+                    "OK".to_string()
+                },
+                None => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| registers(cpu).get(n).copied()) {
+                Some(value) => hex16(value),
+                None => "E01".to_string(),
+            },
+            "P" => match set_register(cpu, args) {
+                Some(()) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "m" => match parse_range(args) {
+                Some((address, len)) => (0..len)
+                    .map(|offset| format!("{:02x}", machine.peek(address.wrapping_add(offset))))
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" => match write_memory(machine, args) {
+                Some(()) => "OK".to_string(),
+                None => "E01".to_string(),
+            },
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let Ok(address) = u16::from_str_radix(args, 16) else {
+                        self.reply("E01");
+                        return Action::Stay;
+                    };
+                    let mut state = CpuState::capture(cpu);
+                    state.pc = address;
+                    state.halted = false;
+                    state.apply(cpu);
+                }
+                if command == "s" {
+                    machine.tick(cpu);
+                    machine.settle(cpu);
+                    format!("S{:02x}", SIGTRAP)
+                } else {
+                    // The reply comes when the machine stops
+                    self.running = true;
+                    return Action::Go;
+                }
+            },
+            "Z" | "z" => match breakpoint(args) {
+                Some(address) => {
+                    if command == "Z" {
+                        monitor.add_breakpoint(address);
+                    } else {
+                        monitor.remove_breakpoint(address);
+                    }
+                    "OK".to_string()
+                },
+                // Watchpoints
+                None => String::new(),
+            },
+            "D" => {
+                action = Action::Detach;
+                "OK".to_string()
+            },
+            // Kill: there's no reply, the debugger closes the connection
+            "k" => return Action::Detach,
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            _ => String::new(),
+        };
+        self.reply(&reply);
+        action
+    }
+
+    fn reply(&mut self, data: &str) {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        if let Err(e) = write!(self.stream, "${}#{:02x}", data, sum) {
+            eprintln!("Warning: Failed to reply to the debugger: {}", e);
+        }
+    }
+}
+
+fn registers(cpu: &mut Cpu) -> [u16; 13] {
+    let state = CpuState::capture(cpu);
+    [state.af, state.bc, state.de, state.hl, state.sp, state.pc, state.ix, state.iy,
+        state.af_alt, state.bc_alt, state.de_alt, state.hl_alt, (state.i as u16) << 8 | state.r as u16]
+}
+
+fn set_registers(cpu: &mut Cpu, values: &[u16; 13]) {
+    let mut state = CpuState::capture(cpu);
+    let [af, bc, de, hl, sp, pc, ix, iy, af_alt, bc_alt, de_alt, hl_alt, ir] = *values;
+    if pc != state.pc {
+        // Jumping away leaves a HALT
+        state.halted = false;
+    }
+    state = CpuState { af, bc, de, hl, sp, pc, ix, iy, af_alt, bc_alt, de_alt, hl_alt, ..state };
+    state.apply(cpu);
+    let regs = cpu.registers();
+    regs.set8(Reg8::I, (ir >> 8) as u8);
+    regs.set8(Reg8::R, ir as u8);
+}
+
+/// P: a register number and its value, "n=value"
+fn set_register(cpu: &mut Cpu, args: &str) -> Option<()> {
+    let (n, value) = args.split_once('=')?;
+    let n = usize::from_str_radix(n, 16).ok()?;
+    let value = parse_hex16(value)?;
+    let mut values = registers(cpu);
+    *values.get_mut(n)? = value;
+    set_registers(cpu, &values);
+    Some(())
+}
+
+fn parse_registers(hex: &str) -> Option<[u16; 13]> {
+    let mut values = [0; 13];
+    if hex.len() != values.len() * 4 {
+        return None;
+    }
+    for (n, value) in values.iter_mut().enumerate() {
+        *value = parse_hex16(hex.get(n * 4..n * 4 + 4)?)?;
+    }
+    Some(values)
+}
+
+/// "address,length"
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (address, len) = args.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+/// M: "address,length:bytes"
+fn write_memory(machine: &mut KayproMachine, args: &str) -> Option<()> {
+    let (range, hex) = args.split_once(':')?;
+    let (address, len) = parse_range(range)?;
+    if hex.len() != len as usize * 2 {
+        return None;
+    }
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        machine.poke(address.wrapping_add(offset as u16), byte);
+    }
+    Some(())
+}
+
+/// Z and z: "type,address,kind", for the breakpoint types
+fn breakpoint(args: &str) -> Option<u16> {
+    let mut fields = args.split(',');
+    if !matches!(fields.next(), Some("0" | "1")) {
+        return None;
+    }
+    u16::from_str_radix(fields.next()?, 16).ok()
+}
+
+/// Little endian, as the target's registers
+fn hex16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_hex16(hex: &str) -> Option<u16> {
+    if hex.len() != 4 {
+        return None;
+    }
+    let lo = u8::from_str_radix(hex.get(0..2)?, 16).ok()?;
+    let hi = u8::from_str_radix(hex.get(2..4)?, 16).ok()?;
+    Some((hi as u16) << 8 | lo as u16)
+}
@@ -0,0 +1,105 @@
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::emulator::{Emulator, Update};
+    use crate::gdb::Server;
+    use crate::kaypro_machine::VideoMode;
+    use crate::test_support::machine;
+
+    fn emulator() -> Emulator {
+        Emulator::new(machine("roms/81-292a.rom", VideoMode::MemoryMapped, false), None, false)
+    }
+
+    fn byte(stream: &mut TcpStream) -> u8 {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).expect("no reply");
+        byte[0]
+    }
+
+    /// The next reply, skipping the acknowledgements.
+    fn reply(stream: &mut TcpStream) -> String {
+        while byte(stream) != b'$' {}
+        let data: Vec<u8> = std::iter::from_fn(|| Some(byte(stream))).take_while(|&byte| byte != b'#').collect();
+        let checksum = vec![byte(stream), byte(stream)];
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        assert_eq!(checksum, format!("{:02x}", sum).into_bytes());
+        String::from_utf8(data).unwrap()
+    }
+
+    fn send(stream: &mut TcpStream, packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", packet, sum).unwrap();
+        reply(stream)
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let server = Server::bind(0).unwrap();
+        let address = server.local_addr().unwrap();
+        let mut emulator = emulator();
+        emulator.debug();
+        let mut emulator = emulator.spawn(false);
+        server.serve(emulator.requests.clone());
+        // The front end takes the frames as the debugger runs the machine
+        let updates = std::mem::replace(&mut emulator.updates, mpsc::sync_channel(0).1);
+        let (resumes, resumed) = mpsc::channel();
+        let front_end = std::thread::spawn(move || {
+            for update in updates {
+                if let Update::Resumed = update {
+                    let _ = resumes.send(());
+                }
+            }
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        assert_eq!(send(&mut stream, "qSupported:swbreak+"), "PacketSize=1000");
+        assert_eq!(send(&mut stream, "?"), "S05");
+        // The ROM is mapped at reset
+        assert_eq!(send(&mut stream, "m0,3"), "c34b00");
+        assert_eq!(send(&mut stream, "vMustReplyEmpty"), "");
+
+        // NOP, NOP, JP 8000H
+        assert_eq!(send(&mut stream, "M8000,5:0000c30080"), "OK");
+        assert_eq!(send(&mut stream, "m8000,5"), "0000c30080");
+        assert_eq!(send(&mut stream, "M8000,2:00"), "E01");
+        assert_eq!(send(&mut stream, "P5=0080"), "OK");
+        assert_eq!(send(&mut stream, "p5"), "0080");
+        assert_eq!(send(&mut stream, "pd"), "E01");
+
+        let registers = send(&mut stream, "g");
+        assert_eq!(registers.len(), 13 * 4);
+        assert_eq!(&registers[20..24], "0080");
+        let registers = format!("{}3412{}", &registers[..12], &registers[16..]);
+        assert_eq!(send(&mut stream, &format!("G{}", registers)), "OK");
+        assert_eq!(send(&mut stream, "p3"), "3412");
+        assert_eq!(send(&mut stream, "g"), registers);
+
+        assert_eq!(send(&mut stream, "Z0,8002,1"), "OK");
+        assert_eq!(send(&mut stream, "c"), "S05");
+        assert_eq!(send(&mut stream, "p5"), "0280");
+        assert_eq!(send(&mut stream, "z0,8002,1"), "OK");
+        assert_eq!(send(&mut stream, "s"), "S05");
+        assert_eq!(send(&mut stream, "p5"), "0080");
+        assert_eq!(send(&mut stream, "Z2,8000,1"), "");
+
+        // Ctrl-C stops it
+        write!(stream, "$c#63").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut stream), "S02");
+        let pc = send(&mut stream, "p5");
+        assert!(["0080", "0180", "0280"].contains(&pc.as_str()), "{}", pc);
+
+        // Detaching lets the machine go
+        assert_eq!(send(&mut stream, "D"), "OK");
+        resumed.recv_timeout(Duration::from_secs(10)).expect("not resumed");
+        emulator.stop();
+        front_end.join().unwrap();
+    }
+}
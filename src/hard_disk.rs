@@ -1,28 +1,28 @@
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
+use std::io::{Error, ErrorKind, Read, Write, Seek, SeekFrom};
+
+use crate::hd_drive::{self, HdGeometry, SECTOR_SIZE};
+use crate::hd_image::{self, HdMetadata};
+use crate::media;
+use crate::overlay::Overlay;
+use crate::rewind::WriteJournal;
+use crate::scheduler::Event;
+use crate::snapshot::{self, StateReader, StateWriter};
 
 /// WD1002-05 Winchester Hard Disk Controller emulation for Kaypro 10.
 ///
-/// The WD1002-05 uses an ST412 interface to control a hard drive with
-/// 306 cylinders, 4 heads, 17 sectors per track, 512 bytes per sector
-/// (total capacity ~10.4 MB). The controller is accessed via I/O ports
-/// 0x80-0x87 (task file registers).
+/// The WD1002-05 uses an ST412 interface to control a hard drive, by
+/// default the Kaypro 10's ST-412 with 306 cylinders, 4 heads, 17 sectors
+/// per track, 512 bytes per sector (total capacity ~10.4 MB); see
+/// `hd_drive` for the other drives. The controller is accessed via I/O
+/// ports 0x80-0x87 (task file registers).
 ///
 /// Reference: 61-031050-0030 WD1002-05 HDO OEM Manual (July 1983)
 
-const CYLINDERS: u16 = 306;
-const HEADS: u8 = 4;
-const SECTORS_PER_TRACK: u8 = 17;
-const SECTOR_SIZE: usize = 512;
-const DISK_SIZE: usize = CYLINDERS as usize * HEADS as usize
-    * SECTORS_PER_TRACK as usize * SECTOR_SIZE;
-const NUM_TRACKS: usize = CYLINDERS as usize * HEADS as usize;
-const TRACK_SIZE: usize = SECTORS_PER_TRACK as usize * SECTOR_SIZE;
-
 // Status register bits (port 0x87 read)
 const STS_BUSY: u8 = 0x80;
 const STS_READY: u8 = 0x40;
-#[allow(dead_code)]
 const STS_WRITE_FAULT: u8 = 0x20;
 const STS_SEEK_DONE: u8 = 0x10;
 const STS_DRQ: u8 = 0x08;
@@ -30,20 +30,80 @@ const STS_DRQ: u8 = 0x08;
 const STS_CORRECTED: u8 = 0x04;
 const STS_ERROR: u8 = 0x01;
 
+// Drive select lines (SDH bits 4:3)
+const UNIT_COUNT: usize = 4;
+
 // Error register bits (port 0x81 read)
-#[allow(dead_code)]
 const ERR_BAD_BLOCK: u8 = 0x80;
-#[allow(dead_code)]
 const ERR_UNCORRECTABLE: u8 = 0x40;
-#[allow(dead_code)]
 const ERR_CRC: u8 = 0x20;
 const ERR_ID_NOT_FOUND: u8 = 0x10;
 const ERR_ABORTED: u8 = 0x04;
 #[allow(dead_code)]
 const ERR_TR000: u8 = 0x02;
-#[allow(dead_code)]
 const ERR_DAM_NOT_FOUND: u8 = 0x01;
 
+// Sector faults, from the "<image>.faults" sidecar and the bad block
+// flags of FORMAT TRACK
+/// ID field flagged bad by FORMAT TRACK
+const FAULT_BAD_BLOCK: u8 = 0x01;
+/// ID field CRC error: the sector is never found
+const FAULT_ID_CRC: u8 = 0x02;
+/// Data field ECC/CRC error beyond correction
+const FAULT_DATA_CRC: u8 = 0x04;
+/// No data address mark after the ID field
+const FAULT_NO_DAM: u8 = 0x08;
+/// The drive reports a write fault when writing the sector
+const FAULT_WRITE: u8 = 0x10;
+
+const FAULT_NAMES: [(&str, u8); 5] = [
+    ("bad", FAULT_BAD_BLOCK),
+    ("idcrc", FAULT_ID_CRC),
+    ("crc", FAULT_DATA_CRC),
+    ("dam", FAULT_NO_DAM),
+    ("wfault", FAULT_WRITE),
+];
+
+/// Sector faults keyed by (cylinder, head, sector ID).
+type FaultMap = HashMap<(u16, u8, u8), u8>;
+
+/// Parse a hard disk fault map: one sector per line as
+/// `cylinder head sector fault...`, with faults among bad, idcrc, crc,
+/// dam and wfault.  '#' starts a comment.
+fn parse_fault_map(text: &str) -> std::io::Result<FaultMap> {
+    let mut faults = FaultMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |what: &str| Error::new(ErrorKind::InvalidData,
+            format!("Fault map line {}: {}", n + 1, what));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(invalid("expected cylinder, head, sector and faults"));
+        }
+        let number = |f: &str| f.parse::<u16>().map_err(|_| invalid(&format!("bad number '{}'", f)));
+        let (cylinder, head, sector) = (number(fields[0])?, number(fields[1])?, number(fields[2])?);
+        if head > 7 || sector > 255 {
+            return Err(invalid("head must be 0-7 and sector 0-255"));
+        }
+        let mut fault = 0;
+        for name in &fields[3..] {
+            fault |= FAULT_NAMES.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|&(_, bit)| bit)
+                .ok_or_else(|| invalid(&format!("unknown fault '{}'", name)))?;
+        }
+        *faults.entry((cylinder, head as u8, sector as u8)).or_insert(0) |= fault;
+    }
+    Ok(faults)
+}
+
+/// Emulated time the reset diagnostics keep BUSY set. The real controller
+/// takes 1-2 s; the Kaypro 10 ROM waits up to about 3 s.
+const DIAGNOSTICS_US: u64 = 1_000_000;
+
 // Diagnostic error codes (written to error register after reset)
 #[allow(dead_code)]
 const DIAG_PASS: u8 = 0x00;
@@ -83,10 +143,14 @@ pub struct HardDisk {
     #[allow(dead_code)]
     precomp: u8,
 
-    // SASI reset state: BUSY is held during diagnostics (~1-2s real HW).
-    // We simulate with a countdown decremented on status reads.
+    // SASI reset state: BUSY is held during diagnostics (~1-2s real HW),
+    // until the posted HdcDiagnostics event.
     reset_pending: bool,
-    busy_countdown: u16,
+
+    // Emulated time in microseconds, set by the machine before each access
+    pub now_us: u64,
+    // Events posted at an emulated time, collected by the machine
+    pub posted: Vec<(u64, Event)>,
 
     // INTRQ state — per spec, asserted on command completion, cleared
     // when host reads Status register or writes a new command.
@@ -98,7 +162,7 @@ pub struct HardDisk {
     // accept commands. Default is LUN1 only (bit 1), matching Kaypro 10.
     ready_lun_mask: u8,
 
-    // When true, SASI reset completes immediately (busy_countdown=1).
+    // When true, SASI reset completes immediately.
     // TurboROM checks BUSY after a very short delay to decide if port 0x14
     // bit 1 is shared with SASI reset. On the Advent board, the controller
     // must appear READY quickly so sltmsk stays at 0x03 (4-drive select).
@@ -110,24 +174,156 @@ pub struct HardDisk {
     data_ix: usize,
     wr_off: usize, // Disk offset latched at WRITE SECTOR start
 
-    // True when a disk image has been loaded (equivalent to Java's driveFd != null).
-    // When false, the controller is detected by the ROM (reset/status/error work)
-    // but all commands fail, causing the ROM to fall back to floppy boot.
-    drive_present: bool,
+    // Drives on the four drive select lines, indexed by LUN. With none
+    // loaded (equivalent to Java's driveFd == null), the controller is
+    // detected by the ROM (reset/status/error work) but all commands fail,
+    // causing the ROM to fall back to floppy boot.
+    units: [Option<HdUnit>; UNIT_COUNT],
+
+    // Copy-on-write mode: when set before load_image(), the image is opened
+    // read-only and writes go to the sidecar overlay instead.
+    pub use_overlay: bool,
+
+    // Drive type for the next image loaded
+    geometry: HdGeometry,
+
+    // Model hint recorded in new hard disk containers
+    model: String,
+
+    // Optional trace log file (when set, traces go here instead of stderr)
+    pub trace_file: Option<File>,
+}
+
+/// A drive on one of the controller's drive select lines.
+pub struct HdUnit {
+    geometry: HdGeometry,
 
     // Backing file for persistent storage
     file: Option<File>,
+    overlay: Option<Overlay>,
+
+    // Model hint of a container image; None for a raw image
+    container: Option<String>,
 
     // Per-track formatted state. On real hardware, an unformatted track
     // has no sector headers (IDAMs), so READ/WRITE SECTOR fail with
     // ID NOT FOUND. FORMAT TRACK writes the headers, enabling access.
     track_formatted: Vec<bool>,
 
+    // Damaged sectors and sectors formatted with the bad block flag
+    faults: FaultMap,
+
     // Hard disk image (raw sector data)
     pub disk_data: Vec<u8>,
 
-    // Optional trace log file (when set, traces go here instead of stderr)
-    pub trace_file: Option<File>,
+    // Previous content of the sectors written since each rewind point,
+    // with the formatted tracks and faults at the point
+    journal: WriteJournal<(Vec<bool>, FaultMap)>,
+}
+
+impl HdUnit {
+    /// Scan disk_data to determine which tracks contain data (formatted).
+    /// On real hardware, an unformatted track has no flux transitions
+    /// (all zeros in our representation). Any non-zero byte means the
+    /// track has been formatted and written with sector headers.
+    #[allow(dead_code)]
+    fn detect_formatted_tracks(&self) -> Vec<bool> {
+        let track_size = self.geometry.track_size();
+        let mut formatted = vec![false; self.geometry.tracks()];
+        for track_idx in 0..self.geometry.tracks() {
+            let off = track_idx * track_size;
+            let end = (off + track_size).min(self.disk_data.len());
+            if off < self.disk_data.len() {
+                formatted[track_idx] = self.disk_data[off..end].iter().any(|&b| b != 0);
+            }
+        }
+        formatted
+    }
+
+    /// Model the container image was made for ("" if it doesn't say),
+    /// or None for a raw image.
+    pub fn model(&self) -> Option<&str> {
+        self.container.as_deref()
+    }
+
+    /// Container metadata for the current state of the drive.
+    fn metadata(&self) -> Option<HdMetadata> {
+        self.container.as_ref()?;
+        Some(self.layout())
+    }
+
+    /// The formatted tracks and bad blocks, with the container's model if
+    /// there is one.
+    fn layout(&self) -> HdMetadata {
+        let mut bad_blocks: Vec<(u16, u8, u8)> = self.faults.iter()
+            .filter(|(_, &fault)| fault & FAULT_BAD_BLOCK != 0)
+            .map(|(&key, _)| key)
+            .collect();
+        bad_blocks.sort_unstable();
+        HdMetadata {
+            geometry: self.geometry,
+            model: self.container.clone().unwrap_or_default(),
+            track_formatted: self.track_formatted.clone(),
+            bad_blocks,
+        }
+    }
+
+    /// Write `disk_data[off..off + len]` through to the overlay or the
+    /// backing file.
+    fn persist(&mut self, off: usize, len: usize) {
+        let base = if self.container.is_some() { hd_image::HEADER_SIZE } else { 0 };
+        if let Some(ref mut overlay) = self.overlay {
+            if let Err(e) = overlay.write(&self.disk_data, off, off + len) {
+                eprintln!("HDC: Warning: failed to write overlay {}: {}", overlay.path(), e);
+            }
+        } else if let Some(ref mut f) = self.file {
+            if f.seek(SeekFrom::Start((base + off) as u64)).is_ok() {
+                let _ = f.write_all(&self.disk_data[off..off + len]);
+            }
+        }
+    }
+
+    /// Write the header and trailer of a container image after the
+    /// formatted tracks or bad blocks change. With an overlay, they go there
+    /// instead, whatever the image.
+    fn persist_metadata(&mut self) {
+        let record = self.layout().overlay_record();
+        if let Some(ref mut overlay) = self.overlay {
+            if let Err(e) = overlay.write_metadata(&record) {
+                eprintln!("HDC: Warning: failed to write overlay {}: {}", overlay.path(), e);
+            }
+            return;
+        }
+        let (Some(metadata), Some(f)) = (self.metadata(), self.file.as_mut()) else {
+            return;
+        };
+        let trailer_off = (hd_image::HEADER_SIZE + self.disk_data.len()) as u64;
+        let trailer = metadata.trailer();
+        let result = f.seek(SeekFrom::Start(0))
+            .and_then(|_| f.write_all(&metadata.header()))
+            .and_then(|_| f.seek(SeekFrom::Start(trailer_off)))
+            .and_then(|_| f.write_all(&trailer))
+            .and_then(|_| f.set_len(trailer_off + trailer.len() as u64));
+        if let Err(e) = result {
+            eprintln!("HDC: Warning: failed to update hard disk container: {}", e);
+        }
+    }
+
+    /// Undo the writes since rewind point `point`, on disk too.
+    fn rewind(&mut self, point: u64) {
+        let Some(((track_formatted, faults), changed)) = self.journal.rewind(point, &mut self.disk_data) else {
+            return;
+        };
+        let metadata_changed = track_formatted != self.track_formatted || faults != self.faults;
+        self.track_formatted = track_formatted;
+        self.faults = faults;
+        if let Some((start, end)) = changed {
+            self.persist(start, end - start);
+        }
+        if metadata_changed {
+            self.persist_metadata();
+        }
+    }
 }
 
 impl HardDisk {
@@ -145,7 +341,8 @@ impl HardDisk {
             cur_cmd: 0,
             precomp: 0,
             reset_pending: false,
-            busy_countdown: 0,
+            now_us: 0,
+            posted: Vec::new(),
             intrq: false,
             ready_lun_mask: 1 << 1,
             quick_reset: false,
@@ -153,46 +350,150 @@ impl HardDisk {
             data_length: SECTOR_SIZE,
             data_ix: 0,
             wr_off: 0,
-            drive_present: false,
-            file: None,
-            track_formatted: Vec::new(),
-            disk_data: Vec::new(),
+            units: [None, None, None, None],
+            use_overlay: false,
+            geometry: hd_drive::ST412,
+            model: String::new(),
             trace_file: None,
         }
     }
 
+    /// Select the drive attached to the controller. Takes effect with the
+    /// next `load_image`.
+    pub fn set_geometry(&mut self, geometry: HdGeometry) {
+        self.geometry = geometry;
+    }
+
+    /// Model name recorded in the hard disk containers created from now on.
+    pub fn set_model(&mut self, model: &str) {
+        self.model = model.to_string();
+    }
+
+    /// Load the image of the drive on LUN 1, the Kaypro 10 drive.
     pub fn load_image(&mut self, path: &str) -> std::io::Result<()> {
+        self.load_unit(1, path)
+    }
+
+    /// Load the image of the drive on `lun` (0-3) and let it report READY.
+    /// A hard disk container brings its own geometry; a raw image is taken
+    /// to be of the drive selected with `set_geometry`.
+    pub fn load_unit(&mut self, lun: u8, path: &str) -> std::io::Result<()> {
+        let mut geometry = self.geometry;
+        let disk_data;
+        let track_formatted;
+        let file;
+        let mut container = None;
+        let mut bad_blocks = Vec::new();
         if std::path::Path::new(path).exists() {
-            let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+            let mut image = OpenOptions::new().read(true).write(!self.use_overlay).open(path)?;
             let mut data = Vec::new();
-            file.read_to_end(&mut data)?;
-            if data.len() != DISK_SIZE {
-                eprintln!("HDC: Warning: disk image size {} does not match expected {} bytes",
-                    data.len(), DISK_SIZE);
-            }
-            // Pad or truncate to expected size to prevent out-of-bounds access
-            data.resize(DISK_SIZE, 0);
-            self.disk_data = data;
-            // An existing image has been through HDFMT which formats all
-            // tracks. Mark every track as formatted so READ/WRITE succeed
-            // everywhere. (The old heuristic — check for non-zero bytes —
-            // fails for tracks that were formatted but never written.)
-            self.track_formatted = vec![true; NUM_TRACKS];
-            eprintln!("HDC: Loaded hard disk image: {} ({} bytes)",
-                path, self.disk_data.len());
-            self.file = Some(file);
+            image.read_to_end(&mut data)?;
+            if hd_image::is_container(&data) {
+                let (metadata, sectors) = hd_image::parse(&data)?;
+                if metadata.geometry != self.geometry {
+                    eprintln!("HDC: {} is a container for an {} drive, using its geometry",
+                        path, metadata.geometry.name);
+                }
+                geometry = metadata.geometry;
+                disk_data = sectors.to_vec();
+                eprintln!("HDC: Loaded hard disk container: {} ({}, {} of {} tracks formatted, {} bad blocks)",
+                    path, geometry.label(), metadata.track_formatted.iter().filter(|&&f| f).count(),
+                    geometry.tracks(), metadata.bad_blocks.len());
+                track_formatted = metadata.track_formatted;
+                bad_blocks = metadata.bad_blocks;
+                container = Some(metadata.model);
+            } else {
+                let disk_size = geometry.size();
+                let hint = match HdGeometry::for_image_size(data.len()) {
+                    Some(other) if other != geometry => format!(" (the size of an {} drive)", other.name),
+                    _ => String::new(),
+                };
+                if data.len() > disk_size {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        format!("image is {} bytes{}, larger than the {} drive",
+                            data.len(), hint, geometry.label())));
+                }
+                // An existing image has been through HDFMT which formats all
+                // tracks. Mark every track as formatted so READ/WRITE succeed
+                // everywhere. (The old heuristic — check for non-zero bytes —
+                // fails for tracks that were formatted but never written.)
+                // Tracks past the end of a short image have never been written.
+                let track_size = geometry.track_size();
+                track_formatted = (0..geometry.tracks())
+                    .map(|t| (t + 1) * track_size <= data.len())
+                    .collect();
+                if data.len() < disk_size {
+                    eprintln!("HDC: Warning: disk image size {}{} is smaller than the {} drive, padding",
+                        data.len(), hint, geometry.label());
+                }
+                // Pad to the drive size to prevent out-of-bounds access
+                data.resize(disk_size, 0);
+                disk_data = data;
+                eprintln!("HDC: Loaded hard disk image: {} ({} bytes, {})",
+                    path, disk_data.len(), geometry.label());
+            }
+            file = Some(image);
         } else {
-            let mut file = File::create(path)?;
-            self.disk_data = vec![0u8; DISK_SIZE];
-            file.write_all(&self.disk_data)?;
-            eprintln!("HDC: Created blank hard disk image: {}", path);
+            disk_data = vec![0u8; geometry.size()];
             // All tracks unformatted on a new image
-            self.track_formatted = vec![false; NUM_TRACKS];
+            let metadata = HdMetadata::new(geometry, &self.model);
+            if hd_image::has_container_extension(path) {
+                std::fs::write(path, hd_image::encode(&metadata, &disk_data))?;
+                eprintln!("HDC: Created blank hard disk container: {} ({})", path, geometry.label());
+                container = Some(metadata.model);
+            } else {
+                std::fs::write(path, &disk_data)?;
+                eprintln!("HDC: Created blank hard disk image: {} ({})", path, geometry.label());
+            }
+            track_formatted = metadata.track_formatted;
             // Re-open read/write for future seeks
-            let file = OpenOptions::new().read(true).write(true).open(path)?;
-            self.file = Some(file);
+            file = Some(OpenOptions::new().read(true).write(true).open(path)?);
+        }
+        let mut unit = HdUnit {
+            geometry,
+            file,
+            overlay: None,
+            container,
+            track_formatted,
+            faults: bad_blocks.into_iter().map(|key| (key, FAULT_BAD_BLOCK)).collect(),
+            disk_data,
+            journal: WriteJournal::new(),
+        };
+        match std::fs::read_to_string(media::fault_map_path(path)) {
+            Ok(text) => match parse_fault_map(&text) {
+                Ok(faults) => {
+                    eprintln!("HDC: {} damaged sectors in {}", faults.len(), media::fault_map_path(path));
+                    for (key, fault) in faults {
+                        *unit.faults.entry(key).or_insert(0) |= fault;
+                    }
+                }
+                Err(e) => eprintln!("HDC: Warning: could not load fault map of '{}': {}", path, e),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => eprintln!("HDC: Warning: could not load fault map of '{}': {}", path, e),
         }
-        self.drive_present = true;
+        if self.use_overlay {
+            let overlay = Overlay::open(path, SECTOR_SIZE)?;
+            overlay.apply(&mut unit.disk_data);
+            if let Some(record) = overlay.metadata() {
+                let mut layout = unit.layout();
+                layout.apply_overlay_record(record)?;
+                unit.track_formatted = layout.track_formatted;
+                for fault in unit.faults.values_mut() {
+                    *fault &= !FAULT_BAD_BLOCK;
+                }
+                unit.faults.retain(|_, fault| *fault != 0);
+                for key in layout.bad_blocks {
+                    *unit.faults.entry(key).or_insert(0) |= FAULT_BAD_BLOCK;
+                }
+            }
+            eprintln!("HDC: Writes go to overlay {} ({} sectors changed)",
+                overlay.path(), overlay.len());
+            unit.overlay = Some(overlay);
+            unit.file = None;
+        }
+        self.units[lun as usize & 0x03] = Some(unit);
+        self.ready_lun_mask |= 1 << (lun & 0x03);
         Ok(())
     }
 
@@ -208,34 +509,156 @@ impl HardDisk {
     }
 
     fn lun_is_ready(&self, lun: u8) -> bool {
-        self.drive_present && (self.ready_lun_mask & (1u8 << lun)) != 0
+        self.units[lun as usize].is_some() && (self.ready_lun_mask & (1u8 << lun)) != 0
     }
 
-    /// Scan disk_data to determine which tracks contain data (formatted).
-    /// On real hardware, an unformatted track has no flux transitions
-    /// (all zeros in our representation). Any non-zero byte means the
-    /// track has been formatted and written with sector headers.
-    #[allow(dead_code)]
-    fn detect_formatted_tracks(&self) -> Vec<bool> {
-        let mut formatted = vec![false; NUM_TRACKS];
-        for track_idx in 0..NUM_TRACKS {
-            let off = track_idx * TRACK_SIZE;
-            let end = (off + TRACK_SIZE).min(self.disk_data.len());
-            if off < self.disk_data.len() {
-                formatted[track_idx] = self.disk_data[off..end].iter().any(|&b| b != 0);
+    /// Drive on `lun`, if an image has been loaded for it.
+    pub fn unit(&self, lun: u8) -> Option<&HdUnit> {
+        self.units[lun as usize & 0x03].as_ref()
+    }
+
+    /// Geometry of the selected drive.
+    fn drive_geometry(&self) -> HdGeometry {
+        self.unit(self.get_lun()).map_or(self.geometry, |unit| unit.geometry)
+    }
+
+    pub fn flush(&mut self) {
+        for unit in self.units.iter_mut().flatten() {
+            let metadata = unit.metadata();
+            if let Some(ref mut f) = unit.file {
+                let content = match metadata {
+                    Some(metadata) => hd_image::encode(&metadata, &unit.disk_data),
+                    None => unit.disk_data.clone(),
+                };
+                if f.seek(SeekFrom::Start(0)).is_ok() {
+                    let _ = f.write_all(&content);
+                }
             }
         }
-        formatted
     }
 
-    pub fn flush(&mut self) {
-        if let Some(ref mut f) = self.file {
-            if f.seek(SeekFrom::Start(0)).is_ok() {
-                let _ = f.write_all(&self.disk_data);
+    /// Start journaling the writes after rewind point `point`.
+    pub fn mark_rewind_point(&mut self, point: u64, oldest: u64) {
+        for unit in self.units.iter_mut().flatten() {
+            let layout = (unit.track_formatted.clone(), unit.faults.clone());
+            unit.journal.mark(point, oldest, layout);
+        }
+    }
+
+    /// Undo the writes since rewind point `point`.
+    pub fn rewind(&mut self, point: u64) {
+        for unit in self.units.iter_mut().flatten() {
+            unit.rewind(point);
+        }
+    }
+
+    pub fn forget_rewind_points(&mut self) {
+        for unit in self.units.iter_mut().flatten() {
+            unit.journal.clear();
+        }
+    }
+
+    /// Bit n set when drive select n has a drive.
+    fn unit_mask(&self) -> u8 {
+        self.units.iter().enumerate()
+            .filter(|(_, unit)| unit.is_some())
+            .fold(0, |mask, (lun, _)| mask | 1 << lun)
+    }
+
+    /// The task file and the transfer in progress. The sector data stays
+    /// in the images, flushed when the snapshot is taken.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.unit_mask());
+        for register in [self.data, self.error, self.sector_count, self.sector_number,
+                self.cylinder_low, self.cylinder_high, self.sdh, self.status,
+                self.cur_cmd, self.precomp] {
+            state.u8(register);
+        }
+        state.bool(self.reset_pending);
+        state.bool(self.intrq);
+        state.u8(self.ready_lun_mask);
+        state.bytes(&self.data_buf);
+        state.usize(self.data_length);
+        state.usize(self.data_ix);
+        state.usize(self.wr_off);
+        for unit in self.units.iter().flatten() {
+            state.bytes(&hd_image::encode_track_map(&unit.track_formatted));
+            let mut faults: Vec<_> = unit.faults.iter().collect();
+            faults.sort_unstable();
+            state.usize(faults.len());
+            for (&(cylinder, head, sector), &fault) in faults {
+                state.u16(cylinder);
+                state.u8(head);
+                state.u8(sector);
+                state.u8(fault);
             }
         }
     }
 
+    pub fn save_disk_hashes(&self, state: &mut StateWriter) {
+        for unit in self.units.iter().flatten() {
+            state.u64(snapshot::content_hash(&unit.disk_data));
+        }
+    }
+
+    /// The hard disks must hold what they held when the snapshot was taken.
+    pub fn check_disk_hashes(&self, state: &mut StateReader) -> std::io::Result<()> {
+        for (lun, unit) in self.units.iter().enumerate() {
+            let Some(unit) = unit else {
+                continue;
+            };
+            if state.u64()? != snapshot::content_hash(&unit.disk_data) {
+                return Err(snapshot::invalid(format!(
+                    "Hard disk {} has been written since the snapshot was taken", lun)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> std::io::Result<()> {
+        let unit_mask = state.u8()?;
+        if unit_mask != self.unit_mask() {
+            return Err(snapshot::invalid(format!(
+                "Snapshot has hard disks on drive selects {:04b}, this machine on {:04b}",
+                unit_mask, self.unit_mask())));
+        }
+        self.data = state.u8()?;
+        self.error = state.u8()?;
+        self.sector_count = state.u8()?;
+        self.sector_number = state.u8()?;
+        self.cylinder_low = state.u8()?;
+        self.cylinder_high = state.u8()?;
+        self.sdh = state.u8()?;
+        self.status = state.u8()?;
+        self.cur_cmd = state.u8()?;
+        self.precomp = state.u8()?;
+        self.reset_pending = state.bool()?;
+        self.intrq = state.bool()?;
+        self.ready_lun_mask = state.u8()?;
+        state.bytes_into(&mut self.data_buf)?;
+        self.data_length = state.usize()?;
+        self.data_ix = state.usize()?;
+        self.wr_off = state.usize()?;
+        if self.data_length > self.data_buf.len() || self.data_ix > self.data_length {
+            return Err(snapshot::invalid("Snapshot has an invalid hard disk transfer".to_string()));
+        }
+        for unit in self.units.iter_mut().flatten() {
+            let track_formatted = hd_image::decode_track_map(state.bytes()?, unit.geometry.tracks());
+            let mut faults = FaultMap::new();
+            for _ in 0..state.usize()? {
+                let key = (state.u16()?, state.u8()?, state.u8()?);
+                faults.insert(key, state.u8()?);
+            }
+            if track_formatted != unit.track_formatted || faults != unit.faults {
+                unit.track_formatted = track_formatted;
+                unit.faults = faults;
+                unit.persist_metadata();
+            }
+        }
+        Ok(())
+    }
+
+
     // --- Geometry helpers ---
 
     fn get_cyl(&self) -> u16 {
@@ -251,9 +674,23 @@ impl HardDisk {
         self.sdh & 0x07
     }
 
-    /// CHS → track index (cyl * HEADS + head).
+    /// CHS → track index (cyl * heads + head).
     fn get_track_index(&self) -> usize {
-        self.get_cyl() as usize * HEADS as usize + self.get_head() as usize
+        self.get_cyl() as usize * self.drive_geometry().heads as usize + self.get_head() as usize
+    }
+
+    /// True if the cylinder and head in the task file exist on the drive.
+    fn cyl_head_valid(&self) -> bool {
+        let geometry = self.drive_geometry();
+        self.get_cyl() < geometry.cylinders && self.get_head() < geometry.heads
+    }
+
+    /// True if the sector in the task file exists on the selected track:
+    /// a sector ID past the end of the track is never found.
+    fn sector_valid(&self) -> bool {
+        let sector_size = self.get_sector_size();
+        self.cyl_head_valid()
+            && (self.sector_number as usize + 1) * sector_size <= self.drive_geometry().track_size()
     }
 
     /// Extract LUN (drive select) from SDH register (bits 4:3).
@@ -264,7 +701,25 @@ impl HardDisk {
     /// Check if the current track has been formatted (sector headers exist).
     fn is_track_formatted(&self) -> bool {
         let idx = self.get_track_index();
-        idx < self.track_formatted.len() && self.track_formatted[idx]
+        self.unit(self.get_lun()).and_then(|unit| unit.track_formatted.get(idx)) == Some(&true)
+    }
+
+    /// Faults of the sector in the task file.
+    fn sector_fault(&self) -> u8 {
+        let key = (self.get_cyl(), self.get_head(), self.sector_number);
+        self.unit(self.get_lun()).and_then(|unit| unit.faults.get(&key)).copied().unwrap_or(0)
+    }
+
+    /// Error register bits for a sector whose ID field can't be used, or 0.
+    fn id_field_error(&self) -> u8 {
+        let fault = self.sector_fault();
+        if fault & FAULT_ID_CRC != 0 {
+            ERR_CRC | ERR_ID_NOT_FOUND
+        } else if fault & FAULT_BAD_BLOCK != 0 {
+            ERR_BAD_BLOCK
+        } else {
+            0
+        }
     }
 
     /// CHS → byte offset into disk_data.
@@ -277,7 +732,8 @@ impl HardDisk {
         let head = self.get_head() as usize;
         let sec = self.sector_number as usize;
         let sector_size = self.get_sector_size();
-        (cyl * HEADS as usize + head) * TRACK_SIZE + sec * sector_size
+        let geometry = self.drive_geometry();
+        (cyl * geometry.heads as usize + head) * geometry.track_size() + sec * sector_size
     }
 
     // --- Status helpers (bit-level mutation like real hardware) ---
@@ -296,11 +752,12 @@ impl HardDisk {
     /// Advance CHS to the next sector for multi-sector operations.
     /// Wraps sector → head → cylinder per WD spec.
     fn advance_sector(&mut self) {
+        let geometry = self.drive_geometry();
         self.sector_number = self.sector_number.wrapping_add(1);
-        if self.sector_number >= SECTORS_PER_TRACK {
+        if self.sector_number >= geometry.sectors_per_track {
             self.sector_number = 0;
             let mut head = self.get_head() + 1;
-            if head >= HEADS {
+            if head >= geometry.heads {
                 head = 0;
                 let cyl = self.get_cyl() + 1;
                 self.cylinder_low = cyl as u8;
@@ -335,11 +792,35 @@ impl HardDisk {
         // BUSY set immediately; cleared when diagnostics complete
         self.status = STS_BUSY;
         self.reset_pending = true;
-        // The ROM polls status in a tight loop. We use a countdown
-        // decremented on each status read so BUSY clears after enough polls.
+        // The ROM polls status in a tight loop until BUSY clears.
         // Advent board systems need quick_reset so TurboROM sees NOT BUSY
         // on its first status check and preserves sltmsk=0x03 (4-drive mode).
-        self.busy_countdown = if self.quick_reset { 1 } else { 20 };
+        if self.quick_reset {
+            self.diagnostics_done();
+        } else {
+            self.posted.push((self.now_us + DIAGNOSTICS_US, Event::HdcDiagnostics));
+        }
+    }
+
+    /// HdcDiagnostics event: the reset diagnostics are over.
+    pub fn diagnostics_done(&mut self) {
+        if !self.reset_pending {
+            return;
+        }
+        // Diagnostics complete. Per WD spec section 5.4:
+        // error register gets diagnostic code, but the ERROR
+        // status bit is NOT set (even for non-zero codes).
+        self.error = DIAG_WD2797; // 0x01: no WD2797 floppy chip
+        self.reset_pending = false;
+        // Set READY if the selected LUN is configured
+        self.status = STS_SEEK_DONE;
+        if self.lun_is_ready(self.get_lun()) {
+            self.status |= STS_READY;
+        }
+        if self.trace {
+            hdc_log!(self.trace_file, "HDC: Reset diagnostics complete, error=0x{:02X}, status=0x{:02X}",
+                self.error, self.status);
+        }
     }
 
     // --- Port I/O ---
@@ -407,7 +888,7 @@ impl HardDisk {
                 // clears SEEK_DONE, clears INTRQ, and clears error state.
                 self.cur_cmd = value;
                 self.status |= STS_BUSY;
-                self.status &= !(STS_ERROR | STS_SEEK_DONE);
+                self.status &= !(STS_ERROR | STS_SEEK_DONE | STS_WRITE_FAULT);
                 self.error = 0;
                 self.intrq = false;
                 self.process_cmd();
@@ -455,28 +936,6 @@ impl HardDisk {
         // Per spec: reading Status register clears INTRQ
         self.intrq = false;
 
-        // Handle busy countdown from SASI reset diagnostics
-        if self.busy_countdown > 0 {
-            self.busy_countdown -= 1;
-            if self.busy_countdown == 0 {
-                if self.reset_pending {
-                    // Diagnostics complete. Per WD spec section 5.4:
-                    // error register gets diagnostic code, but the ERROR
-                    // status bit is NOT set (even for non-zero codes).
-                    self.error = DIAG_WD2797; // 0x01: no WD2797 floppy chip
-                    self.reset_pending = false;
-                    // Set READY if the selected LUN is configured
-                    self.status = STS_SEEK_DONE;
-                    if self.lun_is_ready(self.get_lun()) {
-                        self.status |= STS_READY;
-                    }
-                    if self.trace {
-                        hdc_log!(self.trace_file, "HDC: Reset diagnostics complete, error=0x{:02X}, status=0x{:02X}",
-                            self.error, self.status);
-                    }
-                }
-            }
-        }
         if self.trace {
             hdc_log!(self.trace_file, "HDC: Read Status = 0x{:02X}", self.status);
         }
@@ -495,7 +954,7 @@ impl HardDisk {
         }
 
         // No disk image loaded — all commands fail (Java: driveFd == null)
-        if !self.drive_present {
+        if self.units.iter().all(Option::is_none) {
             if self.trace {
                 hdc_log!(self.trace_file, "HDC: Command 0x{:02X} rejected — no disk image loaded", self.cur_cmd);
             }
@@ -554,7 +1013,7 @@ impl HardDisk {
         // Per spec: SEEK validates cylinder/head only, not sector
         let cyl = self.get_cyl();
         let head = self.get_head();
-        if cyl >= CYLINDERS || head >= HEADS {
+        if !self.cyl_head_valid() {
             if self.trace {
                 hdc_log!(self.trace_file, "HDC: SEEK failed — C={} H={} out of range", cyl, head);
             }
@@ -588,9 +1047,10 @@ impl HardDisk {
     fn cmd_read_sector(&mut self) {
         let off = self.get_off();
         let xfer_size = self.get_sector_size();
-        if off + xfer_size > DISK_SIZE {
+        if !self.sector_valid() {
             if self.trace {
-                hdc_log!(self.trace_file, "HDC: READ SECTOR failed — address out of range");
+                hdc_log!(self.trace_file, "HDC: READ SECTOR failed — C={} H={} S={} not on the drive",
+                    self.get_cyl(), self.get_head(), self.sector_number);
             }
             self.set_error(ERR_ID_NOT_FOUND);
             return;
@@ -608,8 +1068,29 @@ impl HardDisk {
             return;
         }
 
+        // Damaged sector: the command ends without transferring data.
+        // READ LONG returns the data field with its ECC bytes unchecked.
+        let fault = self.sector_fault();
+        let mut err = self.id_field_error();
+        if err == 0 && fault & FAULT_NO_DAM != 0 {
+            err = ERR_DAM_NOT_FOUND;
+        }
+        if err == 0 && fault & FAULT_DATA_CRC != 0 && self.cur_cmd & CMD_LONG == 0 {
+            err = ERR_UNCORRECTABLE;
+        }
+        if err != 0 {
+            if self.trace {
+                hdc_log!(self.trace_file, "HDC: READ SECTOR failed — C={} H={} S={} damaged (error 0x{:02X})",
+                    self.get_cyl(), self.get_head(), self.sector_number, err);
+            }
+            self.set_error(err);
+            return;
+        }
+
         // Copy sector data into transfer buffer using the full SDH sector size.
-        self.data_buf[..xfer_size].copy_from_slice(&self.disk_data[off..off + xfer_size]);
+        if let Some(unit) = &self.units[self.get_lun() as usize] {
+            self.data_buf[..xfer_size].copy_from_slice(&unit.disk_data[off..off + xfer_size]);
+        }
         self.data_length = xfer_size;
 
         // LONG mode: append 4 fake ECC bytes
@@ -643,10 +1124,10 @@ impl HardDisk {
     /// Bit 1 (L): long (expect 4 extra ECC bytes)
     fn cmd_write_sector(&mut self) {
         self.wr_off = self.get_off();
-        let sector_size = self.get_sector_size();
-        if self.wr_off + sector_size > DISK_SIZE {
+        if !self.sector_valid() {
             if self.trace {
-                hdc_log!(self.trace_file, "HDC: WRITE SECTOR failed — address out of range");
+                hdc_log!(self.trace_file, "HDC: WRITE SECTOR failed — C={} H={} S={} not on the drive",
+                    self.get_cyl(), self.get_head(), self.sector_number);
             }
             self.set_error(ERR_ID_NOT_FOUND);
             return;
@@ -661,6 +1142,9 @@ impl HardDisk {
             self.set_error(ERR_ID_NOT_FOUND);
             return;
         }
+        if !self.check_write_faults() {
+            return;
+        }
 
         // Transfer size comes from SDH bits 6:5 (256 or 512 for Kaypro)
         self.data_length = self.get_sector_size();
@@ -677,6 +1161,30 @@ impl HardDisk {
         }
     }
 
+    /// Fail a WRITE SECTOR of a sector with a bad ID field or a drive write
+    /// fault. Returns true if the sector can be written.
+    fn check_write_faults(&mut self) -> bool {
+        let err = self.id_field_error();
+        if err != 0 {
+            if self.trace {
+                hdc_log!(self.trace_file, "HDC: WRITE SECTOR failed — C={} H={} S={} damaged (error 0x{:02X})",
+                    self.get_cyl(), self.get_head(), self.sector_number, err);
+            }
+            self.set_error(err);
+            return false;
+        }
+        if self.sector_fault() & FAULT_WRITE != 0 {
+            if self.trace {
+                hdc_log!(self.trace_file, "HDC: WRITE SECTOR failed — write fault at C={} H={} S={}",
+                    self.get_cyl(), self.get_head(), self.sector_number);
+            }
+            self.status |= STS_WRITE_FAULT;
+            self.set_error(ERR_ABORTED);
+            return false;
+        }
+        true
+    }
+
     /// Extract sector size from SDH register bits 6:5.
     /// Per WD1002-05 spec: 00=256, 01=512, 10=1024, 11=128.
     fn get_sector_size(&self) -> usize {
@@ -697,7 +1205,7 @@ impl HardDisk {
         // Validate cylinder/head (sector number is not relevant for FORMAT)
         let cyl = self.get_cyl();
         let head = self.get_head();
-        if cyl >= CYLINDERS || head >= HEADS {
+        if !self.cyl_head_valid() {
             if self.trace {
                 hdc_log!(self.trace_file, "HDC: FORMAT failed — C={} H={} out of range", cyl, head);
             }
@@ -780,9 +1288,18 @@ impl HardDisk {
                 let sector_size = self.get_sector_size();
                 let write_len = sector_size;
                 let off = self.wr_off;
-                if off + write_len <= DISK_SIZE {
-                    self.disk_data[off..off + write_len]
+                let lun = self.get_lun() as usize;
+                let key = (self.get_cyl(), self.get_head(), self.sector_number);
+                if let Some(unit) = self.units[lun].as_mut().filter(|unit| off + write_len <= unit.disk_data.len()) {
+                    unit.journal.record(&unit.disk_data, off, write_len);
+                    unit.disk_data[off..off + write_len]
                         .copy_from_slice(&self.data_buf[..write_len]);
+                    // A freshly written data field has its mark and a good ECC
+                    if let Some(fault) = unit.faults.get_mut(&key) {
+                        *fault &= !(FAULT_DATA_CRC | FAULT_NO_DAM);
+                    }
+                    // Persist to backing file
+                    unit.persist(off, write_len);
                     if self.trace {
                         hdc_log!(self.trace_file, "HDC: WRITE SECTOR committed {} bytes at offset 0x{:X}",
                             write_len, off);
@@ -792,12 +1309,6 @@ impl HardDisk {
                             .collect::<Vec<_>>().join(" ");
                         hdc_log!(self.trace_file, "HDC: WRITE data[0..{}]: {}", preview_len, preview);
                     }
-                    // Persist to backing file
-                    if let Some(ref mut f) = self.file {
-                        if f.seek(SeekFrom::Start(off as u64)).is_ok() {
-                            let _ = f.write_all(&self.disk_data[off..off + write_len]);
-                        }
-                    }
                 }
 
                 self.data_ix = 0;
@@ -805,6 +1316,9 @@ impl HardDisk {
                     self.sector_count -= 1;
                     self.advance_sector();
                     if self.sector_count > 0 {
+                        if !self.check_write_faults() {
+                            return;
+                        }
                         // Re-latch offset for next sector and request more data
                         self.wr_off = self.get_off();
                         self.status |= STS_DRQ;
@@ -820,23 +1334,43 @@ impl HardDisk {
                 // Mark the track as formatted so subsequent READ/WRITE
                 // commands succeed.
                 let track_idx = self.get_track_index();
-                if track_idx < self.track_formatted.len() {
-                    self.track_formatted[track_idx] = true;
-                }
-                // Fill formatted track data with 0xE5 (standard CP/M blank fill).
-                // This persists the "formatted" state to the image file so that
-                // detect_formatted_tracks correctly identifies it on reload.
-                let off = track_idx * TRACK_SIZE;
-                if off + TRACK_SIZE <= self.disk_data.len() {
-                    self.disk_data[off..off + TRACK_SIZE].fill(0xE5);
-                    if let Some(ref mut f) = self.file {
-                        if f.seek(SeekFrom::Start(off as u64)).is_ok() {
-                            let _ = f.write_all(&self.disk_data[off..off + TRACK_SIZE]);
+                let lun = self.get_lun() as usize;
+                let (cyl, head) = (self.get_cyl(), self.get_head());
+                let sectors = (self.sector_count as usize).min(self.data_length / 2);
+                let mut formatted_count = 0;
+                if let Some(unit) = self.units[lun].as_mut() {
+                    if track_idx < unit.track_formatted.len() {
+                        unit.track_formatted[track_idx] = true;
+                    }
+                    // New ID fields and data fields replace every sector of
+                    // the track, including IDs the table leaves out; a bad
+                    // block flag of 0x80 marks the sector bad. Drive write
+                    // faults remain.
+                    for (&(c, h, _), fault) in unit.faults.iter_mut() {
+                        if (c, h) == (cyl, head) {
+                            *fault &= FAULT_WRITE;
                         }
                     }
+                    unit.faults.retain(|_, fault| *fault != 0);
+                    for entry in self.data_buf[..sectors * 2].chunks(2) {
+                        if entry[0] & 0x80 != 0 {
+                            *unit.faults.entry((cyl, head, entry[1])).or_insert(0) |= FAULT_BAD_BLOCK;
+                        }
+                    }
+                    // Fill formatted track data with 0xE5 (standard CP/M blank fill).
+                    // This persists the "formatted" state to the image file so that
+                    // detect_formatted_tracks correctly identifies it on reload.
+                    let track_size = unit.geometry.track_size();
+                    let off = track_idx * track_size;
+                    if off + track_size <= unit.disk_data.len() {
+                        unit.journal.record(&unit.disk_data, off, track_size);
+                        unit.disk_data[off..off + track_size].fill(0xE5);
+                        unit.persist(off, track_size);
+                    }
+                    unit.persist_metadata();
+                    formatted_count = unit.track_formatted.iter().filter(|&&f| f).count();
                 }
                 if self.trace {
-                    let formatted_count = self.track_formatted.iter().filter(|&&f| f).count();
                     hdc_log!(self.trace_file, "HDC: FORMAT TRACK complete — C={}, H={}, SDH=0x{:02X}, track_idx={}, total_formatted={}",
                         self.get_cyl(), self.get_head(), self.sdh, track_idx, formatted_count);
                 }
@@ -0,0 +1,93 @@
+// Winchester drive geometries for the WD1002-05.
+//
+// The controller addresses a drive by cylinder, head and sector; how many
+// of each there are depends on the drive.  The WD1002-05 records 17
+// 512-byte sectors per track at the ST-506 data rate, takes up to 8 heads
+// (3 head bits in SDH) and up to 1024 cylinders (10 cylinder bits).
+//
+// A geometry is written as a drive model ("st506", "st412", "st225") or
+// as "<cylinders>/<heads>/<sectors>", e.g. "612/4/17".
+
+pub const SECTOR_SIZE: usize = 512;
+
+const MAX_CYLINDERS: u16 = 1024;
+const MAX_HEADS: u8 = 8;
+const MAX_SECTORS: u8 = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HdGeometry {
+    pub name: &'static str,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+}
+
+/// Seagate ST-506, 5MB
+pub const ST506: HdGeometry = HdGeometry { name: "ST-506", cylinders: 153, heads: 4, sectors_per_track: 17 };
+/// Seagate ST-412, 10MB: the Kaypro 10 drive
+pub const ST412: HdGeometry = HdGeometry { name: "ST-412", cylinders: 306, heads: 4, sectors_per_track: 17 };
+/// Seagate ST-225, 20MB
+pub const ST225: HdGeometry = HdGeometry { name: "ST-225", cylinders: 615, heads: 4, sectors_per_track: 17 };
+
+const PROFILES: [(&str, HdGeometry); 3] = [("st506", ST506), ("st412", ST412), ("st225", ST225)];
+
+impl HdGeometry {
+    pub fn parse(spec: &str) -> Result<HdGeometry, String> {
+        let spec = spec.trim().to_ascii_lowercase();
+        if let Some(&(_, geometry)) = PROFILES.iter().find(|(name, _)| *name == spec) {
+            return Ok(geometry);
+        }
+        let fields: Vec<&str> = spec.split('/').collect();
+        let numbers: Vec<u16> = fields.iter().filter_map(|f| f.trim().parse().ok()).collect();
+        if fields.len() != 3 || numbers.len() != 3 {
+            return Err(format!("Hard disk geometry '{}': expected st506, st412, st225 or cylinders/heads/sectors", spec));
+        }
+        HdGeometry::from_dimensions(numbers[0], numbers[1], numbers[2])
+            .map_err(|e| format!("Hard disk geometry '{}': {}", spec, e))
+    }
+
+    /// Geometry with the given dimensions, named after the drive model if
+    /// one matches.
+    pub fn from_dimensions(cylinders: u16, heads: u16, sectors: u16) -> Result<HdGeometry, String> {
+        if cylinders == 0 || cylinders > MAX_CYLINDERS {
+            return Err(format!("1 to {} cylinders", MAX_CYLINDERS));
+        }
+        if heads == 0 || heads > MAX_HEADS as u16 {
+            return Err(format!("1 to {} heads", MAX_HEADS));
+        }
+        if sectors == 0 || sectors > MAX_SECTORS as u16 {
+            return Err(format!("1 to {} sectors per track", MAX_SECTORS));
+        }
+        let geometry = HdGeometry {
+            name: "custom",
+            cylinders,
+            heads: heads as u8,
+            sectors_per_track: sectors as u8,
+        };
+        Ok(PROFILES.iter().map(|&(_, g)| g)
+            .find(|g| (g.cylinders, g.heads, g.sectors_per_track) == (cylinders, heads as u8, sectors as u8))
+            .unwrap_or(geometry))
+    }
+
+    /// Drive model whose image is `size` bytes, if there is one.
+    pub fn for_image_size(size: usize) -> Option<HdGeometry> {
+        PROFILES.iter().map(|&(_, g)| g).find(|g| g.size() == size)
+    }
+
+    pub fn label(&self) -> String {
+        format!("{} ({}/{}/{}, {:.1}MB)", self.name, self.cylinders, self.heads,
+            self.sectors_per_track, self.size() as f64 / 1_000_000.0)
+    }
+
+    pub fn tracks(&self) -> usize {
+        self.cylinders as usize * self.heads as usize
+    }
+
+    pub fn track_size(&self) -> usize {
+        self.sectors_per_track as usize * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        self.tracks() * self.track_size()
+    }
+}
@@ -0,0 +1,102 @@
+#[cfg(test)]
+mod tests {
+    use crate::hard_disk::HardDisk;
+    use crate::hd_drive;
+    use crate::media;
+    use crate::test_support::{hd_command, temp_path};
+
+    /// ST-412 drive with the fault map `faults`.
+    fn hd_with_faults(name: &str, faults: &str) -> HardDisk {
+        let path = temp_path(&format!("hdfault_{}.hd", name));
+        std::fs::write(&path, vec![0xE5; hd_drive::ST412.size()]).unwrap();
+        std::fs::write(media::fault_map_path(&path), faults).unwrap();
+        let mut hd = HardDisk::new(false);
+        hd.load_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(media::fault_map_path(&path)).unwrap();
+        hd
+    }
+
+    fn read(hd: &mut HardDisk, sector: u8) -> u8 {
+        hd_command(hd, 0x20, 1, sector, &mut [0; 512])
+    }
+
+    fn write(hd: &mut HardDisk, sector: u8) -> u8 {
+        hd_command(hd, 0x30, 1, sector, &mut [0x55; 512])
+    }
+
+    /// FORMAT TRACK with sectors 0-16 in order, `bad` flagged as bad blocks.
+    fn format(hd: &mut HardDisk, bad: &[u8]) -> u8 {
+        let mut table = vec![0; 512];
+        for sector in 0..17u8 {
+            let flag = if bad.contains(&sector) { 0x80 } else { 0x00 };
+            table[sector as usize * 2] = flag;
+            table[sector as usize * 2 + 1] = sector;
+        }
+        hd_command(hd, 0x50, 1, 0, &mut table)
+    }
+
+    #[test]
+    fn test_fault_map_errors() {
+        let mut hd = hd_with_faults("map", "\
+            # cylinder head sector faults\n\
+            2 1 3 crc\n\
+            2 1 4 dam\n\
+            2 1 5 idcrc\n\
+            2 1 6 bad\n\
+            2 1 7 wfault\n");
+        assert_eq!(read(&mut hd, 2), 0);
+        assert_eq!(read(&mut hd, 3), 0x40);
+        assert_eq!(hd_command(&mut hd, 0x22, 1, 3, &mut [0; 516]), 0); // READ LONG skips the ECC check
+        assert_eq!(read(&mut hd, 4), 0x01);
+        assert_eq!(read(&mut hd, 5), 0x30);
+        assert_eq!(read(&mut hd, 6), 0x80);
+        assert_eq!(write(&mut hd, 6), 0x80);
+        assert_eq!(read(&mut hd, 7), 0);
+        assert_eq!(write(&mut hd, 7), 0x04);
+        assert_eq!(hd.read_register(0x87) & 0x20, 0x20);
+        // The next command clears the write fault
+        assert_eq!(read(&mut hd, 7), 0);
+        assert_eq!(hd.read_register(0x87) & 0x20, 0);
+
+        // Rewriting a sector gives it a good data field
+        assert_eq!(write(&mut hd, 3), 0);
+        assert_eq!(read(&mut hd, 3), 0);
+        assert_eq!(write(&mut hd, 4), 0);
+        assert_eq!(read(&mut hd, 4), 0);
+        assert_eq!(read(&mut hd, 5), 0x30);
+    }
+
+    #[test]
+    fn test_format_bad_block_flags() {
+        let mut hd = hd_with_faults("format", "2 1 9 crc wfault\n");
+        assert_eq!(write(&mut hd, 5), 0);
+
+        assert_eq!(format(&mut hd, &[5]), 0);
+        assert_eq!(read(&mut hd, 5), 0x80);
+        assert_eq!(write(&mut hd, 5), 0x80);
+        // Formatting replaces the data field but the drive still faults
+        assert_eq!(read(&mut hd, 9), 0);
+        assert_eq!(write(&mut hd, 9), 0x04);
+
+        // Reformatted without the flag, the sector is good again
+        assert_eq!(format(&mut hd, &[]), 0);
+        assert_eq!(read(&mut hd, 5), 0);
+        assert_eq!(write(&mut hd, 5), 0);
+    }
+
+    #[test]
+    fn test_format_clears_faults_of_the_track() {
+        let mut hd = hd_with_faults("reformat", "2 1 3 crc bad\n2 1 4 idcrc\n2 0 3 crc\n");
+        // Sector ID 3 is left out of the new interleave table
+        let mut table = vec![0; 512];
+        for (slot, id) in (0..18u8).filter(|&id| id != 3).enumerate() {
+            table[slot * 2 + 1] = id;
+        }
+        assert_eq!(hd_command(&mut hd, 0x50, 1, 0, &mut table), 0);
+        assert_eq!(read(&mut hd, 3), 0);
+        assert_eq!(read(&mut hd, 4), 0);
+        // Other tracks keep their faults
+        assert_eq!(hd_command(&mut hd, 0x20, 0, 3, &mut [0; 512]), 0x40);
+    }
+}
@@ -0,0 +1,82 @@
+#[cfg(test)]
+mod tests {
+    use crate::hard_disk::HardDisk;
+    use crate::hd_drive::{self, HdGeometry};
+    use crate::test_support::temp_path;
+
+    /// Drive with a formatted image of `size` bytes.
+    fn hd_with_image(name: &str, geometry: HdGeometry, size: usize) -> HardDisk {
+        let path = temp_path(&format!("hdgeom_{}.hd", name));
+        std::fs::write(&path, vec![0xE5; size]).unwrap();
+        let mut hd = HardDisk::new(false);
+        hd.set_geometry(geometry);
+        hd.load_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        hd
+    }
+
+    /// Run `command` on C/H/S with 512-byte sectors; returns the error
+    /// register, reading the sector data if there is any.
+    fn command(hd: &mut HardDisk, command: u8, cyl: u16, head: u8, sector: u8) -> u8 {
+        hd.write_register(0x86, 0x28 | head); // 512-byte sectors, LUN 1
+        hd.write_register(0x84, cyl as u8);
+        hd.write_register(0x85, (cyl >> 8) as u8);
+        hd.write_register(0x83, sector);
+        hd.write_register(0x82, 1);
+        hd.write_register(0x87, command);
+        if hd.read_register(0x87) & 0x08 != 0 {
+            for _ in 0..512 {
+                hd.read_register(0x80);
+            }
+        }
+        if hd.read_register(0x87) & 0x01 != 0 { hd.read_register(0x81) } else { 0 }
+    }
+
+    #[test]
+    fn test_parse_hd_geometry() {
+        assert_eq!(HdGeometry::parse("ST506").unwrap(), hd_drive::ST506);
+        assert_eq!(HdGeometry::parse("st225").unwrap().size(), 615 * 4 * 17 * 512);
+        let custom = HdGeometry::parse("612/4/17").unwrap();
+        assert_eq!((custom.cylinders, custom.heads, custom.sectors_per_track), (612, 4, 17));
+        assert!(HdGeometry::parse("st251").is_err());
+        assert!(HdGeometry::parse("306/4").is_err());
+        assert!(HdGeometry::parse("2000/4/17").is_err());
+        assert!(HdGeometry::parse("306/9/17").is_err());
+        assert!(HdGeometry::parse("306/4/26").is_err());
+        assert_eq!(HdGeometry::for_image_size(hd_drive::ST412.size()), Some(hd_drive::ST412));
+    }
+
+    #[test]
+    fn test_load_image_checks_size() {
+        let path = temp_path("hdgeom_size.hd");
+        std::fs::write(&path, vec![0xE5; hd_drive::ST412.size()]).unwrap();
+        let mut hd = HardDisk::new(false);
+        hd.set_geometry(hd_drive::ST506);
+        let error = hd.load_image(&path).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.contains("ST-412"), "{}", error);
+
+        // A short image is padded with unformatted tracks
+        let track = hd_drive::ST506.track_size();
+        let mut hd = hd_with_image("short", hd_drive::ST506, 10 * track);
+        assert_eq!(hd.unit(1).unwrap().disk_data.len(), hd_drive::ST506.size());
+        assert_eq!(command(&mut hd, 0x20, 2, 1, 0), 0); // track 9
+        assert_eq!(command(&mut hd, 0x20, 2, 2, 0), 0x10); // track 10
+    }
+
+    #[test]
+    fn test_addresses_beyond_the_drive() {
+        let mut hd = hd_with_image("st506", hd_drive::ST506, hd_drive::ST506.size());
+        assert_eq!(command(&mut hd, 0x20, 152, 3, 16), 0);
+        assert_eq!(command(&mut hd, 0x70, 153, 0, 0), 0x10); // SEEK
+        assert_eq!(command(&mut hd, 0x20, 153, 0, 0), 0x10);
+        assert_eq!(command(&mut hd, 0x30, 10, 4, 0), 0x10);
+        assert_eq!(command(&mut hd, 0x20, 10, 0, 17), 0x10);
+        assert_eq!(command(&mut hd, 0x50, 153, 0, 0), 0x10); // FORMAT
+
+        let mut hd = hd_with_image("st225", hd_drive::ST225, hd_drive::ST225.size());
+        assert_eq!(command(&mut hd, 0x70, 600, 0, 0), 0);
+        assert_eq!(command(&mut hd, 0x20, 614, 3, 16), 0);
+        assert_eq!(command(&mut hd, 0x20, 615, 0, 0), 0x10);
+    }
+}
@@ -0,0 +1,197 @@
+// Self-describing hard disk image container (.khd).
+//
+// A raw hard disk image is just the sectors, so the drive it came from,
+// which tracks have been formatted and which sectors FORMAT TRACK flagged
+// bad are lost.  The container keeps them around the same sector data:
+//
+//   Header, one 512-byte sector:
+//     0   "IZKHD" CR LF 0x1A   signature
+//     8   version (1)
+//     9   heads
+//     10  sectors per track
+//     11  reserved
+//     12  cylinders (u16 LE)
+//     14  bad block count (u16 LE)
+//     16  model hint, 16 bytes of ASCII padded with NULs (e.g. "kaypro10")
+//   Sector data, cylinders × heads × sectors × 512 bytes, track by track
+//   Formatted track map, one bit per track, least significant bit first
+//   Bad blocks, 4 bytes each: cylinder (u16 LE), head, sector ID
+//
+// Under --overlay the formatted track map and the bad blocks go to the
+// overlay as its metadata: the bad block count (u16 LE) and the trailer.
+
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+
+use crate::hd_drive::HdGeometry;
+use crate::overlay::Overlay;
+
+const SIGNATURE: &[u8; 8] = b"IZKHD\r\n\x1A";
+const VERSION: u8 = 1;
+/// Offset of the sector data in the file
+pub const HEADER_SIZE: usize = 512;
+const MODEL_OFFSET: usize = 16;
+const MODEL_SIZE: usize = 16;
+const BAD_BLOCK_SIZE: usize = 4;
+
+/// File extension of containers: new images with it are created as one.
+pub const EXTENSION: &str = "khd";
+
+pub fn is_container(data: &[u8]) -> bool {
+    data.starts_with(SIGNATURE)
+}
+
+pub fn has_container_extension(path: &str) -> bool {
+    std::path::Path::new(path).extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(EXTENSION))
+}
+
+/// Everything a container records besides the sector data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdMetadata {
+    pub geometry: HdGeometry,
+    /// Model the image was made for, "" if unknown
+    pub model: String,
+    pub track_formatted: Vec<bool>,
+    /// (cylinder, head, sector ID) of the sectors with a bad block mark
+    pub bad_blocks: Vec<(u16, u8, u8)>,
+}
+
+impl HdMetadata {
+    /// A drive fresh from the factory: no track formatted.
+    pub fn new(geometry: HdGeometry, model: &str) -> HdMetadata {
+        HdMetadata {
+            geometry,
+            model: model.to_string(),
+            track_formatted: vec![false; geometry.tracks()],
+            bad_blocks: Vec::new(),
+        }
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(SIGNATURE);
+        header[8] = VERSION;
+        header[9] = self.geometry.heads;
+        header[10] = self.geometry.sectors_per_track;
+        header[12..14].copy_from_slice(&self.geometry.cylinders.to_le_bytes());
+        header[14..16].copy_from_slice(&(self.bad_blocks.len() as u16).to_le_bytes());
+        let model = self.model.as_bytes();
+        let len = model.len().min(MODEL_SIZE);
+        header[MODEL_OFFSET..MODEL_OFFSET + len].copy_from_slice(&model[..len]);
+        header
+    }
+
+    /// The formatted track map and bad block list that follow the data.
+    pub fn trailer(&self) -> Vec<u8> {
+        let mut trailer = encode_track_map(&self.track_formatted);
+        trailer.resize(self.geometry.tracks().div_ceil(8), 0);
+        for &(cylinder, head, sector) in &self.bad_blocks {
+            trailer.extend_from_slice(&cylinder.to_le_bytes());
+            trailer.extend_from_slice(&[head, sector]);
+        }
+        trailer
+    }
+}
+
+impl HdMetadata {
+    /// The formatted tracks and bad blocks, as kept in an overlay.
+    pub fn overlay_record(&self) -> Vec<u8> {
+        let mut record = (self.bad_blocks.len() as u16).to_le_bytes().to_vec();
+        record.extend_from_slice(&self.trailer());
+        record
+    }
+
+    /// Take the formatted tracks and bad blocks from an overlay record.
+    pub fn apply_overlay_record(&mut self, record: &[u8]) -> Result<()> {
+        let map_size = self.geometry.tracks().div_ceil(8);
+        let bad_count = match record {
+            [lo, hi, ..] => u16::from_le_bytes([*lo, *hi]) as usize,
+            _ => 0,
+        };
+        let Some(bad_blocks) = record.get(2 + map_size..2 + map_size + bad_count * BAD_BLOCK_SIZE) else {
+            return Err(Error::new(ErrorKind::InvalidData, "Hard disk overlay metadata is truncated"));
+        };
+        self.track_formatted = decode_track_map(&record[2..2 + map_size], self.geometry.tracks());
+        self.bad_blocks = bad_blocks.chunks_exact(BAD_BLOCK_SIZE)
+            .map(|b| (u16::from_le_bytes([b[0], b[1]]), b[2], b[3]))
+            .collect();
+        Ok(())
+    }
+}
+
+/// A formatted track map, one bit per track, least significant bit first.
+pub fn encode_track_map(track_formatted: &[bool]) -> Vec<u8> {
+    let mut map = vec![0u8; track_formatted.len().div_ceil(8)];
+    for (track, _) in track_formatted.iter().enumerate().filter(|(_, &f)| f) {
+        map[track / 8] |= 1 << (track % 8);
+    }
+    map
+}
+
+/// The first `tracks` tracks of a formatted track map; those missing from
+/// it are unformatted.
+pub fn decode_track_map(map: &[u8], tracks: usize) -> Vec<bool> {
+    (0..tracks)
+        .map(|track| map.get(track / 8).is_some_and(|&bits| bits & (1 << (track % 8)) != 0))
+        .collect()
+}
+
+/// Split a container into its metadata and sector data.
+pub fn parse(data: &[u8]) -> Result<(HdMetadata, &[u8])> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    if !is_container(data) || data.len() < HEADER_SIZE {
+        return Err(invalid("Not a hard disk container".to_string()));
+    }
+    if data[8] != VERSION {
+        return Err(invalid(format!("Hard disk container version {} is not supported", data[8])));
+    }
+    let cylinders = u16::from_le_bytes([data[12], data[13]]);
+    let geometry = HdGeometry::from_dimensions(cylinders, data[9] as u16, data[10] as u16)
+        .map_err(|e| invalid(format!("Hard disk container geometry: {}", e)))?;
+    let bad_count = u16::from_le_bytes([data[14], data[15]]) as usize;
+    let model = &data[MODEL_OFFSET..MODEL_OFFSET + MODEL_SIZE];
+    let model = String::from_utf8_lossy(&model[..model.iter().position(|&b| b == 0).unwrap_or(MODEL_SIZE)])
+        .into_owned();
+
+    let data_end = HEADER_SIZE + geometry.size();
+    let map_end = data_end + geometry.tracks().div_ceil(8);
+    if data.len() < map_end + bad_count * BAD_BLOCK_SIZE {
+        return Err(invalid(format!("Hard disk container is truncated ({} bytes, {})",
+            data.len(), geometry.label())));
+    }
+    let track_formatted = decode_track_map(&data[data_end..map_end], geometry.tracks());
+    let bad_blocks = data[map_end..map_end + bad_count * BAD_BLOCK_SIZE]
+        .chunks_exact(BAD_BLOCK_SIZE)
+        .map(|b| (u16::from_le_bytes([b[0], b[1]]), b[2], b[3]))
+        .collect();
+    let metadata = HdMetadata { geometry, model, track_formatted, bad_blocks };
+    Ok((metadata, &data[HEADER_SIZE..data_end]))
+}
+
+/// The complete container file for `data`.
+pub fn encode(metadata: &HdMetadata, data: &[u8]) -> Vec<u8> {
+    let mut file = metadata.header();
+    file.extend_from_slice(data);
+    file.extend_from_slice(&metadata.trailer());
+    file
+}
+
+/// Write the overlay of the container `path` into it: the sectors, then
+/// the formatted tracks and bad blocks.
+pub fn commit_overlay(path: &str, overlay: &Overlay) -> Result<()> {
+    overlay.commit_raw(path, HEADER_SIZE as u64)?;
+    let Some(record) = overlay.metadata() else {
+        return Ok(());
+    };
+    let data = std::fs::read(path)?;
+    let (mut metadata, sectors) = parse(&data)?;
+    metadata.apply_overlay_record(record)?;
+    let trailer_off = (HEADER_SIZE + sectors.len()) as u64;
+    let trailer = metadata.trailer();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&metadata.header())?;
+    file.seek(SeekFrom::Start(trailer_off))?;
+    file.write_all(&trailer)?;
+    file.set_len(trailer_off + trailer.len() as u64)
+}
@@ -0,0 +1,116 @@
+#[cfg(test)]
+mod tests {
+    use crate::hard_disk::HardDisk;
+    use crate::hd_drive::{self, HdGeometry};
+    use crate::hd_image::{self, HdMetadata};
+    use crate::overlay::{self, Overlay};
+    use crate::test_support::{hd_command, temp_path};
+
+    #[test]
+    fn test_container_round_trip() {
+        let geometry = HdGeometry::parse("20/2/17").unwrap();
+        let mut metadata = HdMetadata::new(geometry, "turbo_rom_hd");
+        metadata.track_formatted[0] = true;
+        metadata.track_formatted[9] = true;
+        metadata.bad_blocks = vec![(4, 1, 3), (19, 0, 16)];
+        let data: Vec<u8> = (0..geometry.size()).map(|i| i as u8).collect();
+        let file = hd_image::encode(&metadata, &data);
+        assert!(hd_image::is_container(&file));
+        assert!(!hd_image::is_container(&data));
+
+        let (parsed, sectors) = hd_image::parse(&file).unwrap();
+        assert_eq!(parsed, metadata);
+        assert_eq!(sectors, &data[..]);
+        // Drive models are recognized by their dimensions
+        let st225 = HdMetadata::new(HdGeometry::parse("615/4/17").unwrap(), "");
+        let file = hd_image::encode(&st225, &vec![0; hd_drive::ST225.size()]);
+        assert_eq!(hd_image::parse(&file).unwrap().0.geometry, hd_drive::ST225);
+        assert!(hd_image::parse(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_hard_disk_keeps_container_metadata() {
+        let path = temp_path("hdimage_meta.khd");
+        let mut hd = HardDisk::new(false);
+        hd.set_model("kaypro10");
+        hd.load_image(&path).unwrap();
+        assert_eq!(hd.unit(1).unwrap().model(), Some("kaypro10"));
+        assert_eq!(hd_command(&mut hd, 0x20, 1, 3, &mut [0; 512]), 0x10);
+
+        // FORMAT TRACK with sector 5 flagged bad, then write sector 3
+        let mut table = [0u8; 512];
+        for sector in 0..17 {
+            table[sector * 2] = if sector == 5 { 0x80 } else { 0x00 };
+            table[sector * 2 + 1] = sector as u8;
+        }
+        assert_eq!(hd_command(&mut hd, 0x50, 1, 0, &mut table), 0);
+        assert_eq!(hd_command(&mut hd, 0x30, 1, 3, &mut [0x33; 512]), 0);
+        drop(hd);
+
+        // The container has its own geometry, formatted tracks and bad blocks
+        for _ in 0..2 {
+            let mut hd = HardDisk::new(false);
+            hd.set_geometry(hd_drive::ST506);
+            hd.load_image(&path).unwrap();
+            let mut sector = [0u8; 512];
+            assert_eq!(hd_command(&mut hd, 0x20, 1, 3, &mut sector), 0);
+            assert_eq!(sector, [0x33; 512]);
+            assert_eq!(hd_command(&mut hd, 0x20, 1, 5, &mut sector), 0x80);
+            assert_eq!(hd_command(&mut hd, 0x20, 2, 3, &mut sector), 0x10);
+            hd.flush();
+        }
+        let (metadata, _) = hd_image::parse(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(metadata.geometry, hd_drive::ST412);
+        assert_eq!(metadata.bad_blocks, vec![(2, 1, 5)]);
+        assert_eq!(metadata.track_formatted.iter().filter(|&&f| f).count(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_container_overlay_keeps_metadata() {
+        let path = temp_path("hdimage_overlay.khd");
+        let mut hd = HardDisk::new(false);
+        hd.load_image(&path).unwrap();
+        drop(hd);
+        let base = std::fs::read(&path).unwrap();
+
+        let mut hd = HardDisk::new(false);
+        hd.use_overlay = true;
+        hd.load_image(&path).unwrap();
+        let mut table = [0u8; 512];
+        for sector in 0..17 {
+            table[sector * 2] = if sector == 5 { 0x80 } else { 0x00 };
+            table[sector * 2 + 1] = sector as u8;
+        }
+        assert_eq!(hd_command(&mut hd, 0x50, 1, 0, &mut table), 0);
+        assert_eq!(hd_command(&mut hd, 0x30, 1, 3, &mut [0x33; 512]), 0);
+        hd.flush();
+        drop(hd);
+        assert!(std::fs::read(&path).unwrap() == base);
+
+        // The overlay brings back the formatted track and the bad block,
+        // and commit writes them into the container
+        let check = |hd: &mut HardDisk| {
+            let mut sector = [0u8; 512];
+            assert_eq!(hd_command(hd, 0x20, 1, 3, &mut sector), 0);
+            assert_eq!(sector, [0x33; 512]);
+            assert_eq!(hd_command(hd, 0x20, 1, 5, &mut sector), 0x80);
+            assert_eq!(hd_command(hd, 0x20, 2, 3, &mut sector), 0x10);
+        };
+        let mut hd = HardDisk::new(false);
+        hd.use_overlay = true;
+        hd.load_image(&path).unwrap();
+        check(&mut hd);
+        let mut delta = Overlay::open(&path, 512).unwrap();
+        hd_image::commit_overlay(&path, &delta).unwrap();
+        delta.discard().unwrap();
+        let mut hd = HardDisk::new(false);
+        hd.load_image(&path).unwrap();
+        check(&mut hd);
+        let (metadata, _) = hd_image::parse(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(overlay::sidecar_path(&path));
+        assert_eq!(metadata.bad_blocks, vec![(2, 1, 5)]);
+        assert_eq!(metadata.track_formatted.iter().filter(|&&f| f).count(), 1);
+    }
+}
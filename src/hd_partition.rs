@@ -0,0 +1,232 @@
+// CP/M partitions of Kaypro 10 and TurboROM hard disk images.
+//
+// Both BIOSes use 68-record logical tracks, one 17-sector physical track
+// each, but place them on the drive differently:
+//
+// Kaypro 10 (81-478 ROM): drive A is on heads 0-1 and drive B on heads 2-3.
+// Logical track t of drive d is head 2d + (t & 1) of cylinder t/2 + 6,
+// except that A's two system tracks are on cylinder 0.  The rest of
+// cylinders 0-6 holds the 24 spare tracks of the bad track map.  Both
+// drives use the DPB in the ROM: 4K blocks, 1024 directory entries and
+// two reserved tracks.
+//
+// TurboROM: the last sector of track 0 (byte 8192 of the image) is the
+// Advent parameter sector.  Its 512 bytes sum to zero and start with
+// "ADV"; byte 8 is the number of partitions, followed by an 18-byte entry
+// for each with the DPB at offset 3.  Tracks are numbered from the start
+// of the drive (cylinder × heads + head), so OFF places the partition.
+// Byte 0x87 counts the bad tracks the BIOS slips over; images with bad
+// tracks are refused.
+//
+// Images can be raw or hard disk containers (see hd_image), which bring
+// their own geometry.
+
+use std::io::{Error, ErrorKind, Result};
+
+use crate::cpm_fs::{CpmFs, CpmVolume, Dpb, RECORD_SIZE};
+use crate::hd_drive::{HdGeometry, SECTOR_SIZE};
+use crate::hd_image::{self, HdMetadata};
+
+const PARAMETER_SECTOR: usize = 16 * SECTOR_SIZE;
+const PARAMETER_SIGNATURE: &[u8] = b"ADV";
+const PARTITION_COUNT: usize = 8;
+const PARTITION_ENTRIES: usize = 9;
+const PARTITION_ENTRY_SIZE: usize = 18;
+const BAD_TRACK_COUNT: usize = 0x87;
+
+/// Kaypro 10 ROM DPB, for both drives
+const KAYPRO10_DPB: Dpb = Dpb {
+    spt: 68,
+    bsh: 5,
+    blm: 31,
+    exm: 1,
+    dsm: 1125,
+    drm: 1023,
+    al0: 0xFF,
+    al1: 0x00,
+    off: 2,
+};
+/// First cylinder of the Kaypro 10 data tracks
+const KAYPRO10_FIRST_CYLINDER: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HdLayout {
+    Kaypro10,
+    TurboRom,
+}
+
+impl HdLayout {
+    /// TurboROM if the image has an Advent parameter sector.
+    pub fn detect(data: &[u8]) -> HdLayout {
+        if parameter_sector(data).is_some() { HdLayout::TurboRom } else { HdLayout::Kaypro10 }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HdLayout::Kaypro10 => "Kaypro 10",
+            HdLayout::TurboRom => "TurboROM",
+        }
+    }
+}
+
+fn parameter_sector(data: &[u8]) -> Option<&[u8]> {
+    let sector = data.get(PARAMETER_SECTOR..PARAMETER_SECTOR + SECTOR_SIZE)?;
+    let sum = sector.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    (sum == 0 && sector.starts_with(PARAMETER_SIGNATURE)).then_some(sector)
+}
+
+/// The DPBs of the partitions on `data`, drive A first.
+pub fn partitions(data: &[u8], layout: HdLayout, geometry: &HdGeometry) -> Result<Vec<Dpb>> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    let dpbs = match layout {
+        HdLayout::Kaypro10 => {
+            if geometry.heads != 4 {
+                return Err(invalid(format!("The Kaypro 10 layout needs a 4-head drive, not {}",
+                    geometry.label())));
+            }
+            vec![KAYPRO10_DPB; 2]
+        }
+        HdLayout::TurboRom => {
+            let sector = parameter_sector(data).ok_or_else(||
+                invalid("No TurboROM parameter sector on track 0".to_string()))?;
+            if sector[BAD_TRACK_COUNT] != 0 {
+                return Err(invalid(format!("{} bad tracks are mapped out, which is not supported",
+                    sector[BAD_TRACK_COUNT])));
+            }
+            let count = sector[PARTITION_COUNT] as usize;
+            if PARTITION_ENTRIES + count * PARTITION_ENTRY_SIZE > BAD_TRACK_COUNT {
+                return Err(invalid(format!("Parameter sector lists {} partitions", count)));
+            }
+            (0..count)
+                .map(|i| Dpb::from_bytes(&sector[PARTITION_ENTRIES + i * PARTITION_ENTRY_SIZE + 3..]))
+                .collect()
+        }
+    };
+    for (i, dpb) in dpbs.iter().enumerate() {
+        let records = dpb.blocks() * dpb.records_per_block();
+        let tracks = dpb.off as usize + records.div_ceil(dpb.spt.max(1) as usize);
+        let available = match layout {
+            HdLayout::Kaypro10 => (geometry.cylinders as usize - KAYPRO10_FIRST_CYLINDER) * 2,
+            HdLayout::TurboRom => geometry.tracks(),
+        };
+        if dpb.spt == 0 || dpb.spt as usize * RECORD_SIZE > geometry.track_size() || tracks > available {
+            return Err(invalid(format!("Partition {} does not fit on the {} drive",
+                partition_letter(i), geometry.label())));
+        }
+    }
+    Ok(dpbs)
+}
+
+pub fn partition_letter(index: usize) -> char {
+    (b'A' + index as u8) as char
+}
+
+/// One partition of a hard disk image viewed as CP/M logical tracks.
+pub struct HdVolume {
+    pub path: String,
+    pub layout: HdLayout,
+    pub partition: usize,
+    pub geometry: HdGeometry,
+    data: Vec<u8>,
+    /// Container metadata, None for a raw image
+    metadata: Option<HdMetadata>,
+}
+
+impl HdVolume {
+    /// Image offset of a logical record.
+    fn record_index(&self, track: usize, record: usize) -> Result<usize> {
+        let physical = match self.layout {
+            HdLayout::Kaypro10 => {
+                let cylinder = if self.partition == 0 && track < 2 {
+                    0
+                } else {
+                    track / 2 + KAYPRO10_FIRST_CYLINDER
+                };
+                cylinder * self.geometry.heads as usize + 2 * self.partition + (track & 1)
+            }
+            HdLayout::TurboRom => track,
+        };
+        let index = physical * self.geometry.track_size() + record * RECORD_SIZE;
+        if physical >= self.geometry.tracks() || index + RECORD_SIZE > self.data.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                format!("Track {} record {} is outside the drive", track, record)));
+        }
+        Ok(index)
+    }
+}
+
+impl CpmVolume for HdVolume {
+    fn read_record(&self, track: usize, record: usize, buf: &mut [u8; RECORD_SIZE]) -> Result<()> {
+        let index = self.record_index(track, record)?;
+        buf.copy_from_slice(&self.data[index..index + RECORD_SIZE]);
+        Ok(())
+    }
+
+    fn write_record(&mut self, track: usize, record: usize, buf: &[u8; RECORD_SIZE]) -> Result<()> {
+        let index = self.record_index(track, record)?;
+        self.data[index..index + RECORD_SIZE].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Read a raw hard disk image, padded to the size of the drive like the
+/// controller does.
+pub fn read_image(path: &str, geometry: &HdGeometry) -> Result<Vec<u8>> {
+    let mut data = std::fs::read(path)?;
+    if data.len() > geometry.size() {
+        return Err(Error::new(ErrorKind::InvalidData,
+            format!("{} is larger than the {} drive", path, geometry.label())));
+    }
+    data.resize(geometry.size(), 0);
+    Ok(data)
+}
+
+/// Read a raw image of a `geometry` drive or a container, with the
+/// container metadata.
+fn read_any_image(path: &str, geometry: &HdGeometry) -> Result<(Vec<u8>, Option<HdMetadata>)> {
+    let data = std::fs::read(path)?;
+    if hd_image::is_container(&data) {
+        let (metadata, sectors) = hd_image::parse(&data)?;
+        return Ok((sectors.to_vec(), Some(metadata)));
+    }
+    Ok((read_image(path, geometry)?, None))
+}
+
+/// Open every partition of an image as a CP/M filesystem, drive A first.
+/// `geometry` is the drive of a raw image; containers bring their own.
+pub fn open_partitions(path: &str, geometry: HdGeometry) -> Result<Vec<CpmFs<HdVolume>>> {
+    let (data, metadata) = read_any_image(path, &geometry)?;
+    let geometry = metadata.as_ref().map_or(geometry, |m| m.geometry);
+    let layout = HdLayout::detect(&data);
+    partitions(&data, layout, &geometry)?.into_iter().enumerate()
+        .map(|(partition, dpb)| {
+            let volume = HdVolume {
+                path: path.to_string(),
+                layout,
+                partition,
+                geometry,
+                data: data.clone(),
+                metadata: metadata.clone(),
+            };
+            CpmFs::new(volume, dpb)
+        })
+        .collect()
+}
+
+/// Open partition `partition` (0 for drive A) of an image.
+pub fn open_partition(path: &str, geometry: HdGeometry, partition: usize) -> Result<CpmFs<HdVolume>> {
+    let mut all = open_partitions(path, geometry)?;
+    if partition >= all.len() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} has no partition {} ({} partitions)",
+            path, partition_letter(partition), all.len())));
+    }
+    Ok(all.swap_remove(partition))
+}
+
+/// Save the image after modifying a partition.
+pub fn flush_partition(fs: &CpmFs<HdVolume>) -> Result<()> {
+    match fs.volume.metadata {
+        Some(ref metadata) => std::fs::write(&fs.volume.path, hd_image::encode(metadata, &fs.volume.data)),
+        None => std::fs::write(&fs.volume.path, &fs.volume.data),
+    }
+}
@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod tests {
+    use crate::cpm_fs::CpmName;
+    use crate::hd_drive;
+    use crate::hd_partition::{self, HdLayout};
+    use crate::test_support::temp_path;
+
+    /// Formatted ST-412 image with TurboROM partitions at tracks 4 and 612.
+    fn turbo_rom_image() -> Vec<u8> {
+        let mut data = vec![0xE5; hd_drive::ST412.size()];
+        let sector = &mut data[8192..8192 + 512];
+        sector.fill(0);
+        sector[..3].copy_from_slice(b"ADV");
+        sector[8] = 2;
+        for (i, off) in [4u16, 612].iter().enumerate() {
+            // 68 records, 4K blocks, 1126 blocks, 1024 directory entries
+            let dpb = [68, 0, 5, 31, 1, 0x65, 0x04, 0xFF, 0x03, 0xFF, 0x00, 0, 0, *off as u8, (off >> 8) as u8];
+            sector[9 + i * 18 + 3..9 + i * 18 + 18].copy_from_slice(&dpb);
+        }
+        let sum = sector.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        sector[511] = sum.wrapping_neg();
+        data
+    }
+
+    #[test]
+    fn test_kaypro10_partitions() {
+        let path = temp_path("hdpart_k10.hd");
+        std::fs::write(&path, vec![0xE5; hd_drive::ST412.size()]).unwrap();
+        let mut fs = hd_partition::open_partition(&path, hd_drive::ST412, 1).unwrap();
+        assert_eq!(fs.volume.layout, HdLayout::Kaypro10);
+        let name = CpmName::parse("3:hello.txt").unwrap();
+        fs.write_file(&name, b"Hello").unwrap();
+        hd_partition::flush_partition(&fs).unwrap();
+
+        // Drive B's directory starts on track 2: cylinder 7, head 2
+        let image = std::fs::read(&path).unwrap();
+        let directory = (7 * 4 + 2) * 17 * 512;
+        assert_eq!(image[directory], 3);
+        assert_eq!(&image[directory + 1..directory + 9], b"HELLO   ");
+
+        let partitions = hd_partition::open_partitions(&path, hd_drive::ST412).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert!(partitions[0].list().is_empty());
+        assert_eq!(partitions[1].read_file(&name).unwrap()[..5], *b"Hello");
+    }
+
+    #[test]
+    fn test_turbo_rom_partitions() {
+        let path = temp_path("hdpart_trom.hd");
+        std::fs::write(&path, turbo_rom_image()).unwrap();
+        let mut fs = hd_partition::open_partition(&path, hd_drive::ST412, 1).unwrap();
+        assert_eq!(fs.volume.layout, HdLayout::TurboRom);
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let name = CpmName::parse("big.dat").unwrap();
+        fs.write_file(&name, &data).unwrap();
+        hd_partition::flush_partition(&fs).unwrap();
+
+        let image = std::fs::read(&path).unwrap();
+        assert_eq!(&image[612 * 8704 + 1..612 * 8704 + 9], b"BIG     ");
+        let partitions = hd_partition::open_partitions(&path, hd_drive::ST412).unwrap();
+        assert!(partitions[0].list().is_empty());
+        assert_eq!(partitions[1].read_file(&name).unwrap()[..data.len()], data[..]);
+        assert!(hd_partition::open_partition(&path, hd_drive::ST412, 2).is_err());
+
+        // A partition past the end of the drive is refused
+        let mut image = turbo_rom_image();
+        image[8192 + 9 + 18 + 3 + 14] = 0x04;
+        image[8192 + 511] = image[8192 + 511].wrapping_sub(0x02);
+        std::fs::write(&path, image).unwrap();
+        assert!(hd_partition::open_partitions(&path, hd_drive::ST412).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
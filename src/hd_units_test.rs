@@ -0,0 +1,97 @@
+#[cfg(test)]
+mod tests {
+    use crate::hard_disk::HardDisk;
+    use crate::hd_drive;
+    use crate::test_support::temp_path;
+
+    /// Select `lun`, head 0, cylinder 0 and `sector` with 512-byte sectors.
+    fn select(hd: &mut HardDisk, lun: u8, sector: u8) -> bool {
+        hd.write_register(0x86, 0x20 | (lun << 3));
+        hd.write_register(0x84, 0);
+        hd.write_register(0x85, 0);
+        hd.write_register(0x83, sector);
+        hd.write_register(0x82, 1);
+        hd.read_register(0x87) & 0x40 != 0
+    }
+
+    /// Run `command`; returns the error register.
+    fn run(hd: &mut HardDisk, command: u8) -> u8 {
+        hd.write_register(0x87, command);
+        if hd.read_register(0x87) & 0x01 != 0 { hd.read_register(0x81) } else { 0 }
+    }
+
+    fn read_sector(hd: &mut HardDisk, lun: u8, sector: u8) -> Result<Vec<u8>, u8> {
+        select(hd, lun, sector);
+        match run(hd, 0x20) {
+            0 => Ok((0..512).map(|_| hd.read_register(0x80)).collect()),
+            error => Err(error),
+        }
+    }
+
+    fn write_sector(hd: &mut HardDisk, lun: u8, sector: u8, value: u8) -> u8 {
+        select(hd, lun, sector);
+        let error = run(hd, 0x30);
+        if error == 0 {
+            for _ in 0..512 {
+                hd.write_register(0x80, value);
+            }
+        }
+        error
+    }
+
+    #[test]
+    fn test_units_have_their_own_images() {
+        let (path1, path2) = (temp_path("hdunits_one.hd"), temp_path("hdunits_two.hd"));
+        std::fs::write(&path1, vec![0x11; hd_drive::ST412.size()]).unwrap();
+        std::fs::write(&path2, vec![0x22; hd_drive::ST412.size()]).unwrap();
+        let mut hd = HardDisk::new(false);
+        assert!(!select(&mut hd, 2, 0));
+        hd.load_image(&path1).unwrap();
+        assert!(!select(&mut hd, 2, 0));
+        assert_eq!(read_sector(&mut hd, 2, 0), Err(0x04));
+        hd.load_unit(2, &path2).unwrap();
+        assert!(select(&mut hd, 1, 0) && select(&mut hd, 2, 0));
+        assert!(!select(&mut hd, 3, 0));
+
+        assert_eq!(read_sector(&mut hd, 1, 0), Ok(vec![0x11; 512]));
+        assert_eq!(read_sector(&mut hd, 2, 0), Ok(vec![0x22; 512]));
+        assert_eq!(write_sector(&mut hd, 2, 3, 0x5A), 0);
+        assert_eq!(read_sector(&mut hd, 1, 3), Ok(vec![0x11; 512]));
+        assert_eq!(read_sector(&mut hd, 2, 3), Ok(vec![0x5A; 512]));
+
+        // Writes go through to the unit's own file
+        let image1 = std::fs::read(&path1).unwrap();
+        let image2 = std::fs::read(&path2).unwrap();
+        std::fs::remove_file(&path1).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+        assert_eq!(image1[3 * 512], 0x11);
+        assert_eq!(image2[3 * 512], 0x5A);
+    }
+
+    #[test]
+    fn test_units_track_formatting_separately() {
+        let (path1, path2) = (temp_path("hdunits_blank1.hd"), temp_path("hdunits_blank2.hd"));
+        let _ = std::fs::remove_file(&path1);
+        let _ = std::fs::remove_file(&path2);
+        let mut hd = HardDisk::new(false);
+        hd.set_geometry(hd_drive::ST506);
+        hd.load_unit(1, &path1).unwrap();
+        hd.set_geometry(hd_drive::ST225);
+        hd.load_unit(2, &path2).unwrap();
+
+        // FORMAT TRACK on the second drive leaves the first unformatted
+        select(&mut hd, 2, 0);
+        assert_eq!(run(&mut hd, 0x50), 0);
+        for _ in 0..512 {
+            hd.write_register(0x80, 0);
+        }
+        assert_eq!(read_sector(&mut hd, 2, 0), Ok(vec![0xE5; 512]));
+        assert_eq!(read_sector(&mut hd, 1, 0), Err(0x10));
+
+        // Each drive has its own geometry
+        assert_eq!(hd.unit(1).unwrap().disk_data.len(), hd_drive::ST506.size());
+        assert_eq!(hd.unit(2).unwrap().disk_data.len(), hd_drive::ST225.size());
+        std::fs::remove_file(&path1).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+// Host directory mounted as a virtual CP/M floppy.
+//
+// A drive path of the form "dir:<path>" builds a DSDD disk in memory with
+// the files of the host directory in user area 0.  Files whose names don't
+// fit CP/M 8.3 are left out.  The FDC sees an ordinary `Media`; whenever
+// the disk is flushed (after each written sector, and on eject) the blocks
+// written are noted.  Once they include a directory block or a block of a
+// synchronized file, the CP/M directory is read back and the host is
+// brought up to date: new and changed files are written, files erased
+// under CP/M are deleted.  Files whose directory entries and blocks are
+// untouched aren't read again.  Data written to free blocks waits for the
+// directory entry that allocates them.  Only files that came from the
+// mount or were created through it are deleted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::cpm_fs::{CpmFs, CpmName, MediaVolume, RECORD_SIZE};
+use crate::media::{DiskGeometry, Media};
+
+pub const PREFIX: &str = "dir:";
+
+/// Host directory of a "dir:<path>" drive name.
+pub fn host_path(name: &str) -> Option<&str> {
+    name.strip_prefix(PREFIX)
+}
+
+pub struct HostDir {
+    path: PathBuf,
+    /// Each file as last synchronized.
+    files: BTreeMap<CpmName, SyncedFile>,
+    /// Allocation block of each record of the disk
+    block_map: Vec<Option<usize>>,
+    dir_blocks: usize,
+    /// Blocks written since the last synchronization
+    dirty: BTreeSet<usize>,
+}
+
+struct SyncedFile {
+    host: String,
+    /// Contents as CP/M reads them, whole records
+    data: Vec<u8>,
+    blocks: Vec<usize>,
+}
+
+fn cpm_volume(content: Vec<u8>, geometry: DiskGeometry, side1_sector_base: u8) -> Result<CpmFs<MediaVolume>> {
+    let mut media = Media {
+        content,
+        ..Media::new(side1_sector_base)
+    };
+    media.apply_geometry(geometry);
+    let (volume, dpb) = MediaVolume::new(media)?;
+    CpmFs::new(volume, dpb)
+}
+
+impl HostDir {
+    /// Build the disk content for the files in `path`.
+    pub fn mount(path: &str, geometry: DiskGeometry, side1_sector_base: u8) -> Result<(HostDir, Vec<u8>)> {
+        let dir = PathBuf::from(path);
+        if !dir.is_dir() {
+            return Err(Error::new(ErrorKind::NotFound,
+                format!("{} is not a directory", path)));
+        }
+        let mut host_files: Vec<String> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        host_files.sort();
+
+        let sides = if geometry.double_sided { 2 } else { 1 };
+        let size = geometry.tracks as usize * sides
+            * geometry.sectors_per_track as usize * (128usize << geometry.n);
+        let mut cpm = cpm_volume(vec![0xE5; size], geometry, side1_sector_base)?;
+        let mut files = BTreeMap::new();
+        for host in host_files {
+            let name = match CpmName::parse(&host) {
+                Ok(name) => name,
+                Err(_) => {
+                    eprintln!("Note: '{}' skipped, not a CP/M file name", host);
+                    continue;
+                }
+            };
+            if files.contains_key(&name) {
+                eprintln!("Note: '{}' skipped, same CP/M name as another file", host);
+                continue;
+            }
+            let data = fs::read(dir.join(&host))?;
+            if let Err(e) = cpm.write_file(&name, &data) {
+                eprintln!("Note: '{}' skipped: {}", host, e);
+                continue;
+            }
+            let data = cpm.read_file(&name)?;
+            let blocks = cpm.file_blocks(&name);
+            files.insert(name, SyncedFile { host, data, blocks });
+        }
+        let host_dir = HostDir {
+            path: dir,
+            files,
+            block_map: cpm.volume.block_map(&cpm.dpb),
+            dir_blocks: cpm.dpb.dir_blocks(),
+            dirty: BTreeSet::new(),
+        };
+        Ok((host_dir, cpm.volume.media.content))
+    }
+
+    /// Note the bytes `written` of `content` and update the host directory
+    /// from the CP/M directory if they concern it.
+    pub fn sync(&mut self, content: &[u8], written: Range<usize>, geometry: DiskGeometry,
+                side1_sector_base: u8) -> Result<()> {
+        for record in written.start / RECORD_SIZE..written.end.div_ceil(RECORD_SIZE) {
+            if let Some(&Some(block)) = self.block_map.get(record) {
+                self.dirty.insert(block);
+            }
+        }
+        let synced_blocks = self.dirty.iter().any(|&block| block < self.dir_blocks
+            || self.files.values().any(|file| file.blocks.contains(&block)));
+        if !synced_blocks {
+            return Ok(());
+        }
+
+        let cpm = cpm_volume(content.to_vec(), geometry, side1_sector_base)?;
+        let mut present = BTreeSet::new();
+        for file in cpm.list().iter().filter(|f| f.name.user == 0) {
+            // Names that don't parse back could escape the directory
+            if CpmName::parse(&file.name.file_name()).is_err() {
+                continue;
+            }
+            present.insert(file.name);
+            let blocks = cpm.file_blocks(&file.name);
+            let untouched = |synced: &SyncedFile| synced.blocks == blocks
+                && synced.data.len() == file.records * RECORD_SIZE
+                && !blocks.iter().any(|block| self.dirty.contains(block));
+            if self.files.get(&file.name).is_some_and(untouched) {
+                continue;
+            }
+            let data = cpm.read_file(&file.name)?;
+            let host = match self.files.get(&file.name) {
+                Some(synced) => synced.host.clone(),
+                None => file.name.file_name(),
+            };
+            if self.files.get(&file.name).map(|synced| &synced.data) != Some(&data) {
+                fs::write(self.path.join(&host), &data)?;
+            }
+            self.files.insert(file.name, SyncedFile { host, data, blocks });
+        }
+
+        let erased: Vec<CpmName> = self.files.keys()
+            .filter(|name| !present.contains(name))
+            .cloned()
+            .collect();
+        for name in erased {
+            let file = self.files.remove(&name).unwrap();
+            match fs::remove_file(self.path.join(&file.host)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+}
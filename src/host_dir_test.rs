@@ -0,0 +1,102 @@
+#[cfg(test)]
+mod tests {
+    use crate::cpm_fs::{CpmFs, CpmName, MediaVolume};
+    use crate::media::Media;
+    use crate::test_support::temp_path;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let path = std::path::PathBuf::from(temp_path(&format!("hostdir_{}", name)));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        path
+    }
+
+    /// Apply CP/M file operations to the mounted disk the way the FDC
+    /// would: byte writes followed by a flush.
+    fn cpm_edit(media: &mut Media, edit: impl FnOnce(&mut CpmFs<MediaVolume>)) {
+        let mut copy = Media {
+            content: media.content.clone(),
+            ..Media::new(10)
+        };
+        copy.apply_geometry(media.geometry.unwrap());
+        let (volume, dpb) = MediaVolume::new(copy).unwrap();
+        let mut fs = CpmFs::new(volume, dpb).unwrap();
+        edit(&mut fs);
+        for (i, &b) in fs.volume.media.content.iter().enumerate() {
+            if media.content[i] != b {
+                media.write_byte(i, b);
+            }
+        }
+        media.flush_disk();
+    }
+
+    #[test]
+    fn test_host_dir_round_trip() {
+        let dir = temp_dir("sync");
+        let hello: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        std::fs::write(dir.join("hello.com"), &hello).unwrap();
+        std::fs::write(dir.join("readme.txt"), b"read me").unwrap();
+        std::fs::write(dir.join("much_too_long.name"), b"skipped").unwrap();
+
+        let mut media = Media::new(10);
+        media.load_disk(&format!("dir:{}", dir.display())).unwrap();
+        assert!(media.info().contains("host directory"));
+
+        cpm_edit(&mut media, |fs| {
+            let names: Vec<String> = fs.list().iter().map(|f| f.name.file_name()).collect();
+            assert_eq!(names, ["HELLO.COM", "README.TXT"]);
+            assert_eq!(fs.read_file(&CpmName::parse("hello.com").unwrap()).unwrap()[..3000], hello[..]);
+            fs.write_file(&CpmName::parse("new.txt").unwrap(), b"created in CP/M").unwrap();
+            fs.write_file(&CpmName::parse("readme.txt").unwrap(), b"changed").unwrap();
+            fs.delete(&CpmName::parse("hello.com").unwrap()).unwrap();
+            fs.write_file(&CpmName::parse("3:other.txt").unwrap(), b"user 3").unwrap();
+        });
+
+        let new = std::fs::read(dir.join("NEW.TXT")).unwrap();
+        let readme = std::fs::read(dir.join("readme.txt")).unwrap();
+        let mut listing: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        listing.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listing, ["NEW.TXT", "much_too_long.name", "readme.txt"]);
+        assert_eq!(new.len(), 128);
+        assert!(new.starts_with(b"created in CP/M\x1A"));
+        assert!(readme.starts_with(b"changed\x1A"));
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_rewritten() {
+        let dir = temp_dir("unchanged");
+        std::fs::write(dir.join("short.txt"), b"no padding").unwrap();
+        let mut media = Media::new(10);
+        media.load_disk(&format!("dir:{}", dir.display())).unwrap();
+        cpm_edit(&mut media, |fs| {
+            fs.write_file(&CpmName::parse("b.txt").unwrap(), b"b").unwrap();
+        });
+        // Remounting reads the host files again
+        media.load_disk(&format!("dir:{}", dir.display())).unwrap();
+        let short = std::fs::read(dir.join("short.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(short, b"no padding");
+        assert!(media.load_disk("dir:/nonexistent/izkaypro").is_err());
+    }
+
+    #[test]
+    fn test_data_written_in_place_is_synced() {
+        let dir = temp_dir("in_place");
+        let hello: Vec<u8> = (0..=255).collect();
+        std::fs::write(dir.join("hello.com"), &hello).unwrap();
+        let mut media = Media::new(10);
+        media.load_disk(&format!("dir:{}", dir.display())).unwrap();
+
+        // A record of the file rewritten without touching the directory
+        let index = media.content.windows(256).position(|w| w == &hello[..]).unwrap();
+        media.write_byte(index + 130, 0xAA);
+        media.flush_disk();
+        let synced = std::fs::read(dir.join("hello.com")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(synced[129..132], [129, 0xAA, 131]);
+    }
+}
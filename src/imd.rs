@@ -0,0 +1,192 @@
+// ImageDisk (.IMD) container format.
+//
+// File layout:
+//   "IMD v.vv: dd/mm/yyyy hh:mm:ss" CR LF, free-form comment, 0x1A
+//   Per track:
+//     mode      0-2 = FM at 500/300/250 kbps, 3-5 = MFM at 500/300/250 kbps
+//     cylinder
+//     head      bit 7: cylinder map present, bit 6: head map present
+//     sectors   count
+//     size      N code (sector size = 128 << N)
+//     sector ID map, then the optional cylinder and head maps
+//     per sector a type byte and the data:
+//       0 = unavailable (no data), 1/3/5/7 = data follows,
+//       2/4/6/8 = compressed (one fill byte).
+//       Types 3/4 and 7/8 carry a deleted data mark, 5-8 a data CRC error.
+
+use std::io::{Error, ErrorKind, Result};
+
+const COMMENT_END: u8 = 0x1A;
+const CYLINDER_MAP: u8 = 0x80;
+const HEAD_MAP: u8 = 0x40;
+
+/// Sector record type, normalized to the uncompressed code (0, 1, 3, 5, 7).
+pub const SECTOR_UNAVAILABLE: u8 = 0;
+pub const SECTOR_NORMAL: u8 = 1;
+
+/// Type code of a sector with data.
+pub fn sector_type(deleted: bool, crc_error: bool) -> u8 {
+    SECTOR_NORMAL + if deleted { 2 } else { 0 } + if crc_error { 4 } else { 0 }
+}
+
+pub fn is_deleted(kind: u8) -> bool {
+    kind == 3 || kind == 7
+}
+
+pub fn has_crc_error(kind: u8) -> bool {
+    kind == 5 || kind == 7
+}
+
+/// Mode byte for 250 kbps FM or MFM, the rates used by 5.25" drives.
+pub fn mode_for_density(single_density: bool) -> u8 {
+    if single_density { 2 } else { 5 }
+}
+
+pub struct ImdTrack {
+    pub mode: u8,
+    pub cylinder: u8,
+    pub head: u8,
+    pub n: u8,
+    /// Sector IDs in physical (rotational) order
+    pub sector_ids: Vec<u8>,
+    pub cylinder_map: Option<Vec<u8>>,
+    pub head_map: Option<Vec<u8>>,
+    /// Type code per sector, normalized to the uncompressed value
+    pub sector_types: Vec<u8>,
+    /// Sector data, empty for unavailable sectors
+    pub data: Vec<Vec<u8>>,
+}
+
+impl ImdTrack {
+    pub fn single_density(&self) -> bool {
+        self.mode < 3
+    }
+
+    pub fn sector_size(&self) -> usize {
+        128usize << self.n
+    }
+}
+
+pub struct ImdImage {
+    /// Signature line and comment, without the 0x1A terminator
+    pub header: Vec<u8>,
+    pub tracks: Vec<ImdTrack>,
+}
+
+pub fn is_imd(data: &[u8]) -> bool {
+    data.starts_with(b"IMD ")
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::InvalidData, "Truncated IMD image")
+}
+
+impl ImdImage {
+    pub fn parse(data: &[u8]) -> Result<ImdImage> {
+        if !is_imd(data) {
+            return Err(Error::new(ErrorKind::InvalidData, "Not an IMD image"));
+        }
+        let comment_end = data.iter().position(|&b| b == COMMENT_END).ok_or_else(truncated)?;
+        let header = data[..comment_end].to_vec();
+
+        let mut tracks = Vec::new();
+        let mut i = comment_end + 1;
+        while i < data.len() {
+            let fixed = data.get(i..i + 5).ok_or_else(truncated)?;
+            let (mode, cylinder, head_flags, count, n) =
+                (fixed[0], fixed[1], fixed[2], fixed[3] as usize, fixed[4]);
+            i += 5;
+            if mode > 5 {
+                return Err(Error::new(ErrorKind::InvalidData,
+                    format!("IMD track {}: invalid mode {}", tracks.len(), mode)));
+            }
+            if n > 6 {
+                // 0xFF announces a per-sector size table, used by no
+                // format this machine can read.
+                return Err(Error::new(ErrorKind::InvalidData,
+                    format!("IMD track {}: unsupported sector size code 0x{:02x}", tracks.len(), n)));
+            }
+            let mut take = |len: usize| -> Result<Vec<u8>> {
+                let v = data.get(i..i + len).ok_or_else(truncated)?.to_vec();
+                i += len;
+                Ok(v)
+            };
+            let sector_ids = take(count)?;
+            let cylinder_map = if head_flags & CYLINDER_MAP != 0 { Some(take(count)?) } else { None };
+            let head_map = if head_flags & HEAD_MAP != 0 { Some(take(count)?) } else { None };
+
+            let size = 128usize << n;
+            let mut sector_types = Vec::with_capacity(count);
+            let mut sectors = Vec::with_capacity(count);
+            for _ in 0..count {
+                let kind = take(1)?[0];
+                match kind {
+                    0 => {
+                        sector_types.push(SECTOR_UNAVAILABLE);
+                        sectors.push(Vec::new());
+                    }
+                    1 | 3 | 5 | 7 => {
+                        sector_types.push(kind);
+                        sectors.push(take(size)?);
+                    }
+                    2 | 4 | 6 | 8 => {
+                        sector_types.push(kind - 1);
+                        sectors.push(vec![take(1)?[0]; size]);
+                    }
+                    _ => return Err(Error::new(ErrorKind::InvalidData,
+                        format!("IMD track {}: invalid sector type {}", tracks.len(), kind))),
+                }
+            }
+
+            tracks.push(ImdTrack {
+                mode,
+                cylinder,
+                head: head_flags & 0x01,
+                n,
+                sector_ids,
+                cylinder_map,
+                head_map,
+                sector_types,
+                data: sectors,
+            });
+        }
+        Ok(ImdImage { header, tracks })
+    }
+
+    /// Comment text from the header, without the signature line.
+    pub fn comment(&self) -> String {
+        let text = String::from_utf8_lossy(&self.header);
+        match text.split_once("\r\n") {
+            Some((_, comment)) => comment.trim_end().to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Serialize, compressing sectors filled with a single byte value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.header.clone();
+        out.push(COMMENT_END);
+        for track in &self.tracks {
+            let mut head = track.head;
+            if track.cylinder_map.is_some() { head |= CYLINDER_MAP; }
+            if track.head_map.is_some() { head |= HEAD_MAP; }
+            out.extend_from_slice(&[track.mode, track.cylinder, head,
+                track.sector_ids.len() as u8, track.n]);
+            out.extend_from_slice(&track.sector_ids);
+            if let Some(ref map) = track.cylinder_map { out.extend_from_slice(map); }
+            if let Some(ref map) = track.head_map { out.extend_from_slice(map); }
+            for (&kind, data) in track.sector_types.iter().zip(&track.data) {
+                if kind == SECTOR_UNAVAILABLE || data.is_empty() {
+                    out.push(SECTOR_UNAVAILABLE);
+                } else if data.iter().all(|&b| b == data[0]) {
+                    out.push(kind + 1);
+                    out.push(data[0]);
+                } else {
+                    out.push(kind);
+                    out.extend_from_slice(data);
+                }
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod tests {
+    use crate::floppy_controller::FloppyController;
+    use crate::imd::{ImdImage, ImdTrack};
+    use crate::media::{Media, MediaFormat};
+    use crate::test_support::temp_path;
+
+    fn fill(track: u8, side: u8, id: u8) -> u8 {
+        id.wrapping_add(track).wrapping_add(side * 0x40)
+    }
+
+    /// Xerox 820-II DSDD: track 0 side 0 is FM 18×128, everything else
+    /// MFM 17×256, sectors interleaved on the track.
+    fn xerox820ii_image() -> ImdImage {
+        let mut tracks = Vec::new();
+        for cylinder in 0..40u8 {
+            for head in 0..2u8 {
+                let (mode, n, spt) = if cylinder == 0 && head == 0 { (2, 0, 18) } else { (5, 1, 17) };
+                // 2:1 interleave: 1, 3, 5, ... then 2, 4, 6, ...
+                let sector_ids: Vec<u8> = (1..=spt).step_by(2).chain((2..=spt).step_by(2)).collect();
+                let data = sector_ids.iter()
+                    .map(|&id| vec![fill(cylinder, head, id); 128 << n])
+                    .collect();
+                tracks.push(ImdTrack {
+                    mode,
+                    cylinder,
+                    head,
+                    n,
+                    sector_ids,
+                    cylinder_map: None,
+                    head_map: None,
+                    sector_types: vec![1; spt as usize],
+                    data,
+                });
+            }
+        }
+        ImdImage { header: b"IMD 1.18: 01/01/1983 00:00:00\r\nXerox 820-II test".to_vec(), tracks }
+    }
+
+    fn read_sector(media: &Media, side_2: bool, track: u8, id: u8) -> Vec<u8> {
+        let (valid, index, last) = media.sector_index(side_2, track, id);
+        assert!(valid, "track {} side {} sector {} missing", track, side_2 as u8, id);
+        media.content[index..last].to_vec()
+    }
+
+    #[test]
+    fn test_imd_mixed_density_survives_reload() {
+        let path = temp_path("imd_mixed.imd");
+        std::fs::write(&path, xerox820ii_image().to_bytes()).unwrap();
+
+        let mut media = Media::new(0);
+        media.load_disk(&path).unwrap();
+        assert!(media.format == MediaFormat::DsDd);
+        assert!(media.density_matches(false, 0, true));
+        assert!(!media.density_matches(false, 0, false));
+        assert!(media.density_matches(true, 0, false));
+        assert_eq!(read_sector(&media, false, 0, 18).len(), 128);
+        assert_eq!(read_sector(&media, true, 39, 17), vec![fill(39, 1, 17); 256]);
+
+        // Write a sector on the FM track and one on an MFM track
+        let (_, index, last) = media.sector_index(false, 0, 5);
+        for i in index..last {
+            media.write_byte(i, (i - index) as u8);
+        }
+        let (_, index, _) = media.sector_index(true, 20, 9);
+        media.write_byte(index, 0x42);
+        media.flush_disk();
+
+        let mut reloaded = Media::new(0);
+        reloaded.load_disk(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let image = reloaded.imd.as_ref().unwrap();
+        assert_eq!(image.comment(), "Xerox 820-II test");
+        assert_eq!(image.tracks.len(), 80);
+        assert_eq!(image.tracks[0].mode, 2);
+        assert_eq!(image.tracks[1].mode, 5);
+        assert_eq!(image.tracks[0].sector_ids, xerox820ii_image().tracks[0].sector_ids);
+
+        let expected: Vec<u8> = (0..128).map(|i| i as u8).collect();
+        assert_eq!(read_sector(&reloaded, false, 0, 5), expected);
+        assert_eq!(read_sector(&reloaded, true, 20, 9)[0], 0x42);
+        assert_eq!(read_sector(&reloaded, true, 20, 9)[1], fill(20, 1, 9));
+        assert_eq!(read_sector(&reloaded, false, 0, 6), vec![fill(0, 0, 6); 128]);
+    }
+
+    #[test]
+    fn test_imd_read_through_fdc() {
+        let path = temp_path("imd_fdc.imd");
+        std::fs::write(&path, xerox820ii_image().to_bytes()).unwrap();
+        let mut fdc = FloppyController::new(
+            "__nonexistent_test_a__",
+            &path,
+            MediaFormat::DsDd,
+            0,
+            false,
+            false,
+        );
+        std::fs::remove_file(&path).unwrap();
+        fdc.set_drive(1);
+        fdc.set_motor(true);
+
+        // Wrong density on track 0: record not found
+        fdc.set_single_density(false);
+        fdc.put_track(0);
+        fdc.put_sector(3);
+        fdc.put_command(0x80);
+        assert!(fdc.get_status() & 0x10 != 0);
+
+        fdc.set_single_density(true);
+        fdc.put_command(0x80);
+        let data: Vec<u8> = (0..128).map(|_| fdc.get_data()).collect();
+        assert_eq!(data, vec![fill(0, 0, 3); 128]);
+    }
+
+    #[test]
+    fn test_imd_compression_and_sector_types() {
+        let mut image = xerox820ii_image();
+        image.tracks[2].sector_types[0] = 3; // deleted data
+        image.tracks[2].sector_types[1] = 0; // unavailable
+        image.tracks[2].data[1] = Vec::new();
+        image.tracks[2].data[2][0] ^= 0xFF;  // not compressible
+        let bytes = image.to_bytes();
+        let parsed = ImdImage::parse(&bytes).unwrap();
+        assert_eq!(parsed.tracks[2].sector_types[..3], [3, 0, 1]);
+        assert!(parsed.tracks[2].data[1].is_empty());
+        assert_eq!(parsed.tracks[2].data[2], image.tracks[2].data[2]);
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert!(ImdImage::parse(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
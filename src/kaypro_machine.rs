@@ -1,15 +1,19 @@
 use std::fs::{File};
 use std::io::{Write};
-use iz80::Machine;
+use iz80::{Cpu, Machine, Reg8, Reg16};
 use super::FloppyController;
 use super::hard_disk::HardDisk;
+use super::ram_disk::RamDisk;
 #[cfg(unix)]
 use super::keyboard_unix::Keyboard;
 #[cfg(windows)]
 use super::keyboard_win::Keyboard;
 use super::rtc::Rtc;
+use super::scheduler::{Event, Scheduler};
 use super::sio::Sio;
+use super::snapshot::{self, StateReader, StateWriter};
 use super::sy6545::Sy6545;
+use super::z80_timing;
 
 /* Memory map:
 
@@ -30,6 +34,35 @@ pub enum VideoMode {
     Sy6545Crtc,
 }
 
+impl VideoMode {
+    /// Z80 clock of the boards with this video: 2.5 MHz on the Kaypro II
+    /// and 4/83, 4 MHz on the 84 boards.
+    pub fn cpu_mhz(self) -> f64 {
+        match self {
+            VideoMode::MemoryMapped => 2.5,
+            VideoMode::Sy6545Crtc => 4.0,
+        }
+    }
+}
+
+/// T-states the CPU may poll without HALTing before a pending NMI is
+/// delivered anyway (about 10 s at 4 MHz), and how often an NMI past its
+/// deadline checks again for a safe vector.
+const NMI_DEADLINE_TSTATES: u64 = 40_000_000;
+const NMI_RECHECK_TSTATES: u64 = 1024;
+
+/// How often the SIO is checked for an Rx interrupt.
+const SIO_RX_TSTATES: u64 = 4096;
+
+/// Why `run` returned.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunExit {
+    /// An event for the run loop came due
+    Event(Event),
+    /// The CPU HALTed with no interrupt on its way to wake it
+    Halted,
+}
+
 #[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum SystemBit {
@@ -97,6 +130,16 @@ pub struct KayproMachine {
     pub vram_dirty: bool,
     pub system_bits: u8,
     pub port14_raw: u8, // Raw value written to port 0x14 (for 81-292a ROM compatibility)
+
+    // Emulated time. The devices see time pass at the CPU clock no matter
+    // how fast the host runs the instructions.
+    pub tstates: u64,      // T-states executed since power-on
+    time_ps: u64,          // Emulated time since power-on in picoseconds
+    ps_per_tstate: u64,    // Length of a T-state at the CPU clock
+    nmi_signaled: bool,    // NMI handed to the CPU, taken on the next step
+    nmi_pending: bool,     // NMI raised by the FDC, waiting for a HALT or its deadline
+    pub instructions: u64, // Steps run this session, not reset by snapshots or rewinds
+    pub scheduler: Scheduler,
     
     // Video mode and CRTC for Kaypro 2X/4/84
     pub video_mode: VideoMode,
@@ -124,9 +167,18 @@ pub struct KayproMachine {
     // Advent Personality/Decoder Board PIO emulation (ports 0x88-0x8B).
     // Required for TurboROM+HD to detect more than 1 floppy drive.
     // Port 0x88: PIO data (writes accepted, reads return 0xFF = no RAM disk)
-    // Port 0x8A: drive select (1-2)
-    // Port 0x8B: status switch (bit 2 = 0 means drive present)
-    advent_pio_enabled: bool,
+    // Port 0x8A: drive select (1-4, drives C and D only exist here)
+    // Port 0x8B: status switch (bit 2 = 0 means selected drive present)
+    // A RAM disk card shares the ports, see ram_disk.rs.
+    // Also enabled without a hard disk when drives C or D or a RAM disk
+    // are connected.
+    pub advent_pio_enabled: bool,
+    // Last value written to port 0x8A
+    advent_drive_select: u8,
+    // With a RAM disk card, a value written to port 0x8A may be a track of
+    // the card: it selects a floppy drive when the floppy controller is
+    // next used, unless the card's data port is used first.
+    advent_select_pending: bool,
 
     // SIO Channel A port 0x04 read guard: tracks whether a user program
     // reads the serial data register directly (RAM rank). Programs like
@@ -138,6 +190,7 @@ pub struct KayproMachine {
     pub keyboard: Keyboard,
     pub floppy_controller: FloppyController,
     pub hard_disk: Option<HardDisk>,
+    pub ram_disk: Option<RamDisk>,
     pub sio: Sio,
     pub rtc: Rtc,
 }
@@ -177,6 +230,17 @@ impl KayproMachine {
             vram_dirty: false,
             system_bits: SystemBit::Bank as u8 | SystemBit::MotorsOff as u8,
             port14_raw: 0xDF, // Initial value for 81-292a (ROM mode, drive A, motor on)
+            tstates: 0,
+            time_ps: 0,
+            ps_per_tstate: (1_000_000.0 / video_mode.cpu_mhz()).round() as u64,
+            nmi_signaled: false,
+            nmi_pending: false,
+            instructions: 0,
+            scheduler: {
+                let mut scheduler = Scheduler::new();
+                scheduler.schedule(SIO_RX_TSTATES, Event::SioRx);
+                scheduler
+            },
             video_mode,
             crtc,
             sio_b_wr_select: 0,
@@ -190,6 +254,8 @@ impl KayproMachine {
             is_kaypro10_hardware,
             port14_last_bit1: false,
             advent_pio_enabled: has_hard_disk && !is_kaypro10_hardware,
+            advent_drive_select: 0,
+            advent_select_pending: false,
             sio_user_direct_rx: false,
             keyboard: Keyboard::new(),
             floppy_controller,
@@ -202,6 +268,7 @@ impl KayproMachine {
                 }
                 Some(hd)
             } else { None },
+            ram_disk: None,
             sio: Sio::new(trace_sio),
             rtc: Rtc::new(trace_rtc),
         }
@@ -222,6 +289,19 @@ impl KayproMachine {
         self.system_bits & SystemBit::Bank as u8 != 0
     }
 
+    /// What the CPU sees at `address` in the current bank: "ROM", "VID"
+    /// for the memory-mapped video RAM, or "RAM". See peek.
+    pub fn memory_bank(&self, address: u16) -> &'static str {
+        if (address as usize) < self.rom.len() && self.is_rom_rank() {
+            "ROM"
+        } else if (0x3000..0x4000).contains(&address) && self.is_rom_rank()
+                && self.video_mode == VideoMode::MemoryMapped {
+            "VID"
+        } else {
+            "RAM"
+        }
+    }
+
     /// Check if delivering an NMI right now is safe.
     /// The Z80 NMI always vectors to 0x0066. We check the actual byte(s)
     /// at that address (as currently mapped) to determine whether it is a
@@ -267,6 +347,23 @@ impl KayproMachine {
         }
     }
 
+    /// Select the floppy drive written to the Advent board's port 0x8A.
+    fn apply_advent_drive_select(&mut self) {
+        self.advent_select_pending = false;
+        if self.advent_drive_select >= 1 && self.advent_drive_select <= 4 {
+            self.floppy_controller.set_drive(self.advent_drive_select - 1);
+            self.floppy_controller.set_motor(true);
+        }
+    }
+
+    /// The value written to port 0x8A was a track of the RAM disk card.
+    fn cancel_advent_drive_select(&mut self) {
+        if self.advent_select_pending {
+            self.advent_select_pending = false;
+            self.advent_drive_select = 0;
+        }
+    }
+
     // Kaypro 4-84 uses port 0x14 with different bit layout:
     // Bit 7: BANK (0=RAM, 1=ROM/Video)
     // Bit 6: CHSET (character set)
@@ -315,7 +412,17 @@ impl KayproMachine {
         } else {
             // Standard 4/84 encoding: bits 1-0
             let drive_sel = bits & 0x03;
+            if drive_sel != 0x00 {
+                // A native select line takes over from the Advent board
+                self.advent_drive_select = 0;
+                self.advent_select_pending = false;
+            }
             match drive_sel {
+                // Drives C and D have no native select lines; while the
+                // Advent board selects one, port 0x14 leaves it selected.
+                // Port 0x8A also takes the RAM disk card's tracks.
+                0x00 if !self.advent_select_pending && matches!(self.advent_drive_select, 3..=4) =>
+                    Some(self.advent_drive_select - 1),
                 0x02 => Some(0), // A=10 (bit 1 set, bit 0 clear)
                 0x01 => Some(1), // B=01 (bit 0 set, bit 1 clear)
                 0x03 => Some(0), // Both bits = default to A (initialization)
@@ -324,12 +431,10 @@ impl KayproMachine {
             }
         };
 
-        if let Some(d) = drive {
-            if d == 0 {
-                sys_bits |= SystemBit::DriveA as u8;
-            } else {
-                sys_bits |= SystemBit::DriveB as u8;
-            }
+        match drive {
+            Some(0) => sys_bits |= SystemBit::DriveA as u8,
+            Some(1) => sys_bits |= SystemBit::DriveB as u8,
+            _ => {}
         }
 
         // Centronics strobe (bit 3)
@@ -442,6 +547,189 @@ impl KayproMachine {
         }
     }
 
+    /// Set the CPU clock that converts T-states into emulated time.
+    pub fn set_cpu_clock(&mut self, mhz: f64) {
+        self.ps_per_tstate = (1_000_000.0 / mhz).round() as u64;
+    }
+
+    /// Emulated time since power-on in microseconds.
+    pub fn now_us(&self) -> u64 {
+        self.time_ps / 1_000_000
+    }
+
+    /// Pass the emulated time to the devices before an I/O access.
+    fn sync_device_clocks(&mut self) {
+        let now = self.now_us();
+        self.crtc.now_us = now;
+        self.sio.now_us = now;
+        self.rtc.now_us = now;
+        self.floppy_controller.now_us = now;
+        if let Some(ref mut hd) = self.hard_disk {
+            hd.now_us = now;
+        }
+    }
+
+    /// T-state on which emulated time `at_us` falls, at the current clock.
+    fn tstates_at(&self, at_us: u64) -> u64 {
+        let ahead_ps = (at_us * 1_000_000).saturating_sub(self.time_ps);
+        self.tstates + ahead_ps.div_ceil(self.ps_per_tstate)
+    }
+
+    /// Move the events the devices posted into the scheduler.
+    fn collect_device_events(&mut self) {
+        let mut posted = std::mem::take(&mut self.floppy_controller.posted);
+        if let Some(ref mut hd) = self.hard_disk {
+            posted.append(&mut hd.posted);
+        }
+        for (at_us, event) in posted {
+            let at = self.tstates_at(at_us);
+            self.scheduler.schedule(at, event);
+        }
+    }
+
+    fn advance(&mut self, tstates: u32) {
+        self.tstates += tstates as u64;
+        self.time_ps += tstates as u64 * self.ps_per_tstate;
+    }
+
+    /// Execute one instruction and return the T-states it took. A halted
+    /// CPU executes NOPs, and an NMI signaled since the last step adds the
+    /// acknowledge cycle before the first instruction of the handler.
+    pub fn step(&mut self, cpu: &mut Cpu) -> u32 {
+        self.instructions += 1;
+        let mut tstates = 0;
+        let mut pc = cpu.registers().pc();
+        if std::mem::take(&mut self.nmi_signaled) {
+            tstates += z80_timing::NMI_TSTATES;
+            pc = 0x0066;
+        } else if cpu.is_halted() {
+            self.advance(4);
+            return 4;
+        }
+        let timing = z80_timing::instruction(|address| self.peek(address), pc);
+        cpu.execute_instruction(self);
+        tstates += timing.tstates_for(pc, cpu.registers().pc());
+        self.advance(tstates);
+        tstates
+    }
+
+    /// Execute one instruction and dispatch the events that came due.
+    /// Returns the event for the run loop (Refresh, Frame) if one came
+    /// due, or Halted if the CPU is waiting for an interrupt that isn't
+    /// coming.
+    pub fn tick(&mut self, cpu: &mut Cpu) -> Option<RunExit> {
+        self.step(cpu);
+
+        // KayPLUS software clock fixup: intercept the BIOS tick routine
+        // at 0x069E (start of the seconds/minutes/hours increment loop).
+        // Patch RAM counters with real RTC time and skip past the loop
+        // so the display code at 0x06CE reads accurate values.
+        if self.kayplus_clock_fixup
+            && self.is_rom_rank()
+            && cpu.registers().pc() == 0x069E
+        {
+            self.patch_software_clock();
+            cpu.registers().set_pc(0x06CE);
+        }
+
+        // The FDC sets raise_nmi when a command completes or a data byte is
+        // transferred. We latch it as pending and deliver when:
+        //  1. CPU is HALTed (immediate — standard BIOS FDC loops), OR
+        //  2. Deadline reached AND vector at 0x0066 is safe (fallback for
+        //     programs like DIAG4 that poll FDC without HALTing).
+        // KayPLUS (unsafe vector at 0x0066) only gets NMI via path 1.
+        if self.floppy_controller.raise_nmi {
+            self.floppy_controller.raise_nmi = false;
+            self.nmi_pending = true;
+            self.scheduler.schedule(self.tstates + NMI_DEADLINE_TSTATES, Event::NmiDeadline);
+        }
+        self.collect_device_events();
+
+        let mut exit = None;
+        while exit.is_none() {
+            let Some(event) = self.scheduler.pop_due(self.tstates) else {
+                break;
+            };
+            match event {
+                Event::SioRx => {
+                    self.take_sio_interrupt(cpu);
+                    self.scheduler.schedule(self.tstates + SIO_RX_TSTATES, Event::SioRx);
+                }
+                Event::NmiDeadline => {
+                    // Only scheduled while an NMI is pending
+                    if self.nmi_vector_is_safe() {
+                        self.signal_nmi(cpu);
+                    } else {
+                        self.scheduler.schedule(self.tstates + NMI_RECHECK_TSTATES, Event::NmiDeadline);
+                    }
+                }
+                Event::FdcIdField => self.floppy_controller.id_field_passed(),
+                Event::HdcDiagnostics => {
+                    if let Some(ref mut hd) = self.hard_disk {
+                        hd.diagnostics_done();
+                    }
+                }
+                Event::Refresh | Event::Frame => exit = Some(RunExit::Event(event)),
+            }
+        }
+
+        if self.nmi_pending && cpu.is_halted() {
+            self.signal_nmi(cpu);
+        }
+        if exit.is_none() && cpu.is_halted() {
+            exit = Some(RunExit::Halted);
+        }
+        exit
+    }
+
+    /// Run instructions until `tick` has something for the run loop.
+    #[allow(dead_code)]
+    pub fn run(&mut self, cpu: &mut Cpu) -> RunExit {
+        loop {
+            if let Some(exit) = self.tick(cpu) {
+                return exit;
+            }
+        }
+    }
+
+    /// Run until the CPU has taken the NMI signaled to it, if any. iz80
+    /// keeps a signaled NMI to itself, a snapshot can't have one.
+    pub fn settle(&mut self, cpu: &mut Cpu) {
+        while self.nmi_signaled {
+            self.tick(cpu);
+        }
+    }
+
+    /// No NMI is waiting in the CPU, see settle.
+    pub fn is_settled(&self) -> bool {
+        !self.nmi_signaled
+    }
+
+    /// Signal the pending NMI to the CPU; it is taken on the next step.
+    fn signal_nmi(&mut self, cpu: &mut Cpu) {
+        cpu.signal_nmi();
+        self.nmi_signaled = true;
+        self.nmi_pending = false;
+        self.scheduler.cancel(Event::NmiDeadline);
+    }
+
+    /// Deliver a pending SIO interrupt as a mode 2 acknowledge: push the
+    /// PC and jump to the handler from the vector table.
+    fn take_sio_interrupt(&mut self, cpu: &mut Cpu) {
+        let i_reg = cpu.registers().get8(Reg8::I);
+        let Some(handler) = self.sio_check_interrupt(i_reg) else {
+            return;
+        };
+        let regs = cpu.registers();
+        let pc = regs.pc();
+        let sp = regs.get16(Reg16::SP).wrapping_sub(2);
+        regs.set16(Reg16::SP, sp);
+        self.poke(sp, pc as u8);
+        self.poke(sp.wrapping_add(1), (pc >> 8) as u8);
+        cpu.registers().set_pc(handler);
+        self.advance(z80_timing::IM2_TSTATES);
+    }
+
     /// Check if the SIO should generate an interrupt for serial or keyboard input.
     /// Returns the IM2 vector address if an interrupt should fire, or None.
     /// Channel A (serial) has higher priority than Channel B (keyboard).
@@ -480,6 +768,7 @@ impl KayproMachine {
     /// routine). The caller must then advance PC to 0x06CE to skip
     /// the increment loop, so the display code reads the patched values.
     pub fn patch_software_clock(&mut self) {
+        self.sync_device_clocks();
         let (sec, min, hour) = self.rtc.current_time_hms();
         self.ram[0xFF5E] = sec;
         self.ram[0xFF5D] = min;
@@ -503,6 +792,127 @@ impl KayproMachine {
         
         Ok(filename)
     }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Append the state of the machine and the devices to a snapshot.
+    /// The configuration (model, video mode, traces, CPU clock) isn't
+    /// part of it.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bytes(&self.vram);
+        state.u8(self.system_bits);
+        state.u8(self.port14_raw);
+        state.u64(self.tstates);
+        state.u64(self.time_ps);
+        state.bool(self.nmi_pending);
+        state.u8(self.sio_b_wr_select);
+        state.u8(self.sio_b_wr1);
+        state.u8(self.sio_b_wr2);
+        state.bool(self.sio_int_pending);
+        state.bool(self.sio_a_int_pending);
+        state.bool(self.port14_last_bit1);
+        state.u8(self.advent_drive_select);
+        state.bool(self.advent_select_pending);
+        state.bool(self.sio_user_direct_rx);
+        self.crtc.save_state(state);
+        self.floppy_controller.save_state(state);
+        state.bool(self.hard_disk.is_some());
+        if let Some(ref hd) = self.hard_disk {
+            hd.save_state(state);
+        }
+        state.bool(self.ram_disk.is_some());
+        if let Some(ref ram_disk) = self.ram_disk {
+            ram_disk.save_state(state);
+        }
+        self.sio.save_state(state);
+        self.rtc.save_state(state);
+        self.scheduler.save_state(state);
+    }
+
+    /// Append the content hashes of the disks to a snapshot.
+    pub fn save_disk_hashes(&self, state: &mut StateWriter) {
+        self.floppy_controller.save_disk_hashes(state);
+        if let Some(ref hd) = self.hard_disk {
+            hd.save_disk_hashes(state);
+        }
+    }
+
+    /// Check that the disks are the ones the snapshot was taken with,
+    /// after `load_state`.
+    pub fn check_disk_hashes(&self, state: &mut StateReader) -> std::io::Result<()> {
+        self.floppy_controller.check_disk_hashes(state)?;
+        if let Some(ref hd) = self.hard_disk {
+            hd.check_disk_hashes(state)?;
+        }
+        Ok(())
+    }
+
+    /// Start journaling the disk writes after rewind point `point`; the
+    /// points before `oldest` are forgotten.
+    pub fn mark_rewind_point(&mut self, point: u64, oldest: u64) {
+        self.floppy_controller.mark_rewind_point(point, oldest);
+        if let Some(ref mut hd) = self.hard_disk {
+            hd.mark_rewind_point(point, oldest);
+        }
+    }
+
+    /// Undo the disk writes since rewind point `point`. The rest of the
+    /// machine comes back with `load_state`.
+    pub fn rewind_disks(&mut self, point: u64) {
+        self.floppy_controller.rewind(point);
+        if let Some(ref mut hd) = self.hard_disk {
+            hd.rewind(point);
+        }
+    }
+
+    pub fn forget_rewind_points(&mut self) {
+        self.floppy_controller.forget_rewind_points();
+        if let Some(ref mut hd) = self.hard_disk {
+            hd.forget_rewind_points();
+        }
+    }
+
+    /// Take the state of the machine and the devices from a snapshot.
+    /// It fails if the snapshot is of a machine with other devices.
+    pub fn load_state(&mut self, state: &mut StateReader) -> std::io::Result<()> {
+        state.bytes_into(&mut self.ram)?;
+        state.bytes_into(&mut self.vram)?;
+        self.system_bits = state.u8()?;
+        self.port14_raw = state.u8()?;
+        self.tstates = state.u64()?;
+        self.time_ps = state.u64()?;
+        self.nmi_signaled = false;
+        self.nmi_pending = state.bool()?;
+        self.sio_b_wr_select = state.u8()?;
+        self.sio_b_wr1 = state.u8()?;
+        self.sio_b_wr2 = state.u8()?;
+        self.sio_int_pending = state.bool()?;
+        self.sio_a_int_pending = state.bool()?;
+        self.port14_last_bit1 = state.bool()?;
+        self.advent_drive_select = state.u8()?;
+        self.advent_select_pending = state.bool()?;
+        self.sio_user_direct_rx = state.bool()?;
+        self.vram_dirty = true;
+        self.crtc.load_state(state)?;
+        self.floppy_controller.load_state(state)?;
+        match (state.bool()?, self.hard_disk.as_mut()) {
+            (true, Some(hd)) => hd.load_state(state)?,
+            (false, None) => {},
+            _ => return Err(snapshot::invalid("The hard disk controller doesn't match the snapshot".to_string())),
+        }
+        match (state.bool()?, self.ram_disk.as_mut()) {
+            (true, Some(ram_disk)) => ram_disk.load_state(state)?,
+            (false, None) => {},
+            _ => return Err(snapshot::invalid("The RAM disk card doesn't match the snapshot".to_string())),
+        }
+        self.sio.load_state(state)?;
+        self.rtc.load_state(state)?;
+        self.scheduler.load_state(state)?;
+        Ok(())
+    }
 }
 
 impl Machine for KayproMachine {
@@ -540,6 +950,7 @@ impl Machine for KayproMachine {
     }
 
     fn port_out(&mut self, address: u16, value: u8) {
+        self.sync_device_clocks();
 
         let port = address as u8 & 0b_1011_1111; // A7 enables decoder, A6 unused, A5 selects U26/U27
 
@@ -556,17 +967,29 @@ impl Machine for KayproMachine {
         // The board provides additional drive select lines beyond the 2
         // native lines on port 0x14, which is needed when port 0x14 bit 1
         // is shared with the WD1002-05 SASI reset.
+        // With a RAM disk card, port 0x8A latches both its track and the
+        // drive select.
         if self.advent_pio_enabled && port >= 0x88 && port <= 0x8B {
             match port {
-                0x88 => {} // PIO output latch — accepted, not used
+                0x88 => if let Some(ref mut ram_disk) = self.ram_disk {
+                    ram_disk.write_byte(value);
+                    self.cancel_advent_drive_select();
+                }, // Otherwise the PIO output latch — accepted, not used
+                0x89 => if let Some(ref mut ram_disk) = self.ram_disk {
+                    ram_disk.set_record(value);
+                },
                 0x8A => {
-                    if value >= 1 && value <= 2 {
-                        let drive = value - 1;
-                        self.floppy_controller.set_drive(drive);
-                        self.floppy_controller.set_motor(true);
+                    if let Some(ref mut ram_disk) = self.ram_disk {
+                        ram_disk.set_track(value);
+                    }
+                    self.advent_drive_select = value;
+                    if self.ram_disk.is_some() {
+                        self.advent_select_pending = true;
+                    } else {
+                        self.apply_advent_drive_select();
                     }
                 }
-                _ => {} // 0x89, 0x8B: accept and ignore
+                _ => {} // 0x8B: accept and ignore
             }
             return;
         }
@@ -578,6 +1001,9 @@ impl Machine for KayproMachine {
             return
         }
 
+        if self.advent_select_pending && (0x10..=0x13).contains(&port) {
+            self.apply_advent_drive_select();
+        }
         if self.trace_io && port != 0x1c && port != 0x14
             && (port as usize) < IO_PORT_NAMES.len() {
             println!("OUT(0x{:02x} '{}', 0x{:02x}): ", port, IO_PORT_NAMES[port as usize], value);
@@ -657,6 +1083,7 @@ impl Machine for KayproMachine {
     }
 
     fn port_in(&mut self, address: u16) -> u8 {
+        self.sync_device_clocks();
         let port = address as u8 & 0b_1011_1111; // A7 enables decoder, A6 unused, A5 selects U26/U27
 
         // WD1002-05 hard disk controller occupies ports 0x80-0x87
@@ -671,22 +1098,35 @@ impl Machine for KayproMachine {
         // Advent Personality/Decoder Board PIO reads
         if self.advent_pio_enabled && port >= 0x88 && port <= 0x8B {
             return match port {
-                0x88 => {
+                0x88 => match self.ram_disk {
+                    Some(ref mut ram_disk) => {
+                        let value = ram_disk.read_byte();
+                        self.cancel_advent_drive_select();
+                        value
+                    },
                     // PIO data port read: returns input pin state, NOT the
                     // output latch. Without an Advent RAM disk connected,
                     // input pins float high (0xFF). Returning written data
                     // (FIFO) would make TurboROM falsely detect a RAM disk.
-                    0xFF
-                }
+                    None => 0xFF,
+                },
                 0x8B => {
-                    // Status switch: bit 2 = 0 means drive present.
-                    // Return 0x00 (all drives present) — the ROM's probe
-                    // loop increments sltmsk until the switch says "no drive".
-                    // With 2 floppy drives, sltmsk reaches 2 then the 3rd
-                    // probe reads bit 2 = 1 (not present) and stops.
-                    // For simplicity, always return 0 (present). The probe
-                    // loop has its own max-drive limit (sltmsk == 4).
-                    0x00
+                    // Status switch: bit 2 = 0 means the drive selected on
+                    // port 0x8A is present. The ROM's probe loop selects
+                    // drives in turn until the switch says "no drive";
+                    // A and B are always there, C and D only when a disk
+                    // image is attached. Without a drive select (RAM disk
+                    // size probe) the switch reads 0. A RAM disk card adds
+                    // its size switches; with bit 7 of its track set, the
+                    // second bank, it alone drives bit 2.
+                    let switches = self.ram_disk.as_ref().map_or(0x00, |ram_disk| ram_disk.switches());
+                    let absent = match self.advent_drive_select {
+                        0 => 0x00,
+                        n if n & 0x80 != 0 && self.ram_disk.is_some() => 0x00,
+                        n if self.floppy_controller.drive_present(n - 1) => 0x00,
+                        _ => 0x04,
+                    };
+                    switches | absent
                 }
                 _ => 0x00, // 0x89, 0x8A
             };
@@ -697,6 +1137,9 @@ impl Machine for KayproMachine {
             }
             return 0x00
         }
+        if self.advent_select_pending && (0x10..=0x13).contains(&port) {
+            self.apply_advent_drive_select();
+        }
 
         let value = match port {
             // SIO-1 Channel A data register.
@@ -1,4 +1,5 @@
 use std::io::{Read, stdin};
+use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::Duration;
 
@@ -16,17 +17,22 @@ pub enum Command {
     TraceCPU,
     SaveMemory,
     SetSpeed,
+    SaveState,
+    LoadState,
+    Rewind,
+    Monitor,
 }
 
 pub struct Keyboard {
+    // Terminal settings to restore, None once a front end owns the terminal
     initial_termios: Option<Termios>,
     key_buffer: Vec<u8>,  // Buffer for queued keys
     pub commands: Vec<Command>,
     pub idle_polls: u32,      // Consecutive polls with no key available
     pub idle_sleep_enabled: bool, // false for headless/boot-test mode
-    pub gui_key_queue: Vec<u8>,
-    pub gui_command_queue: Vec<Command>,
-    pub gui_mode: bool,
+    // Keys sent by a front end on another thread. The host console isn't
+    // read when set.
+    key_input: Option<Receiver<u8>>,
 }
 
 impl Keyboard {
@@ -40,15 +46,22 @@ impl Keyboard {
             commands: Vec::<Command>::new(),
             idle_polls: 0,
             idle_sleep_enabled: true,
-            gui_key_queue: Vec::new(),
-            gui_command_queue: Vec::new(),
-            gui_mode: false,
+            key_input: None,
         };
 
         c.setup_host_terminal(false);
         c
     }
 
+    /// Take the keys from a front end on another thread instead of the host
+    /// console, and hand the terminal back to it as it was.
+    pub fn set_key_input(&mut self, keys: Receiver<u8>) {
+        if let Some(initial) = self.initial_termios.take() {
+            tcsetattr(STDIN_FD, TCSANOW, &initial).unwrap();
+        }
+        self.key_input = Some(keys);
+    }
+
     fn setup_host_terminal(&self, blocking: bool) {
         if let Some(mut initial) = self.initial_termios {
             initial.c_iflag &= !(IXON | ICRNL);
@@ -63,7 +76,7 @@ impl Keyboard {
         self.consume_input();
         if self.key_buffer.is_empty() {
             self.idle_polls = self.idle_polls.saturating_add(1);
-            if !self.gui_mode && self.idle_sleep_enabled {
+            if self.idle_sleep_enabled {
                 if self.idle_polls > 50_000 {
                     // Idle loop detected (e.g., CONIN polling with no input).
                     // Sleep 1ms to match real hardware polling rates and prevent
@@ -93,6 +106,12 @@ impl Keyboard {
         *self.key_buffer.first().unwrap_or(&0)
     }
 
+    /// Take the keys read from the host console, for a front end to send
+    /// to the emulation thread.
+    pub fn take_keys(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.key_buffer)
+    }
+
     pub fn read_line(&mut self) -> Option<String> {
         use std::io::Write;
         
@@ -133,9 +152,8 @@ impl Keyboard {
     }
 
     pub fn consume_input(&mut self) {
-        if self.gui_mode {
-            self.key_buffer.append(&mut self.gui_key_queue);
-            self.commands.append(&mut self.gui_command_queue);
+        if let Some(ref keys) = self.key_input {
+            self.key_buffer.extend(keys.try_iter());
             return;
         }
         let mut buf = [0;100];
@@ -178,6 +196,9 @@ impl Keyboard {
                 "OQ" | "Oq" => { // F2 (Linux, macOS application mode)
                     self.commands.push(Command::ShowStatus);
                 }
+                "OR" | "Or" | "[13~" => { // F3 (Linux, macOS application mode, rxvt)
+                    self.commands.push(Command::Monitor);
+                }
                 "OS" | "Os" => { // F4 (Linux, macOS application mode)
                     self.commands.push(Command::Quit);
                 }
@@ -196,6 +217,15 @@ impl Keyboard {
                 "[20~" | "Ow" => { // F9 (Linux, macOS application mode)
                     self.commands.push(Command::SetSpeed);
                 }
+                "[21~" => { // F10
+                    self.commands.push(Command::SaveState);
+                }
+                "[23~" => { // F11
+                    self.commands.push(Command::LoadState);
+                }
+                "[24~" => { // F12
+                    self.commands.push(Command::Rewind);
+                }
                 "[3~" => {
                     // "Delete" key mapped to "DEL"
                     self.key_buffer.push(0x7f);
@@ -1,3 +1,4 @@
+use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::Duration;
 
@@ -14,18 +15,23 @@ pub enum Command {
     TraceCPU,
     SaveMemory,
     SetSpeed,
+    SaveState,
+    LoadState,
+    Rewind,
+    Monitor,
 }
 
 pub struct Keyboard {
     stdin_handle: HANDLE,
-    original_mode: u32,
+    // Console mode to restore, None once a front end owns the console
+    original_mode: Option<u32>,
     key_buffer: Vec<u8>,
     pub commands: Vec<Command>,
     pub idle_polls: u32,      // Consecutive polls with no key available
     pub idle_sleep_enabled: bool, // false for headless/boot-test mode
-    pub gui_key_queue: Vec<u8>,
-    pub gui_command_queue: Vec<Command>,
-    pub gui_mode: bool,
+    // Keys sent by a front end on another thread. The host console isn't
+    // read when set.
+    key_input: Option<Receiver<u8>>,
 }
 
 impl Keyboard {
@@ -42,23 +48,32 @@ impl Keyboard {
 
             Keyboard {
                 stdin_handle,
-                original_mode,
+                original_mode: Some(original_mode),
                 key_buffer: Vec::new(),
                 commands: Vec::<Command>::new(),
                 idle_polls: 0,
                 idle_sleep_enabled: true,
-                gui_key_queue: Vec::new(),
-                gui_command_queue: Vec::new(),
-                gui_mode: false,
+                key_input: None,
             }
         }
     }
 
+    /// Take the keys from a front end on another thread instead of the host
+    /// console, and hand the console back to it as it was.
+    pub fn set_key_input(&mut self, keys: Receiver<u8>) {
+        if let Some(original_mode) = self.original_mode.take() {
+            unsafe {
+                SetConsoleMode(self.stdin_handle, original_mode);
+            }
+        }
+        self.key_input = Some(keys);
+    }
+
     pub fn is_key_pressed(&mut self) -> bool {
         self.consume_input();
         if self.key_buffer.is_empty() {
             self.idle_polls = self.idle_polls.saturating_add(1);
-            if !self.gui_mode && self.idle_sleep_enabled {
+            if self.idle_sleep_enabled {
                 if self.idle_polls > 50_000 {
                     // Idle loop detected (e.g., CONIN polling with no input).
                     // Sleep 1ms to match real hardware polling rates and prevent
@@ -88,6 +103,12 @@ impl Keyboard {
         *self.key_buffer.first().unwrap_or(&0)
     }
 
+    /// Take the keys read from the host console, for a front end to send
+    /// to the emulation thread.
+    pub fn take_keys(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.key_buffer)
+    }
+
     pub fn read_line(&mut self) -> Option<String> {
         use std::io::Write;
 
@@ -158,9 +179,8 @@ impl Keyboard {
     }
 
     pub fn consume_input(&mut self) {
-        if self.gui_mode {
-            self.key_buffer.append(&mut self.gui_key_queue);
-            self.commands.append(&mut self.gui_command_queue);
+        if let Some(ref keys) = self.key_input {
+            self.key_buffer.extend(keys.try_iter());
             return;
         }
         loop {
@@ -206,12 +226,16 @@ impl Keyboard {
             match vk as u32 {
                 VK_F1 => { self.commands.push(Command::Help); continue; }
                 VK_F2 => { self.commands.push(Command::ShowStatus); continue; }
+                VK_F3 => { self.commands.push(Command::Monitor); continue; }
                 VK_F4 => { self.commands.push(Command::Quit); continue; }
                 VK_F5 => { self.commands.push(Command::SelectDiskA); continue; }
                 VK_F6 => { self.commands.push(Command::SelectDiskB); continue; }
                 VK_F7 => { self.commands.push(Command::SaveMemory); continue; }
                 VK_F8 => { self.commands.push(Command::TraceCPU); continue; }
                 VK_F9 => { self.commands.push(Command::SetSpeed); continue; }
+                VK_F10 => { self.commands.push(Command::SaveState); continue; }
+                VK_F11 => { self.commands.push(Command::LoadState); continue; }
+                VK_F12 => { self.commands.push(Command::Rewind); continue; }
                 _ => {}
             }
 
@@ -240,8 +264,10 @@ impl Keyboard {
 
 impl Drop for Keyboard {
     fn drop(&mut self) {
-        unsafe {
-            SetConsoleMode(self.stdin_handle, self.original_mode);
+        if let Some(original_mode) = self.original_mode {
+            unsafe {
+                SetConsoleMode(self.stdin_handle, original_mode);
+            }
         }
     }
 }
@@ -255,9 +281,13 @@ const VK_LEFT: u32 = 0x25;
 const VK_RIGHT: u32 = 0x27;
 const VK_F1: u32 = 0x70;
 const VK_F2: u32 = 0x71;
+const VK_F3: u32 = 0x72;
 const VK_F4: u32 = 0x73;
 const VK_F5: u32 = 0x74;
 const VK_F6: u32 = 0x75;
 const VK_F7: u32 = 0x76;
 const VK_F8: u32 = 0x77;
 const VK_F9: u32 = 0x78;
+const VK_F10: u32 = 0x79;
+const VK_F11: u32 = 0x7A;
+const VK_F12: u32 = 0x7B;
@@ -1,34 +1,105 @@
-use clap::Parser;
-use iz80::*;
-use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand};
+use std::time::Duration;
 
 mod config;
+mod cpm_fs;
 mod kaypro_machine;
 mod floppy_controller;
+mod floppy_drive;
+mod gdb;
 mod hard_disk;
+mod hd_drive;
+mod hd_image;
+mod hd_partition;
+mod host_dir;
+mod imd;
+mod overlay;
+mod ram_disk;
+mod replay;
+mod rewind;
+mod td0;
 #[cfg(unix)]
 mod keyboard_unix;
 #[cfg(windows)]
 mod keyboard_win;
 mod media;
+mod monitor;
 mod screen;
 mod rtc;
+mod scheduler;
 mod sio;
+mod snapshot;
 mod sy6545;
+mod z80_timing;
 mod diagnostics;
+mod disassembler;
+mod emulator;
 #[cfg(feature = "gui")]
 mod renderer;
 #[cfg(test)]
 mod format_test;
+#[cfg(test)]
+mod cpm_fs_test;
+#[cfg(test)]
+mod advent_test;
+#[cfg(test)]
+mod disassembler_test;
+#[cfg(test)]
+mod emulator_test;
+#[cfg(test)]
+mod fault_test;
+#[cfg(test)]
+mod floppy_drive_test;
+#[cfg(test)]
+mod gdb_test;
+#[cfg(test)]
+mod hd_fault_test;
+#[cfg(test)]
+mod hd_geometry_test;
+#[cfg(test)]
+mod hd_image_test;
+#[cfg(test)]
+mod hd_partition_test;
+#[cfg(test)]
+mod hd_units_test;
+#[cfg(test)]
+mod host_dir_test;
+#[cfg(test)]
+mod imd_test;
+#[cfg(test)]
+mod monitor_test;
+#[cfg(test)]
+mod overlay_test;
+#[cfg(test)]
+mod ram_disk_test;
+#[cfg(test)]
+mod replay_test;
+#[cfg(test)]
+mod rewind_test;
+#[cfg(test)]
+mod rtc_test;
+#[cfg(test)]
+mod scheduler_test;
+#[cfg(test)]
+mod snapshot_test;
+#[cfg(test)]
+mod td0_test;
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod z80_timing_test;
 
 use self::config::{Config, KayproModel, resolve_path};
+use self::emulator::{Emulator, EmulatorHandle, Frame, GuestTrace, Request, Update};
 use self::kaypro_machine::KayproMachine;
-use self::floppy_controller::FloppyController;
+use self::floppy_controller::{Drive, FloppyController};
+use self::floppy_drive::DriveType;
+use self::hd_drive::HdGeometry;
 use self::screen::Screen;
 #[cfg(unix)]
-use self::keyboard_unix::Command;
+use self::keyboard_unix::{Command, Keyboard};
 #[cfg(windows)]
-use self::keyboard_win::Command;
+use self::keyboard_win::{Command, Keyboard};
 
 #[derive(Parser)]
 #[command(
@@ -45,18 +116,54 @@ struct Cli {
     #[arg(short = 'm', long, value_name = "MODEL")]
     model: Option<String>,
 
-    /// Disk image file for drive A
+    /// Disk image file for drive A (or dir:<path> to mount a host directory)
     #[arg(short = 'a', long, value_name = "FILE")]
     drivea: Option<String>,
 
-    /// Disk image file for drive B
+    /// Disk image file for drive B (or dir:<path> to mount a host directory)
     #[arg(short = 'b', long, value_name = "FILE")]
     driveb: Option<String>,
 
-    /// Hard disk image file for WD1002 models (creates blank image if file doesn't exist)
+    /// Drive A mechanism: 40ds, 80ds, 40ss, ... with ,48tpi ,96tpi ,double-step (default: follows the media)
+    #[arg(long, value_name = "TYPE")]
+    drivea_type: Option<String>,
+
+    /// Drive B mechanism, as --drivea-type
+    #[arg(long, value_name = "TYPE")]
+    driveb_type: Option<String>,
+
+    /// Disk image for a third floppy drive on the Advent board (TurboROM models)
+    #[arg(long, value_name = "FILE")]
+    drivec: Option<String>,
+
+    /// Disk image for a fourth floppy drive on the Advent board (TurboROM models)
     #[arg(long, value_name = "FILE")]
+    drived: Option<String>,
+
+    /// Advent RAM disk card: 256K, 512K, 1M, 1.25M, 1.5M, 1.75M, 2M or none (TurboROM models)
+    #[arg(long, value_name = "SIZE")]
+    ram_disk: Option<String>,
+
+    /// Keep the RAM disk contents in a file across runs
+    #[arg(long, value_name = "FILE")]
+    ram_disk_file: Option<String>,
+
+    /// Hard disk image file for WD1002 models (creates blank image if file doesn't exist)
+    #[arg(long, visible_alias = "hd1", value_name = "FILE")]
     hd: Option<String>,
 
+    /// Image file for a second hard disk, on drive select 2 (TurboROM)
+    #[arg(long, value_name = "FILE")]
+    hd2: Option<String>,
+
+    /// Hard disk drive: st506 (5MB), st412 (10MB), st225 (20MB) or cylinders/heads/sectors
+    #[arg(long, value_name = "GEOMETRY")]
+    hd_geometry: Option<String>,
+
+    /// Copy-on-write: keep disk images untouched and write changes to <image>.overlay
+    #[arg(long)]
+    overlay: bool,
+
     /// Custom ROM file (implies --model=custom)
     #[arg(long, value_name = "FILE")]
     rom: Option<String>,
@@ -65,6 +172,37 @@ struct Cli {
     #[arg(long, value_name = "MHZ")]
     speed: Option<f64>,
 
+    /// Resume the machine from a snapshot saved with F10
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<String>,
+
+    /// Record the keys, disk inserts and commands of the session to a replay file
+    /// (implies --deterministic)
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+
+    /// Replay a session recorded with --record (start with the same options;
+    /// implies --deterministic)
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<String>,
+
+    /// Run on emulated time only so the same inputs give the same output:
+    /// no pacing to the host clock, no idle sleeps, RTC from --start-date
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Date the RTC starts at in deterministic runs, YYYY-MM-DD[THH:MM[:SS]] (default: 1984-01-01)
+    #[arg(long, value_name = "DATE")]
+    start_date: Option<String>,
+
+    /// Start stopped in the machine-language monitor (F3 enters it later)
+    #[arg(long, conflicts_with = "replay")]
+    debug: bool,
+
+    /// Serve a GDB remote debugger on this TCP port of localhost
+    #[arg(long, value_name = "PORT", conflicts_with = "replay")]
+    gdb: Option<u16>,
+
     /// Trace CPU instruction execution
     #[arg(short = 'c', long)]
     cpu_trace: bool,
@@ -152,6 +290,311 @@ struct Cli {
     /// Override phosphor dim color (hex, e.g. "#1A801A")
     #[arg(long, value_name = "HEX")]
     phosphor_dim: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Work with the CP/M files inside a floppy disk image
+    Disk {
+        #[command(subcommand)]
+        action: DiskAction,
+    },
+    /// Work with the CP/M files on a Kaypro 10 or TurboROM hard disk image
+    Hd {
+        /// Partition (drive letter) to work on; `ls` lists all by default
+        #[arg(long, short)]
+        partition: Option<char>,
+        #[command(subcommand)]
+        action: HdAction,
+    },
+    /// Create a blank CP/M floppy image (written as IMD if OUT ends in .imd)
+    Mkdisk {
+        /// Geometry preset, e.g. kaypro_dsdd (see the error for the full list)
+        #[arg(long)]
+        geometry: String,
+        /// Copy the reserved system tracks from this boot disk
+        #[arg(long, value_name = "IMG")]
+        system_from: Option<String>,
+        out: String,
+    },
+    /// Merge or drop the changes recorded by --overlay
+    Overlay {
+        #[command(subcommand)]
+        action: OverlayAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum OverlayAction {
+    /// Write the overlay changes into the image and delete the overlay
+    Commit {
+        image: String,
+    },
+    /// Delete the overlay, keeping the image as it was
+    Discard {
+        image: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum HdAction {
+    #[command(flatten)]
+    Files(DiskAction),
+    /// Convert a raw image to a .khd container, or a container back to a raw image
+    Convert {
+        image: String,
+        out: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DiskAction {
+    /// List the CP/M directory
+    Ls {
+        image: String,
+    },
+    /// Copy a file out of the image (NAME is [user:]NAME.EXT)
+    Get {
+        image: String,
+        name: String,
+        /// Host file to write (default: NAME.EXT in the current directory)
+        dest: Option<String>,
+    },
+    /// Copy a host file into the image, replacing any file with the same name
+    Put {
+        image: String,
+        source: String,
+        /// CP/M name as [user:]NAME.EXT (default: the host file name)
+        name: Option<String>,
+    },
+    /// Delete a file from the image
+    Rm {
+        image: String,
+        name: String,
+    },
+}
+
+fn run_disk_command(action: &DiskAction, side1_sector_base: u8) -> std::io::Result<()> {
+    match action {
+        DiskAction::Ls { image } => {
+            let fs = cpm_fs::open_image(image, side1_sector_base)?;
+            let mut title = fs.volume.media.info();
+            if let Some(ref image) = fs.volume.media.imd {
+                let comment = image.comment();
+                if !comment.is_empty() {
+                    title = format!("{}\n{}", title, comment);
+                }
+            }
+            cpm_fs::print_listing(&fs, &title);
+        }
+        DiskAction::Get { image, name, dest } => {
+            let fs = cpm_fs::open_image(image, side1_sector_base)?;
+            let name = cpm_fs::CpmName::parse(name)?;
+            let data = fs.read_file(&name)?;
+            let dest = dest.clone().unwrap_or_else(|| name.file_name());
+            std::fs::write(&dest, &data)?;
+            println!("{} -> {} ({} bytes)", name, dest, data.len());
+        }
+        DiskAction::Put { image, source, name } => {
+            let mut fs = cpm_fs::open_image(image, side1_sector_base)?;
+            let name = match name {
+                Some(n) => n.clone(),
+                None => std::path::Path::new(source).file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            let name = cpm_fs::CpmName::parse(&name)?;
+            let data = std::fs::read(source)?;
+            fs.write_file(&name, &data)?;
+            cpm_fs::flush_image(&mut fs)?;
+            println!("{} -> {} ({} bytes)", source, name, data.len());
+        }
+        DiskAction::Rm { image, name } => {
+            let mut fs = cpm_fs::open_image(image, side1_sector_base)?;
+            let name = cpm_fs::CpmName::parse(name)?;
+            fs.delete(&name)?;
+            cpm_fs::flush_image(&mut fs)?;
+            println!("Deleted {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn run_hd_command(action: &DiskAction, partition: Option<char>, geometry: HdGeometry) -> std::io::Result<()> {
+    let index = match partition.map(|p| p.to_ascii_uppercase()) {
+        Some(p @ 'A'..='P') => Some(p as usize - 'A' as usize),
+        Some(p) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            format!("Invalid partition '{}'", p))),
+        None => None,
+    };
+    let open = |image: &str| hd_partition::open_partition(image, geometry, index.unwrap_or(0));
+    match action {
+        DiskAction::Ls { image } => {
+            let partitions = match index {
+                Some(_) => vec![open(image)?],
+                None => hd_partition::open_partitions(image, geometry)?,
+            };
+            for (i, fs) in partitions.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                let title = format!("{}: {} partition {}, {}", image, fs.volume.layout.name(),
+                    hd_partition::partition_letter(fs.volume.partition), fs.volume.geometry.label());
+                cpm_fs::print_listing(fs, &title);
+            }
+        }
+        DiskAction::Get { image, name, dest } => {
+            let fs = open(image)?;
+            let name = cpm_fs::CpmName::parse(name)?;
+            let data = fs.read_file(&name)?;
+            let dest = dest.clone().unwrap_or_else(|| name.file_name());
+            std::fs::write(&dest, &data)?;
+            println!("{} -> {} ({} bytes)", name, dest, data.len());
+        }
+        DiskAction::Put { image, source, name } => {
+            let mut fs = open(image)?;
+            let name = match name {
+                Some(n) => n.clone(),
+                None => std::path::Path::new(source).file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            let name = cpm_fs::CpmName::parse(&name)?;
+            let data = std::fs::read(source)?;
+            fs.write_file(&name, &data)?;
+            hd_partition::flush_partition(&fs)?;
+            println!("{} -> {} ({} bytes)", source, name, data.len());
+        }
+        DiskAction::Rm { image, name } => {
+            let mut fs = open(image)?;
+            let name = cpm_fs::CpmName::parse(name)?;
+            fs.delete(&name)?;
+            hd_partition::flush_partition(&fs)?;
+            println!("Deleted {}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Convert between a raw hard disk image of a `geometry` drive and a
+/// container. A raw image converted to a container is taken to be formatted
+/// as far as it goes, and tagged with `model` or else the layout found on it.
+fn run_hd_convert(image: &str, out: &str, geometry: HdGeometry, model: Option<&str>) -> std::io::Result<()> {
+    if std::path::Path::new(out).exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", out)));
+    }
+    let data = std::fs::read(image)?;
+    if hd_image::is_container(&data) {
+        let (metadata, sectors) = hd_image::parse(&data)?;
+        std::fs::write(out, sectors)?;
+        let unformatted = metadata.track_formatted.iter().filter(|&&f| !f).count();
+        println!("{} -> {} (raw image, {})", image, out, metadata.geometry.label());
+        if unformatted > 0 || !metadata.bad_blocks.is_empty() {
+            println!("Dropped {} unformatted tracks and {} bad blocks, which raw images can't hold",
+                unformatted, metadata.bad_blocks.len());
+        }
+    } else {
+        let sectors = hd_partition::read_image(image, &geometry)?;
+        let model = model.unwrap_or(match hd_partition::HdLayout::detect(&sectors) {
+            hd_partition::HdLayout::Kaypro10 => "kaypro10",
+            hd_partition::HdLayout::TurboRom => "turbo_rom_hd",
+        });
+        let mut metadata = hd_image::HdMetadata::new(geometry, model);
+        let track_size = geometry.track_size();
+        for (track, formatted) in metadata.track_formatted.iter_mut().enumerate() {
+            *formatted = (track + 1) * track_size <= data.len();
+        }
+        std::fs::write(out, hd_image::encode(&metadata, &sectors))?;
+        println!("{} -> {} (container for {}, {})", image, out, model, geometry.label());
+    }
+    Ok(())
+}
+
+/// Point out a hard disk container made for the Kaypro 10 used with
+/// TurboROM or the other way round: they lay out the drive differently.
+fn warn_hd_model(hd: &hard_disk::HardDisk, lun: u8, path: &str, model: &str) {
+    match hd.unit(lun).and_then(|unit| unit.model()) {
+        Some(hint) if !hint.is_empty() && (hint == "kaypro10") != (model == "kaypro10") => {
+            eprintln!("Warning: {} was made for the {} model, not {}", path, hint, model);
+        }
+        _ => {}
+    }
+}
+
+fn run_mkdisk_command(geometry: &str, system_from: Option<&str>, out: &str,
+        side1_sector_base: u8) -> std::io::Result<()> {
+    let geometry = cpm_fs::geometry_by_name(geometry).ok_or_else(||
+        std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            format!("Unknown geometry '{}', use one of: {}", geometry,
+                cpm_fs::geometry_names().join(", "))))?;
+    if std::path::Path::new(out).exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", out)));
+    }
+    let source = match system_from {
+        Some(path) => {
+            let mut source = media::Media::new(side1_sector_base);
+            source.load_disk(path)?;
+            Some(source)
+        }
+        None => None,
+    };
+    let disk = cpm_fs::make_disk(geometry, source.as_ref())?;
+    if out.to_ascii_lowercase().ends_with(".imd") {
+        std::fs::write(out, disk.to_imd().to_bytes())?;
+    } else {
+        std::fs::write(out, &disk.content)?;
+        let detected = media::auto_detect_geometry(&disk.content, geometry.side1_sector_id_base);
+        if !detected.is_some_and(|g| g.same_layout(&geometry)) {
+            eprintln!("Note: a blank raw {} image has the same size as {}; \
+                use an .imd file name to keep the geometry",
+                geometry.label, detected.map_or("another format", |g| g.label));
+        }
+    }
+    println!("{}: {}{}", out, geometry.label,
+        if source.is_some() { ", system tracks copied" } else { "" });
+    Ok(())
+}
+
+fn run_overlay_command(action: &OverlayAction, side1_sector_base: u8) -> std::io::Result<()> {
+    let (OverlayAction::Commit { image } | OverlayAction::Discard { image }) = action;
+    let path = overlay::sidecar_path(image);
+    if !std::path::Path::new(&path).exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("No overlay {}", path)));
+    }
+    match action {
+        OverlayAction::Commit { image } => {
+            let block_size = overlay::Overlay::stored_block_size(image)?;
+            let mut delta = overlay::Overlay::open(image, block_size)?;
+            let blocks = delta.len();
+            let content = std::fs::read(image)?;
+            if imd::is_imd(&content) {
+                // Sector data has to be re-encoded into the IMD tracks
+                let mut media = media::Media::new(side1_sector_base);
+                media.load_disk(image)?;
+                media.commit_overlay(&mut delta)?;
+            } else {
+                if hd_image::is_container(&content) {
+                    hd_image::commit_overlay(image, &delta)?;
+                } else {
+                    // A raw image has no room for the formatted tracks
+                    delta.commit_raw(image, 0)?;
+                }
+                delta.discard()?;
+            }
+            println!("Committed {} blocks of {} bytes into {}", blocks, block_size, image);
+        }
+        OverlayAction::Discard { .. } => {
+            std::fs::remove_file(&path)?;
+            println!("Deleted {}", path);
+        }
+    }
+    Ok(())
 }
 
 fn main() {
@@ -165,8 +608,65 @@ fn main() {
         cli.drivea.as_deref(),
         cli.driveb.as_deref(),
     );
+    if cli.drivea_type.is_some() {
+        config.drive_a_type = cli.drivea_type.clone();
+    }
+    if cli.driveb_type.is_some() {
+        config.drive_b_type = cli.driveb_type.clone();
+    }
+    if cli.drivec.is_some() {
+        config.disk_c = cli.drivec.clone();
+    }
+    if cli.drived.is_some() {
+        config.disk_d = cli.drived.clone();
+    }
+    if cli.ram_disk.is_some() {
+        config.ram_disk = cli.ram_disk.clone();
+    }
+    if cli.hd_geometry.is_some() {
+        config.hd_geometry = cli.hd_geometry.clone();
+    }
+    if cli.ram_disk_file.is_some() {
+        config.ram_disk_file = cli.ram_disk_file.clone();
+    }
+
+    if let Some(CliCommand::Disk { ref action }) = cli.command {
+        if let Err(e) = run_disk_command(action, config.get_side1_sector_base()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(CliCommand::Hd { partition, ref action }) = cli.command {
+        let result = HdGeometry::parse(config.get_hd_geometry())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+            .and_then(|geometry| match action {
+                HdAction::Files(action) => run_hd_command(action, partition, geometry),
+                HdAction::Convert { image, out } => run_hd_convert(image, out, geometry, cli.model.as_deref()),
+            });
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(CliCommand::Mkdisk { ref geometry, ref system_from, ref out }) = cli.command {
+        if let Err(e) = run_mkdisk_command(geometry, system_from.as_deref(), out,
+                config.get_side1_sector_base()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(CliCommand::Overlay { ref action }) = cli.command {
+        if let Err(e) = run_overlay_command(action, config.get_side1_sector_base()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let welcome = format!(
+    let mut welcome = format!(
         "izkaypro - Kaypro Emulator\nhttps://github.com/ivanizag/izkaypro\nConfiguration: {}",
         config.get_description()
     );
@@ -189,7 +689,7 @@ fn main() {
     };
 
     let has_trace_log = cli.trace_log.is_some();
-    let mut trace_cpu = cli.cpu_trace || cli.trace_all;
+    let trace_cpu = cli.cpu_trace || cli.trace_all;
     let trace_io = cli.io_trace || cli.trace_all;
     let trace_fdc = cli.fdc_trace || cli.trace_all || has_trace_log;
     let trace_fdc_rw = cli.fdc_trace_rw || cli.trace_all || has_trace_log;
@@ -212,7 +712,7 @@ fn main() {
     let has_hard_disk = config.model == KayproModel::Kaypro10
         || config.model == KayproModel::TurboRomHd
         || config.model == KayproModel::Ultimate
-        || (config.model == KayproModel::TurboRom && cli.hd.is_some());
+        || (config.model == KayproModel::TurboRom && (cli.hd.is_some() || cli.hd2.is_some()));
 
     // When --trace-log is used, traces go to a file and don't affect screen rendering.
     // Only count traces that go to stdout/stderr as "any_trace".
@@ -229,7 +729,7 @@ fn main() {
         || trace_system_bits;
 
     // Init device with configuration
-    let floppy_controller = FloppyController::new(
+    let mut floppy_controller = FloppyController::new(
         &disk_a_path,
         &disk_b_path,
         config.get_disk_format(),
@@ -237,6 +737,46 @@ fn main() {
         trace_fdc,
         trace_fdc_rw,
     );
+    for (drive, spec) in [(Drive::A, &config.drive_a_type), (Drive::B, &config.drive_b_type)] {
+        if let Some(spec) = spec {
+            match DriveType::parse(spec) {
+                Ok(drive_type) => floppy_controller.set_drive_type(drive, Some(drive_type)),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    // Drives C and D hang off the Advent personality board, which only
+    // TurboROM supports.
+    let turbo_rom = config.model == KayproModel::TurboRom
+        || config.model == KayproModel::TurboRomHd
+        || config.model == KayproModel::Ultimate;
+    let mut advent_drives = false;
+    for (drive, path) in [(Drive::C, &config.disk_c), (Drive::D, &config.disk_d)] {
+        let path = match path {
+            Some(path) => resolve_path(path),
+            None => continue,
+        };
+        if !turbo_rom {
+            eprintln!("Warning: '{}' ignored, drives C and D need a TurboROM model", path);
+            continue;
+        }
+        if let Err(e) = floppy_controller.attach_drive(drive, &path) {
+            eprintln!("Error: Could not load disk image '{}': {}", path, e);
+            std::process::exit(1);
+        }
+        advent_drives = true;
+    }
+    if cli.overlay {
+        for drive in [Drive::A, Drive::B, Drive::C, Drive::D] {
+            let media = floppy_controller.media_mut(drive);
+            if let Err(e) = media.enable_overlay() {
+                eprintln!("Warning: Failed to open overlay for '{}': {}", media.name, e);
+            }
+        }
+    }
     let mut screen = Screen::new(!any_trace, config.get_display_name(), cli.no_border);
     let mut machine = KayproMachine::new(
         &resolve_path(config.get_rom_path()),
@@ -252,10 +792,34 @@ fn main() {
         trace_hdc,
     );
 
-    // TurboROM+HD: only LUN 1 should report READY. The ROM probes LUN 2
-    // as well, but reporting it READY with the same backing image causes
-    // the ROM to see two identical drives (4 partitions instead of 2).
-    // LUN 1 only (the default) is correct for a single-drive setup.
+    if advent_drives {
+        machine.advent_pio_enabled = true;
+    }
+    if config.ram_disk.as_deref().is_some_and(|size| size != "none") && config.get_ram_disk_size().is_none() {
+        eprintln!("Warning: RAM disk ignored, the Advent card needs a TurboROM model");
+    }
+    if let Some(size) = config.get_ram_disk_size() {
+        let mut ram_disk = match ram_disk::parse_size(size) {
+            Ok(size) => ram_disk::RamDisk::new(size),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(ref path) = config.ram_disk_file {
+            if let Err(e) = ram_disk.load_file(path) {
+                eprintln!("Error: Could not open RAM disk file '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        machine.ram_disk = Some(ram_disk);
+        machine.advent_pio_enabled = true;
+    }
+
+    // TurboROM+HD: only LUN 1 should report READY unless --hd2 gives
+    // LUN 2 its own image. The ROM probes LUN 2 as well, and reporting it
+    // READY with the same backing image would make it see two identical
+    // drives (4 partitions instead of 2).
 
     machine.kayplus_clock_fixup = config.model == KayproModel::KayPlus84;
 
@@ -281,14 +845,36 @@ fn main() {
         .or_else(|| config.get_default_hd_path().map(|s| resolve_path(s)));
     if let Some(ref hd_path) = hd_path {
         if let Some(ref mut hd) = machine.hard_disk {
+            hd.use_overlay = cli.overlay;
+            hd.set_model(config.get_model_name());
+            match HdGeometry::parse(config.get_hd_geometry()) {
+                Ok(geometry) => hd.set_geometry(geometry),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
             match hd.load_image(hd_path) {
-                Ok(()) => {},
+                Ok(()) => warn_hd_model(hd, 1, hd_path, config.get_model_name()),
                 Err(e) => eprintln!("Warning: Failed to load hard disk image '{}': {}", hd_path, e),
             }
         } else {
             eprintln!("Warning: --hd specified but model doesn't support hard disk (use --model kaypro10|turbo_rom_hd|turbo_rom)");
         }
     }
+    if let Some(ref hd2_path) = cli.hd2 {
+        if let Some(ref mut hd) = machine.hard_disk {
+            if config.model == KayproModel::Kaypro10 {
+                eprintln!("Warning: the Kaypro 10 ROM only boots from the first hard disk");
+            }
+            match hd.load_unit(2, hd2_path) {
+                Ok(()) => warn_hd_model(hd, 2, hd2_path, config.get_model_name()),
+                Err(e) => eprintln!("Warning: Failed to load hard disk image '{}': {}", hd2_path, e),
+            }
+        } else {
+            eprintln!("Warning: --hd2 specified but model doesn't support hard disk (use --model turbo_rom_hd|turbo_rom)");
+        }
+    }
 
     // Set up trace log file(s). ROM/BDOS traces go to the specified file;
     // HDC register-level traces go to a companion file with "-hdc" suffix
@@ -337,13 +923,23 @@ fn main() {
         trace_log = Some(f);
     }
 
-    let mut cpu = Cpu::new_z80();
-    cpu.set_trace(trace_cpu);
+    // Start of the virtual clock of the RTC in deterministic runs. A session
+    // is recorded and replayed on emulated time only.
+    let deterministic = cli.deterministic || cli.record.is_some() || cli.replay.is_some();
+    if cli.start_date.is_some() && !deterministic {
+        eprintln!("Error: --start-date needs --deterministic, --record or --replay");
+        std::process::exit(1);
+    }
+    let start_secs = rtc::parse_date(cli.start_date.as_deref().unwrap_or(rtc::DEFAULT_START_DATE))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
 
     // Run boot tests if requested
     if run_boot_test {
         println!("Running boot tests for all Kaypro models...\n");
-        let results = diagnostics::run_boot_tests();
+        let results = diagnostics::run_boot_tests(deterministic.then_some(start_secs));
         diagnostics::print_results(&results);
         let all_passed = results.iter().all(|r| r.passed);
         std::process::exit(if all_passed { 0 } else { 1 });
@@ -373,6 +969,60 @@ fn main() {
         }
     }
 
+    // Clock speed control: None = unlimited, Some(mhz) = fixed speed
+    let clock_mhz: Option<f64> = cli.speed.and_then(|mhz| {
+        if mhz < 0.0 {
+            None
+        } else if mhz >= 1.0 && mhz <= 100.0 {
+            Some((mhz * 2.0).round() / 2.0)
+        } else {
+            eprintln!("Warning: --speed must be 1-100 MHz, ignoring");
+            None
+        }
+    });
+    let mut emulator = Emulator::new(machine, clock_mhz, is_kaypro10_hardware);
+    if deterministic {
+        emulator.set_deterministic(start_secs);
+    }
+    if trace_rom || trace_bdos {
+        emulator.guest_trace = Some(GuestTrace::new(trace_rom, trace_bdos, trace_log));
+    }
+    if let Some(ref path) = cli.load_state {
+        if let Err(e) = emulator.load_state(path) {
+            eprintln!("Failed to load state '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(ref path) = cli.record {
+        if let Err(e) = emulator.record(path) {
+            eprintln!("Failed to create replay '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(ref path) = cli.replay {
+        if let Err(e) = emulator.replay(path) {
+            eprintln!("Failed to load replay '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if cli.debug {
+        emulator.debug();
+    }
+    let gdb_server = cli.gdb.map(|port| {
+        match gdb::Server::bind(port) {
+            Ok(server) => {
+                if let Ok(address) = server.local_addr() {
+                    welcome.push_str(&format!("\nGDB server on {}", address));
+                }
+                server
+            },
+            Err(e) => {
+                eprintln!("Failed to listen for GDB on port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
     // Chargen mode: launch graphical window instead of terminal rendering
     #[cfg(feature = "gui")]
     if cli.chargen {
@@ -399,7 +1049,8 @@ fn main() {
             }
         }
         println!("{}", welcome);
-        run_gui(&config, machine, cpu, trace_cpu, is_kaypro10_hardware, cli.speed, screen.floppy_drive_labels, phosphor);
+        run_gui(&config, spawn(emulator, trace_cpu, gdb_server), trace_cpu, is_kaypro10_hardware, clock_mhz,
+            screen.floppy_drive_labels, phosphor);
         return;
     }
     #[cfg(not(feature = "gui"))]
@@ -408,90 +1059,115 @@ fn main() {
         std::process::exit(1);
     }
 
-    // Start the cpu
     println!("{}", welcome);
     screen.init();
+    run_terminal(spawn(emulator, trace_cpu, gdb_server), screen, trace_cpu, any_trace,
+        is_kaypro10_hardware, clock_mhz);
+}
 
-    let instructions_per_refresh = if any_trace {256*1024} else {2*1024};
+/// Start the emulation thread, and the GDB server that talks to it.
+fn spawn(emulator: Emulator, trace_cpu: bool, gdb_server: Option<gdb::Server>) -> EmulatorHandle {
+    let emulator = emulator.spawn(trace_cpu);
+    if let Some(server) = gdb_server {
+        server.serve(emulator.requests.clone());
+    }
+    emulator
+}
 
-    // Clock speed control: None = unlimited, Some(mhz) = fixed speed
-    // Average ~4 T-states per instruction, so cycles_per_sec = mhz * 1_000_000
-    let mut clock_mhz: Option<f64> = cli.speed.and_then(|mhz| {
-        if mhz < 0.0 {
-            None
-        } else if mhz >= 1.0 && mhz <= 100.0 {
-            Some((mhz * 2.0).round() / 2.0)
-        } else {
-            eprintln!("Warning: --speed must be 1-100 MHz, ignoring");
-            None
-        }
-    });
-    let mut cycle_count: u64 = 0;
-    let mut speed_start_time = Instant::now();
-    const CYCLES_PER_INSTRUCTION: u64 = 4; // Average Z80 cycles per instruction
+/// Terminal front end: draws the frames of the emulation thread on the
+/// console and sends it the keys and commands.
+fn run_terminal(
+    emulator: EmulatorHandle,
+    mut screen: Screen,
+    mut trace_cpu: bool,
+    any_trace: bool,
+    is_kaypro10_hardware: bool,
+    mut clock_mhz: Option<f64>,
+) {
+    let mut keyboard = Keyboard::new();
+    let Ok(Update::Frame(mut frame)) = emulator.updates.recv() else {
+        emulator.stop();
+        return;
+    };
+    screen.update(&frame, true);
 
-    let mut counter: u64 = 1;
-    let mut nmi_pending = false;
-    let mut nmi_deadline: u64 = 0;
     let mut done = false;
-    // Runtime BIOS base discovery for universal ROM tracing
-    let mut bios_base: Option<u16> = None;
-    let mut last_rom_rank = true; // Start in ROM mode
     while !done {
-
-        cpu.execute_instruction(&mut machine);
-        counter += 1;
-        cycle_count += CYCLES_PER_INSTRUCTION;
-
-        // KayPLUS software clock fixup: intercept the BIOS tick routine
-        // at 0x069E (start of the seconds/minutes/hours increment loop).
-        // Patch RAM counters with real RTC time and skip past the loop
-        // so the display code at 0x06CE reads accurate values.
-        if machine.kayplus_clock_fixup
-            && machine.is_rom_rank()
-            && cpu.registers().pc() == 0x069E
-        {
-            machine.patch_software_clock();
-            cpu.registers().set_pc(0x06CE);
-        }
-
-        // Clock speed throttling
-        if let Some(mhz) = clock_mhz {
-            let target_cycles_per_sec = (mhz * 1_000_000.0) as u64;
-            let elapsed = speed_start_time.elapsed();
-            let expected_cycles = (elapsed.as_secs_f64() * target_cycles_per_sec as f64) as u64;
-            
-            if cycle_count > expected_cycles {
-                // We're running too fast, need to wait
-                let cycles_ahead = cycle_count - expected_cycles;
-                let wait_secs = cycles_ahead as f64 / target_cycles_per_sec as f64;
-                if wait_secs > 0.0001 {
-                    std::thread::sleep(Duration::from_secs_f64(wait_secs));
-                }
+        let mut new_frame = false;
+        let mut messages = Vec::new();
+        let mut halted = false;
+        let mut stopped = None;
+        let mut update = emulator.updates.recv_timeout(Duration::from_millis(10)).ok();
+        while let Some(next) = update {
+            match next {
+                Update::Frame(mut next_frame) => {
+                    // Keep the VRAM changes of a frame that wasn't drawn
+                    next_frame.vram_dirty |= new_frame && frame.vram_dirty;
+                    frame = next_frame;
+                    new_frame = true;
+                },
+                Update::DiskLoaded(_, _, result) => {
+                    if let Err(err) = result {
+                        messages.push(err);
+                    }
+                },
+                Update::BiosSaved(result) => messages.push(match result {
+                    Ok(filename) => format!("BIOS saved as {}", filename),
+                    Err(err) => format!("Error: {}", err),
+                }),
+                Update::StateSaved(result) => messages.push(match result {
+                    Ok(filename) => format!("State saved as {}", filename),
+                    Err(err) => format!("Error: {}", err),
+                }),
+                Update::StateLoaded(_, result) => {
+                    if let Err(err) = result {
+                        messages.push(format!("Error: {}", err));
+                    }
+                },
+                Update::Rewound(result) => messages.push(match result {
+                    Ok(message) => message,
+                    Err(err) => format!("Error: {}", err),
+                }),
+                Update::ReplayFinished(result) => messages.push(match result {
+                    Ok(()) => "Replay finished".to_string(),
+                    Err(err) => format!("Replay finished: {}", err),
+                }),
+                Update::Halted => halted = true,
+                Update::Stopped(registers) => stopped = Some(registers),
+                // Only while the monitor console is open
+                Update::Monitor(_) | Update::Resumed => {},
             }
-            
-            // Reset counters periodically to avoid drift
-            if elapsed.as_secs() >= 1 {
-                speed_start_time = Instant::now();
-                cycle_count = 0;
+            update = emulator.updates.try_recv().ok();
+        }
+        if new_frame {
+            screen.update(&frame, false);
+        }
+        for message in messages {
+            screen.message(&mut keyboard, &frame, &message);
+        }
+        if halted {
+            screen.update(&frame, true);
+            println!("HALT instruction that will never be interrupted");
+            break;
+        }
+        if let Some(registers) = stopped {
+            if !monitor_console(&emulator, &mut keyboard, &mut frame, &registers) {
+                break;
             }
+            // Back to the screen, under the console
+            screen.init();
+            screen.update(&frame, true);
         }
 
-        // IO refresh
-        if counter % instructions_per_refresh == 0 {
-            machine.keyboard.consume_input();
-            screen.update(&mut machine, false);
+        keyboard.consume_input();
+        for key in keyboard.take_keys() {
+            let _ = emulator.keys.send(key);
         }
-
-        if !machine.keyboard.commands.is_empty() {
-            let commands = std::mem::take(&mut machine.keyboard.commands);
+        if !keyboard.commands.is_empty() {
+            let commands = std::mem::take(&mut keyboard.commands);
             for command in commands {
                 match command {
                     Command::Quit => {
-                        machine.floppy_controller.media_selected().flush_disk();
-                        if let Some(ref mut hd) = machine.hard_disk {
-                            hd.flush();
-                        }
                         done = true;
                     },
                     Command::Help => {
@@ -503,65 +1179,67 @@ fn main() {
                     Command::SelectDiskA => {
                         let (la, _) = screen.floppy_drive_labels;
                         let prompt = format!("File to load in Drive {}", la);
-                        if let Some(path) = screen.prompt(&mut machine, &prompt) {
-                            let res = machine.floppy_controller.media_a_mut().load_disk(path.as_str());
-                            if let Err(err) = res {
-                                screen.message(&mut machine, &err.to_string())
-                            } else {
-                                machine.floppy_controller.disk_in_drive = true;
-                                machine.floppy_controller.motor_on = true;
-                                // Kaypro 10: the ROM cached the floppy drive type at
-                                // boot when no disk was present (defaulting to SSDD).
-                                // Patch the drive type table at 0xFFF6 to match the
-                                // actual format of the inserted disk image.
-                                if is_kaypro10_hardware {
-                                    let format = machine.floppy_controller.media_a().format;
-                                    let type_byte = match format {
-                                        media::MediaFormat::DsDd => 0x09,
-                                        media::MediaFormat::SsDd => 0x05,
-                                        _ => 0x01,
-                                    };
-                                    machine.poke(0xFFF6, type_byte);
-                                }
-                            }
+                        if let Some(path) = screen.prompt(&mut keyboard, &frame, &prompt) {
+                            let _ = emulator.requests.send(Request::LoadDisk(Drive::A, path));
                         }
                     }
                     Command::SelectDiskB => {
                         if is_kaypro10_hardware {
-                            screen.message(&mut machine, "Kaypro 10 has only one floppy drive (C:)");
+                            screen.message(&mut keyboard, &frame, "Kaypro 10 has only one floppy drive (C:)");
                         } else {
                             let (_, lb) = screen.floppy_drive_labels;
                             let prompt = format!("File to load in Drive {}", lb);
-                            if let Some(path) = screen.prompt(&mut machine, &prompt) {
-                                let res = machine.floppy_controller.media_b_mut().load_disk(path.as_str());
-                                if let Err(err) = res {
-                                    screen.message(&mut machine, &err.to_string())
-                                }
+                            if let Some(path) = screen.prompt(&mut keyboard, &frame, &prompt) {
+                                let _ = emulator.requests.send(Request::LoadDisk(Drive::B, path));
                             }
                         }
                     }
                     Command::SaveMemory => {
-                        match machine.save_bios() {
-                            Ok(filename) => {
-                                screen.message(&mut machine, &format!("BIOS saved as {}", filename));
-                            }
-                            Err(err) => {
-                                screen.message(&mut machine, &format!("Error: {}", err));
+                        let _ = emulator.requests.send(Request::SaveBios);
+                    }
+                    Command::SaveState => {
+                        let _ = emulator.requests.send(Request::SaveState);
+                    }
+                    Command::LoadState => {
+                        let prompt = format!("State file to load [{}]", snapshot::QUICK_SAVE);
+                        if let Some(path) = screen.prompt(&mut keyboard, &frame, &prompt) {
+                            let path = match path.trim() {
+                                "" => snapshot::QUICK_SAVE,
+                                path => path,
+                            };
+                            let _ = emulator.requests.send(Request::LoadState(path.to_string()));
+                        }
+                    }
+                    Command::Rewind => {
+                        if frame.rewind_points == 0 {
+                            screen.message(&mut keyboard, &frame, "No rewind point yet");
+                        } else {
+                            let prompt = format!("Rewind to point (1 = most recent, up to {}) [1]", frame.rewind_points);
+                            if let Some(input) = screen.prompt(&mut keyboard, &frame, &prompt) {
+                                let input = input.trim();
+                                let back = if input.is_empty() { Some(1) } else { input.parse().ok() };
+                                match back {
+                                    Some(back) => { let _ = emulator.requests.send(Request::Rewind(back)); }
+                                    None => screen.message(&mut keyboard, &frame, "Not a rewind point"),
+                                }
                             }
                         }
                     }
                     Command::TraceCPU => {
                         trace_cpu = !trace_cpu;
-                        cpu.set_trace(trace_cpu);
+                        let _ = emulator.requests.send(Request::SetTrace(trace_cpu));
                         screen.set_in_place(!trace_cpu && !any_trace);
                     },
+                    Command::Monitor => {
+                        let _ = emulator.requests.send(Request::Break);
+                    },
                     Command::SetSpeed => {
                         let current = match clock_mhz {
                             Some(mhz) => format!("{:.1}", mhz),
                             None => "-1".to_string(),
                         };
                         let prompt = format!("CPU speed in MHz (1-100, -1=unlimited) [{}]", current);
-                        if let Some(input) = screen.prompt(&mut machine, &prompt) {
+                        if let Some(input) = screen.prompt(&mut keyboard, &frame, &prompt) {
                             let input = input.trim();
                             if input.is_empty() {
                                 // Keep current setting
@@ -572,152 +1250,51 @@ fn main() {
                                     // Round to 0.5 MHz resolution
                                     let rounded = (mhz * 2.0).round() / 2.0;
                                     clock_mhz = Some(rounded);
-                                    speed_start_time = Instant::now();
-                                    cycle_count = 0;
                                 }
                                 // Invalid range silently ignored
+                                let _ = emulator.requests.send(Request::SetSpeed(clock_mhz));
                             }
                             // Invalid parse silently ignored
                         }
                     },
                 }
             }
-            screen.update(&mut machine, true);
+            screen.update(&frame, true);
         }
+    }
+    emulator.stop();
+}
 
-        // SIO interrupt processing (keyboard)
-        if counter % 1024 == 0 {
-            let i_reg = cpu.registers().get8(Reg8::I);
-            if let Some(handler) = machine.sio_check_interrupt(i_reg) {
-                let regs = cpu.registers();
-                let pc = regs.pc();
-                let mut sp = regs.get16(Reg16::SP);
-                sp = sp.wrapping_sub(2);
-                regs.set16(Reg16::SP, sp);
-                machine.poke(sp, pc as u8);
-                machine.poke(sp.wrapping_add(1), (pc >> 8) as u8);
-                cpu.registers().set_pc(handler);
-            }
-        }
-
-        // NMI processing
-        // The FDC sets raise_nmi when a command completes or a data byte is
-        // transferred. We latch it as pending and deliver when:
-        //  1. CPU is HALTed (immediate — standard BIOS FDC loops), OR
-        //  2. Deadline reached AND vector at 0x0066 is safe (fallback for
-        //     programs like DIAG4 that poll FDC without HALTing).
-        // KayPLUS (unsafe vector at 0x0066) only gets NMI via path 1.
-        if machine.floppy_controller.raise_nmi {
-            machine.floppy_controller.raise_nmi = false;
-            nmi_pending = true;
-            nmi_deadline = counter + 10_000_000;
-        }
-        let mut nmi_signaled = false;
-        if nmi_pending && (cpu.is_halted()
-            || (counter >= nmi_deadline && machine.nmi_vector_is_safe()))
-        {
-            cpu.signal_nmi();
-            nmi_pending = false;
-            nmi_signaled = true;
-        }
-        if !nmi_signaled && cpu.is_halted() {
-            screen.update(&mut machine, true);
-            println!("HALT instruction that will never be interrupted");
-            break;
-        }
-
-        // Runtime BIOS base discovery: detect ROM→RAM transition
-        if (trace_rom || trace_bdos) && has_trace_log {
-            let in_rom = machine.is_rom_rank();
-            if in_rom != last_rom_rank {
-                last_rom_rank = in_rom;
-                if !in_rom && bios_base.is_none() {
-                    let warm_lo = machine.peek(0x0001) as u16;
-                    let warm_hi = machine.peek(0x0002) as u16;
-                    let warm_boot = (warm_hi << 8) | warm_lo;
-                    if warm_boot > 0x100 && warm_boot < 0xFFFF {
-                        let base = warm_boot - 3;
-                        bios_base = Some(base);
-                        if let Some(ref mut f) = trace_log {
-                            use std::io::Write;
-                            let _ = writeln!(f, "[{:>10}] BIOS base discovered: 0x{:04X}", counter, base);
-                        }
-                    }
-                }
-            }
-
-            let pc = cpu.registers().pc();
-
-            // BIOS entry point tracing (runtime, works with any ROM)
-            if !in_rom {
-                if let Some(base) = bios_base {
-                    if pc >= base && pc <= base + 51 && (pc - base) % 3 == 0 {
-                        let entry = (pc - base) / 3;
-                        let msg: Option<String> = match entry {
-                            0 => Some("BOOT".into()),
-                            1 => Some("WBOOT".into()),
-                            8 => Some("HOME".into()),
-                            9 => Some(format!("SELDSK drive={} ({})",
-                                cpu.registers().get8(Reg8::C),
-                                (b'A' + cpu.registers().get8(Reg8::C)) as char)),
-                            10 => Some(format!("SETTRK track={}",
-                                cpu.registers().get8(Reg8::C))),
-                            11 => Some(format!("SETSEC sector={}",
-                                cpu.registers().get8(Reg8::C))),
-                            12 => Some("SETDMA".into()),
-                            13 => Some("READ".into()),
-                            14 => Some("WRITE".into()),
-                            16 => {
-                                let sec = cpu.registers().get16(Reg16::BC);
-                                let xlt = cpu.registers().get16(Reg16::DE);
-                                Some(format!("SECTRAN sector={} xlt=0x{:04X}", sec, xlt))
-                            },
-                            _ => None,
-                        };
-                        if let Some(m) = msg {
-                            if let Some(ref mut f) = trace_log {
-                                use std::io::Write;
-                                let _ = writeln!(f, "[{:>10}] BIOS: {}", counter, m);
-                                let _ = f.flush();
-                            } else {
-                                println!("BIOS: {}", m);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        if trace_bdos && !machine.is_rom_rank()
-                && cpu.registers().pc() == 0x0005 {
-            let command = cpu.registers().get8(Reg8::C);
-            if command != 0x06 /*C_RAWIO*/ {
-                let args = cpu.registers().get16(Reg16::DE);
-                let name = if command < BDOS_COMMAND_NAMES.len() as u8 {
-                    BDOS_COMMAND_NAMES[command as usize]
-                } else {
-                    "unknown"
-                };
-
-                if let Some(ref mut f) = trace_log {
-                    use std::io::Write;
-                    let _ = writeln!(f, "[{:>10}] BDOS {}: {}({:04x})", counter, command, name, args);
-                    // For file operations, dump FCB filename
-                    if command == 15 || command == 17 || command == 22 {
-                        let de = args;
-                        let fcb_drive = machine.peek(de);
-                        let mut filename = String::new();
-                        for i in 1..=11u16 {
-                            let ch = machine.peek(de.wrapping_add(i)) & 0x7F;
-                            if ch >= 0x20 { filename.push(ch as char); }
-                        }
-                        let _ = writeln!(f, "[{:>10}]   FCB: drive={} file=\"{}\"",
-                            counter, fcb_drive, filename.trim());
+/// Console of the machine-language monitor, printed under the screen,
+/// until a command lets the machine go. Returns false if the emulation
+/// thread is gone.
+fn monitor_console(emulator: &EmulatorHandle, keyboard: &mut Keyboard, frame: &mut Box<Frame>,
+        registers: &str) -> bool {
+    use std::io::Write;
+
+    println!();
+    println!("Monitor, ? for help, ESC to go on");
+    println!("{}", registers);
+    loop {
+        print!("- ");
+        let _ = std::io::stdout().flush();
+        let line = keyboard.read_line().unwrap_or_else(|| {
+            println!();
+            "g".to_string()
+        });
+        let _ = emulator.requests.send(Request::Monitor(line));
+        loop {
+            match emulator.updates.recv() {
+                Ok(Update::Monitor(output)) | Ok(Update::Stopped(output)) => {
+                    if !output.is_empty() {
+                        println!("{}", output);
                     }
-                    let _ = f.flush();
-                } else {
-                    println!("BDOS command {}: {}({:04x})", command, name, args);
-                }
+                    break;
+                },
+                Ok(Update::Resumed) => return true,
+                Ok(Update::Frame(next_frame)) => *frame = next_frame,
+                Ok(_) => {},
+                Err(_) => return false,
             }
         }
     }
@@ -726,11 +1303,10 @@ fn main() {
 #[cfg(feature = "gui")]
 fn run_gui(
     config: &Config,
-    mut machine: KayproMachine,
-    mut cpu: iz80::Cpu,
+    emulator: EmulatorHandle,
     mut trace_cpu: bool,
-    _is_kaypro10_hardware: bool,
-    speed: Option<f64>,
+    is_kaypro10_hardware: bool,
+    mut clock_mhz: Option<f64>,
     floppy_drive_labels: (char, char),
     phosphor: renderer::PhosphorColors,
 ) {
@@ -786,139 +1362,103 @@ fn run_gui(
 
     window.set_target_fps(60);
 
-    // Clock speed control
-    let clock_mhz: Option<f64> = speed.and_then(|mhz| {
-        if mhz < 0.0 { None }
-        else if mhz >= 1.0 && mhz <= 100.0 { Some((mhz * 2.0).round() / 2.0) }
-        else { None }
-    });
-    let mut cycle_count: u64 = 0;
-    let mut speed_start_time = Instant::now();
-    const CYCLES_PER_INSTRUCTION: u64 = 4;
-
-    let mut counter: u64 = 1;
-    let mut nmi_pending = false;
-    let mut nmi_deadline: u64 = 0;
-
-    // Run enough instructions per frame for responsive emulation.
-    // At unlimited speed, execute ~166K instructions per 60fps frame
-    // (~10M inst/sec, enough for Kaypro 10 to boot in ~1 second).
-    // With clock speed set, the throttling logic handles pacing.
-    let instructions_per_frame: u64 = 166_000;
-
-    // GUI mode: keyboard input comes from minifb window, not stdin.
-    machine.keyboard.gui_mode = true;
-
     let mut show_help = false;
     let mut show_status = false;
     let mut speed_input: Option<String> = None; // Some = F9 input mode active
-    let mut clock_mhz = clock_mhz; // make mutable for speed changes
+    let mut rewind_input: Option<String> = None; // Some = F12 input mode active
+    let mut monitor_input: Option<String> = None; // Some = monitor console open
+    let mut monitor_lines: Vec<String> = Vec::new();
 
     let mut prev_f5_down = false;
     let mut prev_f6_down = false;
+    let mut prev_f11_down = false;
 
-    while window.is_open() {
-        // Compute instructions per frame based on clock speed.
-        // At a fixed MHz, execute exactly enough cycles for one 60fps frame.
-        // At unlimited speed, run a large batch for fast emulation.
-        // When tracing, reduce batch so stdout doesn't block the GUI loop.
-        let batch: u64 = if trace_cpu {
-            1_000
-        } else if let Some(mhz) = clock_mhz {
-            // cycles_per_frame = target_cycles_per_sec / 60
-            let cycles_per_frame = (mhz * 1_000_000.0 / 60.0) as u64;
-            (cycles_per_frame / CYCLES_PER_INSTRUCTION).max(1000)
-        } else {
-            instructions_per_frame
-        };
-
-        // When idle polling exceeds the threshold (same 50K as terminal mode),
-        // reduce the batch to match ~4 MHz execution speed (~67K cycles/frame
-        // at 60fps = ~17K instructions). This prevents TurboROM's software
-        // timeout counter from firing prematurely at unlimited speed, while
-        // maintaining full speed during real work. idle_polls resets to 0
-        // automatically in is_key_pressed() when a key is available.
-        let effective_batch = if machine.keyboard.idle_polls > 50_000 {
-            batch.min(17_000)
-        } else {
-            batch
-        };
-
-        // Execute a batch of CPU instructions per frame
-        for i in 0..effective_batch {
-            // Simulate CRTC vertical retrace timing within the batch.
-            // TurboROM has a two-phase VRT wait: first wait for VRT=0 (retrace
-            // end), then wait for VRT=1 (retrace start). We pulse VRT high for
-            // the last ~10% of the batch, matching real CRT timing where retrace
-            // occupies ~10% of each frame period.
-            let vrt = i >= batch * 9 / 10;
-            if vrt != machine.crtc.vertical_retrace {
-                machine.crtc.set_vertical_retrace(vrt);
-            }
-            cpu.execute_instruction(&mut machine);
-            counter += 1;
-            cycle_count += CYCLES_PER_INSTRUCTION;
-
-            // KayPLUS software clock fixup
-            if machine.kayplus_clock_fixup
-                && machine.is_rom_rank()
-                && cpu.registers().pc() == 0x069E
-            {
-                machine.patch_software_clock();
-                cpu.registers().set_pc(0x06CE);
-            }
-
-            // NMI processing
-            if machine.floppy_controller.raise_nmi {
-                machine.floppy_controller.raise_nmi = false;
-                nmi_pending = true;
-                nmi_deadline = counter + 10_000_000;
-            }
-            if nmi_pending && (cpu.is_halted()
-                || (counter >= nmi_deadline && machine.nmi_vector_is_safe()))
-            {
-                cpu.signal_nmi();
-                nmi_pending = false;
-            }
-
-            // SIO interrupt processing
-            if counter % 1024 == 0 {
-                let i_reg = cpu.registers().get8(iz80::Reg8::I);
-                if let Some(handler) = machine.sio_check_interrupt(i_reg) {
-                    let regs = cpu.registers();
-                    let pc = regs.pc();
-                    let mut sp = regs.get16(iz80::Reg16::SP);
-                    sp = sp.wrapping_sub(2);
-                    regs.set16(iz80::Reg16::SP, sp);
-                    machine.poke(sp, pc as u8);
-                    machine.poke(sp.wrapping_add(1), (pc >> 8) as u8);
-                    cpu.registers().set_pc(handler);
+    let Ok(Update::Frame(mut frame)) = emulator.updates.recv() else {
+        emulator.stop();
+        return;
+    };
+    let mut commands: Vec<Command> = Vec::new();
+
+    'frames: while window.is_open() {
+        // Take the latest frame and the results of the commands
+        while let Ok(update) = emulator.updates.try_recv() {
+            match update {
+                Update::Frame(next_frame) => frame = next_frame,
+                Update::DiskLoaded(drive, path, result) => match result {
+                    Ok(()) => {
+                        let (la, lb) = floppy_drive_labels;
+                        let label = if let Drive::A = drive { la } else { lb };
+                        let name = std::path::Path::new(&path).file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_else(|| path.clone());
+                        window.set_title(&format!("izkaypro — {} — {}: {}", config.get_display_name(), label, name));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::BiosSaved(result) => match result {
+                    Ok(filename) => {
+                        window.set_title(&format!("izkaypro — {} — BIOS saved as {}", config.get_display_name(), filename));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::StateSaved(result) => match result {
+                    Ok(filename) => {
+                        window.set_title(&format!("izkaypro — {} — State saved as {}", config.get_display_name(), filename));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::StateLoaded(path, result) => match result {
+                    Ok(()) => {
+                        window.set_title(&format!("izkaypro — {} — State loaded from {}", config.get_display_name(), path));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::Rewound(result) => match result {
+                    Ok(message) => {
+                        window.set_title(&format!("izkaypro — {} — {}", config.get_display_name(), message));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::ReplayFinished(result) => match result {
+                    Ok(()) => {
+                        window.set_title(&format!("izkaypro — {} — Replay finished", config.get_display_name()));
+                    }
+                    Err(err) => {
+                        window.set_title(&format!("izkaypro — {} — Replay finished: {}", config.get_display_name(), err));
+                    }
+                },
+                Update::Halted => {
+                    window.set_title(&format!("izkaypro — {} — HALT instruction that will never be interrupted", config.get_display_name()));
                 }
-            }
-        }
-
-        // Clock speed throttling: sleep once per frame if we're ahead of schedule.
-        if let Some(mhz) = clock_mhz {
-            let target_cycles_per_sec = (mhz * 1_000_000.0) as u64;
-            let elapsed = speed_start_time.elapsed();
-            let expected_cycles = (elapsed.as_secs_f64() * target_cycles_per_sec as f64) as u64;
-            if cycle_count > expected_cycles {
-                let cycles_ahead = cycle_count - expected_cycles;
-                let wait_secs = cycles_ahead as f64 / target_cycles_per_sec as f64;
-                if wait_secs > 0.0001 {
-                    std::thread::sleep(Duration::from_secs_f64(wait_secs));
+                Update::Stopped(registers) => {
+                    if monitor_input.is_none() {
+                        monitor_lines.clear();
+                        monitor_lines.push("Monitor, ? for help, ESC to go on".into());
+                        monitor_input = Some(String::new());
+                    }
+                    monitor_lines.extend(registers.lines().map(String::from));
                 }
-            }
-            if elapsed.as_secs() >= 1 {
-                speed_start_time = Instant::now();
-                cycle_count = 0;
+                Update::Monitor(output) => {
+                    monitor_lines.extend(output.lines().map(String::from));
+                }
+                Update::Resumed => monitor_input = None,
             }
         }
 
         // Render frame (update_with_buffer pumps macOS events, must come
         // before key polling so get_keys_pressed returns current state).
         renderer.tick_frame();
-        renderer.render(&machine);
+        renderer.render(&frame);
 
         // Overlay: help and/or status
         if show_help {
@@ -926,9 +1466,10 @@ fn run_gui(
             let help_lines: Vec<String> = vec![
                 "izkaypro: Kaypro Emulator".into(),
                 "".into(),
-                format!("F1: Help  F2: Status  F4: Quit"),
+                format!("F1: Help  F2: Status  F3: Monitor  F4: Quit"),
                 format!("F5: Drive {}  F6: Drive {}  F7: Save BIOS", la, lb),
                 format!("F8: CPU Trace  F9: Set Speed"),
+                format!("F10: Save State  F11: Load State  F12: Rewind"),
                 "".into(),
                 "Delete=DEL  Insert=LINEFEED".into(),
                 "ESC: Close window".into(),
@@ -942,11 +1483,17 @@ fn run_gui(
                 Some(mhz) => format!("{:.1} MHz", mhz),
                 None => "unlimited".to_string(),
             };
-            let status_lines: Vec<String> = vec![
-                format!("{}: {}", la, machine.floppy_controller.media_a().info()),
-                format!("{}: {}", lb, machine.floppy_controller.media_b().info()),
-                format!("CPU speed: {}", speed_str),
+            let mut status_lines: Vec<String> = vec![
+                format!("{}: {}", la, frame.media_info(0)),
+                format!("{}: {}", lb, frame.media_info(1)),
             ];
+            // Advent board drives follow the native ones
+            for (drive, offset) in [(Drive::C, 1), (Drive::D, 2)] {
+                if let Some(ref info) = frame.media[drive as usize] {
+                    status_lines.push(format!("{}: {}", (lb as u8 + offset) as char, info));
+                }
+            }
+            status_lines.push(format!("CPU speed: {}", speed_str));
             let start = if show_help { 14 } else { 2 };
             let refs: Vec<&str> = status_lines.iter().map(|s| s.as_str()).collect();
             renderer.render_overlay(&refs, start);
@@ -962,6 +1509,21 @@ fn run_gui(
             let start = if show_help || show_status { 19 } else { 9 };
             renderer.render_overlay(&lines, start);
         }
+        if let Some(ref buf) = rewind_input {
+            let prompt = format!("Rewind to point (1 = most recent, up to {}) [1]", frame.rewind_points);
+            let input_line = format!("> {}_", buf);
+            let lines = [prompt.as_str(), input_line.as_str(), "", "Enter=confirm  ESC=cancel"];
+            let start = if show_help || show_status { 19 } else { 9 };
+            renderer.render_overlay(&lines, start);
+        }
+        if let Some(ref input) = monitor_input {
+            // The last lines of the console over the screen
+            let prompt = format!("- {}_", input);
+            let first = monitor_lines.len().saturating_sub(MONITOR_ROWS);
+            let mut lines: Vec<&str> = monitor_lines[first..].iter().map(|s| s.as_str()).collect();
+            lines.push(&prompt);
+            renderer.render_overlay(&lines, 0);
+        }
 
         let (dw, dh) = renderer.display_size();
         let buffer = renderer.render_to_display_buffer_only();
@@ -970,13 +1532,18 @@ fn run_gui(
 
         // ESC: dismiss overlays/input first, otherwise send ESC to CP/M.
         if window.is_key_pressed(Key::Escape, KeyRepeat::No) {
-            if speed_input.is_some() {
+            if monitor_input.is_some() {
+                // Lets the machine go, the console closes when it does
+                let _ = emulator.requests.send(Request::Monitor("g".into()));
+            } else if speed_input.is_some() {
                 speed_input = None;
+            } else if rewind_input.is_some() {
+                rewind_input = None;
             } else if show_help || show_status {
                 show_help = false;
                 show_status = false;
             } else {
-                machine.keyboard.gui_key_queue.push(0x1b);
+                let _ = emulator.keys.send(0x1b);
             }
         }
 
@@ -984,7 +1551,30 @@ fn run_gui(
         let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
         let ctrl = window.is_key_down(Key::LeftCtrl) || window.is_key_down(Key::RightCtrl);
 
-        if let Some(ref mut buf) = speed_input {
+        if let Some(ref mut input) = monitor_input {
+            // Monitor console: the keys make the command line
+            let mut entered = None;
+            for key in window.get_keys_pressed(KeyRepeat::Yes) {
+                if let Some(letter) = minifb_key_letter(key) {
+                    input.push((if shift { b'A' } else { b'a' } + letter) as char);
+                } else if let Some(digit) = minifb_key_digit(key) {
+                    input.push((b'0' + digit) as char);
+                } else {
+                    match key {
+                        Key::Space => input.push(' '),
+                        Key::Apostrophe => input.push('\''),
+                        Key::Slash if shift => input.push('?'),
+                        Key::Backspace => { input.pop(); },
+                        Key::Enter => entered = Some(std::mem::take(input)),
+                        _ => {}
+                    }
+                }
+            }
+            if let Some(line) = entered {
+                monitor_lines.push(format!("- {}", line));
+                let _ = emulator.requests.send(Request::Monitor(line));
+            }
+        } else if let Some(ref mut buf) = speed_input {
             // Speed input mode: capture keys into the input buffer
             for key in window.get_keys_pressed(KeyRepeat::Yes) {
                 if let Some(digit) = minifb_key_digit(key) {
@@ -1004,10 +1594,9 @@ fn run_gui(
                                 } else if mhz >= 1.0 && mhz <= 100.0 {
                                     let rounded = (mhz * 2.0).round() / 2.0;
                                     clock_mhz = Some(rounded);
-                                    speed_start_time = Instant::now();
-                                    cycle_count = 0;
                                 }
                             }
+                            let _ = emulator.requests.send(Request::SetSpeed(clock_mhz));
                             let speed_str = match clock_mhz {
                                 Some(mhz) => format!("{:.1} MHz", mhz),
                                 None => "unlimited".to_string(),
@@ -1025,6 +1614,25 @@ fn run_gui(
             if window.is_key_pressed(Key::Enter, KeyRepeat::No) {
                 speed_input = None;
             }
+        } else if let Some(ref mut buf) = rewind_input {
+            // Rewind input mode: the number of points to go back
+            let mut confirmed = false;
+            for key in window.get_keys_pressed(KeyRepeat::Yes) {
+                if let Some(digit) = minifb_key_digit(key) {
+                    buf.push((b'0' + digit) as char);
+                } else {
+                    match key {
+                        Key::Backspace => { buf.pop(); },
+                        Key::Enter => confirmed = true,
+                        _ => {}
+                    }
+                }
+            }
+            if confirmed {
+                let back = buf.trim().parse().unwrap_or(1);
+                let _ = emulator.requests.send(Request::Rewind(back));
+                rewind_input = None;
+            }
         } else {
             // Normal mode: keys go to the emulator
             // Character keys (with auto-repeat)
@@ -1074,19 +1682,22 @@ fn run_gui(
                     }
                 };
                 if let Some(b) = byte {
-                    machine.keyboard.gui_key_queue.push(b);
+                    let _ = emulator.keys.send(b);
                 }
             }
 
             // Command keys (no auto-repeat)
             for key in window.get_keys_pressed(KeyRepeat::No) {
                 match key {
-                    Key::F1 => machine.keyboard.gui_command_queue.push(Command::Help),
-                    Key::F2 => machine.keyboard.gui_command_queue.push(Command::ShowStatus),
-                    Key::F4 => machine.keyboard.gui_command_queue.push(Command::Quit),
-                    Key::F7 => machine.keyboard.gui_command_queue.push(Command::SaveMemory),
-                    Key::F8 => machine.keyboard.gui_command_queue.push(Command::TraceCPU),
-                    Key::F9 => machine.keyboard.gui_command_queue.push(Command::SetSpeed),
+                    Key::F1 => commands.push(Command::Help),
+                    Key::F2 => commands.push(Command::ShowStatus),
+                    Key::F3 => commands.push(Command::Monitor),
+                    Key::F4 => commands.push(Command::Quit),
+                    Key::F7 => commands.push(Command::SaveMemory),
+                    Key::F8 => commands.push(Command::TraceCPU),
+                    Key::F9 => commands.push(Command::SetSpeed),
+                    Key::F10 => commands.push(Command::SaveState),
+                    Key::F12 => commands.push(Command::Rewind),
                     _ => {}
                 }
             }
@@ -1099,134 +1710,110 @@ fn run_gui(
                 prev_f5_down = true;
             } else if prev_f5_down {
                 prev_f5_down = false;
-                machine.keyboard.gui_command_queue.push(Command::SelectDiskA);
+                commands.push(Command::SelectDiskA);
             }
             if window.is_key_down(Key::F6) {
                 prev_f6_down = true;
             } else if prev_f6_down {
                 prev_f6_down = false;
-                machine.keyboard.gui_command_queue.push(Command::SelectDiskB);
+                commands.push(Command::SelectDiskB);
+            }
+            if window.is_key_down(Key::F11) {
+                prev_f11_down = true;
+            } else if prev_f11_down {
+                prev_f11_down = false;
+                commands.push(Command::LoadState);
             }
         }
 
-        // Drain GUI queues into internal buffers
-        machine.keyboard.consume_input();
-
         // Handle emulator commands
-        if !machine.keyboard.commands.is_empty() {
-            let commands = std::mem::take(&mut machine.keyboard.commands);
-            for command in commands {
-                match command {
-                    Command::Quit => {
-                        machine.floppy_controller.media_selected().flush_disk();
-                        if let Some(ref mut hd) = machine.hard_disk {
-                            hd.flush();
-                        }
-                        return;
-                    },
-                    Command::Help => {
-                        show_help = !show_help;
-                    },
-                    Command::ShowStatus => {
-                        show_status = !show_status;
-                    },
-                    Command::TraceCPU => {
-                        trace_cpu = !trace_cpu;
-                        cpu.set_trace(trace_cpu);
-                        let state = if trace_cpu { "ON" } else { "OFF" };
-                        window.set_title(&format!("izkaypro — {} — CPU trace: {}", config.get_display_name(), state));
-                    },
-                    Command::SelectDiskA => {
-                        let (la, _) = floppy_drive_labels;
+        for command in std::mem::take(&mut commands) {
+            match command {
+                Command::Quit => {
+                    break 'frames;
+                },
+                Command::Help => {
+                    show_help = !show_help;
+                },
+                Command::ShowStatus => {
+                    show_status = !show_status;
+                },
+                Command::TraceCPU => {
+                    trace_cpu = !trace_cpu;
+                    let _ = emulator.requests.send(Request::SetTrace(trace_cpu));
+                    let state = if trace_cpu { "ON" } else { "OFF" };
+                    window.set_title(&format!("izkaypro — {} — CPU trace: {}", config.get_display_name(), state));
+                },
+                Command::SelectDiskA => {
+                    let (la, _) = floppy_drive_labels;
+                    if let Some(path) = rfd::FileDialog::new()
+                        .set_title(&format!("Select disk image for Drive {}", la))
+                        .add_filter("Disk Images", &["dsk", "img", "cpm", "raw"])
+                        .add_filter("All Files", &["*"])
+                        .pick_file()
+                    {
+                        let path = path.to_string_lossy().to_string();
+                        let _ = emulator.requests.send(Request::LoadDisk(Drive::A, path));
+                    }
+                },
+                Command::SelectDiskB => {
+                    if is_kaypro10_hardware {
+                        window.set_title(&format!("izkaypro — {} — Kaypro 10 has only one floppy drive (C:)", config.get_display_name()));
+                    } else {
+                        let (_, lb) = floppy_drive_labels;
                         if let Some(path) = rfd::FileDialog::new()
-                            .set_title(&format!("Select disk image for Drive {}", la))
+                            .set_title(&format!("Select disk image for Drive {}", lb))
                             .add_filter("Disk Images", &["dsk", "img", "cpm", "raw"])
                             .add_filter("All Files", &["*"])
                             .pick_file()
                         {
-                            let path_str = path.to_string_lossy();
-                            match machine.floppy_controller.media_a_mut().load_disk(&path_str) {
-                                Ok(_) => {
-                                    machine.floppy_controller.disk_in_drive = true;
-                                    machine.floppy_controller.motor_on = true;
-                                    if _is_kaypro10_hardware {
-                                        let format = machine.floppy_controller.media_a().format;
-                                        let type_byte = match format {
-                                            media::MediaFormat::DsDd => 0x09,
-                                            media::MediaFormat::SsDd => 0x05,
-                                            _ => 0x01,
-                                        };
-                                        machine.poke(0xFFF6, type_byte);
-                                    }
-                                    let name = path.file_name()
-                                        .map(|n| n.to_string_lossy().to_string())
-                                        .unwrap_or_else(|| path_str.to_string());
-                                    window.set_title(&format!("izkaypro — {} — {}: {}", config.get_display_name(), la, name));
-                                }
-                                Err(err) => {
-                                    window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
-                                }
-                            }
+                            let path = path.to_string_lossy().to_string();
+                            let _ = emulator.requests.send(Request::LoadDisk(Drive::B, path));
                         }
-                    },
-                    Command::SelectDiskB => {
-                        if _is_kaypro10_hardware {
-                            window.set_title(&format!("izkaypro — {} — Kaypro 10 has only one floppy drive (C:)", config.get_display_name()));
-                        } else {
-                            let (_, lb) = floppy_drive_labels;
-                            if let Some(path) = rfd::FileDialog::new()
-                                .set_title(&format!("Select disk image for Drive {}", lb))
-                                .add_filter("Disk Images", &["dsk", "img", "cpm", "raw"])
-                                .add_filter("All Files", &["*"])
-                                .pick_file()
-                            {
-                                let path_str = path.to_string_lossy();
-                                match machine.floppy_controller.media_b_mut().load_disk(&path_str) {
-                                    Ok(_) => {
-                                        let name = path.file_name()
-                                            .map(|n| n.to_string_lossy().to_string())
-                                            .unwrap_or_else(|| path_str.to_string());
-                                        window.set_title(&format!("izkaypro — {} — {}: {}", config.get_display_name(), lb, name));
-                                    }
-                                    Err(err) => {
-                                        window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Command::SaveMemory => {
-                        match machine.save_bios() {
-                            Ok(filename) => {
-                                window.set_title(&format!("izkaypro — {} — BIOS saved as {}", config.get_display_name(), filename));
-                            }
-                            Err(err) => {
-                                window.set_title(&format!("izkaypro — {} — Error: {}", config.get_display_name(), err));
-                            }
-                        }
-                    },
-                    Command::SetSpeed => {
-                        speed_input = Some(String::new());
-                    },
-                }
+                    }
+                },
+                Command::SaveMemory => {
+                    let _ = emulator.requests.send(Request::SaveBios);
+                },
+                Command::SetSpeed => {
+                    speed_input = Some(String::new());
+                },
+                Command::SaveState => {
+                    let _ = emulator.requests.send(Request::SaveState);
+                },
+                Command::LoadState => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .set_title("Select machine state to load")
+                        .add_filter("Machine States", &[snapshot::EXTENSION])
+                        .add_filter("All Files", &["*"])
+                        .pick_file()
+                    {
+                        let path = path.to_string_lossy().to_string();
+                        let _ = emulator.requests.send(Request::LoadState(path));
+                    }
+                },
+                Command::Monitor => {
+                    let _ = emulator.requests.send(Request::Break);
+                },
+                Command::Rewind => {
+                    if frame.rewind_points == 0 {
+                        window.set_title(&format!("izkaypro — {} — No rewind point yet", config.get_display_name()));
+                    } else {
+                        rewind_input = Some(String::new());
+                    }
+                },
             }
         }
-
-        // Clear VRAM dirty flags
-        if machine.video_mode == kaypro_machine::VideoMode::Sy6545Crtc {
-            machine.crtc.vram_dirty = false;
-        } else {
-            machine.vram_dirty = false;
-        }
     }
 
-    // Clean shutdown
-    machine.floppy_controller.media_selected().flush_disk();
-    if let Some(ref mut hd) = machine.hard_disk {
-        hd.flush();
-    }
+    // Clean shutdown: the emulation thread flushes the disks
+    emulator.stop();
 }
 
+// Console lines shown over the GUI screen, with the prompt under them
+#[cfg(feature = "gui")]
+const MONITOR_ROWS: usize = 20;
+
 #[cfg(feature = "gui")]
 fn minifb_key_letter(key: minifb::Key) -> Option<u8> {
     use minifb::Key;
@@ -1253,21 +1840,3 @@ fn minifb_key_digit(key: minifb::Key) -> Option<u8> {
         _ => None,
     }
 }
-
-const BDOS_COMMAND_NAMES: [&str; 50] = [
-    // 0
-    "P_TERMCPM", "C_READ", "C_WRITE", "A_READ", "A_WRITE",
-    "L_WRITE", "C_RAWIO", "A_STATIN", "A_STATOUT", "C_WRITESTR",
-    // 10
-    "C_READSTR", "C_STAT", "S_BDOSVER", "DRV_ALLRESET", "DRV_SET",
-    "F_OPEN", "F_CLOSE", "F_SFIRST", "F_SNEXT", "F_DELETE",
-    // 20
-    "F_READ", "F_WRITE", "F_MAKE", "F_RENAME", "DRV_LOGINVEC",
-    "DRV_GET", "F_DMAOFF", "DRV_ALLOCVEC", "DRV_SETRO", "DRV_ROVEC",
-    // 30
-    "F_ATTRIB", "DRV_DPB", "F_USERNUM", "F_READRAND", "F_WRITERAND",
-    "F_SIZE", "F_RANDREC", "DRV_RESET", "*", "",
-    // 40
-    "F_WRITEZ", "", "", "", "",
-    "F_ERRMODE", "", "", "", "",
-    ];
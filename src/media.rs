@@ -2,6 +2,16 @@ use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
 
+use crate::cpm_fs;
+use crate::host_dir::{self, HostDir};
+use crate::imd::{self, ImdImage, ImdTrack};
+use crate::overlay::Overlay;
+use crate::rewind::WriteJournal;
+use crate::td0;
+
+/// Overlay block size for floppy images: one logical CP/M sector.
+const OVERLAY_BLOCK_SIZE: usize = 128;
+
 /*
 Notes on the DSDD disks as seen by different components:
 
@@ -63,6 +73,19 @@ pub struct DiskGeometry {
     pub label: &'static str,
 }
 
+impl DiskGeometry {
+    /// Same physical layout, ignoring the label.
+    pub fn same_layout(&self, other: &DiskGeometry) -> bool {
+        self.n == other.n
+            && self.sectors_per_track == other.sectors_per_track
+            && self.sector_id_base == other.sector_id_base
+            && self.side1_sector_id_base == other.side1_sector_id_base
+            && self.tracks == other.tracks
+            && self.double_sided == other.double_sided
+            && self.single_density == other.single_density
+    }
+}
+
 // ── TurboROM-supported format presets ────────────────────────────────────────
 
 /// Kaypro SSSD / Osborne SSSD: 40T × 10S × 256B, IDs 1-10
@@ -96,7 +119,6 @@ pub const GEOM_ADVENT_SSDD: DiskGeometry = DiskGeometry {
 };
 
 /// Advent DSDD 48TPI: 40T × 2S × 5S × 1024B, side0 IDs 1-5, side1 IDs 11-15
-#[allow(dead_code)]
 pub const GEOM_ADVENT_DSDD48: DiskGeometry = DiskGeometry {
     n: 3, sectors_per_track: 5, sector_id_base: 1, side1_sector_id_base: 11,
     tracks: 40, double_sided: true, single_density: false, label: "Advent DSDD 48TPI",
@@ -128,6 +150,13 @@ pub const GEOM_EPSON_QX10: DiskGeometry = DiskGeometry {
     tracks: 40, double_sided: true, single_density: false, label: "Epson QX-10 DSDD",
 };
 
+/// All presets, for matching geometries read from container images.
+pub const GEOMETRY_PRESETS: [DiskGeometry; 10] = [
+    GEOM_KAYPRO_SSSD, GEOM_KAYPRO_SSDD, GEOM_KAYPRO_DSDD, GEOM_KAYPLUS_DSDD,
+    GEOM_ADVENT_SSDD, GEOM_ADVENT_DSDD48, GEOM_ADVENT_DSDD96,
+    GEOM_MICROCORNUCOPIA, GEOM_XEROX_820, GEOM_EPSON_QX10,
+];
+
 // ─────────────────────────────────────────────────────────────────────────────
 
 const SECTOR_SIZE_DD: usize = 512;
@@ -150,6 +179,15 @@ fn count_valid_cpm_entries(data: &[u8], dir_offset: usize) -> usize {
     count
 }
 
+/// `count_valid_cpm_entries` where the directory of `geometry` is, after
+/// the reserved tracks of the DPB `cpm_fs` opens it with.
+fn directory_score(data: &[u8], geometry: &DiskGeometry) -> usize {
+    match cpm_fs::dpb_for_geometry(geometry) {
+        Some(dpb) => count_valid_cpm_entries(data, dpb.off as usize * dpb.spt as usize * cpm_fs::RECORD_SIZE),
+        None => 0,
+    }
+}
+
 /// Detect disk geometry from raw image bytes.
 ///
 /// `side1_sector_base` — the machine-configured sector ID base for side 1
@@ -169,11 +207,11 @@ pub fn auto_detect_geometry(data: &[u8], side1_sector_base: u8) -> Option<DiskGe
 
         // 204,800 B  →  Kaypro SSDD (10S×512B)  OR  Advent/Osborne SSDD (5S×1024B)
         // Disambiguate via CP/M directory probe at each format's reserved-track offset.
-        //   Kaypro SSDD:  2 reserved tracks × 10S × 512B = 10,240 B
+        //   Kaypro SSDD:  1 reserved track  × 10S × 512B =  5,120 B
         //   Advent SSDD:  3 reserved tracks ×  5S ×1024B = 15,360 B
         204_800..=205_824 => {
-            let kaypro_score = count_valid_cpm_entries(data, 2 * 10 * 512);
-            let advent_score = count_valid_cpm_entries(data,  3 *  5 * 1024);
+            let kaypro_score = directory_score(data, &GEOM_KAYPRO_SSDD);
+            let advent_score = directory_score(data, &GEOM_ADVENT_SSDD);
             if advent_score > kaypro_score {
                 Some(GEOM_ADVENT_SSDD)
             } else {
@@ -204,8 +242,8 @@ pub fn auto_detect_geometry(data: &[u8], side1_sector_base: u8) -> Option<DiskGe
         // directory at offset 3×2×5×1024 = 30,720 B.
         // Micro Cornucopia has 2 reserved tracks, directory at 2×2×10×512 = 20,480 B.
         819_200 => {
-            let advent_score = count_valid_cpm_entries(data, 3 * 2 * 5 * 1024);
-            let micro_score  = count_valid_cpm_entries(data, 2 * 2 * 10 * 512);
+            let advent_score = directory_score(data, &GEOM_ADVENT_DSDD96);
+            let micro_score  = directory_score(data, &GEOM_MICROCORNUCOPIA);
             if micro_score > advent_score {
                 Some(GEOM_MICROCORNUCOPIA)
             } else {
@@ -232,11 +270,142 @@ pub fn detect_media_format(len: usize) -> MediaFormat {
     }
 }
 
-#[derive(Clone, Copy)]
+/// Decode a container image: ImageDisk, or Teledisk converted to the IMD
+/// model.  Returns None for raw sector images.  The flag tells whether the
+/// container can be written back (Teledisk images are import-only).
+/// `raw_size_known` keeps a raw image that happens to start with "TD" raw.
+pub fn decode_container(data: &[u8], raw_size_known: bool) -> Result<Option<(ImdImage, bool)>> {
+    if imd::is_imd(data) {
+        Ok(Some((ImdImage::parse(data)?, true)))
+    } else if td0::is_td0(data) && !raw_size_known {
+        Ok(Some((td0::parse(data)?, false)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Clone)]
 pub struct TrackGeometry {
     pub n: u8,
     pub sector_count: u8,
     pub sector_base: u8,
+    /// Recording mode of the track's ID and data fields (FM or MFM)
+    pub single_density: bool,
+    /// Sector IDs in rotational order, as found in the IDAMs.  Empty means
+    /// `sector_count` consecutive IDs starting at `sector_base`.
+    pub sector_ids: Vec<u8>,
+}
+
+impl TrackGeometry {
+    /// Position of a sector's data within the track's area of the image.
+    /// Sectors are stored in ID order, so a sector map only matters for
+    /// non-consecutive IDs.
+    pub fn slot(&self, sector: u8) -> Option<usize> {
+        if self.sector_ids.is_empty() {
+            let adjusted = sector.checked_sub(self.sector_base)?;
+            (adjusted < self.sector_count).then_some(adjusted as usize)
+        } else if self.sector_ids.contains(&sector) {
+            Some(self.sector_ids.iter().filter(|&&id| id < sector).count())
+        } else {
+            None
+        }
+    }
+
+    /// ID of the sector passing under the head at rotational position `pos`.
+    pub fn id_at(&self, pos: u8) -> u8 {
+        if self.sector_ids.is_empty() {
+            self.sector_base + pos % self.sector_count.max(1)
+        } else {
+            self.sector_ids[pos as usize % self.sector_ids.len()]
+        }
+    }
+
+    /// All sector IDs in rotational order.
+    pub fn ids(&self) -> Vec<u8> {
+        if self.sector_ids.is_empty() {
+            (0..self.sector_count).map(|i| self.sector_base + i).collect()
+        } else {
+            self.sector_ids.clone()
+        }
+    }
+}
+
+// ── Sector faults ────────────────────────────────────────────────────────────
+
+/// Faults of a damaged sector, reported by the FDC on READ/WRITE SECTOR.
+/// Loaded from IMD/TD0 sector types and from a "<image>.faults" sidecar.
+pub const FAULT_CRC: u8 = 0x01;
+/// ID field unreadable: the sector is never found
+pub const FAULT_RNF: u8 = 0x02;
+/// Written with a deleted data mark (F8)
+pub const FAULT_DELETED: u8 = 0x04;
+/// Weak bits: reads return different data each time, with a CRC error
+pub const FAULT_WEAK: u8 = 0x08;
+/// The drive reports a write fault when writing the sector
+pub const FAULT_WRITE: u8 = 0x10;
+
+const FAULT_NAMES: [(&str, u8); 5] = [
+    ("crc", FAULT_CRC),
+    ("rnf", FAULT_RNF),
+    ("deleted", FAULT_DELETED),
+    ("weak", FAULT_WEAK),
+    ("wfault", FAULT_WRITE),
+];
+
+/// Sector faults keyed by (track, side 2, sector ID).
+pub type FaultMap = HashMap<(u8, bool, u8), u8>;
+
+/// Parse a fault map: one sector per line as `track side sector fault...`,
+/// with faults among crc, rnf, deleted, weak and wfault.  '#' starts a
+/// comment.
+///
+///     # track side sector faults
+///     2 0 5 crc
+///     7 1 13 deleted crc
+pub fn parse_fault_map(text: &str) -> Result<FaultMap> {
+    let mut faults = FaultMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |what: &str| Error::new(ErrorKind::InvalidData,
+            format!("Fault map line {}: {}", n + 1, what));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(invalid("expected track, side, sector and faults"));
+        }
+        let number = |f: &str| f.parse::<u8>().map_err(|_| invalid(&format!("bad number '{}'", f)));
+        let (track, side, sector) = (number(fields[0])?, number(fields[1])?, number(fields[2])?);
+        if side > 1 {
+            return Err(invalid("side must be 0 or 1"));
+        }
+        let mut fault = 0;
+        for name in &fields[3..] {
+            fault |= FAULT_NAMES.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|&(_, bit)| bit)
+                .ok_or_else(|| invalid(&format!("unknown fault '{}'", name)))?;
+        }
+        *faults.entry((track, side == 1, sector)).or_insert(0) |= fault;
+    }
+    Ok(faults)
+}
+
+pub fn fault_map_path(image: &str) -> String {
+    format!("{}.faults", image)
+}
+
+/// What WRITE TRACK and WRITE SECTOR can change besides the content, kept
+/// by the rewind journal.
+#[derive(Clone)]
+pub struct MediaLayout {
+    format: MediaFormat,
+    geometry: Option<DiskGeometry>,
+    learned_n: Option<u8>,
+    learned_sector_base: Option<u8>,
+    track_geometry: HashMap<(u8, bool), TrackGeometry>,
+    faults: FaultMap,
 }
 
 pub struct Media {
@@ -269,12 +438,55 @@ pub struct Media {
     /// headers defining N/sector layout.  Takes priority in sector_index() and
     /// read_address().
     pub track_geometry: HashMap<(u8, bool), TrackGeometry>,
+    /// Track headers of an ImageDisk file (sector data stripped: `content`
+    /// holds the data).  When Some, `flush_disk()` rewrites the file as IMD.
+    pub imd: Option<ImdImage>,
+    /// Copy-on-write mode: images inserted with `load_disk()` are opened
+    /// read-only and `flush_disk()` writes to a sidecar overlay instead.
+    pub use_overlay: bool,
+    pub overlay: Option<Overlay>,
+    /// Host directory mounted with a "dir:<path>" name.  `flush_disk()`
+    /// turns the CP/M directory back into host files.
+    pub host_dir: Option<HostDir>,
+    /// Damaged sectors, see `FAULT_*`
+    pub faults: FaultMap,
+    /// Faults that only the "<image>.faults" sidecar gives, kept out of
+    /// the saved image
+    pub sidecar_faults: FaultMap,
+    /// Previous content of the sectors written since each rewind point
+    pub journal: WriteJournal<MediaLayout>,
 
     pub write_min: usize,
     pub write_max: usize,
 }
 
 impl Media {
+    /// An empty drive: no file, no content, unformatted.  Use `load_disk()`
+    /// to insert an image.
+    pub fn new(side1_sector_base: u8) -> Media {
+        Media {
+            file: None,
+            name: String::new(),
+            content: Vec::new(),
+            format: MediaFormat::Unformatted,
+            geometry: None,
+            write_protected: false,
+            side1_sector_base,
+            learned_n: None,
+            learned_sector_base: None,
+            track_geometry: HashMap::new(),
+            imd: None,
+            use_overlay: false,
+            overlay: None,
+            host_dir: None,
+            faults: FaultMap::new(),
+            sidecar_faults: FaultMap::new(),
+            journal: WriteJournal::new(),
+            write_min: usize::MAX,
+            write_max: 0,
+        }
+    }
+
     pub fn double_sided(&self) -> bool {
         if let Some(ref geom) = self.geometry {
             geom.double_sided
@@ -326,11 +538,15 @@ impl Media {
                     n: geom.n,
                     sector_count: geom.sectors_per_track,
                     sector_base: geom.sector_id_base,
+                    single_density: geom.single_density,
+                    sector_ids: Vec::new(),
                 });
                 self.track_geometry.insert((track, true), TrackGeometry {
                     n: geom.n,
                     sector_count: geom.sectors_per_track,
                     sector_base: geom.side1_sector_id_base,
+                    single_density: geom.single_density,
+                    sector_ids: Vec::new(),
                 });
             }
         }
@@ -388,14 +604,18 @@ impl Media {
 
     pub fn load_disk(&mut self, filename: &str) -> Result<()>{
         self.flush_disk();
+        if let Some(path) = host_dir::host_path(filename) {
+            return self.load_host_dir(filename, path);
+        }
 
-        // Try opening writable, then read only
+        // Try opening writable, then read only.  In overlay mode the
+        // image itself is never written.
         let (mut file, readonly) = match OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(!self.use_overlay)
             .open(filename)
             {
-                Ok(file) => (file, false),
+                Ok(file) => (file, self.use_overlay),
                 _ => {
                     // Try opening read-only
                     match OpenOptions::new()
@@ -421,16 +641,33 @@ impl Media {
             Some(file)
         };
 
+        // The overlay of a raw image holds file offsets, so it goes on before
+        // geometry detection (it may have grown the image, e.g. SS to DS).
+        // Container overlays hold offsets into the decoded content.
+        let overlay = if self.use_overlay {
+            Some(Overlay::open(filename, OVERLAY_BLOCK_SIZE)?)
+        } else {
+            None
+        };
+        let container = imd::is_imd(&content) || td0::is_td0(&content);
+        if let (Some(ref overlay), false) = (&overlay, container) {
+            overlay.apply_growing(&mut content, 0xE5);
+        }
+
         let geometry = auto_detect_geometry(&content, self.side1_sector_base);
-        if geometry.is_none() {
+        let (image, read_only_container) = match decode_container(&content, geometry.is_some())? {
+            Some((image, writable)) => (Some(image), !writable),
+            None => (None, false),
+        };
+        if image.is_none() && geometry.is_none() {
             return Err(Error::new(ErrorKind::Other,
                 format!("Unrecognized disk image format (size {} bytes)", content.len())));
         }
 
         let format = detect_media_format(content.len());
 
-        self.write_protected = readonly;
-        self.file = file;
+        self.write_protected = (readonly && overlay.is_none()) || read_only_container;
+        self.file = if read_only_container { None } else { file };
         self.name = filename.to_owned();
         self.content = content;
         self.format = format;
@@ -438,34 +675,311 @@ impl Media {
         self.learned_n = None;
         self.learned_sector_base = None;
         self.track_geometry.clear();
+        self.imd = None;
+        self.host_dir = None;
+        self.faults.clear();
+        self.sidecar_faults.clear();
+        self.journal.clear();
+
+        match image {
+            Some(image) => self.load_imd(image),
+            // Apply detected geometry: populates learned_n, learned_sector_base,
+            // and pre-fills track_geometry for all tracks so that READ ADDRESS
+            // returns the correct N and sector base immediately on disk insertion.
+            None => self.apply_geometry(geometry.unwrap()),
+        }
+        if let (Some(ref overlay), true) = (&overlay, container) {
+            overlay.apply(&mut self.content);
+        }
+        self.overlay = if read_only_container { None } else { overlay };
+        self.load_fault_map()?;
 
-        // Apply detected geometry: populates learned_n, learned_sector_base,
-        // and pre-fills track_geometry for all tracks so that READ ADDRESS
-        // returns the correct N and sector base immediately on disk insertion.
-        self.apply_geometry(geometry.unwrap());
+        Ok(())
+    }
 
+    /// Add the faults of the "<image>.faults" sidecar, if there is one.
+    pub fn load_fault_map(&mut self) -> Result<()> {
+        let text = match std::fs::read_to_string(fault_map_path(&self.name)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for (key, fault) in parse_fault_map(&text)? {
+            let faults = self.faults.entry(key).or_insert(0);
+            *self.sidecar_faults.entry(key).or_insert(0) |= fault & !*faults;
+            *faults |= fault;
+        }
         Ok(())
     }
 
+    pub fn fault(&self, side_2: bool, track: u8, sector: u8) -> u8 {
+        self.faults.get(&(track, side_2, sector)).copied().unwrap_or(0)
+    }
+
+    /// A sector has been rewritten: a fresh data field has no CRC error or
+    /// weak bits, and carries the data mark of the WRITE SECTOR command.
+    pub fn sector_written(&mut self, side_2: bool, track: u8, sector: u8, deleted: bool) {
+        let key = (track, side_2, sector);
+        let mut fault = self.faults.get(&key).copied().unwrap_or(0) & !(FAULT_CRC | FAULT_WEAK | FAULT_DELETED);
+        if deleted {
+            fault |= FAULT_DELETED;
+        }
+        if fault == 0 {
+            self.faults.remove(&key);
+        } else {
+            self.faults.insert(key, fault);
+        }
+        if let Some(sidecar) = self.sidecar_faults.get_mut(&key) {
+            *sidecar &= !(FAULT_CRC | FAULT_WEAK | FAULT_DELETED);
+        }
+    }
+
+    /// Mount a host directory as a Kaypro DSDD disk (KayPLUS numbering when
+    /// the machine uses side 1 sector IDs 0-9).
+    fn load_host_dir(&mut self, name: &str, path: &str) -> Result<()> {
+        let geometry = if self.side1_sector_base == 0 { GEOM_KAYPLUS_DSDD } else { GEOM_KAYPRO_DSDD };
+        let (host, content) = HostDir::mount(path, geometry, self.side1_sector_base)?;
+        self.write_protected = false;
+        self.file = None;
+        self.name = name.to_owned();
+        self.content = content;
+        self.format = MediaFormat::DsDd;
+        self.geometry = None;
+        self.learned_n = None;
+        self.learned_sector_base = None;
+        self.track_geometry.clear();
+        self.imd = None;
+        self.overlay = None;
+        self.faults.clear();
+        self.sidecar_faults.clear();
+        self.journal.clear();
+        self.host_dir = Some(host);
+        self.apply_geometry(geometry);
+        Ok(())
+    }
+
+    /// Switch to copy-on-write mode and reinsert the current image so its
+    /// overlay (if any) takes effect.  Embedded fallback disks have no file
+    /// and stay as they are.
+    pub fn enable_overlay(&mut self) -> Result<()> {
+        self.use_overlay = true;
+        if self.overlay.is_none() && std::path::Path::new(&self.name).is_file() {
+            let name = self.name.clone();
+            self.load_disk(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Write the overlay into the image through the normal write path (which
+    /// re-encodes ImageDisk files) and delete it.  The media must have been
+    /// loaded without overlay mode.
+    pub fn commit_overlay(&mut self, overlay: &mut Overlay) -> Result<()> {
+        if self.write_protected || self.file.is_none() {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                format!("{} is not writable", self.name)));
+        }
+        if self.imd.is_some() {
+            overlay.apply(&mut self.content);
+        } else {
+            overlay.apply_growing(&mut self.content, 0xE5);
+        }
+        self.write_min = 0;
+        self.write_max = self.content.len().saturating_sub(1);
+        self.flush_disk();
+        overlay.discard()
+    }
+
+    /// Replace the content with the sectors of an ImageDisk image.
+    ///
+    /// Every track gets its own `track_geometry` entry with the recorded mode,
+    /// N and sector ID map, so mixed-density disks (e.g. Xerox 820-II with an
+    /// FM track 0) read back exactly as formatted.  Each side-track occupies a
+    /// slot as large as the largest track, in the usual side-interleaved order.
+    /// Unavailable sectors read as E5.
+    pub fn load_imd(&mut self, mut image: ImdImage) {
+        let double_sided = image.tracks.iter().any(|t| t.head == 1);
+        let sides = if double_sided { 2 } else { 1 };
+        let tracks = image.tracks.iter().map(|t| t.cylinder as usize + 1).max().unwrap_or(0);
+        let widest = image.tracks.iter()
+            .max_by_key(|t| t.sector_ids.len() * t.sector_size());
+        let stride = widest.map(|t| t.sector_ids.len() * t.sector_size()).unwrap_or(0);
+
+        self.track_geometry.clear();
+        for t in &image.tracks {
+            self.track_geometry.insert((t.cylinder, t.head == 1), TrackGeometry {
+                n: t.n,
+                sector_count: t.sector_ids.len() as u8,
+                sector_base: t.sector_ids.iter().copied().min().unwrap_or(0),
+                single_density: t.single_density(),
+                sector_ids: t.sector_ids.clone(),
+            });
+        }
+
+        // Describe the disk by a typical data track (cylinder 1) and use the
+        // matching preset when there is one, so CP/M tools recognize it.
+        let side_base = |head: u8| image.tracks.iter()
+            .find(|t| t.cylinder == 1 && t.head == head)
+            .or_else(|| image.tracks.iter().find(|t| t.head == head))
+            .and_then(|t| t.sector_ids.iter().copied().min())
+            .unwrap_or(0);
+        let typical = image.tracks.iter().find(|t| t.cylinder == 1 && t.head == 0)
+            .or(image.tracks.first());
+        let geometry = DiskGeometry {
+            n: typical.map(|t| t.n).unwrap_or(2),
+            sectors_per_track: typical.map(|t| t.sector_ids.len() as u8).unwrap_or(0),
+            sector_id_base: side_base(0),
+            side1_sector_id_base: if double_sided { side_base(1) } else { 0 },
+            tracks: tracks as u8,
+            double_sided,
+            single_density: typical.map(|t| t.single_density()).unwrap_or(false),
+            label: "ImageDisk",
+        };
+        let geometry = GEOMETRY_PRESETS.iter()
+            .find(|g| g.same_layout(&geometry))
+            .copied()
+            .unwrap_or(geometry);
+
+        self.geometry = Some(geometry);
+        self.format = if double_sided {
+            MediaFormat::DsDd
+        } else if geometry.single_density {
+            MediaFormat::SsSd
+        } else {
+            MediaFormat::SsDd
+        };
+        self.learned_n = Some(widest.map(|t| t.n).unwrap_or(2));
+        self.learned_sector_base = Some(geometry.sector_id_base);
+        self.content = vec![0xE5; tracks * sides * stride];
+
+        self.faults.clear();
+        for t in image.tracks.iter_mut() {
+            for ((&id, data), &kind) in t.sector_ids.iter().zip(t.data.iter_mut()).zip(&t.sector_types) {
+                let fault = if imd::is_deleted(kind) { FAULT_DELETED } else { 0 }
+                    | if imd::has_crc_error(kind) { FAULT_CRC } else { 0 };
+                if fault != 0 {
+                    self.faults.insert((t.cylinder, t.head == 1, id), fault);
+                }
+                let (valid, index, last) = self.sector_index(t.head == 1, t.cylinder, id);
+                if valid && data.len() == last - index {
+                    self.content[index..last].copy_from_slice(data);
+                }
+                // The content is authoritative from now on
+                *data = Vec::new();
+            }
+        }
+        self.imd = Some(image);
+    }
+
+    /// Rebuild the ImageDisk image from the content and track geometry.
+    /// Tracks that still have their original sector map keep their mode,
+    /// cylinder/head maps and sector types; reformatted tracks are written
+    /// as plain 250 kbps FM/MFM tracks.
+    pub fn to_imd(&self) -> ImdImage {
+        let header = match self.imd {
+            Some(ref image) => image.header.clone(),
+            None => b"IMD 1.18: izkaypro\r\n".to_vec(),
+        };
+        let mut keys: Vec<&(u8, bool)> = self.track_geometry.keys().collect();
+        keys.sort();
+        let mut tracks = Vec::new();
+        for &(cylinder, side_2) in keys {
+            let geom = &self.track_geometry[&(cylinder, side_2)];
+            let ids = geom.ids();
+            let original = self.imd.as_ref().and_then(|image| image.tracks.iter()
+                .find(|t| t.cylinder == cylinder && (t.head == 1) == side_2
+                    && t.n == geom.n && t.sector_ids == ids
+                    && t.single_density() == geom.single_density));
+            let data: Vec<Vec<u8>> = ids.iter().map(|&id| {
+                let (valid, index, last) = self.sector_index(side_2, cylinder, id);
+                if valid { self.content[index..last].to_vec() } else { Vec::new() }
+            }).collect();
+            // Data marks and CRC errors as they are now, leaving out the
+            // sidecar's; unavailable sectors stay unavailable.
+            let sector_types: Vec<u8> = ids.iter().enumerate().map(|(i, &id)| {
+                match original {
+                    Some(t) if t.sector_types[i] == imd::SECTOR_UNAVAILABLE => imd::SECTOR_UNAVAILABLE,
+                    _ => {
+                        let sidecar = self.sidecar_faults.get(&(cylinder, side_2, id)).copied().unwrap_or(0);
+                        let fault = self.fault(side_2, cylinder, id) & !sidecar;
+                        imd::sector_type(fault & FAULT_DELETED != 0, fault & (FAULT_CRC | FAULT_WEAK) != 0)
+                    }
+                }
+            }).collect();
+            let track = match original {
+                Some(t) => ImdTrack {
+                    mode: t.mode,
+                    cylinder,
+                    head: t.head,
+                    n: t.n,
+                    sector_ids: ids,
+                    cylinder_map: t.cylinder_map.clone(),
+                    head_map: t.head_map.clone(),
+                    sector_types,
+                    data,
+                },
+                None => ImdTrack {
+                    mode: imd::mode_for_density(geom.single_density),
+                    cylinder,
+                    head: side_2 as u8,
+                    n: geom.n,
+                    sector_types,
+                    sector_ids: ids,
+                    cylinder_map: None,
+                    head_map: None,
+                    data,
+                },
+            };
+            tracks.push(track);
+        }
+        ImdImage { header, tracks }
+    }
+
+    /// Write the changes back, only reporting the errors.
     pub fn flush_disk(&mut self) {
+        if let Err(e) = self.try_flush_disk() {
+            eprintln!("Warning: {}", e);
+        }
+    }
+
+    /// Write the sectors changed since the last flush to the image, its
+    /// overlay or the host directory.
+    pub fn try_flush_disk(&mut self) -> Result<()> {
         if self.write_max < self.write_min {
             // nothing to write
-            return;
+            return Ok(());
         }
-
-        if let Some(ref mut file) = self.file {
-            if let Err(e) = file.seek(SeekFrom::Start(self.write_min as u64)) {
-                eprintln!("Warning: Failed to seek disk '{}': {}", self.name, e);
-                return;
+        let name = self.name.clone();
+        let failed = |what: &str, e: Error| Error::new(e.kind(), format!("{} '{}': {}", what, name, e));
+
+        if let Some(ref mut overlay) = self.overlay {
+            let end = (self.write_max + 1).min(self.content.len());
+            overlay.write(&self.content, self.write_min, end)
+                .map_err(|e| Error::new(e.kind(), format!("Failed to write overlay '{}': {}", overlay.path(), e)))?;
+        } else if self.imd.is_some() {
+            // Sector offsets in an IMD file shift with compression, so
+            // the whole image is rewritten.
+            let image = self.to_imd().to_bytes();
+            if let Some(ref mut file) = self.file {
+                file.seek(SeekFrom::Start(0))
+                    .and_then(|_| file.write_all(&image))
+                    .and_then(|_| file.set_len(image.len() as u64))
+                    .map_err(|e| failed("Failed to write disk", e))?;
             }
-            if let Err(e) = file.write_all(&self.content[self.write_min..=self.write_max]) {
-                eprintln!("Warning: Failed to write disk '{}': {}", self.name, e);
-                return;
+        } else if let Some(ref mut host) = self.host_dir {
+            if let Some(geometry) = self.geometry {
+                host.sync(&self.content, self.write_min..self.write_max + 1, geometry, self.side1_sector_base)
+                    .map_err(|e| failed("Failed to update host directory", e))?;
             }
+        } else if let Some(ref mut file) = self.file {
+            file.seek(SeekFrom::Start(self.write_min as u64))
+                .map_err(|e| failed("Failed to seek disk", e))?;
+            file.write_all(&self.content[self.write_min..=self.write_max])
+                .map_err(|e| failed("Failed to write disk", e))?;
         }
 
         self.write_max = 0;
         self.write_min = usize::MAX;
+        Ok(())
     }
 
     pub fn is_valid_track(&self, track: u8) -> bool {
@@ -483,6 +997,7 @@ impl Media {
         let stride = self.track_stride_per_side();
         if stride == 0 { return; }
         let new_len = tracks * 2 * stride;
+        self.journal.record_content(&self.content);
         let mut new_content = vec![0xE5u8; new_len];
         for t in 0..tracks {
             let src_offset = t * stride;
@@ -531,8 +1046,32 @@ impl Media {
         (true, base, n)
     }
 
-    pub fn density_matches(&self, controller_single_density: bool) -> bool {
-        if let Some(ref geom) = self.geometry {
+    /// Layout of a track as it would be found on the disk, or None if the
+    /// track isn't on the media.
+    pub fn track_layout(&self, side_2: bool, track: u8) -> Option<TrackGeometry> {
+        if track >= self.tracks() || (side_2 && !self.double_sided()) {
+            return None;
+        }
+        if let Some(geom) = self.track_geometry.get(&(track, side_2)) {
+            return Some(geom.clone());
+        }
+        let (_, sector_base, n) = self.read_address(side_2, track, 0);
+        Some(TrackGeometry {
+            n,
+            sector_count: self.sectors_per_side(),
+            sector_base,
+            single_density: match self.geometry {
+                Some(ref geom) => geom.single_density,
+                None => self.format == MediaFormat::SsSd,
+            },
+            sector_ids: Vec::new(),
+        })
+    }
+
+    pub fn density_matches(&self, side_2: bool, track: u8, controller_single_density: bool) -> bool {
+        if let Some(geom) = self.track_geometry.get(&(track, side_2)) {
+            geom.single_density == controller_single_density
+        } else if let Some(ref geom) = self.geometry {
             geom.single_density == controller_single_density
         } else {
             true
@@ -549,13 +1088,11 @@ impl Media {
 
         // Look up per-track geometry (from WRITE TRACK), fall back to globals
         if let Some(geom) = self.track_geometry.get(&(track, side_2)) {
-            let n = geom.n;
-            let spt = geom.sector_count;
-            let base = geom.sector_base;
-            let sector_size = 128usize << (n as usize);
-
-            let adjusted = if sector >= base { sector - base } else { return (false, 0, 0); };
-            if adjusted >= spt { return (false, 0, 0); }
+            let sector_size = 128usize << (geom.n as usize);
+            let slot = match geom.slot(sector) {
+                Some(slot) => slot,
+                None => return (false, 0, 0),
+            };
 
             let stride = self.track_stride_per_side();
             if stride == 0 { return (false, 0, 0); }
@@ -563,7 +1100,7 @@ impl Media {
             let side_idx = if side_2 { 1usize } else { 0usize };
             let track_offset = stride * (track as usize * sides + side_idx);
 
-            let index = track_offset + adjusted as usize * sector_size;
+            let index = track_offset + slot * sector_size;
             let last = index + sector_size;
             if last > self.content.len() {
                 return (false, 0, 0);
@@ -604,6 +1141,7 @@ impl Media {
     }
 
     pub fn write_byte(&mut self, index: usize, value: u8) {
+        self.journal.record(&self.content, index, 1);
         self.content[index] = value;
         if index < self.write_min {
             self.write_min = index;
@@ -613,14 +1151,54 @@ impl Media {
         }
     }
 
+    /// Start journaling the writes after rewind point `point`.
+    pub fn mark_rewind_point(&mut self, point: u64, oldest: u64) {
+        let layout = MediaLayout {
+            format: self.format,
+            geometry: self.geometry,
+            learned_n: self.learned_n,
+            learned_sector_base: self.learned_sector_base,
+            track_geometry: self.track_geometry.clone(),
+            faults: self.faults.clone(),
+        };
+        self.journal.mark(point, oldest, layout);
+    }
+
+    /// Undo the writes since rewind point `point` and write the restored
+    /// sectors back to the image.
+    pub fn rewind(&mut self, point: u64) {
+        let Some((layout, changed)) = self.journal.rewind(point, &mut self.content) else {
+            return;
+        };
+        self.format = layout.format;
+        self.geometry = layout.geometry;
+        self.learned_n = layout.learned_n;
+        self.learned_sector_base = layout.learned_sector_base;
+        self.track_geometry = layout.track_geometry;
+        self.faults = layout.faults;
+        if let Some((start, end)) = changed {
+            self.write_min = self.write_min.min(start);
+            self.write_max = self.write_max.max(end - 1).min(self.content.len().saturating_sub(1));
+        }
+        self.flush_disk();
+        // A disk upgraded to double sided since goes back to its size
+        if let (Some(file), None, None) = (&self.file, &self.imd, &self.overlay) {
+            if let Err(e) = file.set_len(self.content.len() as u64) {
+                eprintln!("Warning: Failed to resize disk '{}': {}", self.name, e);
+            }
+        }
+    }
+
     pub fn is_write_protected(&self) -> bool {
         self.write_protected
     }
 
     pub fn info(&self) -> String {
-        let persistence = match self.file {
-            Some(_) => "persistent",
-            _       => "transient",
+        let persistence = match (&self.overlay, &self.file) {
+            _ if self.host_dir.is_some() => "host directory",
+            (Some(_), _) => "overlay",
+            (_, Some(_)) => "persistent",
+            _            => "transient",
         };
         let fmt = if let Some(ref geom) = self.geometry {
             geom.label
@@ -0,0 +1,339 @@
+// Machine-language monitor.
+//
+// The front ends read the command lines and show the replies; the commands
+// run on the emulation thread with the machine stopped between two
+// instructions. Addresses, values and counts are hex:
+//
+//     r                  registers and the next instruction
+//     r REG VALUE        set a register: A F B C D E H L I R, AF BC DE HL,
+//                        AF' BC' DE' HL', IX IY SP PC
+//     d [ADDR [LEN]]     dump memory, carrying on from the last dump
+//     e ADDR BYTE...     edit memory
+//     u [ADDR [COUNT]]   disassemble, from PC or carrying on
+//     b [ADDR]           list the breakpoints, or set one on PC = ADDR
+//     bc [ADDR]          clear a breakpoint, or all of them
+//     s [COUNT]          single-step
+//     o                  step over a CALL, RST, DJNZ or repeated block
+//     g [ADDR]           go, or run to ADDR
+//
+// Memory is shown and edited as the CPU sees it in the current bank: the
+// ROM and, on the Kaypro II and 4/83, the video RAM when the ROM bank is
+// selected, the RAM otherwise. Each line tells which.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+
+use iz80::{Cpu, Machine, Reg8};
+
+use crate::disassembler;
+use crate::kaypro_machine::{KayproMachine, RunExit};
+use crate::snapshot::CpuState;
+
+const HELP: &str = "\
+r [REG VALUE]     Show or set registers  s [COUNT]  Single-step
+d [ADDR [LEN]]    Dump memory            o          Step over
+e ADDR BYTE...    Edit memory            g [ADDR]   Go, or run to ADDR
+u [ADDR [COUNT]]  Disassemble            b [ADDR]   List or set breakpoints
+                                         bc [ADDR]  Clear breakpoints
+Numbers are hex. ESC leaves the monitor and lets the machine go.";
+
+/// Lines shown by d and u when no length is given
+const DUMP_LEN: u16 = 0x80;
+const LIST_COUNT: u16 = 8;
+
+/// What the machine does after a command
+pub enum Reply {
+    /// It stays stopped; the output of the command
+    Stopped(String),
+    /// It runs again
+    Go,
+}
+
+pub struct Monitor {
+    breakpoints: BTreeSet<u16>,
+    /// Stop here once, for step over and run to
+    target: Option<u16>,
+    /// Where d carries on
+    dump_at: u16,
+    /// Where u carries on, None to start from PC
+    list_at: Option<u16>,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor {
+            breakpoints: BTreeSet::new(),
+            target: None,
+            dump_at: 0x0100,
+            list_at: None,
+        }
+    }
+
+    /// There is a breakpoint or a target to check after each instruction.
+    pub fn is_watching(&self) -> bool {
+        !self.breakpoints.is_empty() || self.target.is_some()
+    }
+
+    /// Whether the machine stops before the instruction at `pc`.
+    pub fn stops_at(&mut self, pc: u16) -> bool {
+        if self.target == Some(pc) || self.breakpoints.contains(&pc) {
+            self.target = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Breakpoints of the debugger, see gdb.rs
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    /// Where the machine stopped: the registers and the next instruction.
+    /// The machine must be settled.
+    pub fn stopped_at(&mut self, machine: &KayproMachine, cpu: &mut Cpu) -> String {
+        self.list_at = None;
+        self.registers(machine, cpu)
+    }
+
+    pub fn command(&mut self, line: &str, machine: &mut KayproMachine, cpu: &mut Cpu) -> Reply {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Reply::Stopped(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+        let result = match command.to_ascii_lowercase().as_str() {
+            "r" if args.is_empty() => Ok(self.registers(machine, cpu)),
+            "r" => self.set_register(&args, machine, cpu),
+            "d" => self.dump(&args, machine),
+            "e" => self.edit(&args, machine),
+            "u" => self.list(&args, machine, cpu),
+            "b" => self.set_breakpoint(&args),
+            "bc" => self.clear_breakpoint(&args),
+            "s" => self.step(&args, machine, cpu),
+            "o" => return self.step_over(machine, cpu),
+            "g" => return match args.first().map(|arg| parse(arg)) {
+                None => Reply::Go,
+                Some(Ok(address)) => {
+                    self.target = Some(address);
+                    Reply::Go
+                },
+                Some(Err(err)) => Reply::Stopped(err),
+            },
+            "?" | "h" => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command '{}', ? for help", command)),
+        };
+        Reply::Stopped(result.unwrap_or_else(|err| err))
+    }
+
+    fn registers(&self, machine: &KayproMachine, cpu: &mut Cpu) -> String {
+        let state = CpuState::capture(cpu);
+        let flags: String = "SZYHXPNC".chars().enumerate()
+            .map(|(bit, flag)| if state.af & (0x80 >> bit) != 0 { flag } else { '-' })
+            .collect();
+        format!("AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X} SP={:04X}  {}\n\
+            AF'={:04X} BC'={:04X} DE'={:04X} HL'={:04X} I={:02X} R={:02X}  {} bank{}\n{}",
+            state.af, state.bc, state.de, state.hl, state.ix, state.iy, state.sp, flags,
+            state.af_alt, state.bc_alt, state.de_alt, state.hl_alt, state.i, state.r,
+            if machine.is_rom_rank() { "ROM" } else { "RAM" },
+            if state.halted { "  HALT" } else { "" },
+            self.listing_line(machine, state.pc).0)
+    }
+
+    fn set_register(&self, args: &[&str], machine: &KayproMachine, cpu: &mut Cpu) -> Result<String, String> {
+        let [name, value] = args else {
+            return Err("Expected a register and a value".to_string());
+        };
+        let value = parse(value)?;
+        let mut state = CpuState::capture(cpu);
+        let name = name.to_ascii_uppercase();
+        let (pair, high) = match name.as_str() {
+            "A" => (&mut state.af, Some(true)),
+            "F" => (&mut state.af, Some(false)),
+            "B" => (&mut state.bc, Some(true)),
+            "C" => (&mut state.bc, Some(false)),
+            "D" => (&mut state.de, Some(true)),
+            "E" => (&mut state.de, Some(false)),
+            "H" => (&mut state.hl, Some(true)),
+            "L" => (&mut state.hl, Some(false)),
+            "AF" => (&mut state.af, None),
+            "BC" => (&mut state.bc, None),
+            "DE" => (&mut state.de, None),
+            "HL" => (&mut state.hl, None),
+            "AF'" => (&mut state.af_alt, None),
+            "BC'" => (&mut state.bc_alt, None),
+            "DE'" => (&mut state.de_alt, None),
+            "HL'" => (&mut state.hl_alt, None),
+            "IX" => (&mut state.ix, None),
+            "IY" => (&mut state.iy, None),
+            "SP" => (&mut state.sp, None),
+            "PC" => (&mut state.pc, None),
+            "I" | "R" => {
+                let value = u8::try_from(value).map_err(|_| format!("{} is 8 bits", name))?;
+                let regs = cpu.registers();
+                regs.set8(if name == "I" { Reg8::I } else { Reg8::R }, value);
+                return Ok(self.registers(machine, cpu));
+            },
+            _ => return Err(format!("Unknown register '{}'", name)),
+        };
+        match high {
+            None => {
+                *pair = value;
+                // Jumping away leaves a HALT
+                if name == "PC" {
+                    state.halted = false;
+                }
+            },
+            Some(high) => {
+                let value = u8::try_from(value).map_err(|_| format!("{} is 8 bits", name))? as u16;
+                *pair = if high { (*pair & 0x00FF) | value << 8 } else { (*pair & 0xFF00) | value };
+            },
+        }
+        state.apply(cpu);
+        Ok(self.registers(machine, cpu))
+    }
+
+    fn dump(&mut self, args: &[&str], machine: &KayproMachine) -> Result<String, String> {
+        if let Some(address) = args.first() {
+            self.dump_at = parse(address)?;
+        }
+        let len = match args.get(1) {
+            Some(len) => parse(len)?.max(1),
+            None => DUMP_LEN,
+        };
+        let start = self.dump_at;
+        self.dump_at = start.wrapping_add(len);
+        Ok(dump_lines(machine, start, len))
+    }
+
+    fn edit(&mut self, args: &[&str], machine: &mut KayproMachine) -> Result<String, String> {
+        let Some((address, bytes)) = args.split_first() else {
+            return Err("Expected an address and bytes".to_string());
+        };
+        let address = parse(address)?;
+        let bytes = bytes.iter()
+            .map(|byte| parse(byte).and_then(|value| u8::try_from(value)
+                .map_err(|_| format!("'{}' is not a byte", byte))))
+            .collect::<Result<Vec<u8>, String>>()?;
+        if bytes.is_empty() {
+            return Err("Expected bytes to write".to_string());
+        }
+        for (offset, &byte) in bytes.iter().enumerate() {
+            machine.poke(address.wrapping_add(offset as u16), byte);
+        }
+        // Read back: a write to the ROM goes to the RAM under it
+        Ok(dump_lines(machine, address, bytes.len() as u16))
+    }
+
+    fn list(&mut self, args: &[&str], machine: &KayproMachine, cpu: &mut Cpu) -> Result<String, String> {
+        let mut address = match args.first() {
+            Some(address) => parse(address)?,
+            None => self.list_at.unwrap_or_else(|| cpu.registers().pc()),
+        };
+        let count = match args.get(1) {
+            Some(count) => parse(count)?,
+            None => LIST_COUNT,
+        };
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let (line, len) = self.listing_line(machine, address);
+            lines.push(line);
+            address = address.wrapping_add(len);
+        }
+        self.list_at = Some(address);
+        Ok(lines.join("\n"))
+    }
+
+    /// The instruction at `address` with its bytes, and its length.
+    fn listing_line(&self, machine: &KayproMachine, address: u16) -> (String, u16) {
+        let instruction = disassembler::disassemble(|at| machine.peek(at), address);
+        let bytes: Vec<String> = (0..instruction.len)
+            .map(|offset| format!("{:02X}", machine.peek(address.wrapping_add(offset))))
+            .collect();
+        let mark = if self.breakpoints.contains(&address) { '*' } else { ' ' };
+        let line = format!("{} {:04X}{} {:<12}{}", machine.memory_bank(address), address, mark,
+            bytes.join(" "), instruction.text);
+        (line, instruction.len)
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        for address in args {
+            self.breakpoints.insert(parse(address)?);
+        }
+        if self.breakpoints.is_empty() {
+            return Ok("No breakpoints".to_string());
+        }
+        let addresses: Vec<String> = self.breakpoints.iter().map(|address| format!("{:04X}", address)).collect();
+        Ok(format!("Breakpoints: {}", addresses.join(" ")))
+    }
+
+    fn clear_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        if args.is_empty() {
+            self.breakpoints.clear();
+        }
+        for address in args {
+            let address = parse(address)?;
+            if !self.breakpoints.remove(&address) {
+                return Err(format!("No breakpoint at {:04X}", address));
+            }
+        }
+        self.set_breakpoint(&[])
+    }
+
+    fn step(&mut self, args: &[&str], machine: &mut KayproMachine, cpu: &mut Cpu) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => parse(count)?.max(1),
+            None => 1,
+        };
+        for done in 1..=count {
+            let exit = machine.tick(cpu);
+            machine.settle(cpu);
+            if exit == Some(RunExit::Halted) {
+                return Ok(format!("HALT that will never be interrupted\n{}", self.stopped_at(machine, cpu)));
+            }
+            if done < count && self.breakpoints.contains(&cpu.registers().pc()) {
+                break;
+            }
+        }
+        Ok(self.stopped_at(machine, cpu))
+    }
+
+    fn step_over(&mut self, machine: &mut KayproMachine, cpu: &mut Cpu) -> Reply {
+        let pc = cpu.registers().pc();
+        let instruction = disassembler::disassemble(|at| machine.peek(at), pc);
+        if instruction.steps_over {
+            self.target = Some(pc.wrapping_add(instruction.len));
+            Reply::Go
+        } else {
+            Reply::Stopped(self.step(&[], machine, cpu).unwrap_or_else(|err| err))
+        }
+    }
+}
+
+/// Hex dump of `len` bytes from `start`, 16 to a line.
+fn dump_lines(machine: &KayproMachine, start: u16, len: u16) -> String {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let address = start.wrapping_add(offset);
+        let count = (len - offset).min(16);
+        let bytes: Vec<u8> = (0..count).map(|i| machine.peek(address.wrapping_add(i))).collect();
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = bytes.iter()
+            .map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' })
+            .collect();
+        lines.push(format!("{} {:04X}  {:<48}{}", machine.memory_bank(address), address, hex.join(" "), text));
+        offset += count;
+    }
+    lines.join("\n")
+}
+
+/// A hex number, with an optional H suffix, up to FFFF.
+fn parse(text: &str) -> Result<u16, String> {
+    let digits = text.strip_suffix(['h', 'H']).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number up to FFFF", text))
+}
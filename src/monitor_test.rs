@@ -0,0 +1,107 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iz80::Cpu;
+
+    use crate::emulator::{Emulator, EmulatorHandle, Request, Update};
+    use crate::kaypro_machine::{KayproMachine, VideoMode};
+    use crate::monitor::{Monitor, Reply};
+    use crate::test_support::machine;
+
+    fn kaypro_4() -> KayproMachine {
+        machine("roms/81-292a.rom", VideoMode::MemoryMapped, false)
+    }
+
+    fn command(monitor: &mut Monitor, line: &str, machine: &mut KayproMachine, cpu: &mut Cpu) -> String {
+        match monitor.command(line, machine, cpu) {
+            Reply::Stopped(output) => output,
+            Reply::Go => panic!("'{}' let the machine go", line),
+        }
+    }
+
+    #[test]
+    fn test_monitor_commands() {
+        let mut machine = kaypro_4();
+        let mut cpu = Cpu::new_z80();
+        let mut monitor = Monitor::new();
+        let mut run = |line: &str| command(&mut monitor, line, &mut machine, &mut cpu);
+
+        // The ROM is mapped at reset, and writes under it go to the RAM
+        let rom = run("d 0 4");
+        assert!(rom.starts_with("ROM 0000  "), "{}", rom);
+        assert_eq!(run("e 0 FF FF")[..16], rom[..16]);
+        assert!(run("d 3000 1").starts_with("VID 3000  "));
+
+        assert_eq!(run("e 8000 3E 42 76"), format!("RAM 8000  3E 42 76{}>Bv", " ".repeat(40)));
+        let registers = run("r pc 8000");
+        assert!(registers.ends_with("RAM 8000  3E 42       LD A,42H"), "{}", registers);
+        let registers = run("s");
+        assert!(registers.starts_with("AF=42"), "{}", registers);
+        assert!(registers.ends_with("RAM 8002  76          HALT"), "{}", registers);
+        assert_eq!(run("u 8000 2"), "RAM 8000  3E 42       LD A,42H\nRAM 8002  76          HALT");
+        assert!(run("r hl' 1234").contains("HL'=1234"));
+        assert!(run("r c 56").contains("BC=0056"));
+
+        assert_eq!(run("b 8002 8000"), "Breakpoints: 8000 8002");
+        assert_eq!(run("u 8002 1"), "RAM 8002* 76          HALT");
+        assert_eq!(run("bc 8000"), "Breakpoints: 8002");
+        assert_eq!(run("bc"), "No breakpoints");
+
+        for bad in ["bc 1234", "r a 100", "r q 1", "r pc", "e 8000", "e 8000 123", "d G", "x"] {
+            assert!(!run(bad).starts_with("RAM"), "{}", bad);
+        }
+        assert!(matches!(monitor.command("g", &mut machine, &mut cpu), Reply::Go));
+    }
+
+    /// The next update other than a frame.
+    fn next(emulator: &EmulatorHandle) -> Update {
+        loop {
+            match emulator.updates.recv_timeout(Duration::from_secs(10)) {
+                Ok(Update::Frame(_)) => {},
+                Ok(update) => return update,
+                Err(e) => panic!("no update: {}", e),
+            }
+        }
+    }
+
+    fn send(emulator: &EmulatorHandle, line: &str) -> Update {
+        emulator.requests.send(Request::Monitor(line.to_string())).unwrap();
+        next(emulator)
+    }
+
+    #[test]
+    fn test_emulation_thread_stops_in_the_monitor() {
+        let mut emulator = Emulator::new(kaypro_4(), None, false);
+        emulator.debug();
+        let emulator = emulator.spawn(false);
+        assert!(matches!(next(&emulator), Update::Stopped(registers) if registers.contains("ROM 0000")));
+
+        // NOP, NOP, JP 8000H and a subroutine to step over
+        send(&emulator, "e 8000 00 00 C3 00 80");
+        send(&emulator, "e 8010 CD 20 80 C3 10 80");
+        send(&emulator, "e 8020 00 C9");
+        send(&emulator, "r pc 8000");
+        send(&emulator, "b 8002");
+        assert!(matches!(send(&emulator, "g"), Update::Resumed));
+        assert!(matches!(next(&emulator), Update::Stopped(registers)
+            if registers.ends_with("RAM 8002* C3 00 80    JP 8000H")));
+
+        // Run to
+        send(&emulator, "bc");
+        assert!(matches!(send(&emulator, "g 8001"), Update::Resumed));
+        assert!(matches!(next(&emulator), Update::Stopped(registers) if registers.contains("RAM 8001 ")));
+
+        send(&emulator, "r pc 8010");
+        assert!(matches!(send(&emulator, "o"), Update::Resumed));
+        assert!(matches!(next(&emulator), Update::Stopped(registers) if registers.contains("RAM 8013 ")));
+        // Step over something else steps
+        assert!(matches!(send(&emulator, "o"), Update::Monitor(registers) if registers.contains("RAM 8010 ")));
+
+        // Break into the running machine
+        assert!(matches!(send(&emulator, "g"), Update::Resumed));
+        emulator.requests.send(Request::Break).unwrap();
+        assert!(matches!(next(&emulator), Update::Stopped(registers) if registers.contains("RAM 80")));
+        emulator.stop();
+    }
+}
@@ -0,0 +1,225 @@
+// Copy-on-write overlay for disk images.
+//
+// With --overlay, writes to a floppy or hard disk image go to a sidecar
+// file "<image>.overlay" instead of the image itself.  The sidecar is an
+// append-only log of fixed-size blocks keyed by block number (the offset
+// in the in-memory image divided by the block size); the last record for
+// a block wins.  On load the log is replayed over the base image.
+//
+//   Header: "IZKOVL" 0x01, block size (u32 LE)
+//   Record: block number (u32 LE), block data
+//   Metadata record: 0xFFFFFFFF, length (u32 LE), data
+//
+// The metadata is what the medium records besides the blocks, opaque to
+// the overlay (the formatted tracks of a hard disk); the last record wins.
+//
+// The log is compacted once it holds more than twice as many records as
+// distinct blocks, so repeated directory updates don't grow it forever.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 7] = b"IZKOVL\x01";
+const HEADER_SIZE: usize = 11;
+/// Don't bother compacting small logs
+const COMPACT_MIN_RECORDS: usize = 64;
+/// Block number of the metadata records
+const METADATA: u32 = u32::MAX;
+
+pub struct Overlay {
+    path: String,
+    block_size: usize,
+    blocks: BTreeMap<u32, Vec<u8>>,
+    metadata: Option<Vec<u8>>,
+    /// Records in the log file, including superseded ones
+    records: usize,
+}
+
+pub fn sidecar_path(image: &str) -> String {
+    format!("{}.overlay", image)
+}
+
+impl Overlay {
+    /// Open the overlay of `image`, loading its log if one exists.
+    pub fn open(image: &str, block_size: usize) -> Result<Overlay> {
+        let path = sidecar_path(image);
+        let mut overlay = Overlay { path, block_size, blocks: BTreeMap::new(), metadata: None, records: 0 };
+        let data = match fs::read(&overlay.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(overlay),
+            Err(e) => return Err(e),
+        };
+        if data.len() < HEADER_SIZE || &data[..7] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} is not an overlay file", overlay.path)));
+        }
+        let stored_size = u32::from_le_bytes([data[7], data[8], data[9], data[10]]) as usize;
+        if stored_size != block_size {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} has {}-byte blocks, expected {}", overlay.path, stored_size, block_size)));
+        }
+        // A torn final record (crash during append) is ignored
+        let mut log = &data[HEADER_SIZE..];
+        while log.len() >= 4 {
+            let block = u32::from_le_bytes([log[0], log[1], log[2], log[3]]);
+            let (start, len) = if block == METADATA {
+                match log.get(4..8) {
+                    Some(len) => (8, u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize),
+                    None => break,
+                }
+            } else {
+                (4, block_size)
+            };
+            let Some(record) = log.get(start..start + len) else {
+                break;
+            };
+            if block == METADATA {
+                overlay.metadata = Some(record.to_vec());
+            } else {
+                overlay.blocks.insert(block, record.to_vec());
+            }
+            overlay.records += 1;
+            log = &log[start + len..];
+        }
+        Ok(overlay)
+    }
+
+    /// Block size stored in an existing overlay file.
+    pub fn stored_block_size(image: &str) -> Result<usize> {
+        let mut header = [0u8; HEADER_SIZE];
+        File::open(sidecar_path(image))?.read_exact(&mut header)?;
+        if &header[..7] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} is not an overlay file", sidecar_path(image))));
+        }
+        Ok(u32::from_le_bytes([header[7], header[8], header[9], header[10]]) as usize)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Number of distinct blocks that differ from the base image.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The last metadata written, if any.
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    /// Replay the overlay over the base image content.
+    pub fn apply(&self, content: &mut [u8]) {
+        for (&block, data) in &self.blocks {
+            let start = block as usize * self.block_size;
+            if start + self.block_size <= content.len() {
+                content[start..start + self.block_size].copy_from_slice(data);
+            }
+        }
+    }
+
+    /// Like `apply()`, but first extends the content with `fill` so that
+    /// blocks past its end are kept.
+    pub fn apply_growing(&self, content: &mut Vec<u8>, fill: u8) {
+        if let Some(&last) = self.blocks.keys().next_back() {
+            let end = (last as usize + 1) * self.block_size;
+            if end > content.len() {
+                content.resize(end, fill);
+            }
+        }
+        self.apply(content);
+    }
+
+    /// Record the blocks covering `content[start..end]` and append them to
+    /// the log.
+    pub fn write(&mut self, content: &[u8], start: usize, end: usize) -> Result<()> {
+        let first = start / self.block_size;
+        let last = end.div_ceil(self.block_size).min(content.len() / self.block_size);
+        if first >= last {
+            return Ok(());
+        }
+        let mut log = Vec::with_capacity((last - first) * (4 + self.block_size));
+        for block in first..last {
+            let data = &content[block * self.block_size..(block + 1) * self.block_size];
+            log.extend_from_slice(&(block as u32).to_le_bytes());
+            log.extend_from_slice(data);
+            self.blocks.insert(block as u32, data.to_vec());
+            self.records += 1;
+        }
+
+        self.append(&log)
+    }
+
+    /// Record the metadata and append it to the log.
+    pub fn write_metadata(&mut self, metadata: &[u8]) -> Result<()> {
+        self.metadata = Some(metadata.to_vec());
+        self.records += 1;
+        self.append(&metadata_record(metadata))
+    }
+
+    fn append(&mut self, log: &[u8]) -> Result<()> {
+        let live = self.blocks.len() + self.metadata.is_some() as usize;
+        if self.records > COMPACT_MIN_RECORDS && self.records > 2 * live {
+            return self.rewrite();
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if file.seek(SeekFrom::End(0))? == 0 {
+            file.write_all(&self.header())?;
+        }
+        file.write_all(log)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+        header
+    }
+
+    /// Rewrite the log with one record per block.
+    fn rewrite(&mut self) -> Result<()> {
+        let mut data = self.header();
+        for (&block, bytes) in &self.blocks {
+            data.extend_from_slice(&block.to_le_bytes());
+            data.extend_from_slice(bytes);
+        }
+        if let Some(ref metadata) = self.metadata {
+            data.extend_from_slice(&metadata_record(metadata));
+        }
+        let temp = format!("{}.tmp", self.path);
+        fs::write(&temp, &data)?;
+        fs::rename(&temp, &self.path)?;
+        self.records = self.blocks.len() + self.metadata.is_some() as usize;
+        Ok(())
+    }
+
+    /// Write the overlay blocks into an image file whose content starts at
+    /// byte `base` (0 for a raw image).
+    pub fn commit_raw(&self, image: &str, base: u64) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(image)?;
+        for (&block, data) in &self.blocks {
+            file.seek(SeekFrom::Start(base + block as u64 * self.block_size as u64))?;
+            file.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Forget all changes and delete the log.
+    pub fn discard(&mut self) -> Result<()> {
+        self.blocks.clear();
+        self.metadata = None;
+        self.records = 0;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn metadata_record(metadata: &[u8]) -> Vec<u8> {
+    let mut record = METADATA.to_le_bytes().to_vec();
+    record.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    record.extend_from_slice(metadata);
+    record
+}
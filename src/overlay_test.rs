@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod tests {
+    use crate::hard_disk::HardDisk;
+    use crate::imd::{ImdImage, ImdTrack};
+    use crate::media::Media;
+    use crate::overlay::{self, Overlay};
+    use crate::test_support::{hd_command, temp_path};
+
+    const DSDD_SIZE: usize = 40 * 2 * 10 * 512;
+
+    fn cleanup(path: &str) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(overlay::sidecar_path(path));
+    }
+
+    fn write_sector(media: &mut Media, side_2: bool, track: u8, id: u8, value: u8) {
+        let (valid, index, last) = media.sector_index(side_2, track, id);
+        assert!(valid);
+        for i in index..last {
+            media.write_byte(i, value);
+        }
+        media.flush_disk();
+    }
+
+    #[test]
+    fn test_floppy_overlay_leaves_base_untouched() {
+        let path = temp_path("overlay_floppy.img");
+        std::fs::write(&path, vec![0xE5; DSDD_SIZE]).unwrap();
+
+        let mut media = Media::new(10);
+        media.use_overlay = true;
+        media.load_disk(&path).unwrap();
+        assert!(!media.is_write_protected());
+        write_sector(&mut media, true, 5, 12, 0x42);
+        assert!(media.info().contains("overlay"));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xE5; DSDD_SIZE]);
+
+        // Reinserting replays the overlay; without overlay mode the base shows
+        let mut reloaded = Media::new(10);
+        reloaded.use_overlay = true;
+        reloaded.load_disk(&path).unwrap();
+        let (_, index, last) = reloaded.sector_index(true, 5, 12);
+        assert_eq!(reloaded.content[index..last], vec![0x42; 512][..]);
+        let mut base = Media::new(10);
+        base.load_disk(&path).unwrap();
+        assert_eq!(base.content[index], 0xE5);
+
+        // Commit writes the sector at the same offset and drops the sidecar
+        let mut delta = Overlay::open(&path, 128).unwrap();
+        assert_eq!(delta.len(), 4);
+        delta.commit_raw(&path, 0).unwrap();
+        delta.discard().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        cleanup(&path);
+        assert!(!std::path::Path::new(&overlay::sidecar_path(&path)).exists());
+        assert_eq!(data[index..last], vec![0x42; 512][..]);
+        assert_eq!(data.iter().filter(|&&b| b != 0xE5).count(), 512);
+    }
+
+    #[test]
+    fn test_imd_overlay_commit() {
+        let path = temp_path("overlay_imd.img");
+        let tracks = (0..80u8).map(|t| ImdTrack {
+            mode: 5,
+            cylinder: t / 2,
+            head: t % 2,
+            n: 2,
+            sector_ids: (0..10).map(|i| i + (t % 2) * 10).collect(),
+            cylinder_map: None,
+            head_map: None,
+            sector_types: vec![1; 10],
+            data: vec![vec![0xE5; 512]; 10],
+        }).collect();
+        let original = ImdImage { header: b"IMD 1.18: 01/01/1983 00:00:00\r\n".to_vec(), tracks }.to_bytes();
+        std::fs::write(&path, &original).unwrap();
+
+        let mut media = Media::new(10);
+        media.use_overlay = true;
+        media.load_disk(&path).unwrap();
+        write_sector(&mut media, false, 3, 4, 0x11);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+
+        let mut base = Media::new(10);
+        base.load_disk(&path).unwrap();
+        let mut delta = Overlay::open(&path, 128).unwrap();
+        base.commit_overlay(&mut delta).unwrap();
+        assert!(!std::path::Path::new(&overlay::sidecar_path(&path)).exists());
+
+        let mut committed = Media::new(10);
+        committed.load_disk(&path).unwrap();
+        cleanup(&path);
+        assert!(committed.imd.is_some());
+        let (_, index, last) = committed.sector_index(false, 3, 4);
+        assert_eq!(committed.content[index..last], vec![0x11; 512][..]);
+    }
+
+    #[test]
+    fn test_overlay_log_is_compacted() {
+        let path = temp_path("overlay_compact.img");
+        let mut content = vec![0u8; 4096];
+        let mut delta = Overlay::open(&path, 128).unwrap();
+        for round in 0..200u32 {
+            content[300] = round as u8;
+            delta.write(&content, 300, 301).unwrap();
+        }
+        content[1000] = 0x77;
+        delta.write(&content, 1000, 1001).unwrap();
+        let size = std::fs::metadata(overlay::sidecar_path(&path)).unwrap().len();
+        assert!(size < 100 * 132, "log not compacted: {} bytes", size);
+
+        let reopened = Overlay::open(&path, 128).unwrap();
+        let wrong_size = Overlay::open(&path, 512);
+        cleanup(&path);
+        assert!(wrong_size.is_err());
+        assert_eq!(reopened.len(), 2);
+        let mut replayed = vec![0u8; 4096];
+        reopened.apply(&mut replayed);
+        assert_eq!(replayed, content);
+    }
+
+    #[test]
+    fn test_hard_disk_overlay() {
+        let path = temp_path("overlay_hd.img");
+        cleanup(&path);
+        let mut hd = HardDisk::new(false);
+        hd.load_image(&path).unwrap();
+        let base = std::fs::read(&path).unwrap();
+
+        let mut hd = HardDisk::new(false);
+        hd.use_overlay = true;
+        hd.load_image(&path).unwrap();
+        let mut data: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        assert_eq!(hd_command(&mut hd, 0x30, 0, 3, &mut data), 0);
+        hd.flush();
+        assert!(std::fs::read(&path).unwrap() == base);
+
+        let mut reloaded = HardDisk::new(false);
+        reloaded.use_overlay = true;
+        reloaded.load_image(&path).unwrap();
+        cleanup(&path);
+        let offset = ((2 * 4) * 17 + 3) * 512;
+        let expected: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        assert_eq!(reloaded.unit(1).unwrap().disk_data[offset..offset + 512], expected[..]);
+    }
+
+    #[test]
+    fn test_hard_disk_overlay_keeps_formatted_tracks() {
+        let path = temp_path("overlay_hd_format.img");
+        cleanup(&path);
+        // An image that has never been formatted
+        std::fs::write(&path, []).unwrap();
+        let mut table: Vec<u8> = (0..17u8).flat_map(|sector| [0, sector]).chain([0; 478]).collect();
+
+        let mut hd = HardDisk::new(false);
+        hd.use_overlay = true;
+        hd.load_image(&path).unwrap();
+        assert_ne!(hd_command(&mut hd, 0x30, 1, 0, &mut [0x55; 512]), 0);
+        assert_eq!(hd_command(&mut hd, 0x50, 1, 0, &mut table), 0);
+        assert_eq!(hd_command(&mut hd, 0x30, 1, 0, &mut [0x55; 512]), 0);
+        hd.flush();
+
+        // The formatted track comes back, the others stay unformatted
+        let mut reloaded = HardDisk::new(false);
+        reloaded.use_overlay = true;
+        reloaded.load_image(&path).unwrap();
+        let written = hd_command(&mut reloaded, 0x30, 1, 0, &mut [0x55; 512]);
+        let unformatted = hd_command(&mut reloaded, 0x20, 0, 0, &mut [0; 512]);
+        let base = std::fs::read(&path).unwrap();
+        cleanup(&path);
+        assert_eq!(written, 0);
+        assert_ne!(unformatted, 0);
+        assert!(base.is_empty());
+    }
+}
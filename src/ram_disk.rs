@@ -0,0 +1,201 @@
+// Advent RAM disk card on the personality board PIO (ports 0x88-0x8B).
+//
+// The card is addressed in 128-byte records, 64 records to an 8K track:
+//   OUT 0x89: record within the track (0-63)
+//   OUT 0x8A: track; bit 7 also selects the second bank of size switches
+//   IN/OUT 0x88: data, one byte of the record at a time.  The byte counter
+//                starts at 0 when the record or track is written and wraps
+//                at the end of the record.
+//   IN 0x8B: size switches (bits 2-0)
+// Port 0x8A also latches the board's floppy drive select, and bit 2 of port
+// 0x8B reports the selected drive; codes 0-3 leave that bit clear.  As the
+// card's tracks 1-4 are also drive codes, a write to port 0x8A is held as
+// a pending select: it selects the drive when the floppy controller is
+// next used, and is dropped if port 0x88 is used first (see
+// `KayproMachine::cancel_advent_drive_select`).
+//
+// Reference: TurboROM 3.4 (roms/trom34.rom), whose RAM disk code is the
+// only documentation at hand: the size probe at 0x18FD, the card check at
+// 0x191B and the record transfer at 0x12F3.
+//
+// TurboROM looks for the card at cold boot by reading the last record of
+// track 0 and comparing it with page zero.  If it doesn't match, it writes
+// page zero there, reads it back and, on a fresh card, clears the
+// directory.  The contents of an existing card survive a reboot, as they
+// do in a persistence file.
+//
+// The size switches pick one of TurboROM's RAM disk parameter blocks.
+// Codes 0-3 are read with bit 7 of the track clear; code 3 with bit 2 of
+// the second bank clear (bit 7 set) extends to codes 4-7, one per 256K
+// bank over 1M:
+//   0 = 256K, 1 = 512K, 3 = 1M, 4 = 1.25M, 5 = 1.5M, 6 = 1.75M, 7 = 2M
+// TurboROM's smallest setting, code 0, describes a drive of 496K: on a
+// 256K card the records past its end wrap around to the start, so the
+// drive holds 248K of files.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+use crate::snapshot::{StateReader, StateWriter};
+
+const RECORD_SIZE: usize = 128;
+const RECORDS_PER_TRACK: usize = 64;
+/// Switch bank value with bit 2 set: no extension
+const SWITCH_NONE: u8 = 0x04;
+
+pub struct RamDisk {
+    data: Vec<u8>,
+    track: u8,
+    record: u8,
+    offset: usize,
+    file: Option<File>,
+}
+
+/// Size switch code of a card of `kb` kilobytes.
+fn size_code(kb: usize) -> Option<u8> {
+    match kb {
+        256 => Some(0),
+        512 => Some(1),
+        1024 => Some(3),
+        1280 => Some(4),
+        1536 => Some(5),
+        1792 => Some(6),
+        2048 => Some(7),
+        _ => None,
+    }
+}
+
+/// Card size in bytes from "512K", "1M", "1536k", ...
+pub fn parse_size(spec: &str) -> std::result::Result<usize, String> {
+    let spec = spec.trim().to_ascii_uppercase();
+    let kb = if let Some(k) = spec.strip_suffix('K') {
+        k.parse::<usize>().ok()
+    } else if let Some(m) = spec.strip_suffix('M') {
+        match m {
+            "1" => Some(1024),
+            "1.25" => Some(1280),
+            "1.5" => Some(1536),
+            "1.75" => Some(1792),
+            "2" => Some(2048),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    match kb {
+        Some(kb) if size_code(kb).is_some() => Ok(kb * 1024),
+        _ => Err(format!("RAM disk size '{}': expected 256K, 512K, 1M, 1.25M, 1.5M, 1.75M or 2M", spec)),
+    }
+}
+
+impl RamDisk {
+    /// A card of `size` bytes (a size accepted by `parse_size`) with
+    /// random contents, as after power-on.
+    pub fn new(size: usize) -> RamDisk {
+        // Anything but a copy of page zero in the signature record
+        let mut seed: u32 = 0x9E37_79B9;
+        let data = (0..size).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect();
+        RamDisk {
+            data,
+            track: 0,
+            record: 0,
+            offset: 0,
+            file: None,
+        }
+    }
+
+    /// Keep the contents in `path`, loading them if the file exists.
+    pub fn load_file(&mut self, path: &str) -> Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            file.write_all(&self.data)?;
+        } else if data.len() == self.data.len() {
+            self.data = data;
+        } else {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("{} holds a {}K RAM disk, expected {}K", path, data.len() / 1024, self.data.len() / 1024)));
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub fn set_record(&mut self, record: u8) {
+        self.record = record;
+        self.offset = 0;
+    }
+
+    pub fn set_track(&mut self, track: u8) {
+        self.track = track;
+        self.offset = 0;
+    }
+
+    fn address(&self) -> usize {
+        let record = self.track as usize * RECORDS_PER_TRACK + (self.record as usize % RECORDS_PER_TRACK);
+        // Missing banks alias the ones fitted
+        (record * RECORD_SIZE + self.offset) % self.data.len()
+    }
+
+    pub fn read_byte(&mut self) -> u8 {
+        let value = self.data[self.address()];
+        self.offset = (self.offset + 1) % RECORD_SIZE;
+        value
+    }
+
+    pub fn write_byte(&mut self, value: u8) {
+        let address = self.address();
+        self.data[address] = value;
+        self.offset = (self.offset + 1) % RECORD_SIZE;
+        if self.offset == 0 {
+            self.persist(address + 1 - RECORD_SIZE);
+        }
+    }
+
+    /// Size switches, for the bank selected by bit 7 of the track.
+    pub fn switches(&self) -> u8 {
+        let code = size_code(self.data.len() / 1024).unwrap_or(1);
+        match (self.track & 0x80 != 0, code) {
+            (false, 0..=3) => code,
+            (false, _) => 3,
+            (true, 4..=7) => code & 0x03,
+            (true, _) => SWITCH_NONE,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.track);
+        state.u8(self.record);
+        state.usize(self.offset);
+    }
+
+    /// The card must be the size of the one saved. The contents are
+    /// written through to the RAM disk file.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.data)?;
+        self.track = state.u8()?;
+        self.record = state.u8()?;
+        self.offset = state.usize()? % RECORD_SIZE;
+        if let Some(ref mut f) = self.file {
+            f.seek(SeekFrom::Start(0))?;
+            f.write_all(&self.data)?;
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self, start: usize) {
+        if let Some(ref mut f) = self.file {
+            if f.seek(SeekFrom::Start(start as u64)).is_ok() {
+                if let Err(e) = f.write_all(&self.data[start..start + RECORD_SIZE]) {
+                    eprintln!("Warning: RAM disk file write failed: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+#[cfg(test)]
+mod tests {
+    use iz80::{Cpu, Flag, Machine, Reg16};
+
+    use crate::config::{Config, KayproModel};
+    use crate::floppy_controller::Drive;
+    use crate::kaypro_machine::{KayproMachine, VideoMode};
+    use crate::ram_disk::{self, RamDisk};
+    use crate::test_support::{machine, temp_path};
+
+    fn machine_with_ram_disk(size: &str) -> KayproMachine {
+        let mut machine = machine("roms/trom34.rom", VideoMode::Sy6545Crtc, false);
+        machine.ram_disk = Some(RamDisk::new(ram_disk::parse_size(size).unwrap()));
+        machine.advent_pio_enabled = true;
+        machine
+    }
+
+    /// TurboROM's card check: the last record of track 0 holds page zero.
+    fn signature_matches(machine: &mut KayproMachine, page_zero: &[u8]) -> bool {
+        machine.port_out(0x8A, 0);
+        machine.port_out(0x89, 0x3F);
+        let record: Vec<u8> = (0..128).map(|_| machine.port_in(0x88)).collect();
+        record == page_zero
+    }
+
+    /// TurboROM's size switch probe, as the index of its RAM disk DPB.
+    fn size_code(machine: &mut KayproMachine) -> u8 {
+        machine.port_out(0x8A, 0);
+        let code = machine.port_in(0x8B) & 0x07;
+        if code != 3 {
+            return code;
+        }
+        machine.port_out(0x8A, 0x80);
+        let extension = machine.port_in(0x8B) & 0x07;
+        if extension & 0x04 == 0 { extension | 0x04 } else { code }
+    }
+
+    /// Run `code` at 0x8000 up to its HALT, returning A and the Z flag.
+    fn run_code(machine: &mut KayproMachine, code: &[u8]) -> (u8, bool) {
+        for (i, &b) in code.iter().enumerate() {
+            machine.poke(0x8000 + i as u16, b);
+        }
+        let mut cpu = Cpu::new_z80();
+        cpu.registers().set_pc(0x8000);
+        cpu.registers().set16(Reg16::SP, 0xF000);
+        while !cpu.is_halted() {
+            machine.step(&mut cpu);
+        }
+        (cpu.registers().a(), cpu.registers().get_flag(Flag::Z))
+    }
+
+    /// Call the TurboROM routine at `address`.
+    fn call_rom(machine: &mut KayproMachine, address: u16) -> (u8, bool) {
+        run_code(machine, &[0xCD, address as u8, (address >> 8) as u8, 0x76])
+    }
+
+    #[test]
+    fn test_parse_ram_disk_size() {
+        assert_eq!(ram_disk::parse_size("256K"), Ok(256 * 1024));
+        assert_eq!(ram_disk::parse_size("512k"), Ok(512 * 1024));
+        assert_eq!(ram_disk::parse_size("1M"), Ok(1024 * 1024));
+        assert_eq!(ram_disk::parse_size("1.25M"), Ok(1280 * 1024));
+        assert_eq!(ram_disk::parse_size("1.5M"), Ok(1536 * 1024));
+        assert_eq!(ram_disk::parse_size("1.75M"), Ok(1792 * 1024));
+        assert_eq!(ram_disk::parse_size("2M"), Ok(2048 * 1024));
+        assert!(ram_disk::parse_size("128K").is_err());
+        assert!(ram_disk::parse_size("3M").is_err());
+        assert!(ram_disk::parse_size("1024").is_err());
+    }
+
+    #[test]
+    fn test_turbo_rom_detects_card() {
+        let page_zero: Vec<u8> = (0..128).map(|i| (i * 7) as u8).collect();
+        let mut machine = machine_with_ram_disk("1M");
+        assert!(!signature_matches(&mut machine, &page_zero));
+
+        // OTIR right after the check writes the same record
+        for &b in &page_zero {
+            machine.port_out(0x88, b);
+        }
+        assert!(signature_matches(&mut machine, &page_zero));
+
+        // Records are addressed by track and record
+        machine.port_out(0x89, 5);
+        machine.port_out(0x8A, 2);
+        for i in 0..128 {
+            machine.port_out(0x88, i);
+        }
+        let mut read_record = |track, record| {
+            machine.port_out(0x89, record);
+            machine.port_out(0x8A, track);
+            (0..128).map(|_| machine.port_in(0x88)).collect::<Vec<u8>>()
+        };
+        let written: Vec<u8> = (0..128).collect();
+        assert_eq!(read_record(2, 5), written);
+        assert_ne!(read_record(2, 4), written);
+        assert_ne!(read_record(3, 5), written);
+    }
+
+    #[test]
+    fn test_turbo_rom_probe_code() {
+        let mut machine = machine_with_ram_disk("2M");
+        let drive = machine.floppy_controller.drive;
+        // The size probe reads code 3, then 7 from the second bank
+        assert_eq!(call_rom(&mut machine, 0x18FD), (7, false));
+        // The card check fails on a fresh card...
+        assert!(!call_rom(&mut machine, 0x191B).1);
+        // ... and passes once page zero is in the last record of track 0:
+        // XOR A; OUT (0x8A),A; LD A,0x3F; OUT (0x89),A; LD HL,0;
+        // LD BC,0x8088; OTIR; HALT
+        run_code(&mut machine, &[0xAF, 0xD3, 0x8A, 0x3E, 0x3F, 0xD3, 0x89,
+            0x21, 0x00, 0x00, 0x01, 0x88, 0x80, 0xED, 0xB3, 0x76]);
+        assert!(call_rom(&mut machine, 0x191B).1);
+        assert_eq!(machine.floppy_controller.drive, drive);
+    }
+
+    #[test]
+    fn test_size_switches() {
+        let codes: Vec<u8> = ["256K", "512K", "1M", "1.25M", "1.5M", "1.75M", "2M"].iter()
+            .map(|size| size_code(&mut machine_with_ram_disk(size)))
+            .collect();
+        assert_eq!(codes, [0, 1, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_preset_ram_disk_size() {
+        let mut config = Config { model: KayproModel::Ultimate, ..Config::default() };
+        assert_eq!(config.get_ram_disk_size(), Some("1M"));
+        config.ram_disk = Some("2M".to_string());
+        assert_eq!(config.get_ram_disk_size(), Some("2M"));
+        config.ram_disk = Some("none".to_string());
+        assert_eq!(config.get_ram_disk_size(), None);
+        config.model = KayproModel::TurboRom;
+        config.ram_disk = None;
+        assert_eq!(config.get_ram_disk_size(), None);
+        config.ram_disk = Some("512K".to_string());
+        assert_eq!(config.get_ram_disk_size(), Some("512K"));
+        config.model = KayproModel::Kaypro4_84;
+        assert_eq!(config.get_ram_disk_size(), None);
+    }
+
+    #[test]
+    fn test_drive_select_with_ram_disk() {
+        let path = temp_path("ramdisk_drive_c.img");
+        std::fs::write(&path, vec![0xE5u8; 409_600]).unwrap();
+
+        let mut machine = machine_with_ram_disk("1M");
+        machine.floppy_controller.attach_drive(Drive::C, &path).unwrap();
+        // Drive C is connected, drive D is not
+        machine.port_out(0x8A, 3);
+        assert_eq!(machine.port_in(0x8B) & 0x04, 0x00);
+        machine.port_out(0x8A, 4);
+        assert_eq!(machine.port_in(0x8B) & 0x04, 0x04);
+        // The size probe still reads the switches
+        assert_eq!(size_code(&mut machine), 3);
+
+        // A select takes effect when the floppy controller is used
+        machine.port_out(0x8A, 3);
+        machine.port_in(0x10);
+        assert_eq!(machine.floppy_controller.drive, 2);
+        // Tracks of the card don't select drives
+        machine.port_out(0x89, 0);
+        machine.port_out(0x8A, 1);
+        machine.port_in(0x88);
+        machine.port_in(0x10);
+        assert_eq!(machine.floppy_controller.drive, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ram_disk_file_persists() {
+        let path = temp_path("ramdisk.bin");
+        let _ = std::fs::remove_file(&path);
+
+        let mut card = RamDisk::new(512 * 1024);
+        card.load_file(&path).unwrap();
+        card.set_record(63);
+        card.set_track(63);
+        for i in 0..128 {
+            card.write_byte(i);
+        }
+
+        let mut card = RamDisk::new(512 * 1024);
+        card.load_file(&path).unwrap();
+        card.set_record(63);
+        card.set_track(63);
+        let record: Vec<u8> = (0..128).map(|_| card.read_byte()).collect();
+        assert_eq!(record, (0..128).collect::<Vec<u8>>());
+        assert!(RamDisk::new(1024 * 1024).load_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
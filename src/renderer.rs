@@ -1,6 +1,7 @@
 use std::fs;
 
-use super::kaypro_machine::{KayproMachine, VideoMode};
+use super::emulator::Frame;
+use super::kaypro_machine::VideoMode;
 
 // Blink cycle: ~1.28s at 60fps ≈ 77 frames per cycle
 const BLINK_PERIOD: u32 = 77;
@@ -134,17 +135,17 @@ impl Renderer {
         self.frame_counter = self.frame_counter.wrapping_add(1);
     }
 
-    /// Render full screen from machine state, return pixel buffer.
-    pub fn render(&mut self, machine: &KayproMachine) -> &[u32] {
+    /// Render full screen from a frame snapshot, return pixel buffer.
+    pub fn render(&mut self, frame: &Frame) -> &[u32] {
         let blink_on = (self.frame_counter % BLINK_PERIOD) < (BLINK_PERIOD / 2);
 
         // Fixed display size matching window dimensions
         let display_rows = self.height / self.scanlines_per_char;
 
         // Get cursor info for CRTC mode
-        let (cursor_addr, cursor_visible) = if machine.video_mode == VideoMode::Sy6545Crtc {
-            let addr = machine.crtc.cursor_addr() & 0x7FF;
-            let mode = machine.crtc.cursor_mode();
+        let (cursor_addr, cursor_visible) = if frame.video_mode == VideoMode::Sy6545Crtc {
+            let addr = frame.cursor_addr & 0x7FF;
+            let mode = frame.cursor_mode;
             let visible = match mode {
                 0 => true,                 // steady
                 1 => false,                // invisible
@@ -158,20 +159,20 @@ impl Renderer {
 
         for row in 0..display_rows {
             for col in 0..80 {
-                let (code, attr, is_cursor) = if machine.video_mode == VideoMode::Sy6545Crtc {
-                    let start = machine.crtc.start_addr();
+                let (code, attr, is_cursor) = if frame.video_mode == VideoMode::Sy6545Crtc {
+                    let start = frame.start_addr;
                     let addr = (start + row * 80 + col) & 0x7FF;
                     let at_cursor = cursor_visible && addr == cursor_addr;
-                    (machine.crtc.get_vram(addr), machine.crtc.get_attr(addr), at_cursor)
+                    (frame.get_vram(addr), frame.get_attr(addr), at_cursor)
                 } else {
                     // Memory-mapped mode: 128-byte stride
-                    (machine.vram[row * 128 + col], 0u8, false)
+                    (frame.vram[row * 128 + col], 0u8, false)
                 };
 
                 // Attribute bits
                 let reverse = (attr & 0x01) != 0 || is_cursor;
                 let dim = (attr & 0x02) != 0;
-                let blink = if machine.video_mode == VideoMode::Sy6545Crtc {
+                let blink = if frame.video_mode == VideoMode::Sy6545Crtc {
                     (attr & 0x04) != 0
                 } else {
                     (code & 0x80) != 0
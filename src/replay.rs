@@ -0,0 +1,177 @@
+// Input recording and replay.
+//
+// A replay file lists what came into the machine from outside during a
+// session: the keys, the disks inserted and the commands of the front end.
+// The emulation thread takes them between slices, so each one is stamped
+// with the instruction count at which the machine got it, and a replay
+// hands them over at the same counts.  Sessions are recorded in
+// deterministic runs only, and the header has the date the real time clock
+// started at; a replay must start from the same one:
+//
+//     izkaypro replay 2
+//     start 1984-01-01T00:00:00
+//     1523817 key 44
+//     1523817 disk B disks/games/adventure.img
+//     9806112 speed 4
+//     12004593 end 5c1d0e96a3b2f017
+//
+// The last line has the hash of the state of the machine when the session
+// ended, to tell whether the replay got to the same state.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Error, ErrorKind, LineWriter, Result, Write};
+
+use crate::emulator::Request;
+use crate::floppy_controller::Drive;
+use crate::rtc;
+use crate::snapshot;
+
+const HEADER: &str = "izkaypro replay 2";
+
+pub enum Input {
+    Key(u8),
+    Request(Request),
+    /// The session ended, with the hash of the machine state
+    End(u64),
+}
+
+/// Writes the inputs of a session as they come.
+pub struct Recorder {
+    path: String,
+    file: LineWriter<File>,
+}
+
+impl Recorder {
+    /// Start a replay file for a run whose clock starts at `start_secs`
+    /// (see rtc::parse_date).
+    pub fn create(path: &str, start_secs: i64) -> Result<Recorder> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "start {}", rtc::format_date(start_secs))?;
+        Ok(Recorder { path: path.to_string(), file })
+    }
+
+    pub fn key(&mut self, instructions: u64, key: u8) {
+        self.write(instructions, &format!("key {:02x}", key));
+    }
+
+    pub fn request(&mut self, instructions: u64, request: &Request) {
+        let line = match request {
+            Request::LoadDisk(drive, path) => format!("disk {} {}", drive_letter(*drive), path),
+            Request::SaveBios => "save-bios".to_string(),
+            Request::SaveState => "save-state".to_string(),
+            Request::LoadState(path) => format!("load-state {}", path),
+            Request::Rewind(back) => format!("rewind {}", back),
+            Request::SetTrace(trace) => format!("trace {}", if *trace { "on" } else { "off" }),
+            Request::SetSpeed(Some(mhz)) => format!("speed {}", mhz),
+            Request::SetSpeed(None) => "speed unlimited".to_string(),
+            // The monitor and the debugger aren't part of the session, and
+            // the end has its own line
+            Request::Break | Request::Monitor(_) | Request::Gdb(_) | Request::Quit => return,
+        };
+        self.write(instructions, &line);
+    }
+
+    pub fn end(&mut self, instructions: u64, state_hash: u64) {
+        self.write(instructions, &format!("end {:016x}", state_hash));
+    }
+
+    fn write(&mut self, instructions: u64, line: &str) {
+        if let Err(e) = writeln!(self.file, "{} {}", instructions, line) {
+            eprintln!("Warning: Failed to write replay '{}': {}", self.path, e);
+        }
+    }
+}
+
+/// The inputs of a recorded session, fed back in order.
+pub struct Replay {
+    /// Start of the clock of the recorded run, see rtc::parse_date
+    pub start_secs: i64,
+    inputs: VecDeque<(u64, Input)>,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Replay> {
+        Replay::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Replay> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim()) != Some(HEADER) {
+            return Err(Error::new(ErrorKind::InvalidData, "Not an izkaypro replay file"));
+        }
+        let start = lines.next().and_then(|(_, line)| line.trim().strip_prefix("start "));
+        let start_secs = rtc::parse_date(start.unwrap_or(""))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Replay start: {}", e)))?;
+        let mut inputs = VecDeque::new();
+        let mut last = 0;
+        for (n, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |what: &str| Error::new(ErrorKind::InvalidData,
+                format!("Replay line {}: {}", n + 1, what));
+            let (at, rest) = line.split_once(' ').ok_or_else(|| invalid("expected a count and an input"))?;
+            let at = at.parse::<u64>().map_err(|_| invalid(&format!("bad instruction count '{}'", at)))?;
+            if at < last {
+                return Err(invalid("the instruction counts go back"));
+            }
+            last = at;
+            let (name, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+            let input = match (name, arg) {
+                ("key", key) => Input::Key(u8::from_str_radix(key, 16)
+                    .map_err(|_| invalid(&format!("bad key '{}'", key)))?),
+                ("disk", arg) => {
+                    let (drive, path) = arg.split_once(' ').ok_or_else(|| invalid("expected a drive and a path"))?;
+                    let drive = match drive {
+                        "A" => Drive::A,
+                        "B" => Drive::B,
+                        "C" => Drive::C,
+                        "D" => Drive::D,
+                        _ => return Err(invalid(&format!("bad drive '{}'", drive))),
+                    };
+                    Input::Request(Request::LoadDisk(drive, path.to_string()))
+                },
+                ("save-bios", "") => Input::Request(Request::SaveBios),
+                ("save-state", "") => Input::Request(Request::SaveState),
+                ("load-state", path) => Input::Request(Request::LoadState(path.to_string())),
+                ("rewind", back) => Input::Request(Request::Rewind(back.parse()
+                    .map_err(|_| invalid(&format!("bad rewind point '{}'", back)))?)),
+                ("trace", "on") => Input::Request(Request::SetTrace(true)),
+                ("trace", "off") => Input::Request(Request::SetTrace(false)),
+                ("speed", "unlimited") => Input::Request(Request::SetSpeed(None)),
+                ("speed", mhz) => Input::Request(Request::SetSpeed(Some(mhz.parse()
+                    .map_err(|_| invalid(&format!("bad speed '{}'", mhz)))?))),
+                ("end", hash) => Input::End(u64::from_str_radix(hash, 16)
+                    .map_err(|_| invalid(&format!("bad state hash '{}'", hash)))?),
+                _ => return Err(invalid(&format!("unknown input '{}'", rest))),
+            };
+            inputs.push_back((at, input));
+        }
+        Ok(Replay { start_secs, inputs })
+    }
+
+    /// Take the inputs due at `instructions`, with the count each one was
+    /// recorded at.
+    pub fn take_due(&mut self, instructions: u64) -> Vec<(u64, Input)> {
+        let mut due = Vec::new();
+        while self.inputs.front().is_some_and(|&(at, _)| at <= instructions) {
+            due.extend(self.inputs.pop_front());
+        }
+        due
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+fn drive_letter(drive: Drive) -> char {
+    (b'A' + drive as u8) as char
+}
+
+/// FNV-1a hash of a machine state, see KayproMachine::save_state.
+pub fn state_hash(state: &[u8]) -> u64 {
+    snapshot::content_hash(state)
+}
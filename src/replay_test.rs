@@ -0,0 +1,132 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::{Duration, Instant};
+
+    use crate::emulator::{Emulator, EmulatorHandle, Frame, Request, Update};
+    use crate::floppy_controller::Drive;
+    use crate::kaypro_machine::VideoMode;
+    use crate::replay::{Input, Recorder, Replay};
+    use crate::rtc::{parse_date, DEFAULT_START_DATE};
+    use crate::test_support::{machine, temp_path};
+
+    fn emulator(start_date: &str) -> Emulator {
+        let mut emulator = Emulator::new(machine("roms/81-292a.rom", VideoMode::Sy6545Crtc, false), None, false);
+        emulator.set_deterministic(parse_date(start_date).unwrap());
+        emulator
+    }
+
+    fn screen_text(frame: &Frame) -> String {
+        (0..0x800).map(|offset| (frame.get_vram(offset) & 0x7F) as char).collect()
+    }
+
+    /// Wait for an update that `check` accepts.
+    fn wait_for(emulator: &EmulatorHandle, mut check: impl FnMut(Update) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            match emulator.updates.recv_timeout(Duration::from_secs(1)) {
+                Ok(update) => if check(update) { return; },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(e) => panic!("no update: {}", e),
+            }
+        }
+        panic!("no such update");
+    }
+
+    fn prompt_shown(update: Update) -> bool {
+        matches!(update, Update::Frame(frame) if screen_text(&frame).contains("A0>"))
+    }
+
+    #[test]
+    fn test_replay_file_round_trip() {
+        let path = temp_path("replay_file.txt");
+        let mut recorder = Recorder::create(&path, 446_947_140).unwrap();
+        recorder.key(100, b'D');
+        recorder.request(100, &Request::LoadDisk(Drive::B, "disks/my disk.img".into()));
+        recorder.request(250, &Request::SetSpeed(Some(2.5)));
+        recorder.request(250, &Request::SetSpeed(None));
+        recorder.request(300, &Request::Rewind(3));
+        recorder.request(300, &Request::SetTrace(true));
+        recorder.end(400, 0x0123456789ABCDEF);
+        drop(recorder);
+
+        let mut replay = Replay::open(&path).unwrap();
+        assert_eq!(replay.start_secs, 446_947_140);
+        assert!(replay.take_due(99).is_empty());
+        let due = replay.take_due(100);
+        assert_eq!(due.len(), 2);
+        assert!(matches!(due[0], (100, Input::Key(b'D'))));
+        assert!(matches!(&due[1], (100, Input::Request(Request::LoadDisk(Drive::B, path)))
+            if path == "disks/my disk.img"));
+        let due = replay.take_due(1000);
+        assert_eq!(due.len(), 5);
+        assert!(matches!(due[0], (250, Input::Request(Request::SetSpeed(Some(mhz)))) if mhz == 2.5));
+        assert!(matches!(due[1], (250, Input::Request(Request::SetSpeed(None)))));
+        assert!(matches!(due[2], (300, Input::Request(Request::Rewind(3)))));
+        assert!(matches!(due[3], (300, Input::Request(Request::SetTrace(true)))));
+        assert!(matches!(due[4], (400, Input::End(0x0123456789ABCDEF))));
+        assert!(replay.is_finished());
+        let _ = std::fs::remove_file(&path);
+
+        assert!(Replay::parse("1 key 41\n").is_err());
+        assert!(Replay::parse("izkaypro replay 2\n10 key 41\n").is_err());
+        let start = "izkaypro replay 2\nstart 1984-01-01T00:00:00\n";
+        assert!(Replay::parse(start).is_ok());
+        assert!(Replay::parse(&format!("{}20 key 41\n10 key 42\n", start)).is_err());
+        assert!(Replay::parse(&format!("{}10 key 4G\n", start)).is_err());
+        assert!(Replay::parse(&format!("{}10 disk E x.img\n", start)).is_err());
+        assert!(Replay::parse(&format!("{}10 jump\n", start)).is_err());
+    }
+
+    #[test]
+    fn test_sessions_need_a_deterministic_run() {
+        let path = temp_path("replay_realtime.txt");
+        let mut emulator = Emulator::new(machine("roms/81-292a.rom", VideoMode::Sy6545Crtc, false), None, false);
+        assert!(emulator.record(&path).is_err());
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_replay_reproduces_the_session() {
+        let path = temp_path("replay_session.txt");
+
+        let mut recording = emulator(DEFAULT_START_DATE);
+        recording.record(&path).unwrap();
+        let session = recording.spawn(false);
+        wait_for(&session, prompt_shown);
+        for &key in b"DIR\r" {
+            session.keys.send(key).unwrap();
+        }
+        session.requests.send(Request::LoadDisk(Drive::B, "__nonexistent_disk__".into())).unwrap();
+        wait_for(&session, |update| matches!(update,
+            Update::Frame(frame) if screen_text(&frame).contains("COM")));
+        session.stop();
+
+        // Only from the same start of the clock
+        assert!(emulator("1985-06-01").replay(&path).is_err());
+        let mut replaying = emulator(DEFAULT_START_DATE);
+        replaying.replay(&path).unwrap();
+        let session = replaying.spawn(false);
+        // The keys of the front end wait for the end of the replay
+        session.keys.send(b'X').unwrap();
+        let mut finished = None;
+        let mut disk_loaded = false;
+        wait_for(&session, |update| match update {
+            Update::DiskLoaded(_, path, _) => {
+                disk_loaded = path == "__nonexistent_disk__";
+                false
+            },
+            Update::ReplayFinished(result) => {
+                finished = Some(result);
+                true
+            },
+            _ => false,
+        });
+        assert!(disk_loaded);
+        assert_eq!(finished, Some(Ok(())));
+        wait_for(&session, |update| matches!(update,
+            Update::Frame(frame) if screen_text(&frame).contains("COM") && !screen_text(&frame).contains("X")));
+        session.stop();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,179 @@
+// Rewind history.
+//
+// The emulation thread takes a rewind point every INTERVAL_TSTATES: the
+// state of the machine as a snapshot has it (see
+// KayproMachine::save_state) and the CPU registers, kept in memory for the
+// last POINTS points.  The disk images aren't copied: each disk journals
+// what its sectors held before the first write after each point, and
+// rewinding writes that back.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::snapshot::CpuState;
+
+/// A second at 4 MHz
+pub const INTERVAL_TSTATES: u64 = 4_000_000;
+/// Rewind points kept
+pub const POINTS: usize = 10;
+
+// Granularity of the journal, the smallest floppy sector
+const BLOCK_SIZE: usize = 128;
+
+pub struct RewindPoint {
+    pub id: u64,
+    /// Emulated time of the point
+    pub time_us: u64,
+    /// Machine state, see KayproMachine::save_state
+    pub machine: Vec<u8>,
+    pub cpu: CpuState,
+}
+
+/// The rewind points, oldest first.
+pub struct History {
+    points: VecDeque<RewindPoint>,
+    next_id: u64,
+}
+
+impl History {
+    pub fn new() -> History {
+        History { points: VecDeque::new(), next_id: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Add a point. Returns its id and the id of the oldest point kept,
+    /// for the disk journals.
+    pub fn push(&mut self, time_us: u64, machine: Vec<u8>, cpu: CpuState) -> (u64, u64) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push_back(RewindPoint { id, time_us, machine, cpu });
+        while self.points.len() > POINTS {
+            self.points.pop_front();
+        }
+        (id, self.points[0].id)
+    }
+
+    /// The point `back` points before now (1 is the latest). The points
+    /// after it are forgotten.
+    pub fn rewind(&mut self, back: usize) -> Option<&RewindPoint> {
+        if back == 0 || back > self.points.len() {
+            return None;
+        }
+        self.points.truncate(self.points.len() - back + 1);
+        self.points.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+}
+
+enum Undo {
+    /// Offset and previous data of a block
+    Block(usize, Vec<u8>),
+    /// The whole content before it was replaced (e.g. resized)
+    Content(Vec<u8>),
+}
+
+struct Epoch<L> {
+    point: u64,
+    /// Disk layout (formatted tracks, geometry, faults) at the point
+    layout: L,
+    undo: Vec<Undo>,
+    saved: HashSet<usize>,
+}
+
+/// What a disk held before the writes since each rewind point. `L` is
+/// the layout of the disk, kept whole at each point.
+pub struct WriteJournal<L> {
+    epochs: VecDeque<Epoch<L>>,
+}
+
+impl<L: Clone> WriteJournal<L> {
+    pub fn new() -> WriteJournal<L> {
+        WriteJournal { epochs: VecDeque::new() }
+    }
+
+    /// Journal the writes after rewind point `point`, taken with the disk
+    /// at `layout`. The points before `oldest` are forgotten.
+    pub fn mark(&mut self, point: u64, oldest: u64, layout: L) {
+        self.epochs.retain(|epoch| epoch.point >= oldest);
+        self.epochs.push_back(Epoch { point, layout, undo: Vec::new(), saved: HashSet::new() });
+    }
+
+    /// `content[start..start + len]` is about to be written.
+    pub fn record(&mut self, content: &[u8], start: usize, len: usize) {
+        let Some(epoch) = self.epochs.back_mut() else {
+            return;
+        };
+        if len == 0 {
+            return;
+        }
+        for block in start / BLOCK_SIZE..=(start + len - 1) / BLOCK_SIZE {
+            if epoch.saved.insert(block) {
+                let from = block * BLOCK_SIZE;
+                let to = (from + BLOCK_SIZE).min(content.len());
+                if from < to {
+                    epoch.undo.push(Undo::Block(from, content[from..to].to_vec()));
+                }
+            }
+        }
+    }
+
+    /// `content` is about to be replaced as a whole.
+    pub fn record_content(&mut self, content: &[u8]) {
+        if let Some(epoch) = self.epochs.back_mut() {
+            epoch.undo.push(Undo::Content(content.to_vec()));
+            // The blocks are journaled again against the new content
+            epoch.saved.clear();
+        }
+    }
+
+    /// Undo the writes to `content` since rewind point `point`. Returns
+    /// the layout at the point and the range of `content` that changed, or
+    /// None if the journal doesn't go back to the point.
+    pub fn rewind(&mut self, point: u64, content: &mut Vec<u8>) -> Option<(L, Option<(usize, usize)>)> {
+        let index = self.epochs.iter().position(|epoch| epoch.point == point)?;
+        let mut newer = self.epochs.split_off(index);
+        let mut changed: Option<(usize, usize)> = None;
+        let mut extend = |start: usize, end: usize| {
+            changed = Some(match changed {
+                Some((min, max)) => (min.min(start), max.max(end)),
+                None => (start, end),
+            });
+        };
+        let mut layout = None;
+        while let Some(epoch) = newer.pop_back() {
+            for undo in epoch.undo.into_iter().rev() {
+                match undo {
+                    Undo::Block(offset, data) => {
+                        let end = (offset + data.len()).min(content.len());
+                        if offset < end {
+                            content[offset..end].copy_from_slice(&data[..end - offset]);
+                            extend(offset, end);
+                        }
+                    }
+                    Undo::Content(data) => {
+                        *content = data;
+                        extend(0, content.len());
+                    }
+                }
+            }
+            layout = Some(epoch.layout);
+        }
+        let layout = layout?;
+        // Blocks written before the content shrank are past its end
+        let changed = changed
+            .map(|(start, end)| (start, end.min(content.len())))
+            .filter(|(start, end)| start < end);
+        // The point stays, with nothing written after it
+        self.epochs.push_back(Epoch { point, layout: layout.clone(), undo: Vec::new(), saved: HashSet::new() });
+        Some((layout, changed))
+    }
+
+    pub fn clear(&mut self) {
+        self.epochs.clear();
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iz80::Cpu;
+
+    use crate::emulator::{Emulator, Request, Update};
+    use crate::kaypro_machine::VideoMode;
+    use crate::rewind::{History, WriteJournal, POINTS};
+    use crate::snapshot::{CpuState, StateReader, StateWriter};
+    use crate::test_support::{kaypro_ii, machine, memory, run};
+
+    #[test]
+    fn test_journal_undoes_the_writes_since_a_point() {
+        let original: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let mut content = original.clone();
+        let mut journal = WriteJournal::new();
+
+        // Not journaling yet
+        journal.record(&content, 0, 1);
+        content[0] = 0xFF;
+        assert!(journal.rewind(0, &mut content).is_none());
+        content[0] = 0;
+
+        journal.mark(0, 0, "first");
+        journal.record(&content, 100, 300);
+        content[100..400].fill(0xE5);
+        journal.record(&content, 200, 1);
+        content[200] = 0x42;
+        let at_second = content.clone();
+
+        journal.mark(1, 0, "second");
+        journal.record_content(&content);
+        content.resize(2048, 0xE5);
+        journal.record(&content, 1500, 10);
+        content[1500..1510].fill(0);
+
+        let (layout, changed) = journal.rewind(1, &mut content).unwrap();
+        assert_eq!(layout, "second");
+        assert_eq!(changed, Some((0, 1024)));
+        assert!(content == at_second);
+
+        let (layout, changed) = journal.rewind(0, &mut content).unwrap();
+        assert_eq!(layout, "first");
+        assert_eq!(changed, Some((0, 512)));
+        assert!(content == original);
+
+        // Rewinding keeps the point, later points are gone
+        assert!(journal.rewind(0, &mut content).is_some());
+        assert!(journal.rewind(1, &mut content).is_none());
+
+        // Points before the oldest kept are forgotten
+        journal.mark(2, 2, "third");
+        assert!(journal.rewind(0, &mut content).is_none());
+    }
+
+    #[test]
+    fn test_history_keeps_the_last_points() {
+        let mut history = History::new();
+        assert!(history.rewind(1).is_none());
+        for i in 0..POINTS as u64 + 2 {
+            let (point, oldest) = history.push(i * 1000, Vec::new(), CpuState::default());
+            assert_eq!(point, i);
+            assert_eq!(oldest, i.saturating_sub(POINTS as u64 - 1));
+        }
+        assert_eq!(history.len(), POINTS);
+        assert!(history.rewind(0).is_none());
+        assert!(history.rewind(POINTS + 1).is_none());
+
+        let point = history.rewind(3).unwrap();
+        assert_eq!(point.id, POINTS as u64 - 1);
+        assert_eq!(point.time_us, (POINTS as u64 - 1) * 1000);
+        assert_eq!(history.len(), POINTS - 2);
+        assert_eq!(history.rewind(1).unwrap().id, POINTS as u64 - 1);
+    }
+
+    #[test]
+    fn test_rewind_restores_the_machine_and_the_disk() {
+        let mut machine = kaypro_ii(false);
+        let mut cpu = Cpu::new_z80();
+        run(&mut machine, &mut cpu, 2_000_000);
+        machine.settle(&mut cpu);
+        let mut state = StateWriter::new();
+        machine.save_state(&mut state);
+        let saved = state.into_bytes();
+        let cpu_state = CpuState::capture(&mut cpu);
+        machine.mark_rewind_point(0, 0);
+        let memory_at_point = memory(&machine);
+        let tstates_at_point = machine.tstates;
+        let disk_at_point = machine.floppy_controller.media_a().content.clone();
+
+        run(&mut machine, &mut cpu, 500_000);
+        let media = machine.floppy_controller.media_a_mut();
+        for index in [0, 1, 5000, 5001, 100_000] {
+            let value = !media.read_byte(index);
+            media.write_byte(index, value);
+        }
+        assert!(machine.floppy_controller.media_a().content != disk_at_point);
+
+        machine.settle(&mut cpu);
+        machine.rewind_disks(0);
+        let mut state = StateReader::new(&saved);
+        machine.load_state(&mut state).unwrap();
+        state.finish().unwrap();
+        cpu_state.apply(&mut cpu);
+
+        assert_eq!(machine.tstates, tstates_at_point);
+        assert!(memory(&machine) == memory_at_point);
+        assert_eq!(CpuState::capture(&mut cpu), cpu_state);
+        assert!(machine.floppy_controller.media_a().content == disk_at_point);
+    }
+
+    #[test]
+    fn test_emulation_thread_rewinds() {
+        let mut machine = machine("roms/81-292a.rom", VideoMode::MemoryMapped, false);
+        machine.set_cpu_clock(2.5);
+        let emulator = Emulator::new(machine, None, false).spawn(false);
+        let timeout = Duration::from_secs(10);
+
+        // Unthrottled, the points come quickly
+        loop {
+            match emulator.updates.recv_timeout(timeout) {
+                Ok(Update::Frame(frame)) if frame.rewind_points >= 2 => break,
+                Ok(_) => {},
+                Err(e) => panic!("no rewind points: {}", e),
+            }
+        }
+
+        for (back, ok) in [(POINTS + 1, false), (2, true)] {
+            emulator.requests.send(Request::Rewind(back)).unwrap();
+            loop {
+                match emulator.updates.recv_timeout(timeout) {
+                    Ok(Update::Rewound(result)) => {
+                        assert_eq!(result.is_ok(), ok, "{:?}", result);
+                        break;
+                    },
+                    Ok(_) => {},
+                    Err(e) => panic!("no reply: {}", e),
+                }
+            }
+        }
+        emulator.stop();
+    }
+}
@@ -1,5 +1,10 @@
-use std::io::Write;
-use std::time::{Instant, SystemTime};
+use std::io::{Result, Write};
+use std::time::SystemTime;
+
+use crate::snapshot::{StateReader, StateWriter};
+
+/// Start date of the virtual clock when none is given.
+pub const DEFAULT_START_DATE: &str = "1984-01-01";
 
 /// Route RTC trace output to trace_file when available, otherwise println!.
 macro_rules! rtc_log {
@@ -22,12 +27,17 @@ macro_rules! rtc_log {
 /// On boot, counters are populated from the host system clock.
 /// If the user sets the clock, an offset is stored so the clock
 /// continues ticking from the user-set value for the session.
+///
+/// With a virtual clock the counters follow the emulated time instead,
+/// starting from a fixed date, so a run reads the same time every time.
 
 pub struct Rtc {
     reg_select: u8,
     ram: [u8; 8],           // Alarm/RAM latch registers (0x08-0x0F)
     time_offset_secs: i64,  // Offset from host time (set by user writes)
-    last_status_read: Instant, // For Status Bit register (0x14) rollover detection
+    start_secs: Option<i64>,  // Local time at emulated time zero, None follows the host
+    pub now_us: u64,          // Emulated time, set by the machine before each access
+    last_status_read: u64,    // Emulated time of the last Status Bit (0x14) rollover report
     last_ms_value: u8,        // Last value returned for reg 0x00 (clock-tick detection)
     pub trace: bool,
     pub trace_file: Option<std::fs::File>,
@@ -39,13 +49,26 @@ impl Rtc {
             reg_select: 0,
             ram: [0; 8],
             time_offset_secs: 0,
-            last_status_read: Instant::now(),
+            start_secs: None,
+            now_us: 0,
+            last_status_read: 0,
             last_ms_value: 0xFF,
             trace,
             trace_file: None,
         }
     }
 
+    /// Count from `start_secs` (see parse_date) at emulated time zero
+    /// instead of reading the host clock.
+    pub fn set_virtual_clock(&mut self, start_secs: i64) {
+        self.start_secs = Some(start_secs);
+    }
+
+    /// Start of the virtual clock, None if the clock follows the host.
+    pub fn virtual_clock(&self) -> Option<i64> {
+        self.start_secs
+    }
+
     /// Write to port 0x20 (CLKADD) — select an RTC register.
     pub fn write_addr(&mut self, value: u8) {
         self.reg_select = value & 0x1F;
@@ -133,10 +156,8 @@ impl Rtc {
     /// Bit 2: seconds               Bit 6: day of month
     /// Bit 3: minutes               Bit 7: month
     fn read_status_bit(&mut self) -> u8 {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_status_read);
-
-        let ms = elapsed.as_millis();
+        // Counted in emulated time so the rollovers keep pace with the CPU
+        let ms = self.now_us.saturating_sub(self.last_status_read) / 1_000;
         let mut status: u8 = 0;
         if ms >= 1       { status |= 0x01; } // milliseconds counter updated
         if ms >= 10      { status |= 0x02; } // tenths/hundredths updated
@@ -145,11 +166,11 @@ impl Rtc {
         // hours/dow/day/month rollovers are extremely rare during polling
 
         // Only reset the timer when we report at least one update.
-        // At unlimited CPU speed, the polling loop reads this register
-        // faster than 1ms apart; resetting on every read would prevent
+        // The polling loop reads this register more often than once per
+        // millisecond; resetting on every read would prevent
         // time from accumulating and the driver would never see a tick.
         if status != 0 {
-            self.last_status_read = now;
+            self.last_status_read = self.now_us;
         }
         status
     }
@@ -203,20 +224,23 @@ impl Rtc {
 
     /// Get the current RTC time as (ms, sec, min, hour, dow, day, month).
     fn current_time(&self) -> (u16, u8, u8, u8, u8, u8, u8) {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default();
-
-        let total_secs = now.as_secs() as i64 + self.time_offset_secs;
-        let ms = (now.subsec_millis()) as u16;
-
-        // Convert Unix timestamp to broken-down time components
-        // Using a simplified algorithm (no timezone — uses UTC, but we'll
-        // apply local timezone offset)
-        let local_offset = local_utc_offset_secs();
-        let local_secs = total_secs + local_offset;
+        let (local_secs, ms) = match self.start_secs {
+            Some(start_secs) => {
+                let secs = start_secs + (self.now_us / 1_000_000) as i64;
+                (secs, ((self.now_us / 1000) % 1000) as u16)
+            },
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                // Unix time is UTC, the clock shows local time
+                (now.as_secs() as i64 + local_utc_offset_secs(), now.subsec_millis() as u16)
+            },
+        };
+        let local_secs = local_secs + self.time_offset_secs;
 
-        let (year, month, day) = civil_from_days(local_secs / 86400);
+        // Convert to broken-down time components
+        let (year, month, day) = civil_from_days(local_secs.div_euclid(86400));
         let day_secs = ((local_secs % 86400) + 86400) % 86400;
         let hour = (day_secs / 3600) as u8;
         let min = ((day_secs % 3600) / 60) as u8;
@@ -234,6 +258,25 @@ impl Rtc {
         (sec, min, hour)
     }
 
+    /// The clock keeps following the host: only the offset the guest set
+    /// is saved.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.reg_select);
+        state.bytes(&self.ram);
+        state.i64(self.time_offset_secs);
+        state.u64(self.last_status_read);
+        state.u8(self.last_ms_value);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.reg_select = state.u8()?;
+        state.bytes_into(&mut self.ram)?;
+        self.time_offset_secs = state.i64()?;
+        self.last_status_read = state.u64()?;
+        self.last_ms_value = state.u8()?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn status_string(&self) -> String {
         let (_, sec, min, hour, _, day, month) = self.current_time();
@@ -251,6 +294,50 @@ fn from_bcd(bcd: u8) -> u8 {
     (bcd >> 4) * 10 + (bcd & 0x0F)
 }
 
+/// Parse a start date for the virtual clock, `YYYY-MM-DD` optionally
+/// followed by `THH:MM` or `THH:MM:SS`, into seconds since the Unix epoch
+/// as local time.
+pub fn parse_date(text: &str) -> std::result::Result<i64, String> {
+    let invalid = || format!("Invalid date '{}', expected YYYY-MM-DD[THH:MM[:SS]]", text);
+    let (date, time) = text.split_once(['T', ' ']).unwrap_or((text, "00:00"));
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    if date.len() != 3 || !(2..=3).contains(&time.len()) {
+        return Err(invalid());
+    }
+    let number = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+    let (year, month, day) = (number(date[0])?, number(date[1])?, number(date[2])?);
+    let (hour, min) = (number(time[0])?, number(time[1])?);
+    let sec = if time.len() == 3 { number(time[2])? } else { 0 };
+    let days = days_from_civil(year as i32, month as u8, day as u8);
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year as i32, month as u8, day as u8)
+        || hour > 23 || min > 59 || sec > 59 {
+        return Err(invalid());
+    }
+    Ok(days * 86400 + (hour * 3600 + min * 60 + sec) as i64)
+}
+
+/// Format seconds since the Unix epoch as `YYYY-MM-DDTHH:MM:SS`, the
+/// inverse of parse_date.
+pub fn format_date(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day,
+        time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Convert (year, month, day) to days since the Unix epoch, the inverse
+/// of civil_from_days.
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let y = if month <= 2 { year as i64 - 1 } else { year as i64 };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month as i64 - 3 } else { month as i64 + 9 };
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
 /// Convert days since Unix epoch to (year, month, day).
 /// Algorithm from Howard Hinnant's chrono-compatible date algorithms.
 fn civil_from_days(days: i64) -> (i32, u8, u8) {
@@ -0,0 +1,52 @@
+#[cfg(test)]
+mod tests {
+    use crate::rtc::{format_date, parse_date, Rtc, DEFAULT_START_DATE};
+
+    fn read(rtc: &mut Rtc, reg: u8) -> u8 {
+        rtc.write_addr(reg);
+        rtc.read_data()
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date(DEFAULT_START_DATE), Ok(441_763_200));
+        assert_eq!(parse_date("1984-02-29T23:59"), Ok(446_947_140));
+        assert_eq!(parse_date("1984-02-29 23:59:58"), Ok(446_947_198));
+        assert_eq!(parse_date("1969-12-31T23:59:59"), Ok(-1));
+        assert_eq!(format_date(446_947_198), "1984-02-29T23:59:58");
+        assert_eq!(format_date(-1), "1969-12-31T23:59:59");
+
+        for bad in ["", "1984", "1984-13-01", "1983-02-29", "1984-01-01T24:00",
+                "1984-01-01T12", "1984-01-01T12:00:60", "1984-1-x"] {
+            assert!(parse_date(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock_follows_the_emulated_time() {
+        let mut rtc = Rtc::new(false);
+        rtc.set_virtual_clock(parse_date("1984-02-29T23:59:58").unwrap());
+
+        assert_eq!(read(&mut rtc, 0x02), 0x58);
+        assert_eq!(read(&mut rtc, 0x03), 0x59);
+        assert_eq!(read(&mut rtc, 0x04), 0x23);
+        assert_eq!(read(&mut rtc, 0x05), 4); // Wednesday
+        assert_eq!(read(&mut rtc, 0x06), 0x29);
+        assert_eq!(read(&mut rtc, 0x07), 0x02);
+
+        rtc.now_us = 2_250_000;
+        assert_eq!(read(&mut rtc, 0x01), 0x25);
+        assert_eq!(read(&mut rtc, 0x02), 0x00);
+        assert_eq!(read(&mut rtc, 0x04), 0x00);
+        assert_eq!(read(&mut rtc, 0x06), 0x01);
+        assert_eq!(read(&mut rtc, 0x07), 0x03);
+
+        // The guest sets the clock relative to the virtual time
+        rtc.write_addr(0x04);
+        rtc.write_data(0x12);
+        assert_eq!(read(&mut rtc, 0x04), 0x12);
+        rtc.now_us += 3_600_000_000;
+        assert_eq!(read(&mut rtc, 0x04), 0x13);
+    }
+}
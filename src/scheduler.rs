@@ -0,0 +1,95 @@
+// Cycle-based event scheduler.
+//
+// Devices and the run loops post events at a T-state count instead of
+// counting instructions or status polls.  The machine runs the CPU up to
+// the earliest event and dispatches it; the events the machine doesn't
+// handle itself (Refresh, Frame) are handed back to the run loop.
+//
+// Devices think in emulated microseconds: they leave what they post in
+// their `posted` list, and the machine moves it here after each instruction.
+
+use std::io::Result;
+
+use crate::snapshot::{self, StateReader, StateWriter};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Event {
+    /// The terminal loop polls the keyboard and redraws the screen
+    Refresh,
+    /// The GUI loop has run a frame's worth of T-states
+    Frame,
+    /// Check the SIO for a keyboard or serial Rx interrupt
+    SioRx,
+    /// A pending NMI is delivered even if the CPU doesn't HALT for it
+    NmiDeadline,
+    /// READ ADDRESS has read the ID field it was waiting for
+    FdcIdField,
+    /// The WD1002 has finished its reset diagnostics
+    HdcDiagnostics,
+}
+
+/// All the events, by their number in snapshots
+const EVENTS: [Event; 6] = [
+    Event::Refresh,
+    Event::Frame,
+    Event::SioRx,
+    Event::NmiDeadline,
+    Event::FdcIdField,
+    Event::HdcDiagnostics,
+];
+
+pub struct Scheduler {
+    /// Pending events by T-state, earliest first
+    events: Vec<(u64, Event)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { events: Vec::new() }
+    }
+
+    /// Post `event` at T-state `at`, replacing any earlier post of it.
+    pub fn schedule(&mut self, at: u64, event: Event) {
+        self.cancel(event);
+        let index = self.events.partition_point(|&(t, _)| t <= at);
+        self.events.insert(index, (at, event));
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|&(_, e)| e != event);
+    }
+
+    /// Remove and return the earliest event due at T-state `now`.
+    pub fn pop_due(&mut self, now: u64) -> Option<Event> {
+        match self.events.first() {
+            Some(&(t, event)) if t <= now => {
+                self.events.remove(0);
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.events.len() as u32);
+        for &(at, event) in self.events.iter() {
+            state.u64(at);
+            state.u8(event as u8);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.events.clear();
+        let count = state.u32()?;
+        for _ in 0..count {
+            let at = state.u64()?;
+            let event = state.u8()?;
+            let Some(&event) = EVENTS.get(event as usize) else {
+                return Err(snapshot::invalid(format!("Snapshot has an unknown event {}", event)));
+            };
+            self.schedule(at, event);
+        }
+        Ok(())
+    }
+}
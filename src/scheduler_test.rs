@@ -0,0 +1,67 @@
+#[cfg(test)]
+mod tests {
+    use iz80::{Cpu, Machine};
+
+    use crate::kaypro_machine::{KayproMachine, RunExit, VideoMode};
+    use crate::scheduler::{Event, Scheduler};
+    use crate::test_support::floppy_controller;
+
+    #[test]
+    fn test_events_come_due_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(300, Event::Refresh);
+        scheduler.schedule(100, Event::SioRx);
+        scheduler.schedule(200, Event::FdcIdField);
+        // Posting an event again moves it
+        scheduler.schedule(400, Event::SioRx);
+
+        assert_eq!(scheduler.pop_due(199), None);
+        assert_eq!(scheduler.pop_due(1000), Some(Event::FdcIdField));
+        scheduler.cancel(Event::Refresh);
+        assert_eq!(scheduler.pop_due(1000), Some(Event::SioRx));
+        assert_eq!(scheduler.pop_due(1000), None);
+    }
+
+    #[test]
+    fn test_read_address_busy_until_id_field() {
+        let mut fdc = floppy_controller();
+        fdc.set_motor(true);
+        fdc.set_single_density(false);
+        fdc.now_us = 5_000;
+        fdc.put_command(0xC0);
+        // 10 sectors per revolution: the next ID field comes at 20 ms
+        assert_eq!(fdc.posted, vec![(20_000, Event::FdcIdField)]);
+        assert_eq!(fdc.get_status() & 0x01, 0x01);
+        fdc.id_field_passed();
+        assert_eq!(fdc.get_status() & 0x01, 0);
+
+        // The next READ ADDRESS waits for the following ID field
+        fdc.posted.clear();
+        fdc.put_command(0xC0);
+        assert_eq!(fdc.posted, vec![(40_000, Event::FdcIdField)]);
+    }
+
+    #[test]
+    fn test_machine_runs_to_device_events() {
+        let mut fdc = floppy_controller();
+        fdc.set_motor(true);
+        fdc.set_single_density(false);
+        let mut machine = KayproMachine::new("roms/81-292a.rom", VideoMode::MemoryMapped, fdc,
+            false, false, false, false, false, false, false, false);
+        machine.set_cpu_clock(4.0);
+        let mut cpu = Cpu::new_z80();
+        // JR $ at 0x8000
+        machine.poke(0x8000, 0x18);
+        machine.poke(0x8001, 0xFE);
+        cpu.registers().set_pc(0x8000);
+
+        machine.port_out(0x10, 0xC0); // READ ADDRESS
+        machine.scheduler.schedule(70_000, Event::Refresh);
+        assert_eq!(machine.run(&mut cpu), RunExit::Event(Event::Refresh));
+        // The ID field at 20 ms is 80000 T-states away at 4 MHz
+        assert_eq!(machine.port_in(0x10) & 0x01, 0x01);
+        machine.scheduler.schedule(80_012, Event::Refresh);
+        assert_eq!(machine.run(&mut cpu), RunExit::Event(Event::Refresh));
+        assert_eq!(machine.port_in(0x10) & 0x01, 0);
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{stdout, Write};
-use super::KayproMachine;
+use super::Keyboard;
+use super::emulator::Frame;
 use super::kaypro_machine::VideoMode;
 
 pub struct Screen {
@@ -93,7 +94,7 @@ impl Screen {
         self.in_place = in_place;
     }
 
-    pub fn message(&mut self, machine: &mut KayproMachine, message:  &str) {
+    pub fn message(&mut self, keyboard: &mut Keyboard, frame: &Frame, message:  &str) {
         if self.in_place {
             if self.no_border {
                 print!("\x1b[{}A", 13);
@@ -102,7 +103,7 @@ impl Screen {
                 println!("{:>80}", "Press enter to continue");
                 print!("\x1b[{}A", 2);
                 stdout().flush().unwrap();
-                machine.keyboard.read_line();
+                keyboard.read_line();
                 print!("\x1b[{}B", 12);
             } else {
                 print!("\x1b[{}A", 14);
@@ -112,16 +113,16 @@ impl Screen {
                 print!("\x1b[{}A", 2);
                 print!("|| {} ", message);
                 stdout().flush().unwrap();
-                machine.keyboard.read_line();
+                keyboard.read_line();
                 print!("\x1b[{}B", 13);
             }
-            self.update(machine, true);
+            self.update(frame, true);
         } else {
             print!("{}: ", message);
         }
     }
 
-    pub fn prompt(&mut self, machine: &mut KayproMachine, message: &str) -> Option<String> {
+    pub fn prompt(&mut self, keyboard: &mut Keyboard, frame: &Frame, message: &str) -> Option<String> {
         if self.in_place {
             if self.no_border {
                 print!("\x1b[{}A", 19);
@@ -131,9 +132,9 @@ impl Screen {
                 print!("\x1b[{}A", 2);
                 print!("{}: ", message);
                 stdout().flush().unwrap();
-                let line = machine.keyboard.read_line();
+                let line = keyboard.read_line();
                 print!("\x1b[{}B", 18);
-                self.update(machine, true);
+                self.update(frame, true);
                 line
             } else {
                 print!("\x1b[{}A", 20);
@@ -143,35 +144,29 @@ impl Screen {
                 print!("\x1b[{}A", 2);
                 print!("|| {}: ", message);
                 stdout().flush().unwrap();
-                let line = machine.keyboard.read_line();
+                let line = keyboard.read_line();
                 print!("\x1b[{}B", 19);
-                self.update(machine, true);
+                self.update(frame, true);
                 line
             }
         } else {
             print!("{} (ESC cancels): ", message);
             stdout().flush().unwrap();
-            machine.keyboard.read_line()
+            keyboard.read_line()
         }
     }
 
-    pub fn update(&mut self, machine: &mut KayproMachine, force: bool) {
+    pub fn update(&mut self, frame: &Frame, force: bool) {
         // Check if we need to update based on video mode
-        let vram_dirty = if machine.video_mode == VideoMode::Sy6545Crtc {
-            machine.crtc.vram_dirty
-        } else {
-            machine.vram_dirty
-        };
-        
-        let relevant_system_bits = machine.system_bits & SHOWN_SYSTEM_BITS;
-        if !force && !vram_dirty && self.last_system_bits == relevant_system_bits {
+        let relevant_system_bits = frame.system_bits & SHOWN_SYSTEM_BITS;
+        if !force && !frame.vram_dirty && self.last_system_bits == relevant_system_bits {
             return;
         }
         self.last_system_bits = relevant_system_bits;
 
         // Determine display height for cursor positioning
-        let display_rows = if machine.video_mode == VideoMode::Sy6545Crtc {
-            machine.crtc.displayed_rows().clamp(24, 25)
+        let display_rows = if frame.video_mode == VideoMode::Sy6545Crtc {
+            frame.displayed_rows.clamp(24, 25)
         } else {
             24
         };
@@ -188,18 +183,17 @@ impl Screen {
 
         if !self.no_border {
             if self.show_status {
-                let sio_status = machine.sio.status_string();
                 println!("//====Last key: 0x{:02x}=={:>40}==============\\\\",
-                    machine.keyboard.peek_key(), sio_status);
+                    frame.last_key, frame.sio_status);
             } else {
                 println!("{}", self.format_title_line());
             }
         }
         
         // Get cursor position for CRTC mode
-        let (cursor_addr, cursor_visible) = if machine.video_mode == VideoMode::Sy6545Crtc {
-            let addr = machine.crtc.cursor_addr() & 0x7FF; // Mask to 2KB VRAM
-            let mode = machine.crtc.cursor_mode();
+        let (cursor_addr, cursor_visible) = if frame.video_mode == VideoMode::Sy6545Crtc {
+            let addr = frame.cursor_addr & 0x7FF; // Mask to 2KB VRAM
+            let mode = frame.cursor_mode;
             // Mode 0 = steady, 1 = invisible, 2/3 = blink (we show steady for now)
             (addr, mode != 1)
         } else {
@@ -212,16 +206,16 @@ impl Screen {
                 print!("|| ");
             }
             for col in 0..80 {
-                let (code, attr, is_cursor) = if machine.video_mode == VideoMode::Sy6545Crtc {
+                let (code, attr, is_cursor) = if frame.video_mode == VideoMode::Sy6545Crtc {
                     // CRTC mode: linear 80-byte rows from start_addr (R12:R13)
                     // VRAM wraps at 2KB (0x800) for hardware scrolling
-                    let start = machine.crtc.start_addr();
+                    let start = frame.start_addr;
                     let addr = (start + row * 80 + col) & 0x7FF; // 2KB wrap
                     let at_cursor = cursor_visible && addr == cursor_addr;
-                    (machine.crtc.get_vram(addr), machine.crtc.get_attr(addr), at_cursor)
+                    (frame.get_vram(addr), frame.get_attr(addr), at_cursor)
                 } else {
                     // Memory-mapped mode: 128-byte stride from 0x0
-                    (machine.vram[(row * 128 + col) as usize], 0u8, false)
+                    (frame.vram[(row * 128 + col) as usize], 0u8, false)
                 };
                 let ch = translate_char(code);
                 
@@ -234,7 +228,7 @@ impl Screen {
                 let dim = (attr & 0x02) != 0;
                 // In CRTC mode, blink comes from attribute RAM bit 2
                 // In memory-mapped mode, blink comes from character bit 7
-                let blink = if machine.video_mode == VideoMode::Sy6545Crtc {
+                let blink = if frame.video_mode == VideoMode::Sy6545Crtc {
                     (attr & 0x04) != 0
                 } else {
                     (code & 0x80) != 0
@@ -259,14 +253,14 @@ impl Screen {
 
         if !self.no_border {
             let mut disk_status = "======".to_owned();
-            if self.show_status && machine.floppy_controller.motor_on {
+            if self.show_status && frame.motor_on {
                 let (label_a, label_b) = self.floppy_drive_labels;
-                if machine.floppy_controller.drive == 0 {
+                if frame.drive == 0 {
                     disk_status = format!(" {}", label_a);
                 } else {
                     disk_status = format!(" {}", label_b);
                 }
-                if machine.floppy_controller.single_density {
+                if frame.single_density {
                     disk_status += " SD ";
                 } else {
                     disk_status += " DD ";
@@ -276,33 +270,26 @@ impl Screen {
         }
 
         if self.show_help {
-            self.update_help(machine)
-        }
-        
-        // Clear dirty flag on appropriate VRAM
-        if machine.video_mode == VideoMode::Sy6545Crtc {
-            machine.crtc.vram_dirty = false;
-        } else {
-            machine.vram_dirty = false;
+            self.update_help(frame)
         }
     }
 
-    fn update_help (&mut self, machine: &KayproMachine) {
+    fn update_help (&mut self, frame: &Frame) {
         if self.no_border {
             if self.in_place {
                 print!("\x1b[{}A", 20);
             }
             println!("+------------------------------------------------------------------+          ");
-            println!("| izkaypro: Kaypro emulator             F1: help  F4: quit         |          ");
+            println!("| izkaypro: Kaypro emulator   F1: help  F4: quit  F12: rewind      |          ");
             println!("|------------------------------------------------------------------|          ");
             let (la, lb) = self.floppy_drive_labels;
             println!("| F2: disk status  F5: drive {}  F7: save BIOS  F9: set speed       |          ", la);
-            println!("| F6: drive {}      F8: CPU trace                                   |          ", lb);
+            println!("| F6: drive {}      F8: CPU trace  F10: save state  F11: load state |          ", lb);
             println!("|------------------------------------------------------------------|          ");
-            println!("| Host: Delete=DEL, Insert=LINEFEED                                |          ");
+            println!("| F3: monitor   Host: Delete=DEL, Insert=LINEFEED                  |          ");
             println!("|------------------------------------------------------------------|          ");
-            println!("| {}: {:74}|", la, machine.floppy_controller.media_a().info());
-            println!("| {}: {:74}|", lb, machine.floppy_controller.media_b().info());
+            println!("| {}: {:74}|", la, frame.media_info(0));
+            println!("| {}: {:74}|", lb, frame.media_info(1));
             println!("+------------------------------------------------------------------+          ");
             if self.in_place {
                 print!("\x1b[{}B", 20-11);
@@ -318,15 +305,15 @@ impl Screen {
             println!("||        |  F2: Show/hide disk status    |  Delete to DEL                 |        ||");
             println!("||        |  F4: Quit the emulator        |  Insert to LINEFEED            |        ||");
             let (la, lb) = self.floppy_drive_labels;
-            println!("||        |  F5: Select file for drive {}: |                                |        ||", la);
+            println!("||        |  F5: Select file for drive {}: | F3: Machine-language monitor   |        ||", la);
             println!("||        |  F6: Select file for drive {}: |                                |        ||", lb);
-            println!("||        |  F7: Save BIOS to file        |                                |        ||");
-            println!("||        |  F8: Toggle CPU trace         |                                |        ||");
-            println!("||        |  F9: Set CPU speed (MHz)      |                                |        ||");
+            println!("||        |  F7: Save BIOS to file        | F10: Save machine state        |        ||");
+            println!("||        |  F8: Toggle CPU trace         | F11: Load machine state        |        ||");
+            println!("||        |  F9: Set CPU speed (MHz)      | F12: Rewind a few seconds      |        ||");
             println!("||        +----------------------------------------------------------------+        ||");
             println!("||        |  Loaded images:                                                |        ||");
-            println!("||        |  {}: {:58} |        ||", la, machine.floppy_controller.media_a().info());
-            println!("||        |  {}: {:58} |        ||", lb, machine.floppy_controller.media_b().info());
+            println!("||        |  {}: {:58} |        ||", la, frame.media_info(0));
+            println!("||        |  {}: {:58} |        ||", lb, frame.media_info(1));
             println!("||        +----------------------------------------------------------------+        ||");
 
             if self.in_place {
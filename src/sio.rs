@@ -1,7 +1,8 @@
 use std::collections::VecDeque;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+
+use crate::snapshot::{StateReader, StateWriter};
 
 macro_rules! sio_log {
     ($file:expr, $($arg:tt)*) => {{
@@ -40,8 +41,11 @@ pub struct Sio {
     // Error flags (RR1 bits, latched until Error Reset command)
     rx_overrun: bool,
 
+    // Emulated time in microseconds, set by the machine before each access
+    pub now_us: u64,
+
     // Transmit state
-    tx_ready_at: Instant,
+    tx_ready_at: u64, // Emulated time when the character being sent is out
     tx_file: Option<std::fs::File>,
 
     // Serial device file descriptor for modem control ioctls (Unix only)
@@ -64,7 +68,8 @@ impl Sio {
             reg_pointer: 0,
             rx_fifo: Arc::new(Mutex::new(VecDeque::with_capacity(64))),
             rx_overrun: false,
-            tx_ready_at: Instant::now(),
+            now_us: 0,
+            tx_ready_at: 0,
             tx_file: None,
             #[cfg(unix)]
             serial_fd: None,
@@ -321,8 +326,7 @@ impl Sio {
         }
 
         // Calculate character time and set tx_ready_at
-        let char_time_us = self.character_time_us();
-        self.tx_ready_at = Instant::now() + std::time::Duration::from_micros(char_time_us);
+        self.tx_ready_at = self.now_us + self.character_time_us();
 
         // Forward byte to host serial port
         if let Some(ref mut file) = self.tx_file {
@@ -398,7 +402,7 @@ impl Sio {
         if let Ok(mut fifo) = self.rx_fifo.lock() {
             fifo.clear();
         }
-        self.tx_ready_at = Instant::now();
+        self.tx_ready_at = self.now_us;
         if self.trace {
             sio_log!(self.trace_file, "SIO A: Channel Reset");
         }
@@ -489,7 +493,7 @@ impl Sio {
 
         // D2: Tx Buffer Empty (ready to accept data)
         // D6: Tx Underrun/EOM (transmitter completely idle)
-        if Instant::now() >= self.tx_ready_at {
+        if self.now_us >= self.tx_ready_at {
             status |= 0x04; // Tx Buffer Empty
             status |= 0x40; // Tx Underrun/EOM
         }
@@ -522,7 +526,7 @@ impl Sio {
         let mut status: u8 = 0;
 
         // D0: All Sent — true when Tx is idle
-        if Instant::now() >= self.tx_ready_at {
+        if self.now_us >= self.tx_ready_at {
             status |= 0x01;
         }
 
@@ -588,4 +592,33 @@ impl Sio {
         // = total_tenths * 100_000 / baud_rate
         total_tenths * 100_000 / self.baud_rate as u64
     }
+
+    /// The serial connection isn't part of the state, only the data the
+    /// SIO had received.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.wr);
+        state.u8(self.reg_pointer);
+        let rx_fifo: Vec<u8> = self.rx_fifo.lock().unwrap().iter().copied().collect();
+        state.bytes(&rx_fifo);
+        state.bool(self.rx_overrun);
+        state.u64(self.tx_ready_at);
+        state.u8(self.baud_rate_code);
+        state.u32(self.baud_rate);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> std::io::Result<()> {
+        state.bytes_into(&mut self.wr)?;
+        self.reg_pointer = state.u8()?;
+        let rx_fifo = state.bytes()?;
+        {
+            let mut fifo = self.rx_fifo.lock().unwrap();
+            fifo.clear();
+            fifo.extend(rx_fifo);
+        }
+        self.rx_overrun = state.bool()?;
+        self.tx_ready_at = state.u64()?;
+        self.baud_rate_code = state.u8()?;
+        self.baud_rate = state.u32()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,383 @@
+// Machine snapshots (.ksnap).
+//
+// A snapshot holds the state of the CPU and of the emulated hardware, so
+// a session can be saved and resumed later on the same model:
+//
+//   "IZKSNAP" 0x1A   signature
+//   version (3)
+//   ROM size (u32) and CRC-16 (u16) of the ROM the snapshot was taken with
+//   CPU registers, see CpuState
+//   Machine: RAM, video RAM, system bits, SIO latches and emulated time,
+//   then the SY6545, FDC, hard disk controller, RAM disk, SIO, RTC and
+//   the pending scheduler events
+//   Content hash (u64) of each floppy disk, then of each hard disk
+//
+// Values are little endian; byte strings and texts go after their length
+// (u32).  The disk images aren't part of the snapshot: the disks are
+// flushed when it is taken, the FDC records the names of its disks to
+// reload them on restore, and the hard disks must be the ones the machine
+// had.  A disk written since the snapshot doesn't match what the saved
+// memory (CP/M's directory buffers) expects, so its hash refuses it.
+
+use std::io::{Error, ErrorKind, Result};
+
+use iz80::{Cpu, Machine, Reg16, Reg8};
+
+use crate::floppy_controller::crc16;
+use crate::kaypro_machine::KayproMachine;
+
+const SIGNATURE: &[u8; 8] = b"IZKSNAP\x1A";
+const VERSION: u8 = 3;
+
+/// File extension of snapshots
+#[allow(dead_code)] // Only the GUI file dialog filters by it
+pub const EXTENSION: &str = "ksnap";
+
+/// Snapshot the save state hotkey writes and the load state hotkey
+/// offers.
+pub const QUICK_SAVE: &str = "izkaypro.ksnap";
+
+pub fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// FNV-1a hash of a disk or machine state.
+pub fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Builds a snapshot; the devices append their state with `save_state`.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+    }
+}
+
+/// Reads a snapshot back; the devices take their state with `load_state`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid("Snapshot is truncated".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    /// Fails if something is left after the last device.
+    pub fn finish(&self) -> Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid("Unexpected data at the end of the snapshot".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub fn usize(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Byte string into `target`, that must have its exact size.
+    pub fn bytes_into(&mut self, target: &mut [u8]) -> Result<()> {
+        let bytes = self.bytes()?;
+        if bytes.len() != target.len() {
+            return Err(invalid(format!("Snapshot has {} bytes where {} were expected",
+                bytes.len(), target.len())));
+        }
+        target.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| invalid("Snapshot has an invalid text".to_string()))
+    }
+}
+
+/// Registers of the Z80.
+///
+/// iz80 keeps the alternate registers and the HALT state to itself: they
+/// are read and set by running EX AF,AF' / EXX and HALT on a scratch
+/// memory.  The interrupt flip-flops and mode aren't saved, nothing reads
+/// them: iz80 has no maskable interrupts and the machine delivers the SIO
+/// interrupts itself.  The CPU must not have an NMI waiting, see
+/// `KayproMachine::settle`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuState {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub halted: bool,
+}
+
+// EX AF,AF' and EXX
+const EXCHANGE_ALL: [u8; 2] = [0x08, 0xd9];
+const HALT: [u8; 1] = [0x76];
+
+/// Memory the probe instructions run on: the code from address 0, NOPs
+/// after it.  Writes and ports go nowhere.
+struct ProbeMemory<'a>(&'a [u8]);
+
+impl Machine for ProbeMemory<'_> {
+    fn peek(&self, address: u16) -> u8 {
+        self.0.get(address as usize).copied().unwrap_or(0)
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) {}
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0xff
+    }
+
+    fn port_out(&mut self, _address: u16, _value: u8) {}
+}
+
+/// Take the CPU out of HALT and run `code` on it. Changes PC, R and, if
+/// the CPU was halted, SP.
+fn probe(cpu: &mut Cpu, code: &[u8]) {
+    let mut memory = ProbeMemory(code);
+    if cpu.is_halted() {
+        // The NMI is the only way out of HALT; its handler is a NOP
+        cpu.signal_nmi();
+        cpu.execute_instruction(&mut memory);
+    }
+    cpu.registers().set_pc(0);
+    while (cpu.registers().pc() as usize) < code.len() {
+        cpu.execute_instruction(&mut memory);
+    }
+}
+
+impl CpuState {
+    pub fn capture(cpu: &mut Cpu) -> CpuState {
+        let regs = cpu.registers();
+        let mut state = CpuState {
+            af: regs.get16(Reg16::AF),
+            bc: regs.get16(Reg16::BC),
+            de: regs.get16(Reg16::DE),
+            hl: regs.get16(Reg16::HL),
+            ix: regs.get16(Reg16::IX),
+            iy: regs.get16(Reg16::IY),
+            sp: regs.get16(Reg16::SP),
+            pc: regs.pc(),
+            i: regs.get8(Reg8::I),
+            r: regs.get8(Reg8::R),
+            halted: cpu.is_halted(),
+            ..CpuState::default()
+        };
+        probe(cpu, &EXCHANGE_ALL);
+        let regs = cpu.registers();
+        state.af_alt = regs.get16(Reg16::AF);
+        state.bc_alt = regs.get16(Reg16::BC);
+        state.de_alt = regs.get16(Reg16::DE);
+        state.hl_alt = regs.get16(Reg16::HL);
+        // Put everything back as it was
+        state.apply(cpu);
+        state
+    }
+
+    pub fn apply(&self, cpu: &mut Cpu) {
+        probe(cpu, &[]);
+        let regs = cpu.registers();
+        regs.set16(Reg16::AF, self.af_alt);
+        regs.set16(Reg16::BC, self.bc_alt);
+        regs.set16(Reg16::DE, self.de_alt);
+        regs.set16(Reg16::HL, self.hl_alt);
+        probe(cpu, &EXCHANGE_ALL);
+        let regs = cpu.registers();
+        regs.set16(Reg16::AF, self.af);
+        regs.set16(Reg16::BC, self.bc);
+        regs.set16(Reg16::DE, self.de);
+        regs.set16(Reg16::HL, self.hl);
+        regs.set16(Reg16::IX, self.ix);
+        regs.set16(Reg16::IY, self.iy);
+        regs.set16(Reg16::SP, self.sp);
+        regs.set8(Reg8::I, self.i);
+        if self.halted {
+            probe(cpu, &HALT);
+        }
+        // Last, the probes move them
+        let regs = cpu.registers();
+        regs.set8(Reg8::R, self.r);
+        regs.set_pc(self.pc);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for value in [self.af, self.bc, self.de, self.hl,
+                self.af_alt, self.bc_alt, self.de_alt, self.hl_alt,
+                self.ix, self.iy, self.sp, self.pc] {
+            state.u16(value);
+        }
+        state.u8(self.i);
+        state.u8(self.r);
+        state.bool(self.halted);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<CpuState> {
+        Ok(CpuState {
+            af: state.u16()?,
+            bc: state.u16()?,
+            de: state.u16()?,
+            hl: state.u16()?,
+            af_alt: state.u16()?,
+            bc_alt: state.u16()?,
+            de_alt: state.u16()?,
+            hl_alt: state.u16()?,
+            ix: state.u16()?,
+            iy: state.u16()?,
+            sp: state.u16()?,
+            pc: state.u16()?,
+            i: state.u8()?,
+            r: state.u8()?,
+            halted: state.bool()?,
+        })
+    }
+}
+
+/// Snapshot of `machine` and `cpu`. The machine must be settled and its
+/// disks flushed.
+pub fn save(path: &str, machine: &KayproMachine, cpu: &CpuState) -> Result<()> {
+    let mut state = StateWriter::new();
+    state.data.extend_from_slice(SIGNATURE);
+    state.u8(VERSION);
+    state.u32(machine.rom().len() as u32);
+    state.u16(crc16(machine.rom()));
+    cpu.save_state(&mut state);
+    machine.save_state(&mut state);
+    machine.save_disk_hashes(&mut state);
+    std::fs::write(path, state.into_bytes())
+}
+
+/// Restore the snapshot at `path` on `machine` and return the CPU state
+/// to apply.  On error the machine is left as it was.
+pub fn load(path: &str, machine: &mut KayproMachine) -> Result<CpuState> {
+    let data = std::fs::read(path)?;
+    let mut state = StateReader::new(&data);
+    if state.take(SIGNATURE.len()).ok() != Some(&SIGNATURE[..]) {
+        return Err(invalid(format!("{} is not a snapshot", path)));
+    }
+    let version = state.u8()?;
+    if version != VERSION {
+        return Err(invalid(format!("Snapshot version {} is not supported", version)));
+    }
+    let rom_size = state.u32()? as usize;
+    let rom_crc = state.u16()?;
+    if rom_size != machine.rom().len() || rom_crc != crc16(machine.rom()) {
+        return Err(invalid("Snapshot was taken with a different ROM".to_string()));
+    }
+    let cpu = CpuState::load_state(&mut state)?;
+
+    // Roll back to the current state if the snapshot doesn't fit the
+    // machine (e.g. it has a hard disk and this one doesn't)
+    let mut backup = StateWriter::new();
+    machine.save_state(&mut backup);
+    let backup = backup.into_bytes();
+    let restored = machine.load_state(&mut state)
+        .and_then(|()| machine.check_disk_hashes(&mut state))
+        .and_then(|()| state.finish());
+    if let Err(err) = restored {
+        let _ = machine.load_state(&mut StateReader::new(&backup));
+        return Err(err);
+    }
+    Ok(cpu)
+}
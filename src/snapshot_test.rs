@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod tests {
+    use iz80::Cpu;
+
+    use crate::floppy_controller::Drive;
+    use crate::kaypro_machine::VideoMode;
+    use crate::snapshot::{self, CpuState};
+    use crate::test_support::{self, hd_command, kaypro_ii, memory, run, temp_path};
+
+    #[test]
+    fn test_cpu_state_round_trip() {
+        for halted in [false, true] {
+            let state = CpuState {
+                af: 0x1234, bc: 0x2345, de: 0x3456, hl: 0x4567,
+                af_alt: 0x5678, bc_alt: 0x6789, de_alt: 0x789A, hl_alt: 0x89AB,
+                ix: 0x9ABC, iy: 0xABCD, sp: 0xBCDE, pc: 0xCDEF,
+                i: 0xFE, r: 0x42,
+                halted,
+            };
+            let mut cpu = Cpu::new_z80();
+            state.apply(&mut cpu);
+            assert_eq!(cpu.is_halted(), halted);
+            assert_eq!(CpuState::capture(&mut cpu), state);
+            // Capturing leaves the CPU as it was
+            assert_eq!(CpuState::capture(&mut cpu), state);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_resumes_the_machine() {
+        let path = temp_path("snapshot_resume.ksnap");
+        let mut machine = kaypro_ii(false);
+        let mut cpu = Cpu::new_z80();
+        // Taken while the ROM loads CP/M from the disk
+        run(&mut machine, &mut cpu, 2_000_000);
+        machine.settle(&mut cpu);
+        snapshot::save(&path, &machine, &CpuState::capture(&mut cpu)).unwrap();
+        run(&mut machine, &mut cpu, 500_000);
+
+        let mut restored = kaypro_ii(false);
+        let mut restored_cpu = Cpu::new_z80();
+        snapshot::load(&path, &mut restored).unwrap().apply(&mut restored_cpu);
+        run(&mut restored, &mut restored_cpu, 500_000);
+
+        assert_eq!(restored.tstates, machine.tstates);
+        assert_eq!(restored_cpu.registers().pc(), cpu.registers().pc());
+        assert_eq!(CpuState::capture(&mut restored_cpu), CpuState::capture(&mut cpu));
+        assert!(memory(&restored) == memory(&machine));
+        assert!(restored.vram == machine.vram);
+        assert!(machine.floppy_controller.last_command_count > 0);
+        assert_eq!(restored.floppy_controller.last_command_count,
+            machine.floppy_controller.last_command_count);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_snapshot_of_another_machine_is_rejected() {
+        let path = temp_path("snapshot_reject.ksnap");
+        let mut machine = kaypro_ii(false);
+        let mut cpu = Cpu::new_z80();
+        run(&mut machine, &mut cpu, 100_000);
+        machine.settle(&mut cpu);
+        snapshot::save(&path, &machine, &CpuState::capture(&mut cpu)).unwrap();
+
+        let mut other_rom = test_support::machine("roms/81-232.rom", VideoMode::MemoryMapped, false);
+        assert!(snapshot::load(&path, &mut other_rom).is_err());
+
+        // Same ROM with a hard disk controller: rolled back to where it was
+        let mut with_hd = kaypro_ii(true);
+        let before = memory(&with_hd);
+        assert!(snapshot::load(&path, &mut with_hd).is_err());
+        assert_eq!(with_hd.tstates, 0);
+        assert!(memory(&with_hd) == before);
+
+        // A truncated snapshot
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+        let mut same = kaypro_ii(false);
+        assert!(snapshot::load(&path, &mut same).is_err());
+        assert_eq!(same.tstates, 0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_snapshot_refuses_a_disk_written_since() {
+        let path = temp_path("snapshot_written.ksnap");
+        let mut machine = kaypro_ii(false);
+        let mut cpu = Cpu::new_z80();
+        run(&mut machine, &mut cpu, 100_000);
+        machine.settle(&mut cpu);
+        snapshot::save(&path, &machine, &CpuState::capture(&mut cpu)).unwrap();
+        run(&mut machine, &mut cpu, 100_000);
+
+        machine.floppy_controller.media_mut(Drive::A).content[0] ^= 0xFF;
+        let tstates = machine.tstates;
+        let error = snapshot::load(&path, &mut machine).unwrap_err();
+        assert!(error.to_string().contains("written since the snapshot"));
+        assert_eq!(machine.tstates, tstates);
+        machine.floppy_controller.media_mut(Drive::A).content[0] ^= 0xFF;
+        assert!(snapshot::load(&path, &mut machine).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_hard_disk_layout() {
+        let path = temp_path("snapshot_hd_layout.ksnap");
+        let image = format!("{}.hd", path);
+        // A raw image doesn't record the formatted tracks or bad blocks
+        std::fs::write(&image, []).unwrap();
+        let hd_machine = || {
+            let mut machine = kaypro_ii(true);
+            machine.hard_disk.as_mut().unwrap().load_image(&image).unwrap();
+            machine
+        };
+        let mut machine = hd_machine();
+        let hd = machine.hard_disk.as_mut().unwrap();
+        let mut table: Vec<u8> = (0..17u8).flat_map(|sector| [if sector == 5 { 0x80 } else { 0 }, sector])
+            .chain([0; 478]).collect();
+        assert_eq!(hd_command(hd, 0x50, 1, 0, &mut table), 0);
+        hd.flush();
+        let mut cpu = Cpu::new_z80();
+        machine.settle(&mut cpu);
+        snapshot::save(&path, &machine, &CpuState::capture(&mut cpu)).unwrap();
+
+        let mut restored = hd_machine();
+        let restored_ok = snapshot::load(&path, &mut restored).is_ok();
+        let hd = restored.hard_disk.as_mut().unwrap();
+        let mut sector = [0; 512];
+        let (good, bad, unformatted) = (hd_command(hd, 0x20, 1, 3, &mut sector),
+            hd_command(hd, 0x20, 1, 5, &mut sector), hd_command(hd, 0x20, 0, 3, &mut sector));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&image);
+        assert!(restored_ok);
+        assert_eq!((good, bad, unformatted), (0, 0x80, 0x10));
+    }
+}
@@ -20,6 +20,10 @@
 /// - Sets UR=0 (busy), then UR=1 (ready) after strobe completes
 /// - Increments update address (R18:R19) after access
 
+use std::io::Result;
+
+use crate::snapshot::{StateReader, StateWriter};
+
 pub struct Sy6545 {
     // CRTC registers R0-R19 (timing + update address)
     // R31 is handled specially (dummy register)
@@ -38,10 +42,10 @@ pub struct Sy6545 {
     
     // Status register bits
     update_ready: bool,      // SR7: UR - Update Ready
-    pub vertical_retrace: bool,  // SR5: VRT - Vertical Retrace
-    
-    // Frame counter for VRT timing simulation
-    cycle_counter: u32,
+
+    // Emulated time in microseconds, set by the machine before each access.
+    // SR5 (VRT) is derived from it.
+    pub now_us: u64,
     
     pub trace: bool,
 }
@@ -63,34 +67,19 @@ impl Sy6545 {
             vram_dirty: true,
             addr_latch: 0,
             update_ready: true,  // Start ready
-            vertical_retrace: false,
-            cycle_counter: 0,
+            now_us: 0,
             trace: false,
         }
     }
     
-    /// Set the vertical retrace flag. Called once per GUI frame to pulse
-    /// VRT so ROM code waiting on port 0x1C bit 5 can proceed.
-    pub fn set_vertical_retrace(&mut self, vrt: bool) {
-        self.vertical_retrace = vrt;
+    /// SR5: true during the vertical retrace at the end of each 60 Hz
+    /// frame, roughly 1.6 ms of the 16.7 ms frame.
+    pub fn vertical_retrace(&self) -> bool {
+        const FRAME_US: u64 = 16_667;
+        const RETRACE_US: u64 = 1_600;
+        self.now_us % FRAME_US >= FRAME_US - RETRACE_US
     }
 
-    /// Advance timing by one CPU cycle (for VRT simulation)
-    /// Call this periodically to simulate vertical retrace timing
-    #[allow(dead_code)]
-    pub fn tick(&mut self) {
-        self.cycle_counter = self.cycle_counter.wrapping_add(1);
-        
-        // Simulate vertical retrace at approximately 60Hz
-        // Assuming ~2.5MHz CPU, that's ~41666 cycles per frame
-        // VRT is active for roughly 1/10th of the frame (during retrace)
-        let frame_cycles = 41666u32;
-        let retrace_start = frame_cycles - 4000; // ~4000 cycles of retrace
-        
-        let frame_pos = self.cycle_counter % frame_cycles;
-        self.vertical_retrace = frame_pos >= retrace_start;
-    }
-    
     /// Get display start address from R12:R13
     pub fn start_addr(&self) -> usize {
         ((self.regs[12] as usize) << 8) | (self.regs[13] as usize)
@@ -133,7 +122,7 @@ impl Sy6545 {
         // SR6: Light Pen Register Full (bit 6) - not used, always 0
         
         // SR5: Vertical Retrace (bit 5)
-        if self.vertical_retrace {
+        if self.vertical_retrace() {
             status |= 0x20;
         }
         
@@ -325,6 +314,7 @@ impl Sy6545 {
     }
     
     /// Get attribute byte at offset (for rendering - attribute RAM, 0x800-0xFFF)
+    #[allow(dead_code)]
     pub fn get_attr(&self, offset: usize) -> u8 {
         self.vram[(offset & 0x7FF) + 0x800] // Attribute plane
     }
@@ -356,4 +346,22 @@ impl Sy6545 {
     pub fn cursor_mode(&self) -> u8 {
         (self.regs[10] >> 5) & 0x03
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.regs);
+        state.u8(self.reg_index);
+        state.bytes(&self.vram);
+        state.u16(self.addr_latch);
+        state.bool(self.update_ready);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.regs)?;
+        self.reg_index = state.u8()?;
+        state.bytes_into(&mut self.vram)?;
+        self.addr_latch = state.u16()?;
+        self.update_ready = state.bool()?;
+        self.vram_dirty = true;
+        Ok(())
+    }
 }
@@ -0,0 +1,451 @@
+// Teledisk (.TD0) image import.
+//
+// File layout (all multi-byte values little-endian):
+//   Header, 12 bytes: "TD" (normal) or "td" (advanced compression),
+//     sequence, check signature, version, data rate (bits 0-1:
+//     250/300/500 kbps, bit 7: FM), drive type, stepping (bit 7: comment
+//     present), DOS allocation flag, sides, CRC.
+//   With advanced compression, everything after the header is an LZHUF
+//   stream (Okumura's LZSS + adaptive Huffman, no length prefix).
+//   Optional comment: CRC, length, date (year-1900, month 0-11, day,
+//     hour, minute, second), then `length` bytes of NUL-separated lines.
+//   Tracks: sector count (0xFF ends the image), cylinder, head (bit 7:
+//     FM), CRC.  Each sector: cylinder, head, sector ID, N, flags, CRC,
+//     then unless flags has 0x30 set a data block: length, encoding
+//     (0 raw, 1 repeated 2-byte pattern, 2 run-length blocks), data.
+//
+// The image is converted to an `ImdImage`, which already models per-track
+// mode, sector ID maps and sector status.
+
+use std::io::{Error, ErrorKind, Result};
+
+use crate::imd::{ImdImage, ImdTrack};
+
+const HEADER_SIZE: usize = 12;
+const END_OF_IMAGE: u8 = 0xFF;
+
+const SECTOR_DUPLICATE: u8 = 0x01;
+const SECTOR_CRC_ERROR: u8 = 0x02;
+const SECTOR_DELETED: u8 = 0x04;
+const SECTOR_NO_DATA: u8 = 0x30;
+
+pub fn is_td0(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && (data.starts_with(b"TD") || data.starts_with(b"td"))
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::InvalidData, "Truncated Teledisk image")
+}
+
+/// Decode a Teledisk image into the IMD model.  The comment block, if
+/// present, becomes the IMD comment.
+pub fn parse(data: &[u8]) -> Result<ImdImage> {
+    if !is_td0(data) {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a Teledisk image"));
+    }
+    let header = &data[..HEADER_SIZE];
+    let body = if header[0] == b't' {
+        lzhuf_decode(&data[HEADER_SIZE..])
+    } else {
+        data[HEADER_SIZE..].to_vec()
+    };
+    let rate = header[5];
+    let mut reader = Reader { data: &body, pos: 0 };
+
+    let mut imd_header = format!("IMD 1.18: Teledisk {}.{}", header[4] / 10, header[4] % 10).into_bytes();
+    if header[7] & 0x80 != 0 {
+        let comment = reader.take(10)?;
+        let length = u16::from_le_bytes([comment[2], comment[3]]) as usize;
+        let date = format!(" {:02}/{:02}/{:04} {:02}:{:02}:{:02}",
+            comment[6], comment[5] + 1, comment[4] as u16 + 1900,
+            comment[7], comment[8], comment[9]);
+        imd_header.extend_from_slice(date.as_bytes());
+        imd_header.extend_from_slice(b"\r\n");
+        let text = reader.take(length)?;
+        let lines: Vec<String> = text.split(|&b| b == 0)
+            .map(|l| String::from_utf8_lossy(l).trim_end().to_string())
+            .collect();
+        imd_header.extend_from_slice(lines.join("\r\n").trim_end().as_bytes());
+    } else {
+        imd_header.extend_from_slice(b"\r\n");
+    }
+
+    let mut tracks = Vec::new();
+    loop {
+        let count = reader.byte()?;
+        if count == END_OF_IMAGE {
+            break;
+        }
+        let cylinder = reader.byte()?;
+        let head = reader.byte()?;
+        reader.byte()?; // CRC
+        let single_density = rate & 0x80 != 0 || head & 0x80 != 0;
+        let mode = match (rate & 0x03, single_density) {
+            (2, true) => 0,
+            (1, true) => 1,
+            (_, true) => 2,
+            (2, false) => 3,
+            (1, false) => 4,
+            (_, false) => 5,
+        };
+
+        let mut track = ImdTrack {
+            mode,
+            cylinder,
+            head: head & 0x01,
+            n: 0,
+            sector_ids: Vec::new(),
+            cylinder_map: None,
+            head_map: None,
+            sector_types: Vec::new(),
+            data: Vec::new(),
+        };
+        let mut id_cylinders = Vec::new();
+        let mut id_heads = Vec::new();
+        for _ in 0..count {
+            let sh = reader.take(6)?;
+            let (id_cylinder, id_head, id, n, flags) = (sh[0], sh[1], sh[2], sh[3], sh[4]);
+            let sector = if flags & SECTOR_NO_DATA == 0 && n <= 6 {
+                Some(reader.sector_data(128usize << n)?)
+            } else {
+                None
+            };
+            if flags & SECTOR_DUPLICATE != 0 || track.sector_ids.contains(&id) {
+                continue;
+            }
+            if track.sector_ids.is_empty() {
+                // IMD tracks have a single sector size; the first sector's
+                // size applies to the whole track.
+                track.n = n.min(6);
+            }
+            let kind = match sector {
+                None => 0,
+                Some(_) => 1
+                    + if flags & SECTOR_DELETED != 0 { 2 } else { 0 }
+                    + if flags & SECTOR_CRC_ERROR != 0 { 4 } else { 0 },
+            };
+            let mut sector = sector.unwrap_or_default();
+            if !sector.is_empty() {
+                sector.resize(128usize << track.n, 0xE5);
+            }
+            track.sector_ids.push(id);
+            track.sector_types.push(kind);
+            track.data.push(sector);
+            id_cylinders.push(id_cylinder);
+            id_heads.push(id_head);
+        }
+        if id_cylinders.iter().any(|&c| c != cylinder) {
+            track.cylinder_map = Some(id_cylinders);
+        }
+        if id_heads.iter().any(|&h| h != track.head) {
+            track.head_map = Some(id_heads);
+        }
+        if !track.sector_ids.is_empty() {
+            tracks.push(track);
+        }
+    }
+
+    Ok(ImdImage { header: imd_header, tracks })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos + len).ok_or_else(truncated)?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<usize> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn sector_data(&mut self, size: usize) -> Result<Vec<u8>> {
+        let length = self.word()?;
+        let block = self.take(length)?;
+        let (&encoding, block) = block.split_first().ok_or_else(truncated)?;
+        let mut out = Vec::with_capacity(size);
+        match encoding {
+            0 => out.extend_from_slice(block),
+            1 => {
+                let b = block.get(..4).ok_or_else(truncated)?;
+                let count = u16::from_le_bytes([b[0], b[1]]) as usize;
+                for _ in 0..count {
+                    out.extend_from_slice(&b[2..4]);
+                }
+            }
+            2 => {
+                let mut i = 0;
+                while out.len() < size && i < block.len() {
+                    let kind = block[i];
+                    let len = *block.get(i + 1).ok_or_else(truncated)? as usize;
+                    i += 2;
+                    if kind == 0 {
+                        out.extend_from_slice(block.get(i..i + len).ok_or_else(truncated)?);
+                        i += len;
+                    } else {
+                        let pattern_len = 1usize << kind;
+                        let pattern = block.get(i..i + pattern_len).ok_or_else(truncated)?;
+                        for _ in 0..len {
+                            out.extend_from_slice(pattern);
+                        }
+                        i += pattern_len;
+                    }
+                }
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData,
+                format!("Unknown Teledisk sector encoding {}", encoding))),
+        }
+        out.resize(size, 0xE5);
+        Ok(out)
+    }
+}
+
+// ── LZHUF ────────────────────────────────────────────────────────────────────
+
+/// Ring buffer size
+const N: usize = 4096;
+/// Longest match
+const F: usize = 60;
+/// Shortest match minus one
+const THRESHOLD: usize = 2;
+const N_CHAR: usize = 256 - THRESHOLD + F;
+/// Huffman tree size
+const T: usize = N_CHAR * 2 - 1;
+/// Root position
+const R: usize = T - 1;
+const MAX_FREQ: u16 = 0x8000;
+
+/// Code lengths for the upper 6 bits of a match position
+fn position_code_len(upper: usize) -> u32 {
+    match upper {
+        0 => 3,
+        1..=3 => 4,
+        4..=11 => 5,
+        12..=23 => 6,
+        24..=47 => 7,
+        _ => 8,
+    }
+}
+
+/// Adaptive Huffman tree shared by the decoder and the test encoder.
+pub(crate) struct Huffman {
+    freq: [u16; T + 1],
+    /// Parent of each node; leaves are at T..T+N_CHAR
+    prnt: [usize; T + N_CHAR],
+    son: [usize; T],
+}
+
+impl Huffman {
+    pub(crate) fn new() -> Huffman {
+        let mut h = Huffman { freq: [0; T + 1], prnt: [0; T + N_CHAR], son: [0; T] };
+        for i in 0..N_CHAR {
+            h.freq[i] = 1;
+            h.son[i] = i + T;
+            h.prnt[i + T] = i;
+        }
+        let mut i = 0;
+        for j in N_CHAR..=R {
+            h.freq[j] = h.freq[i] + h.freq[i + 1];
+            h.son[j] = i;
+            h.prnt[i] = j;
+            h.prnt[i + 1] = j;
+            i += 2;
+        }
+        h.freq[T] = 0xFFFF;
+        h.prnt[R] = 0;
+        h
+    }
+
+    fn reconstruct(&mut self) {
+        // Collect leaves, halving their counts
+        let mut j = 0;
+        for i in 0..T {
+            if self.son[i] >= T {
+                self.freq[j] = self.freq[i].div_ceil(2);
+                self.son[j] = self.son[i];
+                j += 1;
+            }
+        }
+        // Rebuild internal nodes, keeping frequencies sorted
+        let mut i = 0;
+        for j in N_CHAR..T {
+            let f = self.freq[i] + self.freq[i + 1];
+            let mut k = j;
+            while k > 0 && f < self.freq[k - 1] {
+                k -= 1;
+            }
+            self.freq.copy_within(k..j, k + 1);
+            self.freq[k] = f;
+            self.son.copy_within(k..j, k + 1);
+            self.son[k] = i;
+            i += 2;
+        }
+        for i in 0..T {
+            let k = self.son[i];
+            self.prnt[k] = i;
+            if k < T {
+                self.prnt[k + 1] = i;
+            }
+        }
+    }
+
+    fn update(&mut self, symbol: usize) {
+        if self.freq[R] == MAX_FREQ {
+            self.reconstruct();
+        }
+        let mut c = self.prnt[symbol + T];
+        loop {
+            self.freq[c] += 1;
+            let k = self.freq[c];
+            // Swap with the last node of lower frequency to keep order
+            let mut l = c + 1;
+            if k > self.freq[l] {
+                while k > self.freq[l + 1] {
+                    l += 1;
+                }
+                self.freq[c] = self.freq[l];
+                self.freq[l] = k;
+
+                let i = self.son[c];
+                self.prnt[i] = l;
+                if i < T {
+                    self.prnt[i + 1] = l;
+                }
+                let j = self.son[l];
+                self.son[l] = i;
+                self.prnt[j] = c;
+                if j < T {
+                    self.prnt[j + 1] = c;
+                }
+                self.son[c] = j;
+                c = l;
+            }
+            c = self.prnt[c];
+            if c == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Code for a symbol as (bits, length), MSB first.
+    #[cfg(test)]
+    pub(crate) fn encode(&mut self, symbol: usize) -> (u32, u32) {
+        let mut code = 0u32;
+        let mut len = 0;
+        let mut k = self.prnt[symbol + T];
+        loop {
+            code |= ((k & 1) as u32) << len;
+            len += 1;
+            k = self.prnt[k];
+            if k == R {
+                break;
+            }
+        }
+        self.update(symbol);
+        (code, len)
+    }
+}
+
+/// Encode a match position (0..N) as (bits, length), MSB first.
+#[cfg(test)]
+pub(crate) fn encode_position(position: usize) -> (u32, u32) {
+    let upper = position >> 6;
+    let len = position_code_len(upper);
+    let code = position_code(upper);
+    (((code as u32) >> (8 - len)) << 6 | (position & 0x3F) as u32, len + 6)
+}
+
+/// Left-aligned 8-bit code for the upper 6 bits of a match position.
+fn position_code(upper: usize) -> u8 {
+    let mut code = 0usize;
+    for u in 0..upper {
+        code += 1 << (8 - position_code_len(u));
+    }
+    code as u8
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+    overrun: bool,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> usize {
+        let byte = self.bit / 8;
+        if byte >= self.data.len() {
+            self.overrun = true;
+            return 0;
+        }
+        let b = (self.data[byte] >> (7 - self.bit % 8)) & 1;
+        self.bit += 1;
+        b as usize
+    }
+
+    fn bits(&mut self, n: u32) -> usize {
+        (0..n).fold(0, |acc, _| acc << 1 | self.bit())
+    }
+}
+
+pub(crate) fn lzhuf_decode(input: &[u8]) -> Vec<u8> {
+    // Upper 6 bits of a position, indexed by the first 8 bits of its code
+    let mut d_code = [0usize; 256];
+    let mut d_len = [0u32; 256];
+    for upper in 0..64 {
+        let start = position_code(upper) as usize;
+        let len = position_code_len(upper);
+        for b in start..start + (1 << (8 - len)) {
+            d_code[b] = upper;
+            d_len[b] = len;
+        }
+    }
+
+    let mut huff = Huffman::new();
+    let mut reader = BitReader { data: input, bit: 0, overrun: false };
+    let mut ring = [b' '; N];
+    let mut r = N - F;
+    let mut out = Vec::new();
+
+    loop {
+        let mut c = huff.son[R];
+        while c < T {
+            c = huff.son[c + reader.bit()];
+        }
+        if reader.overrun {
+            break;
+        }
+        let symbol = c - T;
+        huff.update(symbol);
+
+        if symbol < 256 {
+            out.push(symbol as u8);
+            ring[r] = symbol as u8;
+            r = (r + 1) & (N - 1);
+        } else {
+            let first = reader.bits(8);
+            let extra = reader.bits(d_len[first] - 2);
+            let position = d_code[first] << 6 | ((first << (d_len[first] - 2) | extra) & 0x3F);
+            if reader.overrun {
+                break;
+            }
+            let start = (r + N - position - 1) & (N - 1);
+            for k in 0..symbol - 255 + THRESHOLD {
+                let b = ring[(start + k) & (N - 1)];
+                out.push(b);
+                ring[r] = b;
+                r = (r + 1) & (N - 1);
+            }
+        }
+    }
+    out
+}
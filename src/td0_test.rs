@@ -0,0 +1,202 @@
+#[cfg(test)]
+mod tests {
+    use crate::media::Media;
+    use crate::td0::{self, Huffman};
+    use crate::test_support::temp_path;
+
+    /// Greedy LZSS + adaptive Huffman, the inverse of `td0::lzhuf_decode`.
+    fn lzhuf_encode(data: &[u8]) -> Vec<u8> {
+        let mut huff = Huffman::new();
+        let mut bits: Vec<bool> = Vec::new();
+        let put = |code: u32, len: u32, bits: &mut Vec<bool>| {
+            for b in (0..len).rev() {
+                bits.push(code >> b & 1 != 0);
+            }
+        };
+        let mut i = 0;
+        while i < data.len() {
+            let (mut best_len, mut best_dist) = (0, 0);
+            for dist in 1..=i.min(4000) {
+                let mut len = 0;
+                while len < 60 && i + len < data.len() && data[i + len - dist] == data[i + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = dist;
+                }
+            }
+            if best_len >= 3 {
+                let (code, len) = huff.encode(253 + best_len);
+                put(code, len, &mut bits);
+                let (code, len) = td0::encode_position(best_dist - 1);
+                put(code, len, &mut bits);
+                i += best_len;
+            } else {
+                let (code, len) = huff.encode(data[i] as usize);
+                put(code, len, &mut bits);
+                i += 1;
+            }
+        }
+        bits.chunks(8)
+            .map(|c| c.iter().enumerate().fold(0u8, |acc, (k, &b)| acc | (b as u8) << (7 - k)))
+            .collect()
+    }
+
+    /// A stream compressed by Okumura's LZHUF.C, the encoder Teledisk
+    /// uses, without its 4-byte length prefix.  The leading spaces match
+    /// the ring buffer's initial fill, which `lzhuf_encode` never does.
+    #[test]
+    fn test_lzhuf_decodes_reference_stream() {
+        let packed = [
+            0x8D, 0x00, 0x75, 0xF9, 0xBE, 0x5E, 0xE7, 0x7B, 0xB7, 0xAC, 0xEA, 0xDE,
+            0xDF, 0x70, 0xB8, 0x1F, 0x7B, 0x9C, 0xFC, 0x1D, 0xDF, 0x66, 0xE0, 0x17,
+            0xC7, 0xFD, 0xFF, 0xAB, 0x7D, 0xBA, 0xB0, 0xCC, 0xE5, 0xB0, 0xF9, 0x5D,
+            0x0A, 0x38, 0x11, 0x80, 0x6E, 0x35, 0x34, 0x3B, 0xF6, 0xB1, 0xE2, 0xFB,
+            0x7B, 0xF9, 0x94, 0x52, 0xD1, 0x53, 0x0F, 0xC1, 0x4B, 0x07, 0x74, 0x67,
+            0x5F, 0x08, 0xDA, 0x80, 0xEE, 0x18, 0x5D, 0x16, 0xAC, 0xA9, 0x90, 0xFD,
+            0x24, 0xD5, 0x39, 0xB1, 0x7C, 0xB8, 0x3B, 0xC3, 0xCE, 0x9E, 0xEE, 0x28,
+            0x42, 0x00, 0xBA, 0xB2, 0x9B, 0x51, 0x00, 0x66, 0x40, 0x18, 0xCC, 0x6E,
+            0x38,
+        ];
+        let mut text = b"    KAYPRO II 64k CP/M vers 2.2\r\nA>DIR\r\n\
+            A: ASM      COM : DDT      COM : ED       COM\r\n\
+            A: PIP      COM : STAT     COM : SUBMIT   COM\r\nA>".to_vec();
+        text.extend_from_slice(&[0xE5; 100]);
+        text.extend_from_slice(&[0, 1, 2]);
+        assert_eq!(td0::lzhuf_decode(&packed), text);
+    }
+
+    fn fill(track: u8, side: u8, id: u8) -> u8 {
+        id.wrapping_add(track).wrapping_add(side * 0x40)
+    }
+
+    /// Teledisk body for a 3-cylinder DSDD Kaypro-style disk with an FM
+    /// track 0, using all three sector encodings, a CRC error and an
+    /// ID-only sector.
+    fn td0_body() -> Vec<u8> {
+        let mut body = Vec::new();
+        let comment = b"Kaypro test disk\0second line\0";
+        body.extend_from_slice(&[0, 0]); // CRC
+        body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        body.extend_from_slice(&[83, 4, 17, 12, 30, 0]); // 17/05/1983 12:30:00
+        body.extend_from_slice(comment);
+
+        for cylinder in 0..3u8 {
+            for head in 0..2u8 {
+                let fm = cylinder == 0 && head == 0;
+                let (n, ids): (u8, Vec<u8>) = if fm {
+                    (1, (1..=10).collect())
+                } else {
+                    (2, (0..10).map(|i| (i * 3) % 10 + head * 10).collect())
+                };
+                body.extend_from_slice(&[ids.len() as u8, cylinder, head | if fm { 0x80 } else { 0 }, 0]);
+                for (k, &id) in ids.iter().enumerate() {
+                    let size = 128usize << n;
+                    let value = fill(cylinder, head, id);
+                    let flags = match (cylinder, head, k) {
+                        (1, 0, 0) => 0x02,
+                        (1, 0, 1) => 0x20,
+                        _ => 0,
+                    };
+                    body.extend_from_slice(&[cylinder, head, id, n, flags, 0]);
+                    if flags & 0x30 != 0 {
+                        continue;
+                    }
+                    match k % 3 {
+                        0 => {
+                            body.extend_from_slice(&5u16.to_le_bytes());
+                            body.push(1);
+                            body.extend_from_slice(&((size / 2) as u16).to_le_bytes());
+                            body.extend_from_slice(&[value, value]);
+                        }
+                        1 => {
+                            // Literal head, repeated 4-byte tail
+                            let block = [2, 0, 4, value, 1, 2, 3, 2, ((size - 4) / 4) as u8, value, value, value, value];
+                            body.extend_from_slice(&(block.len() as u16).to_le_bytes());
+                            body.extend_from_slice(&block);
+                        }
+                        _ => {
+                            body.extend_from_slice(&(size as u16 + 1).to_le_bytes());
+                            body.push(0);
+                            body.extend(std::iter::repeat_n(value, size));
+                        }
+                    }
+                }
+            }
+        }
+        body.push(0xFF);
+        body
+    }
+
+    fn td0_file(advanced: bool) -> Vec<u8> {
+        let mut file = vec![if advanced { b't' } else { b'T' }, if advanced { b'd' } else { b'D' },
+            0, 0, 21, 0x00, 3, 0x80, 0, 2, 0, 0];
+        let body = td0_body();
+        if advanced {
+            file.extend_from_slice(&lzhuf_encode(&body));
+        } else {
+            file.extend_from_slice(&body);
+        }
+        file
+    }
+
+    #[test]
+    fn test_lzhuf_round_trip() {
+        let body = td0_body();
+        let compressed = lzhuf_encode(&body);
+        assert!(compressed.len() < body.len() / 4);
+        assert_eq!(td0::lzhuf_decode(&compressed)[..body.len()], body[..]);
+
+        // Enough symbols to force the Huffman tree to be rebuilt
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..36_000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        }).collect();
+        assert_eq!(td0::lzhuf_decode(&lzhuf_encode(&noise))[..noise.len()], noise[..]);
+    }
+
+    #[test]
+    fn test_td0_import() {
+        for advanced in [false, true] {
+            let image = td0::parse(&td0_file(advanced)).unwrap();
+            assert_eq!(image.comment(), "Kaypro test disk\r\nsecond line");
+            assert!(String::from_utf8_lossy(&image.header).contains("17/05/1983 12:30:00"));
+            assert_eq!(image.tracks.len(), 6);
+
+            let t0 = &image.tracks[0];
+            assert!(t0.single_density());
+            assert_eq!((t0.n, t0.sector_ids.len()), (1, 10));
+            assert_eq!(t0.data[1][..5], [fill(0, 0, 2), 1, 2, 3, fill(0, 0, 2)]);
+
+            let t2 = &image.tracks[2]; // cylinder 1 side 0
+            assert!(!t2.single_density());
+            assert_eq!(t2.sector_types[..3], [5, 0, 1]);
+            assert!(t2.data[1].is_empty());
+            assert_eq!(image.tracks[3].sector_ids[..3], [10, 13, 16]);
+        }
+    }
+
+    #[test]
+    fn test_td0_media_is_write_protected() {
+        let path = temp_path("td0.td0");
+        std::fs::write(&path, td0_file(true)).unwrap();
+        let mut media = Media::new(10);
+        let result = media.load_disk(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert!(media.is_write_protected());
+        assert!(media.file.is_none());
+        assert!(media.density_matches(false, 0, true));
+        assert!(media.density_matches(true, 2, false));
+        let (valid, index, last) = media.sector_index(true, 2, 17);
+        assert!(valid);
+        assert_eq!(media.content[index..last], vec![fill(2, 1, 17); 512][..]);
+        // Sector with no data field reads as unformatted fill
+        let (valid, index, _) = media.sector_index(false, 1, 3);
+        assert!(valid);
+        assert_eq!(media.content[index], 0xE5);
+    }
+}
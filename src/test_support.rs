@@ -0,0 +1,79 @@
+// Fixtures shared by the tests.
+
+use iz80::{Cpu, Machine};
+
+use crate::floppy_controller::FloppyController;
+use crate::hard_disk::HardDisk;
+use crate::kaypro_machine::{KayproMachine, VideoMode};
+use crate::media::MediaFormat;
+
+/// Path of the temporary file or directory `name` (e.g. "overlay_hd.img"),
+/// unique to this test run.
+pub fn temp_path(name: &str) -> String {
+    let mut path = std::env::temp_dir();
+    path.push(format!("izkaypro_{}_{}", std::process::id(), name));
+    path.to_string_lossy().into_owned()
+}
+
+/// DSDD floppy controller with the embedded disks in drives A and B.
+pub fn floppy_controller() -> FloppyController {
+    FloppyController::new(
+        "__nonexistent_test_a__",
+        "__nonexistent_test_b__",
+        MediaFormat::DsDd,
+        10,
+        false,
+        false,
+    )
+}
+
+/// Machine running `rom` with the embedded floppy disks, and a hard disk
+/// controller if `has_hard_disk`. It doesn't sleep waiting for keys.
+pub fn machine(rom: &str, video_mode: VideoMode, has_hard_disk: bool) -> KayproMachine {
+    let mut machine = KayproMachine::new(rom, video_mode, floppy_controller(),
+        has_hard_disk, false, false, false, false, false, false, false);
+    machine.keyboard.idle_sleep_enabled = false;
+    machine
+}
+
+/// Kaypro II at 2.5 MHz.
+pub fn kaypro_ii(has_hard_disk: bool) -> KayproMachine {
+    let mut machine = machine("roms/81-149c.rom", VideoMode::MemoryMapped, has_hard_disk);
+    machine.set_cpu_clock(2.5);
+    machine
+}
+
+/// Run for at least `tstates` T-states.
+pub fn run(machine: &mut KayproMachine, cpu: &mut Cpu, tstates: u64) {
+    let end = machine.tstates + tstates;
+    while machine.tstates < end {
+        machine.tick(cpu);
+    }
+}
+
+/// The 64K the CPU sees.
+pub fn memory(machine: &KayproMachine) -> Vec<u8> {
+    (0..=0xFFFF).map(|address| machine.peek(address)).collect()
+}
+
+/// Run hard disk `command` on cylinder 2, `head`, `sector` of LUN 1 with
+/// 512-byte sectors, moving `data` if the controller asks for it: the
+/// sector read into it, or written from it. Returns the error register.
+pub fn hd_command(hd: &mut HardDisk, command: u8, head: u8, sector: u8, data: &mut [u8]) -> u8 {
+    hd.write_register(0x86, 0x28 | head);
+    hd.write_register(0x84, 2);
+    hd.write_register(0x85, 0);
+    hd.write_register(0x83, sector);
+    hd.write_register(0x82, 17);
+    hd.write_register(0x87, command);
+    if hd.read_register(0x87) & 0x08 != 0 {
+        for b in data.iter_mut() {
+            if command & 0xF0 == 0x20 {
+                *b = hd.read_register(0x80);
+            } else {
+                hd.write_register(0x80, *b);
+            }
+        }
+    }
+    if hd.read_register(0x87) & 0x01 != 0 { hd.read_register(0x81) } else { 0 }
+}
@@ -0,0 +1,167 @@
+// Z80 instruction timing in T-states.
+//
+// iz80 doesn't count cycles, so the length and duration of an instruction
+// are worked out here from its opcode bytes.  Conditional jumps, calls and
+// returns, DJNZ and the repeating block instructions take longer when the
+// branch is taken (or the instruction repeats); the caller tells from the
+// PC after execution, which is anywhere but just past the instruction.
+//
+// Each DD/FD prefix adds 4 T-states to the HL instruction it modifies,
+// except where (HL) becomes (IX+d): those read a displacement and take
+// 19 (23 for INC/DEC and the shifts).  Undefined ED opcodes are 8 T-state
+// NOPs.  Reference: Zilog Z80 CPU User Manual (UM0080).
+
+pub struct Timing {
+    /// Length in bytes, prefixes included
+    pub length: u16,
+    /// T-states when the branch is not taken (or for any other instruction)
+    pub tstates: u32,
+    /// T-states when the branch is taken or the block instruction repeats
+    pub taken_tstates: u32,
+}
+
+impl Timing {
+    fn new(length: u16, tstates: u32) -> Timing {
+        Timing { length, tstates, taken_tstates: tstates }
+    }
+
+    fn branch(length: u16, tstates: u32, taken_tstates: u32) -> Timing {
+        Timing { length, tstates, taken_tstates }
+    }
+
+    /// T-states for an execution that left the PC at `pc_after`.
+    pub fn tstates_for(&self, pc: u16, pc_after: u16) -> u32 {
+        if pc_after == pc.wrapping_add(self.length) { self.tstates } else { self.taken_tstates }
+    }
+}
+
+/// T-states of an interrupt acknowledge: NMI, and mode 2 with the vector
+/// fetch and the push of the return address.
+pub const NMI_TSTATES: u32 = 11;
+pub const IM2_TSTATES: u32 = 19;
+
+/// Timing of the instruction at `pc`, reading memory through `peek`.
+pub fn instruction(peek: impl Fn(u16) -> u8, pc: u16) -> Timing {
+    let mut prefixes: u16 = 0;
+    let mut op = peek(pc);
+    while op == 0xDD || op == 0xFD {
+        prefixes += 1;
+        op = peek(pc.wrapping_add(prefixes));
+    }
+    let prefix_tstates = 4 * prefixes as u32;
+    let next = pc.wrapping_add(prefixes + 1);
+    let mut timing = match op {
+        0xCB if prefixes > 0 => {
+            // DD CB d op: the opcode comes after the displacement
+            let op = peek(next.wrapping_add(1));
+            Timing::new(3, if op & 0xC0 == 0x40 { 16 } else { 19 })
+        }
+        0xCB => cb(peek(next)),
+        0xED => ed(peek(next)),
+        _ if prefixes > 0 && uses_hl_indirect(op) => indexed(op),
+        _ => unprefixed(op),
+    };
+    timing.length += prefixes;
+    timing.tstates += prefix_tstates;
+    timing.taken_tstates += prefix_tstates;
+    timing
+}
+
+/// Unprefixed opcodes that address memory through (HL).
+fn uses_hl_indirect(op: u8) -> bool {
+    match op {
+        0x34..=0x36 => true,
+        0x76 => false, // HALT
+        0x40..=0x7F => op & 0x07 == 6 || op & 0xF8 == 0x70,
+        0x80..=0xBF => op & 0x07 == 6,
+        _ => false,
+    }
+}
+
+/// (IX+d) forms of the (HL) instructions, before the prefix is counted.
+fn indexed(op: u8) -> Timing {
+    match op {
+        0x34 | 0x35 => Timing::new(2, 19), // INC/DEC (IX+d)
+        0x36 => Timing::new(3, 15),        // LD (IX+d),n
+        _ => Timing::new(2, 15),           // LD r,(IX+d), LD (IX+d),r, ALU A,(IX+d)
+    }
+}
+
+fn unprefixed(op: u8) -> Timing {
+    let r_is_hl = op & 0x07 == 6;
+    match op {
+        // LD r,r' and the ALU: 4, or 7 through (HL)
+        0x76 => Timing::new(1, 4), // HALT
+        0x70..=0x77 => Timing::new(1, 7),
+        0x40..=0xBF => Timing::new(1, if r_is_hl { 7 } else { 4 }),
+
+        0x00 | 0x07 | 0x08 | 0x0F | 0x17 | 0x1F | 0x27 | 0x2F | 0x37 | 0x3F => Timing::new(1, 4),
+        0x01 | 0x11 | 0x21 | 0x31 => Timing::new(3, 10), // LD rr,nn
+        0x02 | 0x12 | 0x0A | 0x1A => Timing::new(1, 7),  // LD (BC),A etc.
+        0x03 | 0x13 | 0x23 | 0x33 | 0x0B | 0x1B | 0x2B | 0x3B => Timing::new(1, 6),
+        0x34 | 0x35 => Timing::new(1, 11),               // INC/DEC (HL)
+        0x04..=0x3D if op & 0x06 == 0x04 => Timing::new(1, 4), // INC/DEC r
+        0x36 => Timing::new(2, 10),                      // LD (HL),n
+        0x06..=0x3E if op & 0x07 == 0x06 => Timing::new(2, 7), // LD r,n
+        0x09 | 0x19 | 0x29 | 0x39 => Timing::new(1, 11), // ADD HL,rr
+        0x10 => Timing::branch(2, 8, 13),                // DJNZ
+        0x18 => Timing::new(2, 12),                      // JR
+        0x20 | 0x28 | 0x30 | 0x38 => Timing::branch(2, 7, 12),
+        0x22 | 0x2A => Timing::new(3, 16),               // LD (nn),HL / LD HL,(nn)
+        0x32 | 0x3A => Timing::new(3, 13),               // LD (nn),A / LD A,(nn)
+
+        0xC0..=0xFF => match op & 0x07 {
+            0 => Timing::branch(1, 5, 11),               // RET cc
+            1 => match op {
+                0xC9 => Timing::new(1, 10),              // RET
+                0xD9 => Timing::new(1, 4),               // EXX
+                0xE9 => Timing::new(1, 4),               // JP (HL)
+                0xF9 => Timing::new(1, 6),               // LD SP,HL
+                _ => Timing::new(1, 10),                 // POP
+            },
+            2 => Timing::new(3, 10),                     // JP cc,nn
+            3 => match op {
+                0xC3 => Timing::new(3, 10),              // JP nn
+                0xD3 | 0xDB => Timing::new(2, 11),       // OUT (n),A / IN A,(n)
+                0xE3 => Timing::new(1, 19),              // EX (SP),HL
+                _ => Timing::new(1, 4),                  // EX DE,HL, DI, EI
+            },
+            4 => Timing::branch(3, 10, 17),              // CALL cc,nn
+            5 if op == 0xCD => Timing::new(3, 17),       // CALL nn
+            5 => Timing::new(1, 11),                     // PUSH
+            6 => Timing::new(2, 7),                      // ALU A,n
+            _ => Timing::new(1, 11),                     // RST
+        },
+        _ => Timing::new(1, 4),
+    }
+}
+
+fn cb(op: u8) -> Timing {
+    let memory = op & 0x07 == 6;
+    match (memory, op & 0xC0 == 0x40) {
+        (false, _) => Timing::new(2, 8),
+        (true, true) => Timing::new(2, 12),  // BIT b,(HL)
+        (true, false) => Timing::new(2, 15),
+    }
+}
+
+fn ed(op: u8) -> Timing {
+    match op {
+        0x40..=0x7F => match op & 0x07 {
+            0 | 1 => Timing::new(2, 12),                 // IN r,(C) / OUT (C),r
+            2 => Timing::new(2, 15),                     // SBC/ADC HL,rr
+            3 => Timing::new(4, 20),                     // LD (nn),rr / LD rr,(nn)
+            4 => Timing::new(2, 8),                      // NEG
+            5 => Timing::new(2, 14),                     // RETN / RETI
+            6 => Timing::new(2, 8),                      // IM
+            _ => match op {
+                0x67 | 0x6F => Timing::new(2, 18),       // RRD / RLD
+                0x77 | 0x7F => Timing::new(2, 8),
+                _ => Timing::new(2, 9),                  // LD I,A / LD R,A / LD A,I / LD A,R
+            },
+        },
+        0xA0..=0xA3 | 0xA8..=0xAB => Timing::new(2, 16),
+        0xB0..=0xB3 | 0xB8..=0xBB => Timing::branch(2, 16, 21),
+        _ => Timing::new(2, 8),
+    }
+}
@@ -0,0 +1,77 @@
+#[cfg(test)]
+mod tests {
+    use iz80::{Cpu, Machine};
+
+    use crate::kaypro_machine::{KayproMachine, VideoMode};
+    use crate::sy6545::Sy6545;
+    use crate::test_support::machine;
+    use crate::z80_timing;
+
+    /// (not taken, taken) T-states and the length of `code` at address 0.
+    fn timing(code: &[u8]) -> (u32, u32, u16) {
+        let t = z80_timing::instruction(|a| code.get(a as usize).copied().unwrap_or(0), 0);
+        (t.tstates, t.taken_tstates, t.length)
+    }
+
+    fn kaypro_4(video_mode: VideoMode) -> KayproMachine {
+        machine("roms/81-292a.rom", video_mode, false)
+    }
+
+    #[test]
+    fn test_instruction_timing() {
+        assert_eq!(timing(&[0x00]), (4, 4, 1));                   // NOP
+        assert_eq!(timing(&[0x7E]), (7, 7, 1));                   // LD A,(HL)
+        assert_eq!(timing(&[0x34]), (11, 11, 1));                 // INC (HL)
+        assert_eq!(timing(&[0x10, 0xFE]), (8, 13, 2));            // DJNZ
+        assert_eq!(timing(&[0x20, 0xFE]), (7, 12, 2));            // JR NZ
+        assert_eq!(timing(&[0xC4, 0, 0]), (10, 17, 3));           // CALL NZ,nn
+        assert_eq!(timing(&[0xC0]), (5, 11, 1));                  // RET NZ
+        assert_eq!(timing(&[0xCD, 0, 0]), (17, 17, 3));           // CALL nn
+        assert_eq!(timing(&[0xCB, 0x46]), (12, 12, 2));           // BIT 0,(HL)
+        assert_eq!(timing(&[0xED, 0xB0]), (16, 21, 2));           // LDIR
+        assert_eq!(timing(&[0xED, 0x43, 0, 0]), (20, 20, 4));     // LD (nn),BC
+        assert_eq!(timing(&[0xDD, 0x21, 0, 0]), (14, 14, 4));     // LD IX,nn
+        assert_eq!(timing(&[0xDD, 0x7E, 5]), (19, 19, 3));        // LD A,(IX+d)
+        assert_eq!(timing(&[0xDD, 0x36, 5, 0]), (19, 19, 4));     // LD (IX+d),n
+        assert_eq!(timing(&[0xFD, 0x34, 5]), (23, 23, 3));        // INC (IY+d)
+        assert_eq!(timing(&[0xDD, 0xCB, 5, 0x46]), (20, 20, 4));  // BIT 0,(IX+d)
+        assert_eq!(timing(&[0xDD, 0xCB, 5, 0x06]), (23, 23, 4));  // RLC (IX+d)
+        assert_eq!(timing(&[0xDD, 0xE9]), (8, 8, 2));             // JP (IX)
+    }
+
+    #[test]
+    fn test_step_counts_tstates() {
+        let mut machine = kaypro_4(VideoMode::MemoryMapped);
+        let mut cpu = Cpu::new_z80();
+        // LD B,10; DJNZ $; HALT
+        for (i, &b) in [0x06, 10, 0x10, 0xFE, 0x76].iter().enumerate() {
+            machine.poke(0x8000 + i as u16, b);
+        }
+        cpu.registers().set_pc(0x8000);
+
+        let mut tstates = 0;
+        while !cpu.is_halted() {
+            tstates += machine.step(&mut cpu);
+        }
+        assert_eq!(tstates, 7 + 9 * 13 + 8 + 4);
+        // A halted CPU keeps running NOPs
+        assert_eq!(machine.step(&mut cpu), 4);
+        assert_eq!(machine.tstates, 140);
+        // 140 T-states at the Kaypro II's 2.5 MHz
+        assert_eq!(machine.now_us(), 56);
+
+        machine.set_cpu_clock(4.0);
+        machine.step(&mut cpu);
+        assert_eq!(machine.now_us(), 57);
+    }
+
+    #[test]
+    fn test_vertical_retrace_follows_time() {
+        let mut crtc = Sy6545::new();
+        assert_eq!(crtc.read_port_1c() & 0x20, 0);
+        crtc.now_us = 16_000;
+        assert_eq!(crtc.read_port_1c() & 0x20, 0x20);
+        crtc.now_us = 16_667 + 100;
+        assert_eq!(crtc.read_port_1c() & 0x20, 0);
+    }
+}